@@ -0,0 +1,228 @@
+//! Spots as ADIF records, for importing into logging programs.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::AdifConfig;
+#[cfg(feature = "sqlite")]
+use crate::config::StoreConfig;
+use crate::metrics::Metrics;
+use crate::parser::{Activity, DxEntry};
+use crate::sink::{self, Sink};
+#[cfg(feature = "sqlite")]
+use crate::store::{self, SpotQuery};
+use crate::utc;
+
+/// Modes of [crate::parser::MODES] which are submodes in ADIF.
+const SUBMODES: &[(&str, &str)] = &[
+    ("USB", "SSB"),
+    ("LSB", "SSB"),
+    ("FT4", "MFSK"),
+    ("JS8", "MFSK"),
+    ("PSK31", "PSK"),
+];
+
+/// Start of an ADIF file.
+pub fn header() -> String {
+    let mut out = String::from("Spots from puskapupu\n");
+    field(&mut out, "ADIF_VER", "3.1.4");
+    field(&mut out, "PROGRAMID", "puskapupu");
+    field(&mut out, "PROGRAMVERSION", env!("CARGO_PKG_VERSION"));
+    out.push_str("<EOH>\n");
+    out
+}
+
+/// `spot` as a record ending with `<EOR>`. The spot has only time of day,
+/// so the date is taken from when it was `received`.
+pub fn record(spot: &DxEntry, received: SystemTime) -> String {
+    let mut out = String::new();
+    field(&mut out, "CALL", &spot.dx.to_uppercase());
+    let (year, month, day) = spot.date(received);
+    field(
+        &mut out,
+        "QSO_DATE",
+        &format!("{year:04}{month:02}{day:02}"),
+    );
+    let time_on = match spot.minute_of_day() {
+        Some(_) => spot.timestamp.clone(),
+        None => {
+            let minute = utc::minute_of_day(received);
+            format!("{:02}{:02}", minute / 60, minute % 60)
+        }
+    };
+    field(&mut out, "TIME_ON", &time_on);
+    let frequency = spot.frequency_mhz_string();
+    field(&mut out, "FREQ", frequency.trim_end_matches(" MHz"));
+    if let Some(band) = spot.band() {
+        field(&mut out, "BAND", band.name());
+    }
+    if let Some(mode) = spot.mode() {
+        match SUBMODES.iter().find(|(submode, _)| *submode == mode) {
+            Some((submode, mode)) => {
+                field(&mut out, "MODE", mode);
+                field(&mut out, "SUBMODE", submode);
+            }
+            None => field(&mut out, "MODE", mode),
+        }
+    }
+    let activity = spot.cqgma_identifier.map(|(activity, _)| activity);
+    let reference = spot.references().into_iter().next();
+    if let (Some(activity), Some(reference)) = (activity, reference) {
+        match activity {
+            Activity::Sota => field(&mut out, "SOTA_REF", &reference),
+            Activity::Wwff => field(&mut out, "WWFF_REF", &reference),
+            Activity::Iota => field(&mut out, "IOTA", &reference),
+            _ => (),
+        }
+        field(&mut out, "SIG", &activity.name().to_uppercase());
+        field(&mut out, "SIG_INFO", &reference);
+    }
+    if !spot.info.is_empty() {
+        field(&mut out, "COMMENT", &spot.info);
+    }
+    field(&mut out, "APP_PUSKAPUPU_SPOTTER", &spot.reporter);
+    out.push_str("<EOR>\n");
+    out
+}
+
+/// Header followed by a record of each spot and when it was received.
+pub fn export<'a>(spots: impl IntoIterator<Item = (SystemTime, &'a DxEntry)>) -> String {
+    let mut out = header();
+    for (received, spot) in spots {
+        out.push('\n');
+        out.push_str(&record(spot, received));
+    }
+    out
+}
+
+/// Spots in the store matching `query` as ADIF, oldest first.
+#[cfg(feature = "sqlite")]
+pub async fn export_store(config: StoreConfig, query: SpotQuery) -> io::Result<String> {
+    let spots = store::query_received(config, query).await?;
+    Ok(export(
+        spots.iter().rev().map(|(received, spot)| (*received, spot)),
+    ))
+}
+
+fn field(out: &mut String, name: &str, value: &str) {
+    out.push_str(&format!("<{name}:{}>{value} ", value.len()));
+}
+
+/// Appends each spot to an ADIF file, writing the header first if the
+/// file is new.
+pub struct AdifSink {
+    name: String,
+    path: PathBuf,
+}
+
+impl AdifSink {
+    pub fn new(config: &AdifConfig) -> Self {
+        Self {
+            name: format!("adif {}", config.path.display()),
+            path: config.path.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for AdifSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut out = String::new();
+        if file.metadata()?.len() == 0 {
+            out.push_str(&header());
+        }
+        out.push('\n');
+        out.push_str(&record(spot, SystemTime::now()));
+        file.write_all(out.as_bytes())
+    }
+}
+
+/// Append spots from `spots` to the file of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: AdifConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = AdifSink::new(&config);
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{record, AdifSink};
+    use crate::config::AdifConfig;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+
+    /// 2024-03-01 12:00 UTC
+    fn received() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_709_294_400)
+    }
+
+    #[test]
+    fn test_sota_record() {
+        let spot: DxEntry =
+            "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101 ssb             1959Z"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            record(&spot, received()),
+            "<CALL:5>AD6VT <QSO_DATE:8>20240229 <TIME_ON:4>1959 <FREQ:6>14.310 <BAND:3>20m \
+             <MODE:3>SSB <SOTA_REF:9>W6/ND-101 <SIG:4>SOTA <SIG_INFO:9>W6/ND-101 \
+             <COMMENT:13>W6/ND-101 ssb <APP_PUSKAPUPU_SPOTTER:5>AD6VT <EOR>\n"
+        );
+    }
+
+    #[test]
+    fn test_wwff_record() {
+        let spot: DxEntry =
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            record(&spot, received()),
+            "<CALL:8>OH2NOS/P <QSO_DATE:8>20240301 <TIME_ON:4>1146 <FREQ:5>3.644 <BAND:3>80m \
+             <WWFF_REF:9>OHFF-1419 <SIG:4>WWFF <SIG_INFO:9>OHFF-1419 \
+             <COMMENT:18>OHFF-1419 New one! <APP_PUSKAPUPU_SPOTTER:6>OH2NOS <EOR>\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sink_writes_header_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = AdifSink::new(&AdifConfig {
+            path: dir.path().join("spots.adi"),
+        });
+        let spot: DxEntry =
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+                .parse()
+                .unwrap();
+        sink.send(&spot).await.unwrap();
+        sink.send(&spot).await.unwrap();
+
+        let adif = std::fs::read_to_string(dir.path().join("spots.adi")).unwrap();
+        assert!(adif.starts_with("Spots from puskapupu\n<ADIF_VER:5>3.1.4 "));
+        assert_eq!(adif.matches("<EOH>").count(), 1);
+        assert_eq!(adif.matches("<CALL:8>OH2NOS/P ").count(), 2);
+    }
+}
@@ -0,0 +1,258 @@
+//! Sending spots as APRS messages through APRS-IS.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{AprsConfig, DEFAULT_APRS_TEMPLATE};
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+use crate::template::Template;
+
+/// Destination of the packets, which tells the software. `APZ` is for
+/// experimental software.
+const TOCALL: &str = "APZPKP";
+/// Longest message text APRS allows.
+const MAX_TEXT_LEN: usize = 67;
+/// Characters which can't be in message text.
+const FORBIDDEN: &str = "|~{";
+/// Time for connecting and logging in.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct AprsSink {
+    name: String,
+    server: String,
+    callsign: String,
+    passcode: u16,
+    to: String,
+    template: Template,
+    /// Logged in connection, or `None` until the next send connects
+    connection: Mutex<Option<TcpStream>>,
+}
+
+impl AprsSink {
+    pub fn new(config: &AprsConfig) -> Self {
+        let template = config.template.clone().unwrap_or_else(|| {
+            Template::parse(DEFAULT_APRS_TEMPLATE).expect("default template is valid")
+        });
+        Self {
+            name: format!("aprs {}", config.server),
+            server: config.server.clone(),
+            callsign: config.callsign.to_ascii_uppercase(),
+            passcode: config.passcode,
+            to: config.to.to_ascii_uppercase(),
+            template,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Message packet for `spot`, ending with CR LF.
+    fn packet(&self, spot: &DxEntry) -> String {
+        let mut text: String = self
+            .template
+            .render(spot)
+            .chars()
+            .filter(|c| !c.is_control())
+            .map(|c| {
+                if c.is_ascii() && !FORBIDDEN.contains(c) {
+                    c
+                } else {
+                    '?'
+                }
+            })
+            .collect();
+        text.truncate(MAX_TEXT_LEN);
+        format!(
+            "{}>{TOCALL},TCPIP*::{:<9}:{}\r\n",
+            self.callsign,
+            self.to,
+            text.trim_end()
+        )
+    }
+
+    async fn connect(&self) -> io::Result<TcpStream> {
+        let connect = async {
+            let mut stream = TcpStream::connect(&self.server).await?;
+            stream.set_nodelay(true)?;
+            login(&mut stream, &self.callsign, self.passcode).await?;
+            Ok(stream)
+        };
+        tokio::time::timeout(LOGIN_TIMEOUT, connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "APRS-IS login timed out"))?
+    }
+}
+
+#[async_trait]
+impl Sink for AprsSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Connects first if not connected. A failed connection fails the send,
+    /// so reconnecting backs off like any failing sink.
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let packet = self.packet(spot);
+        let mut connection = self.connection.lock().await;
+        if connection.as_ref().map_or(false, |stream| !is_open(stream)) {
+            tracing::warn!("Lost connection to APRS-IS server. Reconnecting.");
+            *connection = None;
+        }
+        let stream = match connection.as_mut() {
+            Some(stream) => stream,
+            None => {
+                let stream = self.connect().await?;
+                tracing::info!("Logged in to APRS-IS server as {}", self.callsign);
+                connection.insert(stream)
+            }
+        };
+        tracing::debug!("aprs tx: ^{}$", packet.trim_end());
+        if let Err(err) = stream.write_all(packet.as_bytes()).await {
+            *connection = None;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        match self.connection.lock().await.take() {
+            Some(mut stream) => stream.shutdown().await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Log in and wait for the server to verify the passcode.
+async fn login(stream: &mut TcpStream, callsign: &str, passcode: u16) -> io::Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+    stream
+        .write_all(
+            format!("user {callsign} pass {passcode} vers puskapupu {version}\r\n").as_bytes(),
+        )
+        .await?;
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        tracing::trace!("aprs rx: ^{line}$");
+        // eg. `# logresp OH8HUB-10 verified, server T2FINLAND`
+        let mut words = line.split_whitespace();
+        if words.nth(1) != Some("logresp") {
+            continue;
+        }
+        return match words.nth(1).map(|status| status.trim_end_matches(',')) {
+            Some("verified") => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("APRS-IS login not verified: {line}"),
+            )),
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "APRS-IS server closed the connection during login",
+    ))
+}
+
+/// Read away whatever the server has sent, eg. keepalives. Returns whether
+/// the connection is still open.
+fn is_open(stream: &TcpStream) -> bool {
+    let mut buf = [0; 1024];
+    loop {
+        match stream.try_read(&mut buf) {
+            Ok(0) => return false,
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Send spots from `spots` to the APRS-IS server of `config` until
+/// `shutdown` is cancelled.
+pub async fn run(
+    config: AprsConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = AprsSink::new(&config);
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::AprsSink;
+    use crate::config::AprsConfig;
+    use crate::sink::Sink;
+    use crate::template::Template;
+
+    fn config(server: String) -> AprsConfig {
+        AprsConfig {
+            server,
+            callsign: "n0call-10".to_string(),
+            passcode: 13023,
+            to: "BLN1DX".to_string(),
+            template: None,
+        }
+    }
+
+    #[test]
+    fn test_packet() {
+        let mut sink = AprsSink::new(&config("rotate.aprs2.net:14580".to_string()));
+        let spot = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            sink.packet(&spot),
+            "N0CALL-10>APZPKP,TCPIP*::BLN1DX   :OH2NOS/P 3.644 MHz OHFF-1419 New one! de OH2NOS\r\n"
+        );
+
+        sink.template = Template::parse("{dx} ~{info}| ä").unwrap();
+        assert_eq!(
+            sink.packet(&spot),
+            "N0CALL-10>APZPKP,TCPIP*::BLN1DX   :OH2NOS/P ?OHFF-1419 New one!? ?\r\n"
+        );
+
+        sink.template = Template::parse(&"{info}".repeat(10)).unwrap();
+        let packet = sink.packet(&spot);
+        let text = packet.split_once("::BLN1DX   :").unwrap().1.trim_end();
+        assert_eq!(text.len(), 67);
+    }
+
+    #[tokio::test]
+    async fn test_login_and_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink = AprsSink::new(&config(listener.local_addr().unwrap().to_string()));
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(b"# aprsc 2.1.14\r\n").await.unwrap();
+            let mut lines = BufReader::new(socket).lines();
+            let login = lines.next_line().await.unwrap().unwrap();
+            lines
+                .get_mut()
+                .write_all(b"# logresp N0CALL-10 verified, server T2TEST\r\n")
+                .await
+                .unwrap();
+            let packet = lines.next_line().await.unwrap().unwrap();
+            (login, packet)
+        });
+
+        let spot = "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z"
+            .parse()
+            .unwrap();
+        sink.send(&spot).await.unwrap();
+        let (login, packet) = server.await.unwrap();
+        assert!(login.starts_with("user N0CALL-10 pass 13023 vers puskapupu "));
+        assert!(packet.starts_with("N0CALL-10>APZPKP,TCPIP*::BLN1DX   :AD6VT 14.310 MHz W6/ND-101"));
+    }
+}
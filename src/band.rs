@@ -0,0 +1,110 @@
+//! Amateur radio bands.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Band {
+    B160m,
+    B80m,
+    B60m,
+    B40m,
+    B30m,
+    B20m,
+    B17m,
+    B15m,
+    B12m,
+    B10m,
+    B6m,
+    B4m,
+    B2m,
+    B70cm,
+    B23cm,
+}
+
+/// Band name and edges in kHz. Edges are the widest found in IARU regions.
+const BANDS: &[(Band, &str, f32, f32)] = &[
+    (Band::B160m, "160m", 1800.0, 2000.0),
+    (Band::B80m, "80m", 3500.0, 4000.0),
+    (Band::B60m, "60m", 5250.0, 5450.0),
+    (Band::B40m, "40m", 7000.0, 7300.0),
+    (Band::B30m, "30m", 10100.0, 10150.0),
+    (Band::B20m, "20m", 14000.0, 14350.0),
+    (Band::B17m, "17m", 18068.0, 18168.0),
+    (Band::B15m, "15m", 21000.0, 21450.0),
+    (Band::B12m, "12m", 24890.0, 24990.0),
+    (Band::B10m, "10m", 28000.0, 29700.0),
+    (Band::B6m, "6m", 50000.0, 54000.0),
+    (Band::B4m, "4m", 70000.0, 70500.0),
+    (Band::B2m, "2m", 144000.0, 148000.0),
+    (Band::B70cm, "70cm", 430000.0, 440000.0),
+    (Band::B23cm, "23cm", 1240000.0, 1300000.0),
+];
+
+impl Band {
+    /// Band of the frequency given in kHz.
+    pub fn from_khz(khz: f32) -> Option<Band> {
+        BANDS
+            .iter()
+            .find(|(_, _, low, high)| *low <= khz && khz <= *high)
+            .map(|(band, _, _, _)| *band)
+    }
+
+    pub fn name(&self) -> &'static str {
+        BANDS
+            .iter()
+            .find(|(band, _, _, _)| band == self)
+            .map_or("", |(_, name, _, _)| name)
+    }
+}
+
+impl fmt::Display for Band {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Band {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        BANDS
+            .iter()
+            .find(|(_, name, _, _)| name.eq_ignore_ascii_case(s))
+            .map(|(band, _, _, _)| *band)
+            .ok_or_else(|| format!("unknown band '{s}'"))
+    }
+}
+
+impl TryFrom<String> for Band {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Band> for String {
+    fn from(band: Band) -> String {
+        band.name().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Band;
+
+    #[test]
+    fn test_band() {
+        assert_eq!(Band::from_khz(3644.0), Some(Band::B80m));
+        assert_eq!(Band::from_khz(14044.0), Some(Band::B20m));
+        assert_eq!(Band::from_khz(145525.0), Some(Band::B2m));
+        assert_eq!(Band::from_khz(11000.0), None);
+        assert_eq!("40M".parse(), Ok(Band::B40m));
+        assert_eq!(Band::B70cm.to_string(), "70cm");
+        assert!("41m".parse::<Band>().is_err());
+    }
+}
@@ -1,40 +1,512 @@
-use std::path::PathBuf;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use argh::FromArgs;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch};
+use tokio_util::sync::CancellationToken;
 
-use puskapupu::{config, cqgma, matrix};
+use puskapupu::config::{Config, LoggingConfig, MatrixConfig};
+use puskapupu::dead_letter::DeadLetter;
+use puskapupu::filter::FilterConfig;
+#[cfg(feature = "websocket")]
+use puskapupu::live::{self, Live};
+use puskapupu::lookup::Lookup;
+use puskapupu::map::{self, SpotMap};
+use puskapupu::metrics::{self, Metrics, LOG_INTERVAL};
+use puskapupu::parser::DxEntry;
+use puskapupu::route::{self, Router};
+use puskapupu::status::Status;
+use puskapupu::supervisor::{self, Supervisor, Task, SHUTDOWN_TIMEOUT};
+use puskapupu::{cqgma, cty, example, http, logging, matrix, reload, watchdog};
 
 /// A Matrix bot alerting hunters for movements of activators
 #[derive(Debug, FromArgs)]
 struct Cli {
     /// config file
     #[argh(option, short = 'c', long = "config")]
-    config: PathBuf,
+    config: Option<PathBuf>,
+    /// print an example config to stdout and exit
+    #[argh(switch)]
+    print_example_config: bool,
+    /// exit when any task finishes instead of restarting it
+    #[argh(switch)]
+    no_restart: bool,
+    /// print forwarded spots to stdout as JSON lines and log to stderr
+    #[argh(switch)]
+    stdout_json: bool,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
     let cli: Cli = argh::from_env();
+    if cli.print_example_config {
+        print!("{}", example::example_toml());
+        return Ok(());
+    }
+    let Some(config_path) = cli.config else {
+        anyhow::bail!("--config is required");
+    };
+
+    let mut config = Config::read_from_file(&config_path)?;
+    init_logging(&config.logging, cli.stdout_json)?;
 
-    let config = config::Config::read_from_file(cli.config)?;
-    let mut fut = Vec::new();
+    if let Some(path) = &config.cty_path {
+        match cty::CtyDat::load(path) {
+            Ok(database) => {
+                tracing::info!("Read {} prefixes from {}", database.len(), path.display());
+                cty::install(database);
+            }
+            Err(err) => tracing::warn!("Couldn't read {}: {err}", path.display()),
+        }
+    }
 
     tracing::info!("Staring CQGMA stuff...");
-    let cqgma_state = cqgma::cqgma_init(&config.cqgma).await;
-    fut.push(cqgma_state.handle);
+    let (filter_tx, filter_rx) = watch::channel(config.filter.clone());
+    // Clusters stop first, so the rest can drain what was already received
+    let intake = CancellationToken::new();
+    let shutdown = CancellationToken::new();
+    let status = Arc::new(Status::new(config.cqgma.len(), config.matrix.len()));
+    let dead_letter = config
+        .dead_letter
+        .as_ref()
+        .map(|config| Arc::new(DeadLetter::new(config)));
+    let cqgma_state = cqgma::cqgma_init(
+        &config.cqgma,
+        filter_rx,
+        dead_letter,
+        config.channel_limits(),
+        intake.clone(),
+        &status,
+    )
+    .await;
+    let mut tasks = Vec::new();
+
+    let router = Arc::new(Router::new(
+        &config.route,
+        &config.dedup,
+        cqgma_state.spots.clone(),
+        config.channel_limits(),
+    ));
+    if !router.is_empty() {
+        let (router, shutdown) = (router.clone(), shutdown.clone());
+        tasks.push(Task::new("route", move || {
+            route::run(router.clone(), shutdown.clone())
+        }));
+    }
 
     tracing::info!("Starting Matrix stuff...");
-    let handles = matrix::matrix_init(&config.matrix, cqgma_state.telnet_rx).await?;
-    fut.extend(handles);
+    let filter_tx = Arc::new(filter_tx);
+    let lookup = config
+        .lookup
+        .as_ref()
+        .map(|config| Arc::new(Lookup::from_config(config)));
+    let mut matrix_tx = Vec::new();
+    for (account, synced) in config.matrix.iter().zip(&status.matrix) {
+        let (updates_tx, updates_rx) = watch::channel(account.clone());
+        let name = format!("matrix {}", account.user_id);
+        let account = account.clone();
+        let home_grid = config.home_grid.clone();
+        let spots = router.spots(&name);
+        let filter_tx = filter_tx.clone();
+        let shutdown = shutdown.clone();
+        let synced = synced.clone();
+        let metrics = status.metrics.clone();
+        let lookup = lookup.clone();
+        tasks.push(Task::new(name, move || {
+            let (account, home_grid, lookup) = (account.clone(), home_grid.clone(), lookup.clone());
+            let (room_rx, filter_tx, updates_rx) =
+                (spots.subscribe(), filter_tx.clone(), updates_rx.clone());
+            let (shutdown, synced, metrics) = (shutdown.clone(), synced.clone(), metrics.clone());
+            async move {
+                let handles = matrix::matrix_init(
+                    &account,
+                    home_grid.as_deref(),
+                    room_rx,
+                    filter_tx,
+                    updates_rx,
+                    shutdown,
+                    synced,
+                    metrics,
+                    lookup,
+                )
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("{err:#}")))?;
+                supervisor::first_finished(handles).await
+            }
+        }));
+        matrix_tx.push(updates_tx);
+    }
+
+    let sinks = SinkTasks {
+        router: &router,
+        metrics: &status.metrics,
+        shutdown: &shutdown,
+    };
+    if let Some(store) = &config.store {
+        #[cfg(feature = "sqlite")]
+        {
+            let store = store.clone();
+            tasks.push(sinks.task("store", move |spots, _, shutdown| {
+                puskapupu::store::run(store.clone(), spots, shutdown)
+            }));
+        }
+        #[cfg(not(feature = "sqlite"))]
+        tracing::warn!("Ignoring [store] {store:?}: built without the sqlite feature");
+    }
+
+    if let Some(mqtt) = &config.mqtt {
+        #[cfg(feature = "mqtt")]
+        {
+            let mqtt = mqtt.clone();
+            tasks.push(sinks.task("mqtt", move |spots, metrics, shutdown| {
+                puskapupu::mqtt::run(mqtt.clone(), spots, metrics, shutdown)
+            }));
+        }
+        #[cfg(not(feature = "mqtt"))]
+        tracing::warn!("Ignoring [mqtt] {mqtt:?}: built without the mqtt feature");
+    }
+
+    for (i, webhook) in config.webhook.iter().enumerate() {
+        #[cfg(feature = "webhook")]
+        {
+            let webhook = webhook.clone();
+            tasks.push(
+                sinks.task(format!("webhook[{i}]"), move |spots, metrics, shutdown| {
+                    puskapupu::webhook::run(webhook.clone(), i, spots, metrics, shutdown)
+                }),
+            );
+        }
+        #[cfg(not(feature = "webhook"))]
+        tracing::warn!("Ignoring webhook[{i}] {webhook:?}: built without the webhook feature");
+    }
+
+    for (i, discord) in config.discord.iter().enumerate() {
+        #[cfg(feature = "discord")]
+        {
+            let discord = discord.clone();
+            tasks.push(
+                sinks.task(format!("discord[{i}]"), move |spots, metrics, shutdown| {
+                    puskapupu::discord::run(discord.clone(), i, spots, metrics, shutdown)
+                }),
+            );
+        }
+        #[cfg(not(feature = "discord"))]
+        tracing::warn!("Ignoring discord[{i}] {discord:?}: built without the discord feature");
+    }
+
+    for (i, telegram) in config.telegram.iter().enumerate() {
+        #[cfg(feature = "telegram")]
+        {
+            let telegram = telegram.clone();
+            tasks.push(
+                sinks.task(format!("telegram[{i}]"), move |spots, metrics, shutdown| {
+                    puskapupu::telegram::run(telegram.clone(), i, spots, metrics, shutdown)
+                }),
+            );
+        }
+        #[cfg(not(feature = "telegram"))]
+        tracing::warn!("Ignoring telegram[{i}] {telegram:?}: built without the telegram feature");
+    }
+
+    for (i, ntfy) in config.ntfy.iter().enumerate() {
+        #[cfg(feature = "ntfy")]
+        {
+            let (ntfy, lookup) = (ntfy.clone(), lookup.clone());
+            tasks.push(
+                sinks.task(format!("ntfy[{i}]"), move |spots, metrics, shutdown| {
+                    puskapupu::ntfy::run(ntfy.clone(), i, spots, lookup.clone(), metrics, shutdown)
+                }),
+            );
+        }
+        #[cfg(not(feature = "ntfy"))]
+        tracing::warn!("Ignoring ntfy[{i}] {ntfy:?}: built without the ntfy feature");
+    }
+
+    if let Some(email) = &config.email {
+        #[cfg(feature = "email")]
+        {
+            let (email, lookup) = (email.clone(), lookup.clone());
+            tasks.push(sinks.task("email", move |spots, metrics, shutdown| {
+                puskapupu::email::run(email.clone(), spots, lookup.clone(), metrics, shutdown)
+            }));
+        }
+        #[cfg(not(feature = "email"))]
+        tracing::warn!("Ignoring [email] {email:?}: built without the email feature");
+    }
+
+    if let Some(aprs) = &config.aprs {
+        #[cfg(feature = "aprs")]
+        {
+            let aprs = aprs.clone();
+            tasks.push(sinks.task("aprs", move |spots, metrics, shutdown| {
+                puskapupu::aprs::run(aprs.clone(), spots, metrics, shutdown)
+            }));
+        }
+        #[cfg(not(feature = "aprs"))]
+        tracing::warn!("Ignoring [aprs] {aprs:?}: built without the aprs feature");
+    }
+
+    if let Some(nostr) = &config.nostr {
+        #[cfg(feature = "nostr")]
+        {
+            let nostr = nostr.clone();
+            tasks.push(sinks.task("nostr", move |spots, metrics, shutdown| {
+                puskapupu::nostr::run(nostr.clone(), spots, metrics, shutdown)
+            }));
+        }
+        #[cfg(not(feature = "nostr"))]
+        tracing::warn!("Ignoring [nostr] {nostr:?}: built without the nostr feature");
+    }
+
+    if let Some(xmpp) = &config.xmpp {
+        #[cfg(feature = "xmpp")]
+        {
+            let xmpp = xmpp.clone();
+            tasks.push(sinks.task("xmpp", move |spots, metrics, shutdown| {
+                puskapupu::xmpp::run(xmpp.clone(), spots, metrics, shutdown)
+            }));
+        }
+        #[cfg(not(feature = "xmpp"))]
+        tracing::warn!("Ignoring [xmpp] {xmpp:?}: built without the xmpp feature");
+    }
+
+    if let Some(influxdb) = &config.influxdb {
+        #[cfg(feature = "influxdb")]
+        {
+            let influxdb = influxdb.clone();
+            tasks.push(sinks.task("influxdb", move |spots, metrics, shutdown| {
+                puskapupu::influxdb::run(influxdb.clone(), spots, metrics, shutdown)
+            }));
+        }
+        #[cfg(not(feature = "influxdb"))]
+        tracing::warn!("Ignoring [influxdb] {influxdb:?}: built without the influxdb feature");
+    }
 
+    if let Some(kafka) = &config.kafka {
+        #[cfg(feature = "kafka")]
+        {
+            let kafka = kafka.clone();
+            tasks.push(sinks.task("kafka", move |spots, metrics, shutdown| {
+                puskapupu::kafka::run(kafka.clone(), spots, metrics, shutdown)
+            }));
+        }
+        #[cfg(not(feature = "kafka"))]
+        tracing::warn!("Ignoring [kafka] {kafka:?}: built without the kafka feature");
+    }
+
+    if let Some(sotawatch) = &config.sotawatch {
+        #[cfg(feature = "sotawatch")]
+        if sotawatch.enabled {
+            let sotawatch = sotawatch.clone();
+            tasks.push(sinks.task("sotawatch", move |spots, metrics, shutdown| {
+                puskapupu::sotawatch::run(sotawatch.clone(), spots, metrics, shutdown)
+            }));
+        } else {
+            tracing::info!("Not posting to SOTAwatch: [sotawatch] isn't enabled");
+        }
+        #[cfg(not(feature = "sotawatch"))]
+        tracing::warn!("Ignoring [sotawatch] {sotawatch:?}: built without the sotawatch feature");
+    }
+
+    if let Some(pota_spots) = &config.pota_spots {
+        #[cfg(feature = "pota_spots")]
+        if pota_spots.enabled {
+            let pota_spots = pota_spots.clone();
+            tasks.push(sinks.task("pota_spots", move |spots, metrics, shutdown| {
+                puskapupu::pota_spots::run(pota_spots.clone(), spots, metrics, shutdown)
+            }));
+        } else {
+            tracing::info!("Not posting to POTA: [pota_spots] isn't enabled");
+        }
+        #[cfg(not(feature = "pota_spots"))]
+        tracing::warn!(
+            "Ignoring [pota_spots] {pota_spots:?}: built without the pota_spots feature"
+        );
+    }
+
+    if let Some(adif) = &config.adif {
+        let adif = adif.clone();
+        tasks.push(sinks.task("adif", move |spots, metrics, shutdown| {
+            puskapupu::adif::run(adif.clone(), spots, metrics, shutdown)
+        }));
+    }
+
+    if let Some(jsonl) = &config.jsonl {
+        let jsonl = jsonl.clone();
+        tasks.push(sinks.task("jsonl", move |spots, metrics, shutdown| {
+            puskapupu::jsonl::run(jsonl.clone(), spots, metrics, shutdown)
+        }));
+    }
+
+    if let Some(csv) = &config.csv {
+        let csv = csv.clone();
+        tasks.push(sinks.task("csv", move |spots, metrics, shutdown| {
+            puskapupu::csv::run(csv.clone(), spots, metrics, shutdown)
+        }));
+    }
+
+    if cli.stdout_json {
+        tasks.push(sinks.task("stdout", puskapupu::stdout::run));
+    }
+
+    // Cancelled by the watchdog if spots have stopped flowing
+    let stalled = CancellationToken::new();
+    if let Some(watchdog) = &config.watchdog {
+        let (watchdog, spots) = (watchdog.clone(), cqgma_state.spots.clone());
+        let (reconnect, stalled, shutdown) = (
+            cqgma_state.reconnect.clone(),
+            stalled.clone(),
+            shutdown.clone(),
+        );
+        tasks.push(Task::new("watchdog", move || {
+            watchdog::run(
+                watchdog.clone(),
+                spots.subscribe(),
+                reconnect.clone(),
+                stalled.clone(),
+                shutdown.clone(),
+            )
+        }));
+    }
+
+    let (metrics, shutdown_metrics) = (status.metrics.clone(), shutdown.clone());
+    tasks.push(Task::new("metrics", move || {
+        metrics::log_periodically(metrics.clone(), LOG_INTERVAL, shutdown_metrics.clone())
+    }));
+
+    if let Some(http) = &config.http {
+        let app = http::router(status.clone());
+        #[cfg(feature = "sqlite")]
+        let app = match &config.store {
+            Some(store) => app.merge(http::spots_router(store.clone())),
+            None => app,
+        };
+        #[cfg(feature = "websocket")]
+        let app = {
+            let live = Arc::new(Live::new(http.live_backlog, shutdown.clone()));
+            let (feed, spots) = (live.clone(), cqgma_state.spots.clone());
+            tasks.push(Task::new("live", move || {
+                live::run(feed.clone(), spots.subscribe())
+            }));
+            app.merge(live::router(live))
+        };
+        let app = {
+            let window = Duration::from_secs(http.map_minutes * 60);
+            let region = http.map_region.unwrap_or_default();
+            let map = Arc::new(SpotMap::new(window, region, lookup.clone()));
+            let (spot_map, spots) = (map.clone(), cqgma_state.spots.clone());
+            let shutdown = shutdown.clone();
+            tasks.push(Task::new("map", move || {
+                map::run(spot_map.clone(), spots.subscribe(), shutdown.clone())
+            }));
+            app.merge(map::router(map))
+        };
+        let (http, shutdown) = (http.clone(), shutdown.clone());
+        tasks.push(Task::new("http", move || {
+            http::serve(http.clone(), app.clone(), shutdown.clone())
+        }));
+    }
+
+    let clusters = Supervisor::new(cqgma_state.tasks, !cli.no_restart, intake.clone());
+    let mut clusters = tokio::spawn(clusters.run());
+    let supervisor = Supervisor::new(tasks, !cli.no_restart, shutdown.clone());
+    let mut supervisor = tokio::spawn(supervisor.run());
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
     loop {
-        for handle in &fut {
-            if handle.is_finished() {
-                tracing::error!("Task {handle:?} has finished. This is error. Exiting.");
-                panic!("Task {handle:?} has finished. This is error. Exiting.");
+        tokio::select! {
+            result = &mut clusters => {
+                result??;
+                anyhow::bail!("All clusters have finished. Exiting.");
+            }
+            result = &mut supervisor => {
+                result??;
+                anyhow::bail!("All tasks have finished. Exiting.");
             }
+            _ = hangup.recv() => reload_config(&config_path, &mut config, &filter_tx, &matrix_tx),
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+            _ = stalled.cancelled() => break,
+        }
+    }
+
+    tracing::info!("Shutting down...");
+    let stages = vec![(intake, clusters), (shutdown, supervisor)];
+    if let Err(err) = supervisor::shut_down(stages, SHUTDOWN_TIMEOUT).await {
+        tracing::warn!("Unclean shutdown: {err}");
+    }
+    if stalled.is_cancelled() {
+        anyhow::bail!("Spots stopped flowing. Exiting for a restart.");
+    }
+    Ok(())
+}
+
+/// What every sink task needs.
+struct SinkTasks<'a> {
+    router: &'a Router,
+    metrics: &'a Arc<Metrics>,
+    shutdown: &'a CancellationToken,
+}
+
+impl SinkTasks<'_> {
+    /// Task of the sink `name`. `run` is called with the spots routed to
+    /// the sink on each start.
+    fn task<F, Fut>(&self, name: impl Into<String>, mut run: F) -> Task
+    where
+        F: FnMut(broadcast::Receiver<Arc<DxEntry>>, Arc<Metrics>, CancellationToken) -> Fut
+            + Send
+            + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        let spots = self.router.spots(&name);
+        let (metrics, shutdown) = (self.metrics.clone(), self.shutdown.clone());
+        Task::new(name, move || {
+            run(spots.subscribe(), metrics.clone(), shutdown.clone())
+        })
+    }
+}
+
+fn init_logging(config: &LoggingConfig, stderr: bool) -> anyhow::Result<()> {
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let subscriber = logging::init_subscriber(config, rust_log.as_deref(), stderr)?;
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(())
+}
+
+/// Re-read config and apply changes which are safe to apply while running.
+fn reload_config(
+    path: &Path,
+    config: &mut Config,
+    filter_tx: &watch::Sender<FilterConfig>,
+    matrix_tx: &[watch::Sender<MatrixConfig>],
+) {
+    tracing::info!("Got SIGHUP. Reloading config from {path:?}");
+    let new = match Config::read_from_file(path) {
+        Ok(new) => new,
+        Err(err) => {
+            tracing::error!("Couldn't reload config: {err}. Keeping the old one.");
+            return;
         }
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    };
+
+    let reload = reload::diff(config, &new);
+    reload.apply_to(config);
+    if let Some(filter) = reload.filter {
+        tracing::info!("Applying new filter: {filter}");
+        filter_tx.send_replace(filter);
+    }
+    for (tx, matrix) in matrix_tx.iter().zip(reload.matrix) {
+        if let Some(matrix) = matrix {
+            tx.send_replace(matrix);
+        }
+    }
+    for field in &reload.restart_required {
+        tracing::warn!("Config {field} changed. Restart required for it to take effect.");
     }
 }
@@ -0,0 +1,154 @@
+//! Channels of spots from clusters to routing and sinks. Each channel keeps
+//! [ChannelLimits::capacity] spots for its slowest subscriber and
+//! [OverflowPolicy] decides what happens to a new spot when it's full.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{OverflowPolicy, DEFAULT_CHANNEL_CAPACITY};
+use crate::parser::DxEntry;
+
+/// How often a blocked sender checks whether there's room again. Broadcast
+/// channels don't tell the sender when a subscriber has received a spot,
+/// so [OverflowPolicy::Block] polls.
+pub const BLOCK_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelLimits {
+    /// Spots kept for the slowest subscriber. Tokio rounds the capacity of
+    /// the channel up to a power of two, but [OverflowPolicy::DropNewest]
+    /// and [OverflowPolicy::Block] count against this number.
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for ChannelLimits {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CHANNEL_CAPACITY,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl ChannelLimits {
+    /// A new channel of spots.
+    pub fn channel(&self) -> broadcast::Sender<Arc<DxEntry>> {
+        broadcast::channel(self.capacity).0
+    }
+
+    /// Send `spot` to the subscribers of `spots` as the policy says. A
+    /// blocked send checks for room every [BLOCK_POLL]. It gives up waiting
+    /// when `shutdown` is cancelled, and sends the spot over the oldest one.
+    pub async fn send(
+        &self,
+        spots: &broadcast::Sender<Arc<DxEntry>>,
+        spot: Arc<DxEntry>,
+        shutdown: &CancellationToken,
+    ) {
+        let is_full = || spots.receiver_count() > 0 && spots.len() >= self.capacity;
+        match self.overflow {
+            OverflowPolicy::DropOldest => (),
+            OverflowPolicy::DropNewest if is_full() => {
+                tracing::warn!("Spot channel is full. Dropped {}.", spot.dx);
+                return;
+            }
+            OverflowPolicy::DropNewest => (),
+            OverflowPolicy::Block => {
+                while is_full() && !shutdown.is_cancelled() {
+                    tokio::select! {
+                        _ = tokio::time::sleep(BLOCK_POLL) => (),
+                        _ = shutdown.cancelled() => (),
+                    }
+                }
+            }
+        }
+        // Nobody is listening while a sink restarts
+        if spots.send(spot).is_err() {
+            tracing::debug!("No one listening for spots. Dropped.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::broadcast::error::RecvError;
+    use tokio_util::sync::CancellationToken;
+
+    use super::ChannelLimits;
+    use crate::config::OverflowPolicy;
+    use crate::parser::DxEntry;
+
+    const SPOTS: [&str; 3] = [
+        "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z",
+        "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+        "DX de OH8HUB:    14062.0  OH6XYZ/P     x01f OHFF-0123 CW              1150Z",
+    ];
+
+    fn spot(i: usize) -> Arc<DxEntry> {
+        Arc::new(SPOTS[i].parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_overflow_policy() {
+        let shutdown = CancellationToken::new();
+        let limits = |overflow| ChannelLimits {
+            capacity: 2,
+            overflow,
+        };
+
+        // Subscribers too far behind lose the oldest spots
+        let drop_oldest = limits(OverflowPolicy::DropOldest);
+        let spots = drop_oldest.channel();
+        let mut rx = spots.subscribe();
+        for i in 0..3 {
+            drop_oldest.send(&spots, spot(i), &shutdown).await;
+        }
+        assert_eq!(rx.recv().await.unwrap_err(), RecvError::Lagged(1));
+        assert_eq!(rx.recv().await.unwrap().dx, "OH2NOS/P");
+        assert_eq!(rx.recv().await.unwrap().dx, "OH6XYZ/P");
+
+        // New spots are dropped while the channel is full
+        let drop_newest = limits(OverflowPolicy::DropNewest);
+        let spots = drop_newest.channel();
+        let mut rx = spots.subscribe();
+        for i in 0..3 {
+            drop_newest.send(&spots, spot(i), &shutdown).await;
+        }
+        assert_eq!(rx.recv().await.unwrap().dx, "AD6VT");
+        assert_eq!(rx.recv().await.unwrap().dx, "OH2NOS/P");
+        assert!(rx.try_recv().is_err());
+
+        // The sender waits for the slowest subscriber
+        let block = limits(OverflowPolicy::Block);
+        let spots = block.channel();
+        let mut rx = spots.subscribe();
+        for i in 0..2 {
+            block.send(&spots, spot(i), &shutdown).await;
+        }
+        let send = block.send(&spots, spot(2), &shutdown);
+        tokio::pin!(send);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut send)
+            .await
+            .is_err());
+        assert_eq!(rx.recv().await.unwrap().dx, "AD6VT");
+        tokio::time::timeout(Duration::from_secs(5), send)
+            .await
+            .expect("room for the spot");
+        assert_eq!(rx.recv().await.unwrap().dx, "OH2NOS/P");
+        assert_eq!(rx.recv().await.unwrap().dx, "OH6XYZ/P");
+
+        // Not after shutdown, when the subscribers may be gone
+        block.send(&spots, spot(0), &shutdown).await;
+        block.send(&spots, spot(1), &shutdown).await;
+        shutdown.cancel();
+        block.send(&spots, spot(2), &shutdown).await;
+        assert_eq!(rx.recv().await.unwrap_err(), RecvError::Lagged(1));
+    }
+}
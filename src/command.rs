@@ -0,0 +1,378 @@
+//! Commands given to the bot in the Matrix room.
+
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use tokio::sync::watch;
+
+use crate::band::Band;
+use crate::filter::FilterConfig;
+use crate::metrics::Metrics;
+
+/// Words starting our commands. Other messages are ignored.
+pub const COMMANDS: &[&str] = &["!pause", "!resume", "!filter", "!stats"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// `!pause [duration]`: Stop posting spots, optionally only for a while.
+    Pause(Option<Duration>),
+    /// `!resume`: Start posting spots again.
+    Resume,
+    /// `!filter ...`: Show or change the filter.
+    Filter(FilterCommand),
+    /// `!stats`: Show connection stats of the clusters.
+    Stats,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FilterCommand {
+    /// `!filter show`
+    Show,
+    /// `!filter band 40m,20m` or `!filter band all`
+    Bands(Vec<Band>),
+}
+
+impl FilterCommand {
+    /// The filter after this command is applied to `filter`.
+    pub fn apply(&self, filter: &FilterConfig) -> FilterConfig {
+        let mut filter = filter.clone();
+        match self {
+            FilterCommand::Show => (),
+            FilterCommand::Bands(bands) => filter.bands = bands.clone(),
+        }
+        filter
+    }
+}
+
+impl Command {
+    /// Only admins may run this command.
+    pub fn requires_admin(&self) -> bool {
+        matches!(self, Command::Filter(_))
+    }
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+
+        match (command, args.as_slice()) {
+            ("!pause", []) => Ok(Command::Pause(None)),
+            ("!pause", [duration]) => parse_duration(duration)
+                .map(|d| Command::Pause(Some(d)))
+                .ok_or_else(|| format!("invalid duration '{duration}', expected eg. 30m or 2h")),
+            ("!resume", []) => Ok(Command::Resume),
+            ("!stats", []) => Ok(Command::Stats),
+            ("!filter", ["show"]) => Ok(Command::Filter(FilterCommand::Show)),
+            ("!filter", ["band", "all"]) => Ok(Command::Filter(FilterCommand::Bands(Vec::new()))),
+            ("!filter", ["band", bands]) => bands
+                .split(',')
+                .filter(|band| !band.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map(|bands| Command::Filter(FilterCommand::Bands(bands))),
+            _ => Err(format!("unknown command: {s}")),
+        }
+    }
+}
+
+/// Parse durations like `45s`, `30m`, `2h` or `1d`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let n: u64 = s[..s.len() - 1].parse().ok()?;
+    match n.checked_mul(unit)? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Is forwarding of spots paused. Shared between the command handler and the
+/// forward task.
+#[derive(Debug, Default)]
+pub struct Pause {
+    /// Unix time in seconds until which we are paused. Zero means not paused
+    /// and [u64::MAX] paused until resumed.
+    until: AtomicU64,
+}
+
+impl Pause {
+    pub fn pause(&self, duration: Option<Duration>, now: SystemTime) {
+        let until = match duration {
+            Some(d) => unix_secs(now).saturating_add(d.as_secs()),
+            None => u64::MAX,
+        };
+        self.until.store(until, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.until.store(0, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self, now: SystemTime) -> bool {
+        unix_secs(now) < self.until.load(Ordering::Relaxed)
+    }
+
+    /// Human readable state for replies.
+    pub fn describe(&self, now: SystemTime) -> String {
+        let until = self.until.load(Ordering::Relaxed);
+        let now = unix_secs(now);
+        if until == u64::MAX {
+            "Forwarding is paused until !resume.".to_string()
+        } else if now < until {
+            let minutes = (until - now + 59) / 60;
+            format!("Forwarding is paused for {minutes} more minutes.")
+        } else {
+            "Forwarding is on.".to_string()
+        }
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Everything commands can inspect and change.
+#[derive(Debug)]
+pub struct CommandState {
+    pub pause: Arc<Pause>,
+    pub filter: Arc<watch::Sender<FilterConfig>>,
+    /// Users allowed to run privileged commands
+    pub admins: Vec<OwnedUserId>,
+    pub metrics: Arc<Metrics>,
+}
+
+/// Execute the command given by `sender` and return the reply to be sent to
+/// the room.
+pub fn execute(command: Command, sender: &UserId, state: &CommandState, now: SystemTime) -> String {
+    if command.requires_admin() && !state.admins.iter().any(|admin| admin == sender) {
+        return "Sorry, only admins can do that.".to_string();
+    }
+
+    match command {
+        Command::Pause(duration) => {
+            state.pause.pause(duration, now);
+            state.pause.describe(now)
+        }
+        Command::Resume => {
+            state.pause.resume();
+            state.pause.describe(now)
+        }
+        Command::Filter(cmd) => {
+            let filter = cmd.apply(&state.filter.borrow());
+            let reply = format!("Filter {filter}");
+            if cmd != FilterCommand::Show {
+                state.filter.send_replace(filter);
+            }
+            reply
+        }
+        Command::Stats => stats(&state.metrics, now),
+    }
+}
+
+/// Reply to `!stats`, one line for each cluster, eg.
+/// `www.cqgma.org:7300 up 2h 5m, 1 reconnects, 12 spots/min, last error: connection closed`
+pub fn stats(metrics: &Metrics, now: SystemTime) -> String {
+    let clusters = metrics.clusters();
+    if clusters.is_empty() {
+        return "No clusters connected yet.".to_string();
+    }
+    let mut reply = String::new();
+    for cluster in clusters {
+        if !reply.is_empty() {
+            reply.push('\n');
+        }
+        let _ = write!(reply, "{} ", cluster.name);
+        match cluster.uptime(now) {
+            Some(uptime) => {
+                let _ = write!(reply, "up {}", format_uptime(uptime));
+            }
+            None => reply.push_str("down"),
+        }
+        let _ = write!(
+            reply,
+            ", {} reconnects, {} spots/min",
+            cluster.reconnects.load(Ordering::Relaxed),
+            cluster.spots_per_minute(now)
+        );
+        if let Some(err) = cluster.last_error() {
+            let _ = write!(reply, ", last error: {err}");
+        }
+    }
+    reply
+}
+
+/// `45s`, `5m`, `2h 5m` or `3d 4h`
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{days}d {hours}h")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else if minutes > 0 {
+        format!("{minutes}m")
+    } else {
+        format!("{secs}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use matrix_sdk::ruma::user_id;
+    use tokio::sync::watch;
+
+    use super::{execute, format_uptime, Command, CommandState, FilterCommand, Pause};
+    use crate::band::Band;
+    use crate::filter::FilterConfig;
+    use crate::metrics::Metrics;
+
+    fn state() -> (CommandState, watch::Receiver<FilterConfig>) {
+        let (filter, filter_rx) = watch::channel(FilterConfig::default());
+        let state = CommandState {
+            pause: Arc::new(Pause::default()),
+            filter: Arc::new(filter),
+            admins: vec![user_id!("@oh8hub:pikaviestin.fi").to_owned()],
+            metrics: Arc::new(Metrics::default()),
+        };
+        (state, filter_rx)
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("!pause".parse(), Ok(Command::Pause(None)));
+        assert_eq!(
+            "!pause 30m".parse(),
+            Ok(Command::Pause(Some(Duration::from_secs(30 * 60))))
+        );
+        assert_eq!(
+            " !pause  2h ".parse(),
+            Ok(Command::Pause(Some(Duration::from_secs(2 * 60 * 60))))
+        );
+        assert_eq!("!resume".parse(), Ok(Command::Resume));
+        assert_eq!("!stats".parse(), Ok(Command::Stats));
+        assert!("!stats all".parse::<Command>().is_err());
+        assert!("!pause 30x".parse::<Command>().is_err());
+        assert!("!pause 0m".parse::<Command>().is_err());
+        assert!("!pause 30m 2h".parse::<Command>().is_err());
+        assert!("!resume now".parse::<Command>().is_err());
+        assert!("!foo".parse::<Command>().is_err());
+
+        assert_eq!(
+            "!filter show".parse(),
+            Ok(Command::Filter(FilterCommand::Show))
+        );
+        assert_eq!(
+            "!filter band 40m,20m".parse(),
+            Ok(Command::Filter(FilterCommand::Bands(vec![
+                Band::B40m,
+                Band::B20m
+            ])))
+        );
+        assert_eq!(
+            "!filter band all".parse(),
+            Ok(Command::Filter(FilterCommand::Bands(vec![])))
+        );
+        assert!("!filter band 40m,41m".parse::<Command>().is_err());
+        assert!("!filter".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_filter_command() {
+        let (state, filter_rx) = state();
+        let admin = user_id!("@oh8hub:pikaviestin.fi");
+        let now = SystemTime::now();
+
+        let reply = execute("!filter show".parse().unwrap(), admin, &state, now);
+        assert_eq!(reply, "Filter bands: all");
+
+        let cmd = "!filter band 40m,20m".parse().unwrap();
+        let reply = execute(cmd, admin, &state, now);
+        assert_eq!(reply, "Filter bands: 40m,20m");
+        assert_eq!(
+            *filter_rx.borrow(),
+            FilterConfig {
+                bands: vec![Band::B40m, Band::B20m],
+                ..FilterConfig::default()
+            }
+        );
+
+        let cmd = "!filter band all".parse().unwrap();
+        let reply = execute(cmd, user_id!("@oh9xxx:pikaviestin.fi"), &state, now);
+        assert_eq!(reply, "Sorry, only admins can do that.");
+        assert_eq!(filter_rx.borrow().bands, vec![Band::B40m, Band::B20m]);
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let (state, _) = state();
+        let pause = &state.pause;
+        let user = user_id!("@oh9xxx:pikaviestin.fi");
+        let now = SystemTime::now();
+        assert!(!pause.is_paused(now));
+
+        let reply = execute(Command::Pause(None), user, &state, now);
+        assert_eq!(reply, "Forwarding is paused until !resume.");
+        assert!(pause.is_paused(now + Duration::from_secs(365 * 24 * 60 * 60)));
+
+        let reply = execute(Command::Resume, user, &state, now);
+        assert_eq!(reply, "Forwarding is on.");
+        assert!(!pause.is_paused(now));
+
+        let half_hour = Duration::from_secs(30 * 60);
+        let reply = execute(Command::Pause(Some(half_hour)), user, &state, now);
+        assert_eq!(reply, "Forwarding is paused for 30 more minutes.");
+        assert!(pause.is_paused(now + half_hour - Duration::from_secs(1)));
+        assert!(!pause.is_paused(now + half_hour));
+    }
+
+    #[test]
+    fn test_stats() {
+        let (state, _) = state();
+        let user = user_id!("@oh9xxx:pikaviestin.fi");
+        let now = SystemTime::now();
+        let reply = execute(Command::Stats, user, &state, now);
+        assert_eq!(reply, "No clusters connected yet.");
+
+        let cqgma = state.metrics.cluster("www.cqgma.org:7300");
+        cqgma.connected(now - Duration::from_secs(3 * 60 * 60));
+        cqgma.failed(&"connection closed");
+        cqgma.connected(now - Duration::from_secs(2 * 60 * 60 + 5 * 60 + 10));
+        for secs in [90, 20, 5] {
+            cqgma.spot(now - Duration::from_secs(secs));
+        }
+        let other = state.metrics.cluster("dxc.example.org:7300");
+        other.failed(&"connection refused");
+
+        let reply = execute(Command::Stats, user, &state, now);
+        assert_eq!(
+            reply,
+            "www.cqgma.org:7300 up 2h 5m, 1 reconnects, 2 spots/min, last error: connection closed\n\
+             dxc.example.org:7300 down, 0 reconnects, 0 spots/min, last error: connection refused"
+        );
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        let secs = Duration::from_secs;
+        assert_eq!(format_uptime(secs(45)), "45s");
+        assert_eq!(format_uptime(secs(5 * 60 + 10)), "5m");
+        assert_eq!(format_uptime(secs(2 * 60 * 60 + 5 * 60)), "2h 5m");
+        assert_eq!(format_uptime(secs(3 * 86400 + 4 * 60 * 60 + 59)), "3d 4h");
+    }
+}
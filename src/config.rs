@@ -1,72 +1,3647 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::io;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use matrix_sdk::ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId};
-use serde::Deserialize;
+#[cfg(feature = "matrix")]
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing_subscriber::EnvFilter;
 
-#[derive(Debug, Deserialize)]
+use crate::channel::ChannelLimits;
+use crate::filter::FilterConfig;
+use crate::parser::Activity;
+use crate::template::{Format, Template};
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct Config {
-    pub matrix: MatrixConfig,
-    pub cqgma: CqgmaConfig,
+    /// One or more Matrix accounts where spots are posted
+    #[cfg(feature = "matrix")]
+    #[serde(deserialize_with = "one_or_many")]
+    pub matrix: Vec<MatrixConfig>,
+    /// One or more clusters where spots are read from
+    #[serde(deserialize_with = "one_or_many")]
+    pub cqgma: Vec<CqgmaConfig>,
+    /// Maidenhead locator of the operator, 4, 6 or 8 characters, eg. `KP20le`
+    pub home_grid: Option<String>,
+    /// `cty.dat` file for countries and zones of callsigns
+    pub cty_path: Option<PathBuf>,
+    /// Spots kept for the slowest sink in each channel of spots. Defaults
+    /// to [DEFAULT_CHANNEL_CAPACITY].
+    #[serde(default = "default_channel_capacity")]
+    pub channel_capacity: usize,
+    /// What happens to a new spot when a sink is `channel_capacity` spots
+    /// behind
+    #[serde(default)]
+    pub overflow_policy: OverflowPolicy,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    /// Programs turned on or off as a whole, in every filter
+    #[serde(default)]
+    pub activities: ActivitiesConfig,
+    /// Rules sending spots matching a filter only to some sinks. Sinks
+    /// named in no rule get every spot.
+    #[serde(default)]
+    pub route: Vec<RouteConfig>,
+    /// Stricter deduplication for some sinks, on top of
+    /// `filter.dedup_window_secs`
+    #[serde(default)]
+    pub dedup: Vec<DedupConfig>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// HTTP server for health checks. Not started unless configured.
+    pub http: Option<HttpConfig>,
+    /// Keep history of forwarded spots. Needs the `sqlite` feature.
+    pub store: Option<StoreConfig>,
+    /// Publish spots to MQTT broker. Needs the `mqtt` feature.
+    pub mqtt: Option<MqttConfig>,
+    /// POST spots to HTTP endpoints. Needs the `webhook` feature.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub webhook: Vec<WebhookConfig>,
+    /// Post spots to Discord channels. Needs the `discord` feature.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub discord: Vec<DiscordConfig>,
+    /// Send spots to Telegram chats. Needs the `telegram` feature.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub telegram: Vec<TelegramConfig>,
+    /// Push notifications through ntfy. Needs the `ntfy` feature.
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub ntfy: Vec<NtfyConfig>,
+    /// Email alerts of rare references. Needs the `email` feature.
+    pub email: Option<EmailConfig>,
+    /// Send spots to the APRS network. Needs the `aprs` feature.
+    pub aprs: Option<AprsConfig>,
+    /// Publish spots to Nostr relays. Needs the `nostr` feature.
+    pub nostr: Option<NostrConfig>,
+    /// Post spots to an XMPP multi-user chat room. Needs the `xmpp` feature.
+    pub xmpp: Option<XmppConfig>,
+    /// Write spots to InfluxDB for dashboards. Needs the `influxdb` feature.
+    pub influxdb: Option<InfluxdbConfig>,
+    /// Produce spots to a Kafka topic. Needs the `kafka` feature.
+    pub kafka: Option<KafkaConfig>,
+    /// Post SOTA spots missing from SOTAwatch there. Needs the `sotawatch`
+    /// feature.
+    pub sotawatch: Option<SotawatchConfig>,
+    /// Post spots of POTA parks to the POTA site. Needs the `pota_spots`
+    /// feature.
+    pub pota_spots: Option<PotaSpotsConfig>,
+    /// Append spots to ADIF file
+    pub adif: Option<AdifConfig>,
+    /// Append spots as JSON lines to a file rotated daily
+    pub jsonl: Option<JsonlConfig>,
+    /// Append spots to CSV file
+    pub csv: Option<CsvConfig>,
+    /// Look up details of spotted references, eg. summit names
+    pub lookup: Option<LookupConfig>,
+    /// File for cluster lines which couldn't be parsed
+    pub dead_letter: Option<DeadLetterConfig>,
+    /// Reconnect or exit if spots stop flowing
+    pub watchdog: Option<WatchdogConfig>,
+    /// File merged over this config, so secrets can be kept out of
+    /// version control. Relative to the directory of the config file.
+    pub secrets_path: Option<PathBuf>,
+}
+
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 256;
+/// Spots are small, but sinks may hold on to a lot of them.
+const MAX_CHANNEL_CAPACITY: usize = 1 << 20;
+
+fn default_channel_capacity() -> usize {
+    DEFAULT_CHANNEL_CAPACITY
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Slow sinks skip the oldest spots, so the newest are always sent
+    #[default]
+    DropOldest,
+    /// New spots are dropped until the slowest sink catches up
+    DropNewest,
+    /// Reading clusters waits for the slowest sink, so no spot is lost.
+    /// Room is checked for every [crate::channel::BLOCK_POLL], which delays
+    /// each spot sent after a wait by up to that.
+    Block,
+}
+
+/// Spots of activities turned off here are never forwarded, eg.
+/// `wwff = false`. All are on by default.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ActivitiesConfig {
+    pub wwff: bool,
+    pub iota: bool,
+    pub cota: bool,
+    pub sota: bool,
+    pub gma: bool,
+    pub lighthouses: bool,
+    pub rda: bool,
+    pub agcw: bool,
+}
+
+impl Default for ActivitiesConfig {
+    fn default() -> Self {
+        Self {
+            wwff: true,
+            iota: true,
+            cota: true,
+            sota: true,
+            gma: true,
+            lighthouses: true,
+            rda: true,
+            agcw: true,
+        }
+    }
+}
+
+impl ActivitiesConfig {
+    /// Activities turned off.
+    pub fn disabled(&self) -> Vec<Activity> {
+        let activities = [
+            (Activity::Wwff, self.wwff),
+            (Activity::Iota, self.iota),
+            (Activity::Cota, self.cota),
+            (Activity::Sota, self.sota),
+            (Activity::Gma, self.gma),
+            (Activity::Lighthouses, self.lighthouses),
+            (Activity::Rda, self.rda),
+            (Activity::Agcw, self.agcw),
+        ];
+        activities
+            .into_iter()
+            .filter(|(_, enabled)| !enabled)
+            .map(|(activity, _)| activity)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RouteConfig {
+    /// Sinks by the names in [Config::sink_names], eg. `mqtt`, `webhook[0]`
+    /// or `matrix @puskapupu:example.org`
+    pub sinks: Vec<String>,
+    /// Spots passing this filter are sent to the sinks. Unset fields let
+    /// all spots through, unlike in the global filter. `dedup_window_secs`
+    /// isn't used.
+    #[serde(default = "FilterConfig::all", deserialize_with = "all_unless_set")]
+    pub filter: FilterConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DedupConfig {
+    /// Sinks by the names in [Config::sink_names], like in [RouteConfig]
+    pub sinks: Vec<String>,
+    /// Each of the sinks gets a spot of the same activation only once
+    /// within this many seconds
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct HttpConfig {
+    /// Address and port to listen on, eg. `127.0.0.1:8080`
+    pub listen: SocketAddr,
+    /// Spots sent to WebSocket clients of `/spots/live` when they connect.
+    /// Defaults to [DEFAULT_LIVE_BACKLOG].
+    #[serde(default = "default_live_backlog")]
+    pub live_backlog: usize,
+    /// Spots of this many last minutes are drawn on `/spots/map.svg`, over
+    /// lines of latitude and longitude without coastlines. Defaults to
+    /// [DEFAULT_MAP_MINUTES].
+    #[serde(default = "default_map_minutes")]
+    pub map_minutes: u64,
+    /// Part of the world drawn on the map. The whole world if not given.
+    /// The map has no coastlines, so a familiar region is easier to read.
+    pub map_region: Option<MapRegion>,
+}
+
+pub const DEFAULT_LIVE_BACKLOG: usize = 20;
+pub const DEFAULT_MAP_MINUTES: u64 = 60;
+
+fn default_live_backlog() -> usize {
+    DEFAULT_LIVE_BACKLOG
+}
+
+fn default_map_minutes() -> u64 {
+    DEFAULT_MAP_MINUTES
+}
+
+/// Latitudes and longitudes of the edges of a map in degrees, north and
+/// east positive.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct MapRegion {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+}
+
+impl Default for MapRegion {
+    /// The whole world
+    fn default() -> Self {
+        Self {
+            south: -90.0,
+            west: -180.0,
+            north: 90.0,
+            east: 180.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct StoreConfig {
+    /// SQLite database file
+    pub path: PathBuf,
+    /// Spots older than this many days are deleted. Defaults to
+    /// [DEFAULT_RETENTION_DAYS].
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u64,
+}
+
+pub const DEFAULT_RETENTION_DAYS: u64 = 30;
+
+fn default_retention_days() -> u64 {
+    DEFAULT_RETENTION_DAYS
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct MqttConfig {
+    /// Broker URL, eg. `mqtt://localhost:1883`
+    pub broker: String,
+    /// Defaults to [DEFAULT_MQTT_CLIENT_ID]
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Topic of each spot, with placeholders like in [crate::template].
+    /// Defaults to [DEFAULT_MQTT_TOPIC].
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: Template,
+    /// Quality of service: 0 at most once, 1 at least once or 2 exactly once
+    #[serde(default)]
+    pub qos: u8,
+    /// Payload of each spot. Spots are published as [DxEntry] JSON if not
+    /// set.
+    ///
+    /// [DxEntry]: crate::parser::DxEntry
+    pub template: Option<Template>,
+    /// Markup of the template: `plain` text or `json`. Defaults to json.
+    #[serde(default = "default_mqtt_format")]
+    pub format: Format,
+}
+
+pub const DEFAULT_MQTT_CLIENT_ID: &str = "puskapupu";
+pub const DEFAULT_MQTT_TOPIC: &str = "puskapupu/spots/{band}";
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+
+fn default_mqtt_client_id() -> String {
+    DEFAULT_MQTT_CLIENT_ID.to_string()
+}
+
+fn default_mqtt_topic() -> Template {
+    Template::parse(DEFAULT_MQTT_TOPIC).expect("default topic is valid")
+}
+
+fn default_mqtt_format() -> Format {
+    Format::Json
+}
+
+impl MqttConfig {
+    /// Host and port of [MqttConfig::broker]. Port defaults to
+    /// [DEFAULT_MQTT_PORT].
+    pub fn broker_addr(&self) -> Option<(&str, u16)> {
+        let addr = self.broker.strip_prefix("mqtt://")?.trim_end_matches('/');
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (addr, DEFAULT_MQTT_PORT),
+        };
+        (!host.is_empty()).then_some((host, port))
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    /// `http://` or `https://` URL where spots are POSTed
+    pub url: String,
+    /// Sent with every request, eg. `Authorization`
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Defaults to [DEFAULT_WEBHOOK_TIMEOUT_SECS]
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Retries after timeouts, connection errors and 5xx responses.
+    /// Defaults to [DEFAULT_WEBHOOK_RETRIES].
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+}
+
+pub const DEFAULT_WEBHOOK_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_WEBHOOK_RETRIES: u32 = 3;
+
+fn default_webhook_timeout_secs() -> u64 {
+    DEFAULT_WEBHOOK_TIMEOUT_SECS
+}
+
+fn default_webhook_retries() -> u32 {
+    DEFAULT_WEBHOOK_RETRIES
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct DiscordConfig {
+    /// Webhook URL of the channel. Secret, as anyone knowing it can post.
+    pub webhook_url: String,
+    /// Shown as the poster instead of the name of the webhook
+    pub username: Option<String>,
+    /// Body of the embed, see [crate::template]. Defaults to
+    /// [DEFAULT_DISCORD_TEMPLATE].
+    pub template: Option<Template>,
+    /// Color of the embed as `0xRRGGBB`
+    pub color: Option<u32>,
+}
+
+pub const DEFAULT_DISCORD_TEMPLATE: &str = "{info}";
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct TelegramConfig {
+    /// Token of the bot from @BotFather
+    pub bot_token: String,
+    /// Numeric id of the chat or `@name` of a channel
+    pub chat_id: String,
+    /// Message after the callsign, see [crate::template]. Defaults to
+    /// [DEFAULT_TELEGRAM_TEMPLATE].
+    pub template: Option<Template>,
+    /// Markup of the template: `plain` text, `html` or `markdown`
+    /// (MarkdownV2). Defaults to plain.
+    #[serde(default)]
+    pub format: Format,
+}
+
+pub const DEFAULT_TELEGRAM_TEMPLATE: &str = "{frequency} {info} (de {reporter} {time})";
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct NtfyConfig {
+    /// Defaults to [DEFAULT_NTFY_SERVER]
+    #[serde(default = "default_ntfy_server")]
+    pub server: String,
+    /// Topic the notifications are published to. Anyone knowing the name
+    /// of a topic without access control can read it.
+    pub topic: String,
+    /// Access token for topics with access control
+    pub token: Option<String>,
+    /// Message under the title, see [crate::template]. Defaults to
+    /// [DEFAULT_NTFY_TEMPLATE].
+    pub template: Option<Template>,
+    /// Priority of `New one!` spots from 1 (min) to 5 (max). Defaults to
+    /// [DEFAULT_NTFY_NEW_ONE_PRIORITY]. Other spots have the default 3.
+    #[serde(default = "default_ntfy_new_one_priority")]
+    pub new_one_priority: u8,
+}
+
+pub const DEFAULT_NTFY_SERVER: &str = "https://ntfy.sh";
+pub const DEFAULT_NTFY_TEMPLATE: &str = "{frequency} ({band}) de {reporter} {time}";
+pub const DEFAULT_NTFY_NEW_ONE_PRIORITY: u8 = 4;
+
+fn default_ntfy_server() -> String {
+    DEFAULT_NTFY_SERVER.to_string()
+}
+
+fn default_ntfy_new_one_priority() -> u8 {
+    DEFAULT_NTFY_NEW_ONE_PRIORITY
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct EmailConfig {
+    /// SMTP server as host:port, eg. `smtp.example.org:587`
+    pub server: String,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    /// Login, if the server wants one
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender address, eg. `puskapupu@example.org`
+    pub from: String,
+    /// Recipient addresses
+    #[serde(deserialize_with = "one_or_many")]
+    pub to: Vec<String>,
+    /// References to alert on whenever spotted, eg. `OHFF-1419`
+    #[serde(default)]
+    pub watch: Vec<String>,
+    /// Alert on spots marked `New one!`
+    #[serde(default)]
+    pub new_one: bool,
+    /// The same reference is alerted again only after this many seconds.
+    /// Defaults to [DEFAULT_EMAIL_COOLDOWN_SECS].
+    #[serde(default = "default_email_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+pub const DEFAULT_EMAIL_COOLDOWN_SECS: u64 = 60 * 60;
+
+fn default_email_cooldown_secs() -> u64 {
+    DEFAULT_EMAIL_COOLDOWN_SECS
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// TLS from the start, usually port 465
+    Tls,
+    /// Plain connection upgraded with `STARTTLS`, usually port 587
+    #[default]
+    Starttls,
+    /// No encryption, eg. for a relay on localhost
+    None,
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct AprsConfig {
+    /// APRS-IS server as host:port. Defaults to [DEFAULT_APRS_SERVER].
+    #[serde(default = "default_aprs_server")]
+    pub server: String,
+    /// Callsign and SSID the spots are sent from, eg. `OH8HUB-10`
+    pub callsign: String,
+    /// APRS-IS passcode of [AprsConfig::callsign]
+    pub passcode: u16,
+    /// Callsign or bulletin the messages are addressed to, eg. `BLN1DX`
+    pub to: String,
+    /// Message text, see [crate::template]. Cut to fit in a message.
+    /// Defaults to [DEFAULT_APRS_TEMPLATE].
+    pub template: Option<Template>,
+}
+
+pub const DEFAULT_APRS_SERVER: &str = "rotate.aprs2.net:14580";
+pub const DEFAULT_APRS_TEMPLATE: &str = "{dx} {frequency} {info} de {reporter}";
+
+fn default_aprs_server() -> String {
+    DEFAULT_APRS_SERVER.to_string()
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct NostrConfig {
+    /// Relays the notes are sent to, eg. `wss://relay.damus.io`
+    pub relays: Vec<String>,
+    /// Key the notes are signed with as 64 hex digits or `nsec1...`
+    pub secret_key: String,
+    /// Text of the notes, see [crate::template]. Defaults to
+    /// [DEFAULT_NOSTR_TEMPLATE].
+    pub template: Option<Template>,
+}
+
+pub const DEFAULT_NOSTR_TEMPLATE: &str = "{dx} {frequency} {info} de {reporter} {time}";
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct XmppConfig {
+    /// Account the spots are posted from, eg. `puskapupu@example.org`
+    pub jid: String,
+    pub password: String,
+    /// Server as host:port, connected with direct TLS. STARTTLS isn't
+    /// supported. Defaults to the domain of [XmppConfig::jid] and port
+    /// [DEFAULT_XMPP_PORT].
+    pub server: Option<String>,
+    /// Multi-user chat room, eg. `spots@conference.example.org`
+    pub room: String,
+    /// Nickname in the room. Defaults to [DEFAULT_XMPP_NICK].
+    #[serde(default = "default_xmpp_nick")]
+    pub nick: String,
+    /// Message, see [crate::template]. Defaults to the same as in Matrix,
+    /// [crate::template::DEFAULT_TEMPLATE].
+    pub template: Option<Template>,
+}
+
+pub const DEFAULT_XMPP_PORT: u16 = 5223;
+pub const DEFAULT_XMPP_NICK: &str = "puskapupu";
+
+fn default_xmpp_nick() -> String {
+    DEFAULT_XMPP_NICK.to_string()
+}
+
+impl XmppConfig {
+    /// Local part and domain of [XmppConfig::jid], without resource.
+    pub fn account(&self) -> Option<(&str, &str)> {
+        let bare = self.jid.split('/').next().unwrap_or_default();
+        let (local, domain) = bare.split_once('@')?;
+        (!local.is_empty() && !domain.is_empty()).then_some((local, domain))
+    }
+
+    /// Host and port to connect to.
+    pub fn server_addr(&self) -> Option<(&str, u16)> {
+        match &self.server {
+            Some(server) => {
+                let (host, port) = server.rsplit_once(':')?;
+                (!host.is_empty()).then_some((host, port.parse().ok()?))
+            }
+            None => Some((self.account()?.1, DEFAULT_XMPP_PORT)),
+        }
+    }
+}
+
+/// APRS-IS passcode of `callsign`. The SSID doesn't matter.
+pub fn aprs_passcode(callsign: &str) -> u16 {
+    let base = callsign.split('-').next().unwrap_or_default();
+    let mut hash: u16 = 0x73e2;
+    for (i, byte) in base.to_ascii_uppercase().bytes().enumerate() {
+        hash ^= if i % 2 == 0 {
+            u16::from(byte) << 8
+        } else {
+            u16::from(byte)
+        };
+    }
+    hash & 0x7fff
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct InfluxdbConfig {
+    /// Base URL of the server, eg. `http://localhost:8086`
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    /// API token with write access to [InfluxdbConfig::bucket]
+    pub token: String,
+    /// Most spots written in one request. Defaults to
+    /// [DEFAULT_INFLUXDB_BATCH_SIZE].
+    #[serde(default = "default_influxdb_batch_size")]
+    pub batch_size: usize,
+    /// Spots are written at least this often even if the batch isn't full.
+    /// Defaults to [DEFAULT_INFLUXDB_FLUSH_SECS].
+    #[serde(default = "default_influxdb_flush_secs")]
+    pub flush_secs: u64,
+}
+
+pub const DEFAULT_INFLUXDB_BATCH_SIZE: usize = 100;
+pub const DEFAULT_INFLUXDB_FLUSH_SECS: u64 = 10;
+
+fn default_influxdb_batch_size() -> usize {
+    DEFAULT_INFLUXDB_BATCH_SIZE
+}
+
+fn default_influxdb_flush_secs() -> u64 {
+    DEFAULT_INFLUXDB_FLUSH_SECS
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct KafkaConfig {
+    /// Brokers asked for the leaders of the topic, eg. `localhost:9092`
+    #[serde(deserialize_with = "one_or_many")]
+    pub brokers: Vec<String>,
+    /// Each spot is a JSON message keyed by the callsign of the activator
+    pub topic: String,
+    /// Defaults to [DEFAULT_KAFKA_CLIENT_ID]
+    #[serde(default = "default_kafka_client_id")]
+    pub client_id: String,
+    /// Acknowledgements waited for: 1 for the leader only or -1 for all
+    /// in-sync replicas. Defaults to [DEFAULT_KAFKA_ACKS].
+    #[serde(default = "default_kafka_acks")]
+    pub acks: i16,
+}
+
+pub const DEFAULT_KAFKA_CLIENT_ID: &str = "puskapupu";
+pub const DEFAULT_KAFKA_ACKS: i16 = -1;
+
+fn default_kafka_client_id() -> String {
+    DEFAULT_KAFKA_CLIENT_ID.to_string()
+}
+
+fn default_kafka_acks() -> i16 {
+    DEFAULT_KAFKA_ACKS
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct SotawatchConfig {
+    /// Nothing is posted unless this is `true`, as spots are posted under
+    /// the account of the operator.
+    pub enabled: bool,
+    /// SOTA account, usually a callsign
+    pub username: String,
+    pub password: String,
+    /// Spotter of the posted spots. Defaults to the username.
+    pub callsign: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct PotaSpotsConfig {
+    /// Nothing is posted unless this is `true`, as spots are posted under
+    /// the account of the operator.
+    pub enabled: bool,
+    /// Spotter of the posted spots
+    pub callsign: String,
+    /// API token of the POTA account
+    pub token: String,
+    /// The same park is posted again only after this many seconds.
+    /// Defaults to [DEFAULT_POTA_SPOTS_COOLDOWN_SECS].
+    #[serde(default = "default_pota_spots_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+pub const DEFAULT_POTA_SPOTS_COOLDOWN_SECS: u64 = 10 * 60;
+
+fn default_pota_spots_cooldown_secs() -> u64 {
+    DEFAULT_POTA_SPOTS_COOLDOWN_SECS
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AdifConfig {
+    /// File the records are appended to, eg. `spots.adi`
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct JsonlConfig {
+    /// File the spots are appended to. Moved to `<path>.YYYY-MM-DD` when
+    /// written on a new day.
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CsvConfig {
+    pub path: PathBuf,
+    /// Move the file to `<path>.YYYY-MM-DD` when written on a new day
+    #[serde(default)]
+    pub rotate_daily: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LookupConfig {
+    /// Summit names, altitudes and points from the SOTA API. Needs the
+    /// `sota` feature.
+    #[serde(default)]
+    pub sota: bool,
+    /// Park names and locations from the POTA API. Needs the `pota`
+    /// feature.
+    #[serde(default)]
+    pub pota: bool,
+    /// WWFF directory file, eg. `wwff_directory.csv` from wwff.co, for
+    /// names and locations of WWFF references
+    pub wwff_path: Option<PathBuf>,
+    /// Answers are remembered this many seconds. Defaults to
+    /// [DEFAULT_LOOKUP_CACHE_SECS].
+    #[serde(default = "default_lookup_cache_secs")]
+    pub cache_secs: u64,
+    /// Spots are sent without details if a directory doesn't answer in
+    /// this many seconds. Defaults to [DEFAULT_LOOKUP_TIMEOUT_SECS].
+    #[serde(default = "default_lookup_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+pub const DEFAULT_LOOKUP_CACHE_SECS: u64 = 24 * 60 * 60;
+pub const DEFAULT_LOOKUP_TIMEOUT_SECS: u64 = 5;
+
+fn default_lookup_cache_secs() -> u64 {
+    DEFAULT_LOOKUP_CACHE_SECS
 }
 
-#[derive(Deserialize)]
+fn default_lookup_timeout_secs() -> u64 {
+    DEFAULT_LOOKUP_TIMEOUT_SECS
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DeadLetterConfig {
+    pub path: PathBuf,
+    /// The file is moved aside when it would grow over this size. Defaults
+    /// to [DEFAULT_DEAD_LETTER_MAX_BYTES].
+    #[serde(default = "default_dead_letter_max_bytes")]
+    pub max_bytes: u64,
+}
+
+pub const DEFAULT_DEAD_LETTER_MAX_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WatchdogConfig {
+    /// Clusters are reconnected when no spot has been forwarded for this
+    /// many seconds, and the process exits if there are still none after as
+    /// long again. Defaults to [DEFAULT_STALL_SECS].
+    #[serde(default = "default_stall_secs")]
+    pub stall_secs: u64,
+    /// Spots are expected only within these hours (UTC). Always if not
+    /// given.
+    pub active_hours: Option<QuietHours>,
+}
+
+pub const DEFAULT_STALL_SECS: u64 = 60 * 60;
+
+fn default_stall_secs() -> u64 {
+    DEFAULT_STALL_SECS
+}
+
+fn default_dead_letter_max_bytes() -> u64 {
+    DEFAULT_DEAD_LETTER_MAX_BYTES
+}
+
+impl StoreConfig {
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention_days * 24 * 60 * 60)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Log level or `RUST_LOG` style directives, eg. `info,matrix_sdk=warn`
+    pub level: String,
+    pub format: LogFormat,
+    /// Log to syslog instead of stdout
+    pub syslog: Option<SyslogConfig>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+            syslog: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SyslogConfig {
+    #[serde(default)]
+    pub facility: Facility,
+    /// Remote syslog server as host:port, sent to over UDP. Local syslog
+    /// through `/dev/log` if not given, which needs a Unix system.
+    pub host: Option<String>,
+}
+
+/// Syslog facility, see RFC 5424.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    #[default]
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl LoggingConfig {
+    /// Filter for log events. `rust_log`, ie. the `RUST_LOG` environment
+    /// variable, overrides the configured level when set.
+    pub fn env_filter(&self, rust_log: Option<&str>) -> io::Result<EnvFilter> {
+        let directives = match rust_log {
+            Some(rust_log) if !rust_log.trim().is_empty() => rust_log,
+            _ => &self.level,
+        };
+        EnvFilter::try_new(directives)
+            .map_err(|err| invalid("logging.level", &format!("'{directives}': {err}")))
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        self.env_filter(None)?;
+        let host = self.syslog.as_ref().and_then(|syslog| syslog.host.as_ref());
+        if let Some(host) = host {
+            let host_ok = host.rsplit_once(':').map_or(false, |(host, port)| {
+                !host.is_empty() && port.parse::<u16>().is_ok()
+            });
+            if !host_ok {
+                return Err(invalid(
+                    "logging.syslog.host",
+                    &format!("expected host:port, eg. localhost:514; got '{host}'"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "matrix")]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 pub struct MatrixConfig {
     pub homeserver: url::Url,
+    /// May be left out of the file of the first account, if given in
+    /// [ENV_MATRIX_ACCESS_TOKEN]
     pub access_token: String,
+    /// Used by the SDK to get a new access token when it expires
+    pub refresh_token: Option<String>,
     pub user_id: OwnedUserId,
     pub device_id: OwnedDeviceId,
-    pub room_id: OwnedRoomId,
+    /// Rooms where spots are posted. Accepts also a single `room_id`.
+    #[serde(alias = "room_id", deserialize_with = "one_or_many")]
+    pub rooms: Vec<OwnedRoomId>,
+    /// Don't post spots to the room during these hours
+    pub quiet_hours: Option<QuietHours>,
+    /// File where the Matrix sync token is kept between restarts
+    pub sync_token_path: Option<PathBuf>,
+    /// Don't post the same spot again within this many seconds. Defaults to
+    /// [DEFAULT_DEDUP_WINDOW_SECS].
+    #[serde(default = "default_dedup_window_secs")]
+    pub dedup_window_secs: u64,
+    /// File where recently posted spots are kept, so they are not
+    /// posted again right after a restart
+    pub dedup_path: Option<PathBuf>,
+    /// Users allowed to change settings with commands
+    #[serde(default)]
+    pub admins: Vec<OwnedUserId>,
+    /// Message template for spots, see [crate::template]
+    pub template: Option<Template>,
+    /// Markup of the template: `plain` text or `html`. Defaults to plain.
+    #[serde(default)]
+    pub format: Format,
+}
+
+/// A daily window of time in UTC. The window may cross midnight, eg. from
+/// `22:00` to `06:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct QuietHours {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+impl QuietHours {
+    /// Is the given minute of UTC day within quiet hours?
+    pub fn contains(&self, minute_of_day: u16) -> bool {
+        let (start, end) = (self.start.0, self.end.0);
+        if start <= end {
+            start <= minute_of_day && minute_of_day < end
+        } else {
+            start <= minute_of_day || minute_of_day < end
+        }
+    }
+}
+
+/// Time of day as minutes since midnight. Written as `"HH:MM"` in config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u16);
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> String {
+        format!("{:02}:{:02}", t.0 / 60, t.0 % 60)
+    }
 }
 
-#[derive(Debug, Deserialize)]
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let err = || format!("invalid time of day '{s}', expected HH:MM");
+        let (h, m) = s.split_once(':').ok_or_else(err)?;
+        let h: u16 = h.parse().map_err(|_| err())?;
+        let m: u16 = m.parse().map_err(|_| err())?;
+        if h > 23 || m > 59 {
+            return Err(err());
+        }
+        Ok(TimeOfDay(h * 60 + m))
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 pub struct CqgmaConfig {
     pub host: String,
     pub username: String,
+    /// Sent if the cluster asks for one after login
+    pub password: Option<String>,
+    /// Filter used for this cluster instead of the global `[filter]`
+    pub filter: Option<FilterConfig>,
+    /// Reconnect waits randomly between min and max seconds. Defaults to
+    /// [DEFAULT_RECONNECT_MIN_SECS] and [DEFAULT_RECONNECT_MAX_SECS].
+    #[serde(default = "default_reconnect_min_secs")]
+    pub reconnect_min_secs: u64,
+    #[serde(default = "default_reconnect_max_secs")]
+    pub reconnect_max_secs: u64,
+    /// Stop reconnecting after this many failed connections in a row, so
+    /// the cluster is restarted or the bot exits. Connections which stayed
+    /// up for [STABLE_CONNECTION_SECS] reset the count. Retries forever if
+    /// not set.
+    pub max_consecutive_failures: Option<u32>,
+    /// Connect through this proxy instead of directly
+    pub proxy: Option<ProxyConfig>,
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct ProxyConfig {
+    #[serde(rename = "type")]
+    pub kind: ProxyKind,
+    /// host:port of the proxy
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    Socks5,
+    /// HTTP proxy supporting `CONNECT`
+    Http,
 }
 
+pub const DEFAULT_DEDUP_WINDOW_SECS: u64 = 10 * 60;
+pub const DEFAULT_RECONNECT_MIN_SECS: u64 = 17;
+pub const DEFAULT_RECONNECT_MAX_SECS: u64 = 34;
+pub const STABLE_CONNECTION_SECS: u64 = 5 * 60;
+
+#[cfg(feature = "matrix")]
+fn default_dedup_window_secs() -> u64 {
+    DEFAULT_DEDUP_WINDOW_SECS
+}
+
+fn default_reconnect_min_secs() -> u64 {
+    DEFAULT_RECONNECT_MIN_SECS
+}
+
+fn default_reconnect_max_secs() -> u64 {
+    DEFAULT_RECONNECT_MAX_SECS
+}
+
+/// Accept either a single value or an array of them.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(v) => vec![v],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+/// [FilterConfig] with the fields not set letting all spots through.
+fn all_unless_set<'de, D>(deserializer: D) -> Result<FilterConfig, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let toml::Value::Table(set) = toml::Value::deserialize(deserializer)? else {
+        return Err(D::Error::custom("expected a filter table"));
+    };
+    let mut filter = toml::Table::try_from(FilterConfig::all()).map_err(D::Error::custom)?;
+    filter.extend(set);
+    filter.try_into().map_err(D::Error::custom)
+}
+
+/// Environment variable overriding access token of the first `[matrix]`
+/// account, or giving it if the file has none. Other accounts can use
+/// `${VAR}` interpolation.
+pub const ENV_MATRIX_ACCESS_TOKEN: &str = "PUSKAPUPU_MATRIX_ACCESS_TOKEN";
+
 impl Config {
+    /// Read config from TOML, YAML or JSON file, chosen by the extension of
+    /// the file name. Files without known extension are read as TOML.
+    ///
+    /// Files listed in `include` are merged first, later ones overriding
+    /// earlier ones, and the including file overrides them all. If
+    /// `secrets_path` is given, that file is merged over the result, lists
+    /// of values such as `spotter_prefixes` replacing those of the config.
+    /// `${VAR}` in any string value is replaced with the value of
+    /// environment variable `VAR`. Additionally [ENV_MATRIX_ACCESS_TOKEN]
+    /// gives the access token of the first account.
     pub fn read_from_file<P: AsRef<Path>>(file: P) -> io::Result<Config> {
-        let s = std::fs::read(file)?;
-        let s = std::str::from_utf8(&s)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "config is not valid utf-8"))?;
-        toml::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        let env = |name: &str| std::env::var(name).ok();
+        let file = file.as_ref();
+        let mut value = read_with_includes(file, &mut Vec::new())?;
+        if let Some(secrets_path) = value.get("secrets_path") {
+            let secrets_path = secrets_path
+                .as_str()
+                .ok_or_else(|| invalid("secrets_path", "must be a string"))?;
+            let dir = file.parent().unwrap_or_else(|| Path::new(""));
+            let secrets_path = dir.join(secrets_path);
+            let secrets = read_value(&secrets_path).map_err(|err| {
+                invalid(
+                    "secrets_path",
+                    &format!("couldn't read secrets from {secrets_path:?}: {err}"),
+                )
+            })?;
+            merge(&mut value, secrets);
+            #[cfg(feature = "matrix")]
+            require_secrets(&value, &secrets_path, &env)?;
+        }
+        Config::from_value(value, &env)
+    }
+
+    #[cfg(all(test, feature = "matrix"))]
+    fn from_toml_str(s: &str, env: &dyn Fn(&str) -> Option<String>) -> io::Result<Config> {
+        let value =
+            toml::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Config::from_value(value, env)
+    }
+
+    fn from_value(
+        mut value: toml::Value,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> io::Result<Config> {
+        interpolate(&mut value, env)?;
+        #[cfg(feature = "matrix")]
+        {
+            if let Some(token) = env(ENV_MATRIX_ACCESS_TOKEN) {
+                override_access_token(&mut value, token);
+            }
+            check_ids(&value)?;
+        }
+        let mut config: Config = value
+            .try_into()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        config.exclude_disabled_activities();
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Make every filter drop the activities turned off in `[activities]`.
+    fn exclude_disabled_activities(&mut self) {
+        let disabled = self.activities.disabled();
+        let filters = std::iter::once(&mut self.filter)
+            .chain(self.cqgma.iter_mut().filter_map(|c| c.filter.as_mut()))
+            .chain(self.route.iter_mut().map(|route| &mut route.filter));
+        for filter in filters {
+            filter.excluded_activities = disabled.clone();
+        }
+    }
+
+    /// Check things which deserialization alone can't. Errors name the
+    /// offending field and what was expected.
+    pub fn validate(&self) -> io::Result<()> {
+        #[cfg(feature = "matrix")]
+        {
+            if self.matrix.is_empty() {
+                return Err(invalid("matrix", "at least one account is required"));
+            }
+            for (i, matrix) in self.matrix.iter().enumerate() {
+                matrix.validate(&format!("matrix[{i}]"))?;
+            }
+        }
+        if self.cqgma.is_empty() {
+            return Err(invalid("cqgma", "at least one cluster is required"));
+        }
+        for (i, cqgma) in self.cqgma.iter().enumerate() {
+            cqgma.validate(&format!("cqgma[{i}]"))?;
+        }
+        if let Some(grid) = &self.home_grid {
+            if crate::geo::grid_to_latlon(grid).is_none() {
+                return Err(invalid(
+                    "home_grid",
+                    &format!("invalid Maidenhead locator '{grid}', expected eg. KP20 or KP20le"),
+                ));
+            }
+        }
+        if let Some(store) = &self.store {
+            if store.retention_days == 0 {
+                return Err(invalid("store.retention_days", "must be greater than zero"));
+            }
+        }
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.validate()?;
+        }
+        for (i, webhook) in self.webhook.iter().enumerate() {
+            webhook.validate(&format!("webhook[{i}]"))?;
+        }
+        for (i, discord) in self.discord.iter().enumerate() {
+            if !is_http_url(&discord.webhook_url) {
+                return Err(invalid(
+                    &format!("discord[{i}].webhook_url"),
+                    "expected http:// or https:// URL",
+                ));
+            }
+            if discord.color.map_or(false, |color| color > 0xff_ff_ff) {
+                return Err(invalid(&format!("discord[{i}].color"), "expected 0xRRGGBB"));
+            }
+        }
+        for (i, telegram) in self.telegram.iter().enumerate() {
+            telegram.validate(&format!("telegram[{i}]"))?;
+        }
+        for (i, ntfy) in self.ntfy.iter().enumerate() {
+            ntfy.validate(&format!("ntfy[{i}]"))?;
+        }
+        if let Some(email) = &self.email {
+            email.validate()?;
+        }
+        if let Some(aprs) = &self.aprs {
+            aprs.validate()?;
+        }
+        if let Some(nostr) = &self.nostr {
+            nostr.validate()?;
+        }
+        if let Some(xmpp) = &self.xmpp {
+            xmpp.validate()?;
+        }
+        if let Some(influxdb) = &self.influxdb {
+            influxdb.validate()?;
+        }
+        if let Some(kafka) = &self.kafka {
+            kafka.validate()?;
+        }
+        if let Some(sotawatch) = &self.sotawatch {
+            sotawatch.validate()?;
+        }
+        if let Some(pota_spots) = &self.pota_spots {
+            pota_spots.validate()?;
+        }
+        if let Some(http) = &self.http {
+            if http.map_minutes == 0 {
+                return Err(invalid("http.map_minutes", "must be greater than zero"));
+            }
+            if let Some(region) = &http.map_region {
+                let lat = -90.0..=90.0;
+                let lon = -180.0..=180.0;
+                let in_range = lat.contains(&region.south)
+                    && lat.contains(&region.north)
+                    && lon.contains(&region.west)
+                    && lon.contains(&region.east);
+                if !in_range || region.south >= region.north || region.west >= region.east {
+                    return Err(invalid(
+                        "http.map_region",
+                        "expected south < north within ±90 and west < east within ±180",
+                    ));
+                }
+            }
+        }
+        if let Some(watchdog) = &self.watchdog {
+            if watchdog.stall_secs == 0 {
+                return Err(invalid("watchdog.stall_secs", "must be greater than zero"));
+            }
+            if let Some(active) = &watchdog.active_hours {
+                if active.start == active.end {
+                    return Err(invalid(
+                        "watchdog.active_hours",
+                        "start and end must differ",
+                    ));
+                }
+            }
+        }
+        if !(1..=MAX_CHANNEL_CAPACITY).contains(&self.channel_capacity) {
+            return Err(invalid(
+                "channel_capacity",
+                &format!("must be between 1 and {MAX_CHANNEL_CAPACITY}"),
+            ));
+        }
+        if let Some(lookup) = &self.lookup {
+            if lookup.timeout_secs == 0 {
+                return Err(invalid("lookup.timeout_secs", "must be greater than zero"));
+            }
+        }
+        if let Some(dead_letter) = &self.dead_letter {
+            if dead_letter.max_bytes == 0 {
+                return Err(invalid(
+                    "dead_letter.max_bytes",
+                    "must be greater than zero",
+                ));
+            }
+        }
+        let sinks = self.sink_names();
+        for (i, route) in self.route.iter().enumerate() {
+            let field = format!("route[{i}].sinks");
+            if route.sinks.is_empty() {
+                return Err(invalid(&field, "at least one sink is required"));
+            }
+            if let Some(unknown) = route.sinks.iter().find(|sink| !sinks.contains(sink)) {
+                return Err(invalid(
+                    &field,
+                    &format!(
+                        "no sink named '{unknown}', expected one of: {}",
+                        sinks.join(", ")
+                    ),
+                ));
+            }
+        }
+        let mut windowed = BTreeSet::new();
+        for (i, dedup) in self.dedup.iter().enumerate() {
+            let field = format!("dedup[{i}].sinks");
+            if dedup.sinks.is_empty() {
+                return Err(invalid(&field, "at least one sink is required"));
+            }
+            if let Some(unknown) = dedup.sinks.iter().find(|sink| !sinks.contains(sink)) {
+                return Err(invalid(
+                    &field,
+                    &format!(
+                        "no sink named '{unknown}', expected one of: {}",
+                        sinks.join(", ")
+                    ),
+                ));
+            }
+            if let Some(twice) = dedup.sinks.iter().find(|sink| !windowed.insert(*sink)) {
+                return Err(invalid(
+                    &field,
+                    &format!("sink '{twice}' has more than one window"),
+                ));
+            }
+            if dedup.window_secs == 0 {
+                return Err(invalid(
+                    &format!("dedup[{i}].window_secs"),
+                    "must be greater than zero",
+                ));
+            }
+        }
+        self.logging.validate()?;
+        Ok(())
+    }
+
+    /// Size of the channels of spots and what happens when they're full.
+    pub fn channel_limits(&self) -> ChannelLimits {
+        ChannelLimits {
+            capacity: self.channel_capacity,
+            overflow: self.overflow_policy,
+        }
+    }
+
+    /// Names of the configured sinks, as in logs and `[[route]]` rules.
+    /// Sinks configured more than once are numbered, eg. `webhook[0]`.
+    pub fn sink_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        #[cfg(feature = "matrix")]
+        names.extend(self.matrix.iter().map(|m| format!("matrix {}", m.user_id)));
+        let numbered = [
+            ("webhook", self.webhook.len()),
+            ("discord", self.discord.len()),
+            ("telegram", self.telegram.len()),
+            ("ntfy", self.ntfy.len()),
+        ];
+        for (name, count) in numbered {
+            names.extend((0..count).map(|i| format!("{name}[{i}]")));
+        }
+        let single = [
+            ("store", self.store.is_some()),
+            ("mqtt", self.mqtt.is_some()),
+            ("email", self.email.is_some()),
+            ("aprs", self.aprs.is_some()),
+            ("nostr", self.nostr.is_some()),
+            ("xmpp", self.xmpp.is_some()),
+            ("influxdb", self.influxdb.is_some()),
+            ("kafka", self.kafka.is_some()),
+            (
+                "sotawatch",
+                self.sotawatch.as_ref().map_or(false, |s| s.enabled),
+            ),
+            (
+                "pota_spots",
+                self.pota_spots.as_ref().map_or(false, |p| p.enabled),
+            ),
+            ("adif", self.adif.is_some()),
+            ("jsonl", self.jsonl.is_some()),
+            ("csv", self.csv.is_some()),
+        ];
+        names.extend(
+            single
+                .into_iter()
+                .filter(|(_, configured)| *configured)
+                .map(|(name, _)| name.to_string()),
+        );
+        // Enabled on the command line
+        names.push("stdout".to_string());
+        names
     }
 }
 
-impl fmt::Debug for MatrixConfig {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("MatrixConfig")
-            .field("homeserver", &self.homeserver)
-            .field("access_token", &"<IS SECRET>")
-            .field("user_id", &self.user_id)
-            .field("device_id", &self.device_id)
-            .field("room", &self.room_id)
-            .finish()
+#[cfg(feature = "matrix")]
+impl MatrixConfig {
+    fn validate(&self, name: &str) -> io::Result<()> {
+        if self.access_token.is_empty() {
+            return Err(invalid(
+                &format!("{name}.access_token"),
+                "must not be empty",
+            ));
+        }
+        if self.rooms.is_empty() {
+            return Err(invalid(
+                &format!("{name}.rooms"),
+                "at least one room is required",
+            ));
+        }
+        for (i, room) in self.rooms.iter().enumerate() {
+            if self.rooms[..i].contains(room) {
+                return Err(invalid(
+                    &format!("{name}.rooms"),
+                    &format!("room {room} is listed more than once"),
+                ));
+            }
+        }
+        if let Some(quiet) = &self.quiet_hours {
+            if quiet.start == quiet.end {
+                return Err(invalid(
+                    &format!("{name}.quiet_hours"),
+                    "start and end must differ",
+                ));
+            }
+        }
+        if self.dedup_window_secs == 0 {
+            return Err(invalid(
+                &format!("{name}.dedup_window_secs"),
+                "must be greater than zero",
+            ));
+        }
+        let formats = [Format::Plain, Format::Html];
+        check_template(name, self.template.as_ref(), self.format, &formats)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::Config;
+impl MqttConfig {
+    fn validate(&self) -> io::Result<()> {
+        if self.broker_addr().is_none() {
+            return Err(invalid(
+                "mqtt.broker",
+                &format!(
+                    "expected mqtt://host[:port], eg. mqtt://localhost:1883; got '{}'",
+                    self.broker
+                ),
+            ));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(invalid("mqtt.password", "needs also username"));
+        }
+        if self.qos > 2 {
+            return Err(invalid("mqtt.qos", "must be 0, 1 or 2"));
+        }
+        let formats = [Format::Plain, Format::Json];
+        check_template("mqtt", self.template.as_ref(), self.format, &formats)
+    }
+}
 
-    #[test]
-    fn test_read_config() {
-        let raw = r##"
-        [matrix]
-        homeserver = "https://matrix.pikaviestin.fi:8448"
-        access_token = "abcdefghijklmnopqrstuvwxyz12345678901234567890"
-        user_id = "@puskapupu:pikaviestin.fi"
-        device_id = "puskapupu"
-        room_id = "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+/// Starts with `http://` or `https://` followed by something.
+fn is_http_url(url: &str) -> bool {
+    let rest = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"));
+    rest.map_or(false, |rest| !rest.is_empty())
+}
 
-        [cqgma]
-        host = "www.cqgma.org:7300"
-        username = "oh9xxx-4"
-        "##;
+impl WebhookConfig {
+    fn validate(&self, name: &str) -> io::Result<()> {
+        if !is_http_url(&self.url) {
+            return Err(invalid(
+                &format!("{name}.url"),
+                &format!("expected http:// or https:// URL; got '{}'", self.url),
+            ));
+        }
+        for (header, value) in &self.headers {
+            let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+            if header.is_empty() || !header.chars().all(is_token) {
+                return Err(invalid(
+                    &format!("{name}.headers"),
+                    &format!("invalid header name '{header}'"),
+                ));
+            }
+            if value.chars().any(|c| c.is_ascii_control()) {
+                return Err(invalid(
+                    &format!("{name}.headers.{header}"),
+                    "must not contain control characters",
+                ));
+            }
+        }
+        if self.timeout_secs == 0 {
+            return Err(invalid(
+                &format!("{name}.timeout_secs"),
+                "must be greater than zero",
+            ));
+        }
+        Ok(())
+    }
+}
 
-        let parsed: Config = toml::from_str(raw).unwrap();
-        dbg!(parsed);
+impl TelegramConfig {
+    fn validate(&self, name: &str) -> io::Result<()> {
+        // Tokens look like `123456:ABC-DEF1234ghIkl-zyx57W2v1u123ew11`
+        let token_ok = self
+            .bot_token
+            .split_once(':')
+            .map_or(false, |(id, secret)| {
+                !id.is_empty()
+                    && id.chars().all(|c| c.is_ascii_digit())
+                    && !secret.is_empty()
+                    && secret
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        if !token_ok {
+            return Err(invalid(
+                &format!("{name}.bot_token"),
+                "expected <bot id>:<secret> from @BotFather",
+            ));
+        }
+        let chat_ok = match self.chat_id.strip_prefix('@') {
+            Some(channel) => !channel.is_empty(),
+            None => self.chat_id.parse::<i64>().is_ok(),
+        };
+        if !chat_ok {
+            return Err(invalid(
+                &format!("{name}.chat_id"),
+                &format!("expected numeric id or @channel; got '{}'", self.chat_id),
+            ));
+        }
+        let formats = [Format::Plain, Format::Html, Format::Markdown];
+        check_template(name, self.template.as_ref(), self.format, &formats)
+    }
+}
+
+impl NostrConfig {
+    fn validate(&self) -> io::Result<()> {
+        if self.relays.is_empty() {
+            return Err(invalid("nostr.relays", "at least one relay is needed"));
+        }
+        for relay in &self.relays {
+            if !relay.starts_with("wss://") && !relay.starts_with("ws://") {
+                return Err(invalid(
+                    "nostr.relays",
+                    &format!("expected wss:// URL; got '{relay}'"),
+                ));
+            }
+        }
+        #[cfg(feature = "nostr")]
+        let key_ok = crate::nostr::parse_secret_key(&self.secret_key).is_some();
+        #[cfg(not(feature = "nostr"))]
+        let key_ok = !self.secret_key.is_empty();
+        if !key_ok {
+            return Err(invalid(
+                "nostr.secret_key",
+                "expected 64 hex digits or nsec1...",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl NtfyConfig {
+    fn validate(&self, name: &str) -> io::Result<()> {
+        if !is_http_url(&self.server) {
+            return Err(invalid(
+                &format!("{name}.server"),
+                &format!("expected http:// or https:// URL; got '{}'", self.server),
+            ));
+        }
+        let topic_ok = !self.topic.is_empty()
+            && self.topic.len() <= 64
+            && self
+                .topic
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !topic_ok {
+            return Err(invalid(
+                &format!("{name}.topic"),
+                &format!(
+                    "expected up to 64 letters, digits, - and _; got '{}'",
+                    self.topic
+                ),
+            ));
+        }
+        if self
+            .token
+            .as_ref()
+            .map_or(false, |token| token.chars().any(|c| c.is_ascii_control()))
+        {
+            return Err(invalid(
+                &format!("{name}.token"),
+                "must not contain control characters",
+            ));
+        }
+        if !(1..=5).contains(&self.new_one_priority) {
+            return Err(invalid(
+                &format!("{name}.new_one_priority"),
+                "expected 1 to 5",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl EmailConfig {
+    fn validate(&self) -> io::Result<()> {
+        let server_ok = self.server.rsplit_once(':').map_or(false, |(host, port)| {
+            !host.is_empty() && port.parse::<u16>().is_ok()
+        });
+        if !server_ok {
+            return Err(invalid(
+                "email.server",
+                &format!(
+                    "expected host:port, eg. smtp.example.org:587; got '{}'",
+                    self.server
+                ),
+            ));
+        }
+        if self.username.is_some() != self.password.is_some() {
+            return Err(invalid(
+                "email.password",
+                "username and password go together",
+            ));
+        }
+        let is_address = |address: &str| {
+            address.split_once('@').map_or(false, |(local, domain)| {
+                !local.is_empty()
+                    && !domain.is_empty()
+                    && !address.contains(|c: char| c.is_whitespace() || "<>".contains(c))
+            })
+        };
+        if !is_address(&self.from) {
+            return Err(invalid(
+                "email.from",
+                &format!("expected email address; got '{}'", self.from),
+            ));
+        }
+        if self.to.is_empty() {
+            return Err(invalid("email.to", "at least one recipient is needed"));
+        }
+        if let Some(to) = self.to.iter().find(|to| !is_address(to)) {
+            return Err(invalid(
+                "email.to",
+                &format!("expected email address; got '{to}'"),
+            ));
+        }
+        if self.watch.is_empty() && !self.new_one {
+            return Err(invalid(
+                "email.watch",
+                "nothing to alert on without references to watch or new_one",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl InfluxdbConfig {
+    fn validate(&self) -> io::Result<()> {
+        if !is_http_url(&self.url) {
+            return Err(invalid(
+                "influxdb.url",
+                &format!("expected http:// or https:// URL; got '{}'", self.url),
+            ));
+        }
+        if self.org.is_empty() {
+            return Err(invalid("influxdb.org", "must not be empty"));
+        }
+        if self.bucket.is_empty() {
+            return Err(invalid("influxdb.bucket", "must not be empty"));
+        }
+        if self.token.chars().any(|c| c.is_ascii_control()) {
+            return Err(invalid(
+                "influxdb.token",
+                "must not contain control characters",
+            ));
+        }
+        if self.batch_size == 0 {
+            return Err(invalid("influxdb.batch_size", "must be greater than zero"));
+        }
+        if self.flush_secs == 0 {
+            return Err(invalid("influxdb.flush_secs", "must be greater than zero"));
+        }
+        Ok(())
+    }
+}
+
+impl KafkaConfig {
+    fn validate(&self) -> io::Result<()> {
+        if self.brokers.is_empty() {
+            return Err(invalid("kafka.brokers", "at least one broker is required"));
+        }
+        for broker in &self.brokers {
+            let broker_ok = broker.rsplit_once(':').map_or(false, |(host, port)| {
+                !host.is_empty() && port.parse::<u16>().is_ok()
+            });
+            if !broker_ok {
+                return Err(invalid(
+                    "kafka.brokers",
+                    &format!("expected host:port, eg. localhost:9092; got '{broker}'"),
+                ));
+            }
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || "._-".contains(c);
+        if self.topic.is_empty() || self.topic.len() > 249 || !self.topic.chars().all(valid) {
+            return Err(invalid(
+                "kafka.topic",
+                &format!(
+                    "expected up to 249 letters, digits, '.', '_' or '-'; got '{}'",
+                    self.topic
+                ),
+            ));
+        }
+        if ![1, -1].contains(&self.acks) {
+            return Err(invalid(
+                "kafka.acks",
+                &format!("expected 1 or -1; got {}", self.acks),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SotawatchConfig {
+    fn validate(&self) -> io::Result<()> {
+        if self.username.is_empty() {
+            return Err(invalid("sotawatch.username", "must not be empty"));
+        }
+        if self.password.is_empty() {
+            return Err(invalid("sotawatch.password", "must not be empty"));
+        }
+        if let Some(callsign) = &self.callsign {
+            if callsign.is_empty()
+                || !callsign
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '/')
+            {
+                return Err(invalid(
+                    "sotawatch.callsign",
+                    &format!("expected a callsign; got '{callsign}'"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PotaSpotsConfig {
+    fn validate(&self) -> io::Result<()> {
+        if self.callsign.is_empty()
+            || !self
+                .callsign
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '/')
+        {
+            return Err(invalid(
+                "pota_spots.callsign",
+                &format!("expected a callsign; got '{}'", self.callsign),
+            ));
+        }
+        if self.token.is_empty() || self.token.chars().any(|c| c.is_ascii_control()) {
+            return Err(invalid(
+                "pota_spots.token",
+                "must not be empty or contain control characters",
+            ));
+        }
+        if self.cooldown_secs == 0 {
+            return Err(invalid(
+                "pota_spots.cooldown_secs",
+                "must be greater than zero",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl XmppConfig {
+    fn validate(&self) -> io::Result<()> {
+        if self.account().is_none() {
+            return Err(invalid(
+                "xmpp.jid",
+                &format!("expected user@domain; got '{}'", self.jid),
+            ));
+        }
+        if self.server_addr().is_none() {
+            return Err(invalid(
+                "xmpp.server",
+                &format!(
+                    "expected host:port, eg. example.org:{DEFAULT_XMPP_PORT}; got '{}'",
+                    self.server.as_deref().unwrap_or_default()
+                ),
+            ));
+        }
+        let room_ok = self.room.split_once('@').map_or(false, |(local, domain)| {
+            !local.is_empty() && !domain.is_empty() && !domain.contains('/')
+        });
+        if !room_ok {
+            return Err(invalid(
+                "xmpp.room",
+                &format!(
+                    "expected room@service, eg. spots@conference.example.org; got '{}'",
+                    self.room
+                ),
+            ));
+        }
+        if self.nick.trim().is_empty() {
+            return Err(invalid("xmpp.nick", "must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+impl AprsConfig {
+    fn validate(&self) -> io::Result<()> {
+        let server_ok = self.server.rsplit_once(':').map_or(false, |(host, port)| {
+            !host.is_empty() && port.parse::<u16>().is_ok()
+        });
+        if !server_ok {
+            return Err(invalid(
+                "aprs.server",
+                &format!(
+                    "expected host:port, eg. {DEFAULT_APRS_SERVER}; got '{}'",
+                    self.server
+                ),
+            ));
+        }
+        if !is_aprs_address(&self.callsign) {
+            return Err(invalid(
+                "aprs.callsign",
+                &format!(
+                    "expected callsign with optional SSID, eg. OH8HUB-10; got '{}'",
+                    self.callsign
+                ),
+            ));
+        }
+        if self.passcode != aprs_passcode(&self.callsign) {
+            return Err(invalid(
+                "aprs.passcode",
+                &format!("isn't the passcode of {}", self.callsign),
+            ));
+        }
+        if !is_aprs_address(&self.to) {
+            return Err(invalid(
+                "aprs.to",
+                &format!(
+                    "expected callsign or bulletin, eg. BLN1DX; got '{}'",
+                    self.to
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// At most 9 letters, digits and `-`, as in the addressee of a message.
+fn is_aprs_address(address: &str) -> bool {
+    (1..=9).contains(&address.len())
+        && address
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+impl CqgmaConfig {
+    fn validate(&self, name: &str) -> io::Result<()> {
+        let host_err = || {
+            invalid(
+                &format!("{name}.host"),
+                &format!(
+                    "expected host:port, eg. www.cqgma.org:7300; got '{}'",
+                    self.host
+                ),
+            )
+        };
+        let (host, port) = self.host.rsplit_once(':').ok_or_else(host_err)?;
+        if host.is_empty() || port.parse::<u16>().is_err() {
+            return Err(host_err());
+        }
+        if self.username.trim().is_empty() {
+            return Err(invalid(&format!("{name}.username"), "must not be empty"));
+        }
+        if self.reconnect_min_secs > self.reconnect_max_secs {
+            return Err(invalid(
+                &format!("{name}.reconnect_min_secs"),
+                &format!(
+                    "must not be greater than reconnect_max_secs ({})",
+                    self.reconnect_max_secs
+                ),
+            ));
+        }
+        if self.max_consecutive_failures == Some(0) {
+            return Err(invalid(
+                &format!("{name}.max_consecutive_failures"),
+                "must be greater than zero",
+            ));
+        }
+        if let Some(proxy) = &self.proxy {
+            proxy.validate(&format!("{name}.proxy"))?;
+        }
+        Ok(())
+    }
+}
+
+impl ProxyConfig {
+    fn validate(&self, name: &str) -> io::Result<()> {
+        let port = self
+            .address
+            .rsplit_once(':')
+            .map(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if port != Some(true) {
+            return Err(invalid(
+                &format!("{name}.address"),
+                &format!(
+                    "expected host:port, eg. localhost:1080; got '{}'",
+                    self.address
+                ),
+            ));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(invalid(&format!("{name}.password"), "needs also username"));
+        }
+        if self.kind == ProxyKind::Socks5 {
+            let too_long = |s: &Option<String>| s.as_ref().map_or(false, |s| s.len() > 255);
+            if too_long(&self.username) || too_long(&self.password) {
+                return Err(invalid(
+                    name,
+                    "SOCKS5 username and password must be at most 255 bytes",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read any of the supported file formats into [toml::Value], so the rest
+/// of config handling is the same for all of them.
+fn read_value(file: &Path) -> io::Result<toml::Value> {
+    let s = std::fs::read(file)?;
+    let s = std::str::from_utf8(&s)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "config is not valid utf-8"))?;
+    let extension = file.extension().and_then(|ext| ext.to_str());
+    match extension {
+        Some("yaml" | "yml") => {
+            serde_yaml::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+        Some("json") => {
+            serde_json::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+        _ => toml::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+    }
+}
+
+/// Read `file` and the files it includes. `stack` holds the files being
+/// included to detect cycles.
+fn read_with_includes(file: &Path, stack: &mut Vec<PathBuf>) -> io::Result<toml::Value> {
+    let canonical = file.canonicalize().unwrap_or_else(|_| file.to_path_buf());
+    if stack.contains(&canonical) {
+        let cycle: Vec<String> = stack
+            .iter()
+            .chain([&canonical])
+            .map(|path| path.display().to_string())
+            .collect();
+        return Err(invalid("include", &format!("cycle {}", cycle.join(" -> "))));
+    }
+
+    let mut value = read_value(file)?;
+    let includes = match &mut value {
+        toml::Value::Table(table) => table.remove("include"),
+        _ => None,
+    };
+    let includes = match includes {
+        None => return Ok(value),
+        Some(toml::Value::Array(includes)) => includes,
+        Some(include) => vec![include],
+    };
+
+    stack.push(canonical);
+    let dir = file.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = toml::Value::Table(toml::Table::new());
+    for include in includes {
+        let include = include
+            .as_str()
+            .ok_or_else(|| invalid("include", "must be a list of file names"))?;
+        let path = dir.join(include);
+        let included = read_with_includes(&path, stack).map_err(|err| {
+            if err.kind() == io::ErrorKind::NotFound {
+                invalid(
+                    "include",
+                    &format!("couldn't read {path:?} included from {file:?}: {err}"),
+                )
+            } else {
+                err
+            }
+        })?;
+        merge(&mut merged, included);
+    }
+    stack.pop();
+
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merge `over` into `base`. Tables are merged key by key and arrays of
+/// tables item by item, so `[[matrix]]` in secrets fills in the accounts of
+/// the config. Other arrays are replaced, so an override can also shorten a
+/// list. A single table and an array of tables are merged as if both were
+/// arrays.
+fn merge(base: &mut toml::Value, over: toml::Value) {
+    use toml::Value;
+
+    let is_tables = |array: &[Value]| !array.is_empty() && array.iter().all(Value::is_table);
+    match (base, over) {
+        (Value::Table(base), Value::Table(over)) => {
+            for (key, over) in over {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, over),
+                    None => {
+                        base.insert(key, over);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(over)) if is_tables(base) && is_tables(&over) => {
+            for (i, over) in over.into_iter().enumerate() {
+                match base.get_mut(i) {
+                    Some(base) => merge(base, over),
+                    None => base.push(over),
+                }
+            }
+        }
+        (Value::Array(base), over @ Value::Table(_)) => match base.first_mut() {
+            Some(base) => merge(base, over),
+            None => base.push(over),
+        },
+        (base @ Value::Table(_), Value::Array(over)) => {
+            let mut array = Value::Array(vec![base.clone()]);
+            merge(&mut array, Value::Array(over));
+            *base = array;
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// Set the access token of the first `[matrix]` account in `value`.
+#[cfg(feature = "matrix")]
+fn override_access_token(value: &mut toml::Value, token: String) {
+    let account = match value.get_mut("matrix") {
+        Some(toml::Value::Array(accounts)) => accounts.first_mut(),
+        account => account,
+    };
+    if let Some(toml::Value::Table(account)) = account {
+        account.insert("access_token".to_string(), toml::Value::String(token));
+    }
+}
+
+/// Fail clearly if secrets expected from the secrets file are missing after
+/// merging. The first access token may come from [ENV_MATRIX_ACCESS_TOKEN]
+/// instead.
+#[cfg(feature = "matrix")]
+fn require_secrets(
+    value: &toml::Value,
+    secrets_path: &Path,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> io::Result<()> {
+    let accounts = match value.get("matrix") {
+        Some(toml::Value::Array(accounts)) => accounts.iter().collect(),
+        Some(account) => vec![account],
+        None => Vec::new(),
+    };
+    let from_env = env(ENV_MATRIX_ACCESS_TOKEN).is_some();
+    for (i, account) in accounts.into_iter().enumerate() {
+        if account.get("access_token").is_none() && !(i == 0 && from_env) {
+            return Err(invalid(
+                &format!("matrix[{i}].access_token"),
+                &format!("missing from both config and secrets file {secrets_path:?}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check Matrix IDs before deserializing them, as errors from the ID types
+/// themselves don't tell which field or value was wrong.
+#[cfg(feature = "matrix")]
+fn check_ids(value: &toml::Value) -> io::Result<()> {
+    fn items(value: Option<&toml::Value>) -> Vec<&toml::Value> {
+        match value {
+            Some(toml::Value::Array(values)) => values.iter().collect(),
+            Some(value) => vec![value],
+            None => Vec::new(),
+        }
+    }
+
+    for (i, account) in items(value.get("matrix")).into_iter().enumerate() {
+        let name = format!("matrix[{i}]");
+        if let Some(user_id) = account.get("user_id").and_then(toml::Value::as_str) {
+            check_user_id(&format!("{name}.user_id"), user_id)?;
+        }
+        if let Some(device_id) = account.get("device_id").and_then(toml::Value::as_str) {
+            if device_id.trim().is_empty() || device_id.contains(char::is_whitespace) {
+                return Err(invalid(
+                    &format!("{name}.device_id"),
+                    &format!(
+                        "device_id must not be empty or contain whitespace; got '{device_id}'"
+                    ),
+                ));
+            }
+        }
+        let rooms = account.get("rooms").or_else(|| account.get("room_id"));
+        for (j, room) in items(rooms).into_iter().enumerate() {
+            if let Some(room_id) = room.as_str() {
+                check_room_id(&format!("{name}.rooms[{j}]"), room_id)?;
+            }
+        }
+        for (j, admin) in items(account.get("admins")).into_iter().enumerate() {
+            if let Some(user_id) = admin.as_str() {
+                check_user_id(&format!("{name}.admins[{j}]"), user_id)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "matrix")]
+fn check_user_id(field: &str, user_id: &str) -> io::Result<()> {
+    if !user_id.starts_with('@') || !user_id.contains(':') {
+        return Err(invalid(
+            field,
+            &format!("user_id must start with '@' and contain ':'; got '{user_id}'"),
+        ));
+    }
+    UserId::parse(user_id).map(|_| ()).map_err(|err| {
+        invalid(
+            field,
+            &format!("user_id is invalid ({err}); got '{user_id}'"),
+        )
+    })
+}
+
+#[cfg(feature = "matrix")]
+fn check_room_id(field: &str, room_id: &str) -> io::Result<()> {
+    if !room_id.starts_with('!') || !room_id.contains(':') {
+        return Err(invalid(
+            field,
+            &format!("room_id must start with '!' and contain ':'; got '{room_id}'"),
+        ));
+    }
+    RoomId::parse(room_id).map(|_| ()).map_err(|err| {
+        invalid(
+            field,
+            &format!("room_id is invalid ({err}); got '{room_id}'"),
+        )
+    })
+}
+
+/// Replace `${VAR}`s in all strings within `value`.
+fn interpolate(value: &mut toml::Value, env: &dyn Fn(&str) -> Option<String>) -> io::Result<()> {
+    match value {
+        toml::Value::String(s) => {
+            let mut out = String::new();
+            let mut rest = s.as_str();
+            while let Some(start) = rest.find("${") {
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or_else(|| invalid("config", &format!("unclosed '${{' in '{s}'")))?;
+                let name = &rest[start + 2..start + end];
+                let var = env(name).ok_or_else(|| {
+                    invalid(
+                        "config",
+                        &format!("environment variable {name} referenced in config is not set"),
+                    )
+                })?;
+                out.push_str(&rest[..start]);
+                out.push_str(&var);
+                rest = &rest[start + end + 1..];
+            }
+            out.push_str(rest);
+            *s = out;
+        }
+        toml::Value::Array(values) => {
+            for value in values {
+                interpolate(value, env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                interpolate(value, env)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Check that the sink `name` supports `format` and that `template`
+/// renders valid `format`.
+fn check_template(
+    name: &str,
+    template: Option<&Template>,
+    format: Format,
+    supported: &[Format],
+) -> io::Result<()> {
+    if !supported.contains(&format) {
+        let names: Vec<&str> = supported.iter().map(Format::name).collect();
+        return Err(invalid(
+            &format!("{name}.format"),
+            &format!(
+                "expected one of {}; got '{}'",
+                names.join(", "),
+                format.name()
+            ),
+        ));
+    }
+    if let Some(template) = template {
+        template
+            .check(format)
+            .map_err(|err| invalid(&format!("{name}.template"), &err))?;
+    }
+    Ok(())
+}
+
+fn invalid(field: &str, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{field}: {msg}"))
+}
+
+/// Shown in place of secrets in [Debug](fmt::Debug) output. Structs holding
+/// secrets implement [Debug](fmt::Debug) by hand to use this.
+const SECRET: &str = "<IS SECRET>";
+
+impl fmt::Debug for CqgmaConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CqgmaConfig")
+            .field("host", &self.host)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| SECRET))
+            .field("filter", &self.filter)
+            .field("reconnect_min_secs", &self.reconnect_min_secs)
+            .field("reconnect_max_secs", &self.reconnect_max_secs)
+            .field("max_consecutive_failures", &self.max_consecutive_failures)
+            .field("proxy", &self.proxy)
+            .finish()
+    }
+}
+
+impl fmt::Debug for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyConfig")
+            .field("kind", &self.kind)
+            .field("address", &self.address)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| SECRET))
+            .finish()
+    }
+}
+
+impl fmt::Debug for WebhookConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Header values are often credentials
+        let headers: BTreeMap<_, _> = self.headers.keys().map(|k| (k, SECRET)).collect();
+        f.debug_struct("WebhookConfig")
+            .field("url", &self.url)
+            .field("headers", &headers)
+            .field("timeout_secs", &self.timeout_secs)
+            .field("retries", &self.retries)
+            .finish()
+    }
+}
+
+impl fmt::Debug for DiscordConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiscordConfig")
+            .field("webhook_url", &SECRET)
+            .field("username", &self.username)
+            .field("template", &self.template)
+            .field("color", &self.color)
+            .finish()
+    }
+}
+
+impl fmt::Debug for TelegramConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TelegramConfig")
+            .field("bot_token", &SECRET)
+            .field("chat_id", &self.chat_id)
+            .field("template", &self.template)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+impl fmt::Debug for AprsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AprsConfig")
+            .field("server", &self.server)
+            .field("callsign", &self.callsign)
+            .field("passcode", &SECRET)
+            .field("to", &self.to)
+            .field("template", &self.template)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NostrConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NostrConfig")
+            .field("relays", &self.relays)
+            .field("secret_key", &SECRET)
+            .field("template", &self.template)
+            .finish()
+    }
+}
+
+impl fmt::Debug for XmppConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XmppConfig")
+            .field("jid", &self.jid)
+            .field("password", &SECRET)
+            .field("server", &self.server)
+            .field("room", &self.room)
+            .field("nick", &self.nick)
+            .field("template", &self.template)
+            .finish()
+    }
+}
+
+impl fmt::Debug for NtfyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NtfyConfig")
+            .field("server", &self.server)
+            .field("topic", &self.topic)
+            .field("token", &self.token.as_ref().map(|_| SECRET))
+            .field("template", &self.template)
+            .field("new_one_priority", &self.new_one_priority)
+            .finish()
+    }
+}
+
+impl fmt::Debug for EmailConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmailConfig")
+            .field("server", &self.server)
+            .field("security", &self.security)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| SECRET))
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .field("watch", &self.watch)
+            .field("new_one", &self.new_one)
+            .field("cooldown_secs", &self.cooldown_secs)
+            .finish()
+    }
+}
+
+impl fmt::Debug for SotawatchConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SotawatchConfig")
+            .field("enabled", &self.enabled)
+            .field("username", &self.username)
+            .field("password", &SECRET)
+            .field("callsign", &self.callsign)
+            .finish()
+    }
+}
+
+impl fmt::Debug for PotaSpotsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PotaSpotsConfig")
+            .field("enabled", &self.enabled)
+            .field("callsign", &self.callsign)
+            .field("token", &SECRET)
+            .field("cooldown_secs", &self.cooldown_secs)
+            .finish()
+    }
+}
+
+impl fmt::Debug for InfluxdbConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InfluxdbConfig")
+            .field("url", &self.url)
+            .field("org", &self.org)
+            .field("bucket", &self.bucket)
+            .field("token", &SECRET)
+            .field("batch_size", &self.batch_size)
+            .field("flush_secs", &self.flush_secs)
+            .finish()
+    }
+}
+
+impl fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("broker", &self.broker)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| SECRET))
+            .field("topic", &self.topic)
+            .field("qos", &self.qos)
+            .field("template", &self.template)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+#[cfg(feature = "matrix")]
+impl fmt::Debug for MatrixConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MatrixConfig")
+            .field("homeserver", &self.homeserver)
+            .field("access_token", &SECRET)
+            .field(
+                "refresh_token",
+                &self.refresh_token.as_ref().map(|_| SECRET),
+            )
+            .field("user_id", &self.user_id)
+            .field("device_id", &self.device_id)
+            .field("rooms", &self.rooms)
+            .field("quiet_hours", &self.quiet_hours)
+            .field("sync_token_path", &self.sync_token_path)
+            .field("dedup_window_secs", &self.dedup_window_secs)
+            .field("dedup_path", &self.dedup_path)
+            .field("admins", &self.admins)
+            .field("template", &self.template)
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "matrix"))]
+mod tests {
+    use super::{
+        aprs_passcode, require_secrets, Config, Facility, LogFormat, OverflowPolicy, ProxyConfig,
+        ProxyKind, QuietHours, SmtpSecurity, TimeOfDay, DEFAULT_LOOKUP_CACHE_SECS,
+        ENV_MATRIX_ACCESS_TOKEN,
+    };
+    use crate::band::Band;
+    use crate::channel::ChannelLimits;
+    use crate::filter::{FilterConfig, FrequencyRange};
+    use crate::parser::Activity;
+    use crate::template::Format;
+
+    #[test]
+    fn test_read_config() {
+        let raw = r##"
+        home_grid = "KP20le"
+
+        [matrix]
+        homeserver = "https://matrix.pikaviestin.fi:8448"
+        access_token = "abcdefghijklmnopqrstuvwxyz12345678901234567890"
+        user_id = "@puskapupu:pikaviestin.fi"
+        device_id = "puskapupu"
+        room_id = "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+        quiet_hours = { start = "22:00", end = "06:00" }
+        sync_token_path = "/var/lib/puskapupu/sync_token"
+        admins = ["@oh8hub:pikaviestin.fi"]
+        template = "{dx} {frequency} {info}"
+
+        [cqgma]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+
+        [filter]
+        bands = ["40m", "20m"]
+        "##;
+
+        let parsed: Config = toml::from_str(raw).unwrap();
+        dbg!(parsed);
+    }
+
+    #[test]
+    fn test_read_filter_config() {
+        let raw = format!(
+            r##"{MINIMAL}
+        [filter]
+        spotter_prefixes = ["OH", "OG", "OF"]
+        references = ["OHFF-"]
+        bands = ["40m", "20m"]
+        activities = ["wwff", "sota"]
+        modes = ["CW", "SSB"]
+        frequencies = [{{ min_khz = 7000.0, max_khz = 7040.0 }}]
+        dedup_window_secs = 300
+        max_age_secs = 900
+        "##
+        );
+
+        let config: Config = toml::from_str(&raw).unwrap();
+        assert_eq!(
+            config.filter,
+            FilterConfig {
+                spotter_prefixes: vec!["OH".to_string(), "OG".to_string(), "OF".to_string()],
+                references: vec!["OHFF-".to_string()],
+                bands: vec![Band::B40m, Band::B20m],
+                activities: vec![Activity::Wwff, Activity::Sota],
+                modes: vec!["CW".to_string(), "SSB".to_string()],
+                frequencies: vec![FrequencyRange {
+                    min_khz: 7000.0,
+                    max_khz: 7040.0
+                }],
+                dedup_window_secs: Some(300),
+                max_age_secs: Some(900),
+                excluded_activities: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_logging_config() {
+        let config: Config = toml::from_str(MINIMAL).unwrap();
+        assert_eq!(config.logging.format, LogFormat::Text);
+        let filter = config.logging.env_filter(None).unwrap();
+        assert_eq!(filter.to_string(), "info");
+
+        let raw = format!(
+            r##"{MINIMAL}
+        [logging]
+        level = "debug,matrix_sdk=warn"
+        format = "json"
+        "##
+        );
+        let config: Config = toml::from_str(&raw).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.logging.format, LogFormat::Json);
+        let filter = config.logging.env_filter(None).unwrap();
+        assert_eq!(filter.to_string(), "matrix_sdk=warn,debug");
+        let filter = config.logging.env_filter(Some("trace")).unwrap();
+        assert_eq!(filter.to_string(), "trace");
+        let filter = config.logging.env_filter(Some("")).unwrap();
+        assert_eq!(filter.to_string(), "matrix_sdk=warn,debug");
+
+        let mut config = config;
+        config.logging.level = "matrix_sdk=loud".to_string();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .starts_with("logging.level: 'matrix_sdk=loud'"));
+
+        let raw = format!(
+            r##"{MINIMAL}
+        [logging.syslog]
+        facility = "local3"
+        host = "logs.example.com:514"
+        "##
+        );
+        let mut config: Config = toml::from_str(&raw).unwrap();
+        assert!(config.validate().is_ok());
+        let syslog = config.logging.syslog.as_mut().unwrap();
+        assert_eq!(syslog.facility, Facility::Local3);
+        syslog.host = Some("logs.example.com".to_string());
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "logging.syslog.host: expected host:port, eg. localhost:514; got 'logs.example.com'"
+        );
+    }
+
+    #[test]
+    fn test_secrets_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("puskapupu.toml");
+        let base = r##"
+        secrets_path = "secrets.toml"
+
+        [matrix]
+        homeserver = "https://matrix.pikaviestin.fi:8448"
+        user_id = "@puskapupu:pikaviestin.fi"
+        device_id = "puskapupu"
+        room_id = "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+
+        [cqgma]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+
+        [filter]
+        spotter_prefixes = ["OH", "SM", "LA"]
+        "##;
+        std::fs::write(&config_path, base).unwrap();
+
+        let err = Config::read_from_file(&config_path).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("secrets_path: couldn't read secrets from"));
+
+        let secrets_path = dir.path().join("secrets.toml");
+        std::fs::write(&secrets_path, "[cqgma]\npassword = \"hunter2\"\n").unwrap();
+        let err = Config::read_from_file(&config_path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("matrix[0].access_token: missing from both config and secrets file {secrets_path:?}")
+        );
+        // Unless it's in the environment
+        let env = |name: &str| (name == ENV_MATRIX_ACCESS_TOKEN).then(|| "from-env".to_string());
+        require_secrets(&toml::from_str(base).unwrap(), &secrets_path, &env).unwrap();
+
+        let secrets = r##"
+        [[matrix]]
+        access_token = "abcdefghijklmnopqrstuvwxyz12345678901234567890"
+
+        [cqgma]
+        password = "hunter2"
+
+        [filter]
+        spotter_prefixes = ["OH"]
+        "##;
+        std::fs::write(&secrets_path, secrets).unwrap();
+        let config = Config::read_from_file(&config_path).unwrap();
+        assert_eq!(
+            config.matrix[0].access_token,
+            "abcdefghijklmnopqrstuvwxyz12345678901234567890"
+        );
+        assert_eq!(config.matrix[0].user_id, "@puskapupu:pikaviestin.fi");
+        assert_eq!(config.cqgma[0].password.as_deref(), Some("hunter2"));
+        assert_eq!(config.cqgma[0].username, "oh9xxx-4");
+        // Lists of values are replaced, not merged item by item
+        assert_eq!(config.filter.spotter_prefixes, ["OH"]);
+    }
+
+    #[test]
+    fn test_config_formats() {
+        let yaml = r##"
+        home_grid: KP20le
+        matrix:
+          homeserver: "https://matrix.pikaviestin.fi:8448"
+          access_token: abcdefghijklmnopqrstuvwxyz12345678901234567890
+          user_id: "@puskapupu:pikaviestin.fi"
+          device_id: puskapupu
+          room_id: "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+          quiet_hours: { start: "22:00", end: "06:00" }
+        cqgma:
+          - host: "www.cqgma.org:7300"
+            username: oh9xxx-4
+        filter:
+          bands: [40m, 20m]
+        "##;
+        let json = r##"{
+            "home_grid": "KP20le",
+            "matrix": {
+                "homeserver": "https://matrix.pikaviestin.fi:8448",
+                "access_token": "abcdefghijklmnopqrstuvwxyz12345678901234567890",
+                "user_id": "@puskapupu:pikaviestin.fi",
+                "device_id": "puskapupu",
+                "room_id": "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi",
+                "quiet_hours": { "start": "22:00", "end": "06:00" }
+            },
+            "cqgma": [{ "host": "www.cqgma.org:7300", "username": "oh9xxx-4" }],
+            "filter": { "bands": ["40m", "20m"] }
+        }"##;
+        let toml = r##"
+        home_grid = "KP20le"
+
+        [matrix]
+        homeserver = "https://matrix.pikaviestin.fi:8448"
+        access_token = "abcdefghijklmnopqrstuvwxyz12345678901234567890"
+        user_id = "@puskapupu:pikaviestin.fi"
+        device_id = "puskapupu"
+        room_id = "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+        quiet_hours = { start = "22:00", end = "06:00" }
+
+        [[cqgma]]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+
+        [filter]
+        bands = ["40m", "20m"]
+        "##;
+
+        let dir = tempfile::tempdir().unwrap();
+        let read = |name: &str, content: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            Config::read_from_file(path).unwrap()
+        };
+        let from_toml = read("puskapupu.toml", toml);
+        assert_eq!(from_toml.filter.bands, vec![Band::B40m, Band::B20m]);
+        assert_eq!(read("puskapupu.yaml", yaml), from_toml);
+        assert_eq!(read("puskapupu.yml", yaml), from_toml);
+        assert_eq!(read("puskapupu.json", json), from_toml);
+        assert_eq!(read("puskapupu", toml), from_toml);
+    }
+
+    #[test]
+    fn test_malformed_ids() {
+        let err = |from: &str, to: &str| {
+            let raw = MINIMAL.replace(from, to);
+            Config::from_toml_str(&raw, &|_| None)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err("!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi", "#spots:pikaviestin.fi"),
+            "matrix[0].rooms[0]: room_id must start with '!' and contain ':'; got '#spots:pikaviestin.fi'"
+        );
+        assert_eq!(
+            err("!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi", "!hVUOVQnjnxUgSTCdCJ"),
+            "matrix[0].rooms[0]: room_id must start with '!' and contain ':'; got '!hVUOVQnjnxUgSTCdCJ'"
+        );
+        assert_eq!(
+            err("@puskapupu:pikaviestin.fi", "puskapupu"),
+            "matrix[0].user_id: user_id must start with '@' and contain ':'; got 'puskapupu'"
+        );
+        assert!(err("@puskapupu:pikaviestin.fi", "@puskapupu:")
+            .starts_with("matrix[0].user_id: user_id is invalid ("));
+        assert_eq!(
+            err("device_id = \"puskapupu\"", "device_id = \"pupu pupu\""),
+            "matrix[0].device_id: device_id must not be empty or contain whitespace; got 'pupu pupu'"
+        );
+        assert_eq!(
+            err(
+                "device_id = \"puskapupu\"",
+                "device_id = \"puskapupu\"\nadmins = [\"@oh8hub:pikaviestin.fi\", \"oh8hub\"]"
+            ),
+            "matrix[0].admins[1]: user_id must start with '@' and contain ':'; got 'oh8hub'"
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = format!("include = [\"filters.toml\", \"bands.toml\"]\n{MINIMAL}");
+        let config_path = dir.path().join("puskapupu.toml");
+        std::fs::write(&config_path, base).unwrap();
+
+        let err = Config::read_from_file(&config_path).unwrap_err();
+        assert!(
+            err.to_string().starts_with("include: couldn't read \""),
+            "{err}"
+        );
+
+        let filters = r##"
+        [filter]
+        spotter_prefixes = ["OH", "OG", "OF"]
+        bands = ["40m"]
+        "##;
+        std::fs::write(dir.path().join("filters.toml"), filters).unwrap();
+        // A shorter list replaces the whole list
+        std::fs::write(
+            dir.path().join("bands.toml"),
+            "[filter]\nbands = [\"20m\"]\nspotter_prefixes = [\"OH\"]\n",
+        )
+        .unwrap();
+        let config = Config::read_from_file(&config_path).unwrap();
+        assert_eq!(config.filter.spotter_prefixes, vec!["OH".to_string()]);
+        assert_eq!(config.filter.bands, vec![Band::B20m]);
+        assert_eq!(config.cqgma[0].username, "oh9xxx-4");
+
+        std::fs::write(
+            dir.path().join("bands.toml"),
+            "include = \"puskapupu.toml\"\n",
+        )
+        .unwrap();
+        let err = Config::read_from_file(&config_path).unwrap_err();
+        assert!(err.to_string().starts_with("include: cycle "), "{err}");
+        assert!(err.to_string().ends_with("puskapupu.toml"), "{err}");
+    }
+
+    #[test]
+    fn test_debug_masks_secrets() {
+        let raw = r##"
+        [[matrix]]
+        homeserver = "https://matrix.pikaviestin.fi:8448"
+        access_token = "first-access-token"
+        refresh_token = "first-refresh-token"
+        user_id = "@puskapupu:pikaviestin.fi"
+        device_id = "puskapupu"
+        room_id = "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+
+        [[matrix]]
+        homeserver = "https://matrix.org"
+        access_token = "second-access-token"
+        user_id = "@puskapupu:matrix.org"
+        device_id = "puskapupu"
+        room_id = "!hVUOVQnjnxUgSTCdCJ:matrix.org"
+
+        [[cqgma]]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+        password = "cluster-password"
+        "##;
+        let config: Config = toml::from_str(raw).unwrap();
+        let debug = format!("{config:?}");
+        for secret in [
+            "first-access-token",
+            "first-refresh-token",
+            "second-access-token",
+            "cluster-password",
+        ] {
+            assert!(!debug.contains(secret), "{secret} leaked in {debug}");
+        }
+        let debug = format!("{config:#?}");
+        assert!(!debug.contains("-token\""));
+        assert!(debug.contains("<IS SECRET>"));
+    }
+
+    const MINIMAL: &str = r##"
+        [matrix]
+        homeserver = "https://matrix.pikaviestin.fi:8448"
+        access_token = "abcdefghijklmnopqrstuvwxyz12345678901234567890"
+        user_id = "@puskapupu:pikaviestin.fi"
+        device_id = "puskapupu"
+        room_id = "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+
+        [cqgma]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+        "##;
+
+    #[test]
+    fn test_minimal_config_defaults() {
+        let config: Config = toml::from_str(MINIMAL).unwrap();
+        assert!(config.validate().is_ok());
+
+        assert_eq!(config.home_grid, None);
+        assert_eq!(config.filter, FilterConfig::default());
+
+        let matrix = &config.matrix[0];
+        assert_eq!(matrix.quiet_hours, None);
+        assert_eq!(matrix.sync_token_path, None);
+        assert_eq!(matrix.dedup_window_secs, 600);
+        assert!(matrix.admins.is_empty());
+        assert_eq!(matrix.template, None);
+
+        let cqgma = &config.cqgma[0];
+        assert_eq!(cqgma.password, None);
+        assert_eq!(cqgma.filter, None);
+        assert_eq!(cqgma.reconnect_min_secs, 17);
+        assert_eq!(cqgma.reconnect_max_secs, 34);
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config = || toml::from_str::<Config>(MINIMAL).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+
+        let mut c = config();
+        c.cqgma[0].host = String::new();
+        assert_eq!(
+            err(c),
+            "cqgma[0].host: expected host:port, eg. www.cqgma.org:7300; got ''"
+        );
+
+        let mut c = config();
+        c.cqgma[0].host = "www.cqgma.org:telnet".to_string();
+        assert_eq!(
+            err(c),
+            "cqgma[0].host: expected host:port, eg. www.cqgma.org:7300; got 'www.cqgma.org:telnet'"
+        );
+
+        let mut c = config();
+        c.cqgma[0].username = " ".to_string();
+        assert_eq!(err(c), "cqgma[0].username: must not be empty");
+
+        let mut c = config();
+        c.matrix[0].rooms.clear();
+        assert_eq!(err(c), "matrix[0].rooms: at least one room is required");
+
+        let mut c = config();
+        let room = c.matrix[0].rooms[0].clone();
+        c.matrix[0].rooms.push(room);
+        assert_eq!(
+            err(c),
+            "matrix[0].rooms: room !hVUOVQnjnxUgSTCdCJ:pikaviestin.fi is listed more than once"
+        );
+
+        let mut c = config();
+        let time = TimeOfDay::try_from("22:00".to_string()).unwrap();
+        c.matrix[0].quiet_hours = Some(QuietHours {
+            start: time,
+            end: time,
+        });
+        assert_eq!(err(c), "matrix[0].quiet_hours: start and end must differ");
+
+        let mut c = config();
+        c.cqgma[0].reconnect_min_secs = 60;
+        c.cqgma[0].reconnect_max_secs = 30;
+        assert_eq!(
+            err(c),
+            "cqgma[0].reconnect_min_secs: must not be greater than reconnect_max_secs (30)"
+        );
+
+        let mut c = config();
+        c.cqgma[0].max_consecutive_failures = Some(0);
+        assert_eq!(
+            err(c),
+            "cqgma[0].max_consecutive_failures: must be greater than zero"
+        );
+
+        let proxy = |address: &str, password: Option<&str>| ProxyConfig {
+            kind: ProxyKind::Socks5,
+            address: address.to_string(),
+            username: None,
+            password: password.map(str::to_string),
+        };
+        let mut c = config();
+        c.cqgma[0].proxy = Some(proxy("localhost", None));
+        assert_eq!(
+            err(c),
+            "cqgma[0].proxy.address: expected host:port, eg. localhost:1080; got 'localhost'"
+        );
+        let mut c = config();
+        c.cqgma[0].proxy = Some(proxy("localhost:1080", Some("hunter2")));
+        assert_eq!(err(c), "cqgma[0].proxy.password: needs also username");
+
+        let mut c = config();
+        c.home_grid = Some("KP20le".to_string());
+        assert!(c.validate().is_ok());
+        c.home_grid = Some("KP20l".to_string());
+        assert_eq!(
+            err(c),
+            "home_grid: invalid Maidenhead locator 'KP20l', expected eg. KP20 or KP20le"
+        );
+
+        let mut c = config();
+        c.home_grid = Some("ZZ99".to_string());
+        assert_eq!(
+            err(c),
+            "home_grid: invalid Maidenhead locator 'ZZ99', expected eg. KP20 or KP20le"
+        );
+
+        let mut c = config();
+        c.cqgma.clear();
+        assert_eq!(err(c), "cqgma: at least one cluster is required");
+
+        let mut c = config();
+        c.matrix.clear();
+        assert_eq!(err(c), "matrix: at least one account is required");
+    }
+
+    #[test]
+    fn test_mqtt_config() {
+        let raw = format!("{MINIMAL}\n[mqtt]\nbroker = \"mqtt://localhost\"\n");
+        let config: Config = toml::from_str(&raw).unwrap();
+        assert!(config.validate().is_ok());
+        let mqtt = config.mqtt.unwrap();
+        assert_eq!(mqtt.broker_addr(), Some(("localhost", 1883)));
+        assert_eq!(mqtt.client_id, "puskapupu");
+        assert_eq!(mqtt.topic.to_string(), "puskapupu/spots/{band}");
+        assert_eq!(mqtt.qos, 0);
+
+        let err = |mqtt: &str| {
+            let raw = format!("{MINIMAL}\n[mqtt]\n{mqtt}\n");
+            let config: Config = toml::from_str(&raw).unwrap();
+            config.validate().unwrap_err().to_string()
+        };
+        assert_eq!(
+            err("broker = \"localhost:1883\""),
+            "mqtt.broker: expected mqtt://host[:port], eg. mqtt://localhost:1883; got 'localhost:1883'"
+        );
+        assert!(err("broker = \"mqtt://localhost:mqtt\"").starts_with("mqtt.broker: "));
+        assert_eq!(
+            err("broker = \"mqtt://localhost\"\npassword = \"hunter2\""),
+            "mqtt.password: needs also username"
+        );
+        assert_eq!(
+            err("broker = \"mqtt://localhost\"\nqos = 3"),
+            "mqtt.qos: must be 0, 1 or 2"
+        );
+        assert_eq!(
+            err("broker = \"mqtt://localhost\"\nformat = \"html\""),
+            "mqtt.format: expected one of plain, json; got 'html'"
+        );
+        assert!(
+            err("broker = \"mqtt://localhost\"\ntemplate = \"{dx} {frequency}\"")
+                .starts_with("mqtt.template: template '{dx} {frequency}' doesn't give JSON: ")
+        );
+
+        let raw = format!(
+            "{MINIMAL}\n[mqtt]\nbroker = \"mqtt://localhost\"\n\
+             template = '{{{{\"dx\": \"{{dx}}\", \"info\": \"{{info}}\"}}}}'\n"
+        );
+        let config: Config = toml::from_str(&raw).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.mqtt.unwrap().format, Format::Json);
+    }
+
+    #[test]
+    fn test_webhook_config() {
+        let webhooks = r##"
+        [[webhook]]
+        url = "https://example.org/spots"
+        headers = { Authorization = "Bearer secret-token" }
+
+        [[webhook]]
+        url = "http://localhost:8000/"
+        timeout_secs = 2
+        retries = 0
+        "##;
+        let config: Config = toml::from_str(&format!("{MINIMAL}{webhooks}")).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.webhook.len(), 2);
+        assert_eq!(config.webhook[0].timeout_secs, 10);
+        assert_eq!(config.webhook[0].retries, 3);
+        assert_eq!(config.webhook[1].retries, 0);
+        assert!(!format!("{config:?}").contains("secret-token"));
+
+        let err = |webhook: &str| {
+            let raw = format!("{MINIMAL}\n[webhook]\n{webhook}\n");
+            let config: Config = toml::from_str(&raw).unwrap();
+            config.validate().unwrap_err().to_string()
+        };
+        assert_eq!(
+            err("url = \"example.org\""),
+            "webhook[0].url: expected http:// or https:// URL; got 'example.org'"
+        );
+        assert_eq!(
+            err("url = \"https://example.org\"\nheaders = { \"X Key\" = \"1\" }"),
+            "webhook[0].headers: invalid header name 'X Key'"
+        );
+        assert_eq!(
+            err("url = \"https://example.org\"\ntimeout_secs = 0"),
+            "webhook[0].timeout_secs: must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn test_discord_config() {
+        let discord = r##"
+        [discord]
+        webhook_url = "https://discord.com/api/webhooks/123/secret-token"
+        color = 0x2e7d32
+        "##;
+        let config: Config = toml::from_str(&format!("{MINIMAL}{discord}")).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.discord[0].color, Some(0x2e7d32));
+        assert_eq!(config.discord[0].template, None);
+        assert!(!format!("{config:?}").contains("secret-token"));
+
+        let mut c = config;
+        c.discord[0].color = Some(0x1000000);
+        assert_eq!(
+            c.validate().unwrap_err().to_string(),
+            "discord[0].color: expected 0xRRGGBB"
+        );
+        c.discord[0].webhook_url = "discord.com/api/webhooks/123/secret-token".to_string();
+        assert_eq!(
+            c.validate().unwrap_err().to_string(),
+            "discord[0].webhook_url: expected http:// or https:// URL"
+        );
+    }
+
+    #[test]
+    fn test_telegram_config() {
+        let telegram = r##"
+        [telegram]
+        bot_token = "123456:ABC-DEF1234ghIkl-zyx57W2v1u123ew11"
+        chat_id = "-1001234567890"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{telegram}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("zyx57W2v1u123ew11"));
+
+        let mut c = config();
+        c.telegram[0].bot_token = "ABC-DEF1234".to_string();
+        assert_eq!(
+            err(c),
+            "telegram[0].bot_token: expected <bot id>:<secret> from @BotFather"
+        );
+
+        let mut c = config();
+        c.telegram[0].chat_id = "spots".to_string();
+        assert_eq!(
+            err(c),
+            "telegram[0].chat_id: expected numeric id or @channel; got 'spots'"
+        );
+
+        let mut c = config();
+        c.telegram[0].chat_id = "@ohffspots".to_string();
+        assert!(c.validate().is_ok());
+
+        let mut c = config();
+        c.telegram[0].format = Format::Markdown;
+        assert!(c.validate().is_ok());
+        c.telegram[0].format = Format::Json;
+        assert_eq!(
+            err(c),
+            "telegram[0].format: expected one of plain, html, markdown; got 'json'"
+        );
+    }
+
+    #[test]
+    fn test_aprs_config() {
+        let aprs = r##"
+        [aprs]
+        callsign = "N0CALL-10"
+        passcode = 13023
+        to = "BLN1DX"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{aprs}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert_eq!(config().aprs.unwrap().server, "rotate.aprs2.net:14580");
+        assert!(!format!("{:?}", config()).contains("13023"));
+        assert_eq!(aprs_passcode("n0call"), 13023);
+
+        let mut c = config();
+        c.aprs.as_mut().unwrap().passcode = 12345;
+        assert_eq!(err(c), "aprs.passcode: isn't the passcode of N0CALL-10");
+
+        let mut c = config();
+        c.aprs.as_mut().unwrap().to = "BLN1DX/SPOTS".to_string();
+        assert_eq!(
+            err(c),
+            "aprs.to: expected callsign or bulletin, eg. BLN1DX; got 'BLN1DX/SPOTS'"
+        );
+
+        let mut c = config();
+        c.aprs.as_mut().unwrap().server = "rotate.aprs2.net".to_string();
+        assert!(err(c).starts_with("aprs.server: expected host:port"));
+    }
+
+    #[test]
+    fn test_lookup_config() {
+        let config =
+            toml::from_str::<Config>(&format!("{MINIMAL}[lookup]\nsota = true\npota = true\n"))
+                .unwrap();
+        assert!(config.validate().is_ok());
+        let lookup = config.lookup.clone().unwrap();
+        assert!(lookup.sota && lookup.pota);
+        assert_eq!(lookup.wwff_path, None);
+        assert_eq!(lookup.cache_secs, DEFAULT_LOOKUP_CACHE_SECS);
+
+        let mut c = config;
+        c.lookup.as_mut().unwrap().timeout_secs = 0;
+        assert_eq!(
+            c.validate().unwrap_err().to_string(),
+            "lookup.timeout_secs: must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn test_nostr_config() {
+        let nostr = r##"
+        [nostr]
+        relays = ["wss://relay.damus.io", "wss://nos.lol"]
+        secret_key = "67dea2ed018072d675f5415ecfaed7d2597555e202d85b3d65ea4e58d2d92ffa"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{nostr}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("67dea2ed"));
+
+        let mut c = config();
+        c.nostr.as_mut().unwrap().relays = vec!["https://relay.damus.io".to_string()];
+        assert_eq!(
+            err(c),
+            "nostr.relays: expected wss:// URL; got 'https://relay.damus.io'"
+        );
+
+        let mut c = config();
+        c.nostr.as_mut().unwrap().secret_key = String::new();
+        assert_eq!(
+            err(c),
+            "nostr.secret_key: expected 64 hex digits or nsec1..."
+        );
+    }
+
+    #[test]
+    fn test_xmpp_config() {
+        let xmpp = r##"
+        [xmpp]
+        jid = "puskapupu@example.org"
+        password = "hunter2"
+        room = "spots@conference.example.org"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{xmpp}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("hunter2"));
+        let xmpp = config().xmpp.unwrap();
+        assert_eq!(xmpp.nick, "puskapupu");
+        assert_eq!(xmpp.server_addr(), Some(("example.org", 5223)));
+
+        let mut c = config();
+        c.xmpp.as_mut().unwrap().server = Some("xmpp.example.org:443".to_string());
+        assert_eq!(
+            c.xmpp.as_ref().unwrap().server_addr(),
+            Some(("xmpp.example.org", 443))
+        );
+        assert!(c.validate().is_ok());
+
+        let mut c = config();
+        c.xmpp.as_mut().unwrap().jid = "example.org".to_string();
+        assert_eq!(err(c), "xmpp.jid: expected user@domain; got 'example.org'");
+
+        let mut c = config();
+        c.xmpp.as_mut().unwrap().server = Some("xmpp.example.org".to_string());
+        assert!(err(c).starts_with("xmpp.server: expected host:port"));
+
+        let mut c = config();
+        c.xmpp.as_mut().unwrap().room = "spots".to_string();
+        assert!(err(c).starts_with("xmpp.room: expected room@service"));
+    }
+
+    #[test]
+    fn test_ntfy_config() {
+        let ntfy = r##"
+        [[ntfy]]
+        topic = "ohff_spots"
+        token = "tk_hunter2"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{ntfy}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("hunter2"));
+        assert_eq!(config().ntfy[0].server, "https://ntfy.sh");
+        assert_eq!(config().ntfy[0].new_one_priority, 4);
+
+        let mut c = config();
+        c.ntfy[0].topic = "ohff spots".to_string();
+        assert_eq!(
+            err(c),
+            "ntfy[0].topic: expected up to 64 letters, digits, - and _; got 'ohff spots'"
+        );
+
+        let mut c = config();
+        c.ntfy[0].new_one_priority = 6;
+        assert_eq!(err(c), "ntfy[0].new_one_priority: expected 1 to 5");
+    }
+
+    #[test]
+    fn test_email_config() {
+        let email = r##"
+        [email]
+        server = "smtp.example.org:465"
+        security = "tls"
+        username = "oh8hub"
+        password = "hunter2"
+        from = "puskapupu@example.org"
+        to = "oh8hub@example.org"
+        watch = ["OHFF-1419"]
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{email}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("hunter2"));
+        let email = config().email.unwrap();
+        assert_eq!(email.security, SmtpSecurity::Tls);
+        assert_eq!(email.to, ["oh8hub@example.org"]);
+        assert!(!email.new_one);
+
+        let mut c = config();
+        c.email.as_mut().unwrap().password = None;
+        assert_eq!(err(c), "email.password: username and password go together");
+
+        let mut c = config();
+        c.email.as_mut().unwrap().to = vec!["OH8HUB <oh8hub@example.org>".to_string()];
+        assert_eq!(
+            err(c),
+            "email.to: expected email address; got 'OH8HUB <oh8hub@example.org>'"
+        );
+
+        let mut c = config();
+        c.email.as_mut().unwrap().watch.clear();
+        assert!(err(c).starts_with("email.watch: nothing to alert on"));
+    }
+
+    #[test]
+    fn test_kafka_config() {
+        let kafka = r##"
+        [kafka]
+        brokers = "localhost:9092"
+        topic = "spots"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{kafka}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        let kafka = config().kafka.unwrap();
+        assert_eq!(kafka.brokers, ["localhost:9092"]);
+        assert_eq!(kafka.client_id, "puskapupu");
+        assert_eq!(kafka.acks, -1);
+
+        let mut c = config();
+        c.kafka
+            .as_mut()
+            .unwrap()
+            .brokers
+            .push("localhost".to_string());
+        assert_eq!(
+            err(c),
+            "kafka.brokers: expected host:port, eg. localhost:9092; got 'localhost'"
+        );
+
+        let mut c = config();
+        c.kafka.as_mut().unwrap().topic = "spots/20m".to_string();
+        assert!(err(c).starts_with("kafka.topic: expected up to 249 letters"));
+
+        let mut c = config();
+        c.kafka.as_mut().unwrap().acks = 0;
+        assert_eq!(err(c), "kafka.acks: expected 1 or -1; got 0");
+    }
+
+    #[test]
+    fn test_sotawatch_config() {
+        let sotawatch = r##"
+        [sotawatch]
+        enabled = true
+        username = "oh8hub"
+        password = "hunter2"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{sotawatch}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("hunter2"));
+        assert!(config().sink_names().contains(&"sotawatch".to_string()));
+
+        let mut c = config();
+        c.sotawatch.as_mut().unwrap().enabled = false;
+        assert!(!c.sink_names().contains(&"sotawatch".to_string()));
+
+        // enabled must be given
+        assert!(toml::from_str::<Config>(&format!(
+            "{MINIMAL}[sotawatch]\nusername = \"oh8hub\"\npassword = \"hunter2\"\n"
+        ))
+        .is_err());
+
+        let mut c = config();
+        c.sotawatch.as_mut().unwrap().password.clear();
+        assert_eq!(err(c), "sotawatch.password: must not be empty");
+
+        let mut c = config();
+        c.sotawatch.as_mut().unwrap().callsign = Some("OH8HUB de".to_string());
+        assert_eq!(
+            err(c),
+            "sotawatch.callsign: expected a callsign; got 'OH8HUB de'"
+        );
+    }
+
+    #[test]
+    fn test_http_config() {
+        let http = r##"
+        [http]
+        listen = "127.0.0.1:8080"
+
+        [http.map_region]
+        south = 55.0
+        west = 5.0
+        north = 72.0
+        east = 32.0
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{http}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        let http = config().http.unwrap();
+        assert_eq!(http.map_minutes, 60);
+        assert_eq!(http.map_region.unwrap().north, 72.0);
+
+        let mut c = config();
+        c.http.as_mut().unwrap().map_region.as_mut().unwrap().west = 40.0;
+        assert!(err(c).starts_with("http.map_region: expected south < north"));
+
+        let mut c = config();
+        c.http.as_mut().unwrap().map_minutes = 0;
+        assert_eq!(err(c), "http.map_minutes: must be greater than zero");
+    }
+
+    #[test]
+    fn test_pota_spots_config() {
+        let pota_spots = r##"
+        [pota_spots]
+        enabled = true
+        callsign = "OH8HUB"
+        token = "hunter2"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{pota_spots}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("hunter2"));
+        assert_eq!(config().pota_spots.unwrap().cooldown_secs, 600);
+        assert!(config().sink_names().contains(&"pota_spots".to_string()));
+
+        let mut c = config();
+        c.pota_spots.as_mut().unwrap().token.clear();
+        assert_eq!(
+            err(c),
+            "pota_spots.token: must not be empty or contain control characters"
+        );
+
+        let mut c = config();
+        c.pota_spots.as_mut().unwrap().cooldown_secs = 0;
+        assert_eq!(
+            err(c),
+            "pota_spots.cooldown_secs: must be greater than zero"
+        );
+    }
+
+    #[test]
+    fn test_route_config() {
+        let route = r##"
+        [mqtt]
+        broker = "mqtt://localhost:1883"
+
+        [[webhook]]
+        url = "https://example.org/spots"
+
+        [[route]]
+        sinks = ["mqtt"]
+        filter = { activities = ["wwff"] }
+
+        [[route]]
+        sinks = ["webhook[0]", "matrix @puskapupu:pikaviestin.fi"]
+        [route.filter]
+        spotter_prefixes = []
+        references = []
+        activities = ["sota"]
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{route}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        let routes = config().route;
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].filter.activities, [Activity::Wwff]);
+        // Unset fields let all spots through, unlike in the global filter
+        assert_eq!(
+            routes[0].filter,
+            FilterConfig {
+                activities: vec![Activity::Wwff],
+                ..FilterConfig::all()
+            }
+        );
+        assert!(routes[1].filter.references.is_empty());
+
+        let mut c = config();
+        c.route[0].sinks = vec!["webhook[1]".to_string()];
+        assert_eq!(
+            err(c),
+            "route[0].sinks: no sink named 'webhook[1]', expected one of: \
+             matrix @puskapupu:pikaviestin.fi, webhook[0], mqtt, stdout"
+        );
+
+        let mut c = config();
+        c.route[1].sinks.clear();
+        assert_eq!(err(c), "route[1].sinks: at least one sink is required");
+    }
+
+    #[test]
+    fn test_channel_config() {
+        let config: Config = toml::from_str(MINIMAL).unwrap();
+        assert_eq!(config.channel_limits(), ChannelLimits::default());
+        assert_eq!(config.channel_capacity, 256);
+        assert_eq!(config.overflow_policy, OverflowPolicy::DropOldest);
+
+        let raw = format!("channel_capacity = 1024\noverflow_policy = \"block\"\n{MINIMAL}");
+        let config: Config = toml::from_str(&raw).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.channel_limits().capacity, 1024);
+        assert_eq!(config.channel_limits().overflow, OverflowPolicy::Block);
+
+        let raw = format!("overflow_policy = \"drop_newest\"\n{MINIMAL}");
+        let config: Config = toml::from_str(&raw).unwrap();
+        assert_eq!(config.overflow_policy, OverflowPolicy::DropNewest);
+        assert!(
+            toml::from_str::<Config>(&format!("overflow_policy = \"drop\"\n{MINIMAL}")).is_err()
+        );
+
+        let raw = format!("channel_capacity = 0\n{MINIMAL}");
+        let config: Config = toml::from_str(&raw).unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "channel_capacity: must be between 1 and 1048576"
+        );
+    }
+
+    #[test]
+    fn test_activities_config() {
+        let config = Config::from_toml_str(MINIMAL, &|_| None).unwrap();
+        assert!(config.activities.disabled().is_empty());
+        assert!(config.filter.excluded_activities.is_empty());
+
+        let activities = r##"
+        [[cqgma]]
+        host = "cluster.example.org:7300"
+        username = "oh9xxx-4"
+        filter = { activities = ["sota", "gma"] }
+
+        [[route]]
+        sinks = ["stdout"]
+
+        [activities]
+        sota = false
+        iota = false
+        "##;
+        let raw = MINIMAL.replace("[cqgma]", "[[cqgma]]") + activities;
+        let config = Config::from_toml_str(&raw, &|_| None).unwrap();
+        assert!(config.activities.wwff);
+        let disabled = [Activity::Iota, Activity::Sota];
+        assert_eq!(config.activities.disabled(), disabled);
+        assert_eq!(config.filter.excluded_activities, disabled);
+        // Clusters without a filter of their own use the global one
+        assert_eq!(config.cqgma[0].filter, None);
+        let cluster = config.cqgma[1].filter.as_ref().unwrap();
+        assert_eq!(cluster.activities, [Activity::Sota, Activity::Gma]);
+        assert_eq!(cluster.excluded_activities, disabled);
+        assert_eq!(config.route[0].filter.excluded_activities, disabled);
+    }
+
+    #[test]
+    fn test_dedup_config() {
+        let dedup = r##"
+        [mqtt]
+        broker = "mqtt://localhost:1883"
+
+        [[webhook]]
+        url = "https://example.org/spots"
+
+        [[dedup]]
+        sinks = ["mqtt", "webhook[0]"]
+        window_secs = 3600
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{dedup}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert_eq!(config().dedup[0].window_secs, 3600);
+
+        let mut c = config();
+        c.dedup[0].sinks.push("email".to_string());
+        assert!(err(c).starts_with("dedup[0].sinks: no sink named 'email', expected one of: "));
+
+        let mut c = config();
+        c.dedup.push(c.dedup[0].clone());
+        c.dedup[1].sinks.remove(0);
+        assert_eq!(
+            err(c),
+            "dedup[1].sinks: sink 'webhook[0]' has more than one window"
+        );
+
+        let mut c = config();
+        c.dedup[0].window_secs = 0;
+        assert_eq!(err(c), "dedup[0].window_secs: must be greater than zero");
+    }
+
+    #[test]
+    fn test_influxdb_config() {
+        let influxdb = r##"
+        [influxdb]
+        url = "http://localhost:8086"
+        org = "oh8hub"
+        bucket = "spots"
+        token = "hunter2"
+        "##;
+        let config = || toml::from_str::<Config>(&format!("{MINIMAL}{influxdb}")).unwrap();
+        let err = |config: Config| config.validate().unwrap_err().to_string();
+        assert!(config().validate().is_ok());
+        assert!(!format!("{:?}", config()).contains("hunter2"));
+        assert_eq!(config().influxdb.unwrap().batch_size, 100);
+
+        let mut c = config();
+        c.influxdb.as_mut().unwrap().url = "localhost:8086".to_string();
+        assert_eq!(
+            err(c),
+            "influxdb.url: expected http:// or https:// URL; got 'localhost:8086'"
+        );
+
+        let mut c = config();
+        c.influxdb.as_mut().unwrap().batch_size = 0;
+        assert_eq!(err(c), "influxdb.batch_size: must be greater than zero");
+    }
+
+    #[test]
+    fn test_env_interpolation() {
+        let env = |name: &str| match name {
+            "MATRIX_TOKEN" => Some("secret-from-env".to_string()),
+            "CQGMA_USER" => Some("oh9xxx".to_string()),
+            _ => None,
+        };
+        let raw = MINIMAL
+            .replace(
+                "abcdefghijklmnopqrstuvwxyz12345678901234567890",
+                "${MATRIX_TOKEN}",
+            )
+            .replace("oh9xxx-4", "${CQGMA_USER}-4");
+
+        let config = Config::from_toml_str(&raw, &env).unwrap();
+        assert_eq!(config.matrix[0].access_token, "secret-from-env");
+        assert_eq!(config.cqgma[0].username, "oh9xxx-4");
+        assert!(!format!("{config:?}").contains("secret-from-env"));
+
+        let raw = MINIMAL.replace("oh9xxx-4", "${NOT_SET}");
+        let err = Config::from_toml_str(&raw, &env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "config: environment variable NOT_SET referenced in config is not set"
+        );
+    }
+
+    #[test]
+    fn test_env_override() {
+        let env = |name: &str| match name {
+            super::ENV_MATRIX_ACCESS_TOKEN => Some("overridden".to_string()),
+            _ => None,
+        };
+        let config = Config::from_toml_str(MINIMAL, &env).unwrap();
+        assert_eq!(config.matrix[0].access_token, "overridden");
+        assert!(!format!("{config:?}").contains("overridden"));
+
+        let config = Config::from_toml_str(MINIMAL, &|_| None).unwrap();
+        assert_eq!(
+            config.matrix[0].access_token,
+            "abcdefghijklmnopqrstuvwxyz12345678901234567890"
+        );
+
+        // No token in the file
+        let raw = MINIMAL.replace(
+            r#"access_token = "abcdefghijklmnopqrstuvwxyz12345678901234567890""#,
+            "",
+        );
+        let config = Config::from_toml_str(&raw, &env).unwrap();
+        assert_eq!(config.matrix[0].access_token, "overridden");
+        assert!(Config::from_toml_str(&raw, &|_| None).is_err());
+
+        // Only the first account
+        let raw = r##"
+        [[matrix]]
+        homeserver = "https://matrix.pikaviestin.fi:8448"
+        user_id = "@puskapupu:pikaviestin.fi"
+        device_id = "puskapupu"
+        room_id = "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+
+        [[matrix]]
+        homeserver = "https://matrix.org"
+        access_token = "zyxwvutsrqponmlkjihgfedcba09876543210987654321"
+        user_id = "@puskapupu:matrix.org"
+        device_id = "puskapupu"
+        room_id = "!QwErTyUiOpAsDfGhJk:matrix.org"
+
+        [cqgma]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+        "##;
+        let config = Config::from_toml_str(raw, &env).unwrap();
+        assert_eq!(config.matrix[0].access_token, "overridden");
+        assert_eq!(
+            config.matrix[1].access_token,
+            "zyxwvutsrqponmlkjihgfedcba09876543210987654321"
+        );
+    }
+
+    #[test]
+    fn test_read_config_many_accounts() {
+        let raw = r##"
+        [[matrix]]
+        homeserver = "https://matrix.pikaviestin.fi:8448"
+        access_token = "abcdefghijklmnopqrstuvwxyz12345678901234567890"
+        user_id = "@puskapupu:pikaviestin.fi"
+        device_id = "puskapupu"
+        rooms = ["!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi", "!aBcDeFgHiJkLmNoPqR:pikaviestin.fi"]
+
+        [[matrix]]
+        homeserver = "https://matrix.org"
+        access_token = "zyxwvutsrqponmlkjihgfedcba09876543210987654321"
+        user_id = "@puskapupu:matrix.org"
+        device_id = "puskapupu"
+        room_id = "!QwErTyUiOpAsDfGhJk:matrix.org"
+
+        [cqgma]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+        "##;
+
+        let parsed: Config = toml::from_str(raw).unwrap();
+        assert_eq!(parsed.matrix.len(), 2);
+        assert_eq!(parsed.matrix[0].rooms.len(), 2);
+        assert_eq!(parsed.matrix[1].homeserver.as_str(), "https://matrix.org/");
+        assert_eq!(
+            parsed.matrix[1].rooms[0].as_str(),
+            "!QwErTyUiOpAsDfGhJk:matrix.org"
+        );
+    }
+
+    #[test]
+    fn test_read_config_many_clusters() {
+        let single: Config = toml::from_str(MINIMAL).unwrap();
+        assert_eq!(single.cqgma.len(), 1);
+        assert_eq!(single.cqgma[0].host, "www.cqgma.org:7300");
+        assert_eq!(single.cqgma[0].password, None);
+
+        let raw = MINIMAL.replace(
+            r#"[cqgma]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4""#,
+            r#"[[cqgma]]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+
+        [[cqgma]]
+        host = "dxc.example.org:8000"
+        username = "oh9xxx"
+        password = "hunter2"
+        filter = { bands = ["2m"] }"#,
+        );
+        let many: Config = toml::from_str(&raw).unwrap();
+        assert_eq!(many.cqgma.len(), 2);
+        assert_eq!(many.cqgma[0], single.cqgma[0]);
+        assert_eq!(many.cqgma[1].host, "dxc.example.org:8000");
+        assert_eq!(many.cqgma[1].password.as_deref(), Some("hunter2"));
+        assert_eq!(
+            many.cqgma[1].filter.as_ref().unwrap().bands,
+            vec![crate::band::Band::B2m]
+        );
+        assert!(!format!("{many:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn test_quiet_hours_across_midnight() {
+        let time = |s: &str| TimeOfDay::try_from(s.to_string()).unwrap();
+        let quiet = QuietHours {
+            start: time("22:00"),
+            end: time("06:30"),
+        };
+
+        assert!(!quiet.contains(time("21:59").0));
+        assert!(quiet.contains(time("22:00").0));
+        assert!(quiet.contains(time("23:59").0));
+        assert!(quiet.contains(time("00:00").0));
+        assert!(quiet.contains(time("06:29").0));
+        assert!(!quiet.contains(time("06:30").0));
+        assert!(!quiet.contains(time("12:00").0));
+
+        let quiet = QuietHours {
+            start: time("01:00"),
+            end: time("05:00"),
+        };
+        assert!(!quiet.contains(time("00:59").0));
+        assert!(quiet.contains(time("01:00").0));
+        assert!(!quiet.contains(time("05:00").0));
+
+        assert!(TimeOfDay::try_from("24:00".to_string()).is_err());
+        assert!(TimeOfDay::try_from("2200".to_string()).is_err());
     }
 }
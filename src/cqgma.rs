@@ -3,68 +3,167 @@
 use std::fmt;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
 
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio::task::JoinHandle;
-use tokio::time::Duration;
+use tokio::sync::{broadcast, watch, Mutex, Notify};
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 
-use crate::config::CqgmaConfig;
+use crate::channel::ChannelLimits;
+use crate::config::{CqgmaConfig, STABLE_CONNECTION_SECS};
+use crate::dead_letter::DeadLetter;
+use crate::dedup::Dedup;
+use crate::filter::FilterConfig;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::proxy;
+use crate::status::Status;
+use crate::supervisor::Task;
 
 pub struct CqgmaState {
-    /// CQGMA telnet connection management task
-    pub handle: JoinHandle<io::Result<()>>,
-    /// A channel to send content to CQGMA telnet
-    pub telnet_tx: UnboundedSender<String>,
-    /// A channel receiving content from CQGMA telnet
-    pub telnet_rx: UnboundedReceiver<String>,
+    /// CQGMA telnet connection management task for each cluster, to be run by
+    /// [Supervisor](crate::supervisor::Supervisor)
+    pub tasks: Vec<Task>,
+    /// A channel to send content to CQGMA telnet of each cluster
+    pub telnet_tx: Vec<UnboundedSender<String>>,
+    /// Drop the connection of each cluster and connect again, even if the
+    /// cluster doesn't answer anymore. Signalled with
+    /// [Notify::notify_waiters], so nothing happens while disconnected.
+    pub reconnect: Vec<Arc<Notify>>,
+    /// Subscribe to receive spots from all CQGMA telnets. Every subscriber
+    /// gets every spot, unless it falls more than [ChannelLimits::capacity]
+    /// spots behind. Then by default it loses the oldest ones and gets
+    /// [RecvError::Lagged](broadcast::error::RecvError::Lagged) telling how
+    /// many, after which it continues from the oldest kept spot. Other
+    /// [OverflowPolicy](crate::config::OverflowPolicy)s drop new spots or
+    /// make clusters wait instead.
+    pub spots: broadcast::Sender<Arc<DxEntry>>,
 }
 
-pub async fn cqgma_init(config: &CqgmaConfig) -> CqgmaState {
-    let (telnet_rx, user_tx) = unbounded_channel();
-    let (user_rx, telnet_tx) = unbounded_channel();
-    let host = config.host.clone();
-    let user = config.username.clone();
-    let handle = tokio::spawn(async { manage_telnet(host, user, telnet_rx, telnet_tx).await });
-    CqgmaState {
-        handle,
-        telnet_rx: user_tx,
-        telnet_tx: user_rx,
+/// Prepare connecting to all clusters. Spots passing `filter` are sent to
+/// [CqgmaState::spots] subscribers. The filter can be changed while running,
+/// except for clusters having their own filter. Tasks finish when `shutdown`
+/// is cancelled. Connection state of each cluster is kept in
+/// [Status::clusters]. Lines are counted in [Status::metrics] and spots which
+/// couldn't be parsed go to `dead_letter`. The channel of spots is sized by
+/// `limits`.
+pub async fn cqgma_init(
+    configs: &[CqgmaConfig],
+    filter: watch::Receiver<FilterConfig>,
+    dead_letter: Option<Arc<DeadLetter>>,
+    limits: ChannelLimits,
+    shutdown: CancellationToken,
+    status: &Status,
+) -> CqgmaState {
+    let spots = limits.channel();
+    let mut state = CqgmaState {
+        tasks: Vec::new(),
+        telnet_tx: Vec::new(),
+        reconnect: Vec::new(),
+        spots,
+    };
+
+    for (config, connected) in configs.iter().zip(&status.clusters) {
+        let (user_rx, telnet_tx) = unbounded_channel();
+        let config = config.clone();
+        let filter = match &config.filter {
+            Some(own) => watch::channel(own.clone()).1,
+            None => filter.clone(),
+        };
+        let telnet_rx = state.spots.clone();
+        // Kept over restarts of the task, so the sender stays usable
+        let telnet_tx = Arc::new(Mutex::new(telnet_tx));
+        let name = format!("cqgma {}", config.host);
+        let parser = LineParser {
+            metrics: status.metrics.clone(),
+            dead_letter: dead_letter.clone(),
+        };
+        let shutdown = shutdown.clone();
+        let connected = connected.clone();
+        let reconnect = Arc::new(Notify::new());
+        state.reconnect.push(reconnect.clone());
+        let task = Task::new(name, move || {
+            let (config, filter, telnet_rx) = (config.clone(), filter.clone(), telnet_rx.clone());
+            let (telnet_tx, reconnect, shutdown) =
+                (telnet_tx.clone(), reconnect.clone(), shutdown.clone());
+            let (parser, connected) = (parser.clone(), connected.clone());
+            async move {
+                let mut telnet_tx = telnet_tx.lock().await;
+                let stats = parser.metrics.cluster(&config.host);
+                let result = manage_telnet(
+                    config,
+                    filter,
+                    parser,
+                    telnet_rx,
+                    limits,
+                    &mut telnet_tx,
+                    &reconnect,
+                    shutdown,
+                    &connected,
+                )
+                .await;
+                connected.store(false, Ordering::Relaxed);
+                stats.disconnected();
+                result
+            }
+        });
+        state.tasks.push(task);
+        state.telnet_tx.push(user_rx);
     }
+
+    state
 }
 
 /// Keep telnet connection to CQGMA going.
-#[instrument(skip(telnet_rx, telnet_tx))]
-async fn manage_telnet<H>(
-    host: H,
-    username: String,
-    telnet_rx: UnboundedSender<String>,
-    mut telnet_tx: UnboundedReceiver<String>,
-) -> io::Result<()>
-where
-    H: ToSocketAddrs + fmt::Debug,
-{
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(host = %config.host))]
+async fn manage_telnet(
+    config: CqgmaConfig,
+    filter: watch::Receiver<FilterConfig>,
+    parser: LineParser,
+    telnet_rx: broadcast::Sender<Arc<DxEntry>>,
+    limits: ChannelLimits,
+    telnet_tx: &mut UnboundedReceiver<String>,
+    reconnect: &Notify,
+    shutdown: CancellationToken,
+    connected: &AtomicBool,
+) -> io::Result<()> {
+    let reconnect_min = Duration::from_secs(config.reconnect_min_secs);
+    let reconnect_max = Duration::from_secs(config.reconnect_max_secs);
+    let mut dedup = Dedup::new(Duration::ZERO);
+    let mut failures = Failures::new(config.max_consecutive_failures);
+    let stats = parser.metrics.cluster(&config.host);
+
     loop {
         // Pre-calculate next sleep duration
-        let sleep_for = rand_sleep();
+        let sleep_for = rand_sleep(&mut rand::thread_rng(), reconnect_min, reconnect_max);
 
-        let mut stream = match connect(&host).await {
+        let mut stream = match connect_to(&config).await {
             Ok(s) => s,
             Err(err) => {
+                stats.failed(&err);
+                failures.fail(&err)?;
                 tracing::error!(
                     "Telnet connection failed: {err}. Will retry in {} seconds.",
                     sleep_for.as_secs()
                 );
-                tokio::time::sleep(sleep_for).await;
-                continue;
+                tokio::select! {
+                    _ = tokio::time::sleep(sleep_for) => continue,
+                    _ = shutdown.cancelled() => return Ok(()),
+                }
             }
         };
 
-        match login(&mut stream, &username).await {
+        match login(&mut stream, &config.username, config.password.as_deref()).await {
             Ok(()) => (),
             Err(err) => {
+                stats.failed(&format_args!("login failed: {err}"));
                 tracing::error!("Telnet login failed: {err}.");
                 return Err(io::Error::new(
                     io::ErrorKind::ConnectionAborted,
@@ -73,24 +172,42 @@ where
             }
         }
 
+        connected.store(true, Ordering::Relaxed);
+        stats.connected(SystemTime::now());
+        let connected_at = Instant::now();
         let (rx, mut tx) = stream.split();
         let mut lines = BufReader::new(rx).lines();
 
         'select: loop {
             tokio::select! {
+                _ = shutdown.cancelled() => {
+                    tracing::info!("Shutting down telnet connection.");
+                    return Ok(());
+                }
+                _ = reconnect.notified() => {
+                    tracing::warn!("Reconnect requested. Dropping telnet connection.");
+                    stats.failed(&"reconnect requested");
+                    break 'select;
+                }
                 v = lines.next_line() => match v {
                     Ok(Some(line)) => {
                         let line: String = line.trim_end().trim_end_matches('\x07').to_string();
                         tracing::debug!("telnet rx: ^{line}$");
-                        if line_filter(&line) {
-                            if let Err(err) = telnet_rx.send(line) {
-                                tracing::error!("Error when trying to send to channel: {err:?}");
-                                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "telnet channel (rx) closed"));
-                            }
+                        let entry = spot_filter(
+                            &line,
+                            &filter.borrow(),
+                            &mut dedup,
+                            &parser,
+                            SystemTime::now(),
+                        );
+                        if let Some(entry) = entry {
+                            stats.spot(SystemTime::now());
+                            limits.send(&telnet_rx, Arc::new(entry), &shutdown).await;
                         }
                     }
                     Ok(None) => {
                         tracing::error!("No more lines to read from telnet. Connection dead?");
+                        stats.failed(&"connection closed");
                         break 'select;
                     }
                     Err(err) => tracing::warn!("Invalid line from telnet: {err:?}"),
@@ -101,6 +218,7 @@ where
                         let s = format!("{line}\n");
                         if let Err(err) = tx.write_all(s.as_bytes()).await {
                             tracing::error!("Error when trying to send to telnet: {err:?}.");
+                            stats.failed(&err);
                             break 'select;
                         }
                     }
@@ -112,12 +230,71 @@ where
             }
         }
 
+        connected.store(false, Ordering::Relaxed);
+        failures.lost(connected_at.elapsed())?;
         tracing::error!(
             "Probably lost telnet connection. Going to reconnect in {} seconds...",
             sleep_for.as_secs()
         );
-        tokio::time::sleep(sleep_for).await;
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_for) => (),
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+/// Failed connections in a row, up to an optional ceiling.
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    max: Option<u32>,
+}
+
+impl Failures {
+    fn new(max: Option<u32>) -> Self {
+        Self { count: 0, max }
     }
+
+    /// Count a failure for `reason`. Fails when there have been the
+    /// maximum number of them in a row.
+    fn fail(&mut self, reason: &dyn fmt::Display) -> io::Result<()> {
+        self.count += 1;
+        match self.max {
+            Some(max) if self.count >= max => {
+                tracing::error!(
+                    "Giving up after {} failed connections in a row.",
+                    self.count
+                );
+                Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("{} failed connections in a row, last: {reason}", self.count),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// A connection was lost after `uptime`. Counted as a failure unless
+    /// it was stable, which starts the count over.
+    fn lost(&mut self, uptime: Duration) -> io::Result<()> {
+        if uptime >= Duration::from_secs(STABLE_CONNECTION_SECS) {
+            self.count = 0;
+            return Ok(());
+        }
+        self.fail(&"connection lost")
+    }
+}
+
+/// Connection to the cluster of `config`, through its proxy if it has one.
+async fn connect_to(config: &CqgmaConfig) -> io::Result<TcpStream> {
+    let Some(proxy) = &config.proxy else {
+        return connect(config.host.as_str()).await;
+    };
+    let mut stream = connect(proxy.address.as_str()).await?;
+    proxy::tunnel(&mut stream, proxy, &config.host)
+        .await
+        .map_err(|err| io::Error::new(err.kind(), format!("proxy {}: {err}", proxy.address)))?;
+    Ok(stream)
 }
 
 #[instrument]
@@ -145,8 +322,8 @@ where
     ))
 }
 
-#[instrument]
-async fn login(stream: &mut TcpStream, username: &str) -> io::Result<()> {
+#[instrument(skip(password))]
+async fn login(stream: &mut TcpStream, username: &str, password: Option<&str>) -> io::Result<()> {
     let (rx, mut tx) = stream.split();
     let mut rx = BufReader::new(rx);
 
@@ -157,7 +334,19 @@ async fn login(stream: &mut TcpStream, username: &str) -> io::Result<()> {
         tracing::trace!("First line received: {s}");
         if s.starts_with("login:") {
             tx.write_all(format!("{username}\n").as_bytes()).await?;
-            return Ok(());
+            let Some(password) = password else {
+                return Ok(());
+            };
+
+            buf.clear();
+            rx.read_until(b' ', &mut buf).await?;
+            if String::from_utf8_lossy(&buf)
+                .trim_start()
+                .starts_with("password:")
+            {
+                tx.write_all(format!("{password}\n").as_bytes()).await?;
+                return Ok(());
+            }
         }
     }
 
@@ -168,51 +357,181 @@ async fn login(stream: &mut TcpStream, username: &str) -> io::Result<()> {
     ))
 }
 
-fn line_filter(line: &str) -> bool {
-    let line = line.to_lowercase();
-
-    // Line is not a cluster spot
-    if !line.starts_with("dx de") {
-        return false;
-    }
+/// Parses cluster lines looking like spots, counting successes and
+/// failures. Lines which couldn't be parsed go to the dead letter file.
+#[derive(Clone, Default)]
+struct LineParser {
+    metrics: Arc<Metrics>,
+    dead_letter: Option<Arc<DeadLetter>>,
+}
 
-    // Spots from OH and OG stations
-    if line.starts_with("dx de oh") || line.starts_with("dx de og") {
-        if line.chars().nth(8) >= Some('0') && line.chars().nth(8) <= Some('9') {
-            return true;
+impl LineParser {
+    fn parse(&self, line: &str) -> Option<DxEntry> {
+        if !line
+            .get(..6)
+            .map_or(false, |start| start.eq_ignore_ascii_case("dx de "))
+        {
+            return None;
+        }
+        match DxEntry::parse_line(line) {
+            Ok(entry) => {
+                self.metrics.parse_ok.fetch_add(1, Ordering::Relaxed);
+                Some(entry)
+            }
+            Err(err) => {
+                self.metrics.parse_err.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("Couldn't parse spot: ^{line}$: {err}");
+                if let Some(dead_letter) = &self.dead_letter {
+                    if let Err(err) = dead_letter.record(line, &err) {
+                        tracing::warn!("Couldn't write to dead letter file: {err}");
+                    }
+                }
+                None
+            }
         }
     }
+}
 
-    // WWFF spots
-    if line.contains("ohff-") {
-        return true;
+/// Filter by the raw line and the parsed spot. Returns the spot if it
+/// passes. All spots are parsed, so failures are noticed whether the spot
+/// would have passed or not.
+fn spot_filter(
+    line: &str,
+    filter: &FilterConfig,
+    dedup: &mut Dedup,
+    parser: &LineParser,
+    now: SystemTime,
+) -> Option<DxEntry> {
+    let entry = parser.parse(line)?;
+    if !filter.matches_line(line) || !filter.matches(&entry, now) {
+        return None;
     }
-
-    // POTA spots
-    if line.contains("oh-") {
-        return true;
+    if let Some(window) = filter.dedup_window_secs {
+        dedup.set_window(Duration::from_secs(window));
+        if dedup.is_duplicate(entry.dedup_key(), now) {
+            return None;
+        }
     }
-
-    false
+    Some(entry)
 }
 
-/// This provides [Duration] between [17, 34] seconds.
-fn rand_sleep() -> Duration {
+/// This provides [Duration] between [min, max] drawn from `rng`.
+fn rand_sleep<R: rand::Rng + ?Sized>(rng: &mut R, min: Duration, max: Duration) -> Duration {
     use rand::distributions::Uniform;
-    use rand::{thread_rng, Rng};
 
-    const TIMEOUT: Duration = Duration::from_secs(17);
-    let timeout_fuzz: Uniform<Duration> = Uniform::new_inclusive(Duration::from_secs(0), TIMEOUT);
-
-    TIMEOUT + thread_rng().sample(timeout_fuzz)
+    let timeout: Uniform<Duration> = Uniform::new_inclusive(min, max);
+    rng.sample(timeout)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::line_filter;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+    use tokio::sync::{broadcast, watch, Notify};
+    use tokio::task::JoinHandle;
+    use tokio_util::sync::CancellationToken;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{cqgma_init, manage_telnet, rand_sleep, spot_filter, Failures, LineParser};
+    use crate::channel::ChannelLimits;
+    use crate::config::{
+        CqgmaConfig, DeadLetterConfig, ProxyConfig, ProxyKind, DEFAULT_DEAD_LETTER_MAX_BYTES,
+    };
+    use crate::dead_letter::DeadLetter;
+    use crate::dedup::Dedup;
+    use crate::filter::FilterConfig;
+    use crate::parser::DxEntry;
+    use crate::status::Status;
+    use crate::testutil::{MockCluster, MockProxy, Session};
+
+    const SPOT_1: &str =
+        "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z";
+    const SPOT_2: &str =
+        "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z";
+    /// Neither the spotter nor the reference is Finnish
+    const FOREIGN_SPOT: &str =
+        "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101                 1959Z";
+
+    struct Telnet {
+        handle: JoinHandle<io::Result<()>>,
+        spots: broadcast::Receiver<Arc<DxEntry>>,
+        // Closing this would end the connection
+        _commands: UnboundedSender<String>,
+        reconnect: Arc<Notify>,
+    }
+
+    fn config(cluster: &MockCluster) -> CqgmaConfig {
+        CqgmaConfig {
+            host: cluster.host.clone(),
+            username: "N0CALL".to_string(),
+            password: None,
+            filter: None,
+            reconnect_min_secs: 0,
+            reconnect_max_secs: 0,
+            max_consecutive_failures: None,
+            proxy: None,
+        }
+    }
+
+    /// Run [manage_telnet] against `cluster` with the default filter.
+    fn telnet(cluster: &MockCluster, shutdown: &CancellationToken) -> Telnet {
+        run_telnet(config(cluster), shutdown)
+    }
+
+    fn run_telnet(config: CqgmaConfig, shutdown: &CancellationToken) -> Telnet {
+        let filter = watch::channel(FilterConfig::default()).1;
+        let (spots_tx, spots) = broadcast::channel(16);
+        let (_commands, mut commands) = unbounded_channel();
+        let reconnect = Arc::new(Notify::new());
+        let shutdown = shutdown.clone();
+        let signal = reconnect.clone();
+        let handle = tokio::spawn(async move {
+            let connected = AtomicBool::new(false);
+            manage_telnet(
+                config,
+                filter,
+                LineParser::default(),
+                spots_tx,
+                ChannelLimits::default(),
+                &mut commands,
+                &signal,
+                shutdown,
+                &connected,
+            )
+            .await
+        });
+        Telnet {
+            handle,
+            spots,
+            _commands,
+            reconnect,
+        }
+    }
+
+    async fn next_spot(telnet: &mut Telnet) -> String {
+        let spot = tokio::time::timeout(Duration::from_secs(5), telnet.spots.recv());
+        spot.await.expect("a spot in time").unwrap().line.clone()
+    }
 
     #[test]
     fn test_line_filter() {
+        let line_filter = |line| {
+            let mut dedup = Dedup::new(Duration::ZERO);
+            spot_filter(
+                line,
+                &FilterConfig::default(),
+                &mut dedup,
+                &LineParser::default(),
+                SystemTime::now(),
+            )
+            .is_some()
+        };
         assert!(!line_filter(
             "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101                 1959Z"
         ));
@@ -223,4 +542,253 @@ mod tests {
             "DX de OG0Z:      14310.0  AD6VT        x04s W6/ND-101                 1959Z"
         ));
     }
+
+    #[test]
+    fn test_cluster_dedup() {
+        let line = "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z";
+        let filter = FilterConfig {
+            dedup_window_secs: Some(60),
+            ..FilterConfig::default()
+        };
+        let mut dedup = Dedup::new(Duration::ZERO);
+        let parser = LineParser::default();
+        let now = SystemTime::now();
+        assert!(spot_filter(line, &filter, &mut dedup, &parser, now).is_some());
+        assert!(spot_filter(line, &filter, &mut dedup, &parser, now).is_none());
+        let later = now + Duration::from_secs(60);
+        assert!(spot_filter(line, &filter, &mut dedup, &parser, later).is_some());
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead_letter.log");
+        let parser = LineParser {
+            metrics: Default::default(),
+            dead_letter: Some(Arc::new(DeadLetter::new(&DeadLetterConfig {
+                path: path.clone(),
+                max_bytes: DEFAULT_DEAD_LETTER_MAX_BYTES,
+            }))),
+        };
+        let mut dedup = Dedup::new(Duration::ZERO);
+        let filter = FilterConfig::default();
+        let mut spot_filter =
+            |line| spot_filter(line, &filter, &mut dedup, &parser, SystemTime::now());
+
+        // Not spots at all
+        assert!(spot_filter("OH8HUB de OH2NOS").is_none());
+        assert!(spot_filter(SPOT_1).is_some());
+        assert!(!path.exists());
+
+        // Even if the spotter wouldn't pass the filter
+        let malformed = "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101";
+        assert!(spot_filter(malformed).is_none());
+        let dead = std::fs::read_to_string(&path).unwrap();
+        let (line, err) = dead.trim_end().split_once('\t').unwrap();
+        assert_eq!(line, malformed);
+        assert!(!err.is_empty());
+    }
+
+    #[test]
+    fn test_parse_metrics() {
+        let parser = LineParser::default();
+        let lines = [
+            SPOT_1,
+            "OH8HUB de OH2NOS",
+            "DX de OH8HUB: this is not a spot",
+            FOREIGN_SPOT,
+            SPOT_2,
+            "DX de OH2NOS:     3644.0",
+        ];
+        let mut dedup = Dedup::new(Duration::ZERO);
+        let filter = FilterConfig::default();
+        let passed = lines
+            .iter()
+            .filter_map(|line| spot_filter(line, &filter, &mut dedup, &parser, SystemTime::now()))
+            .count();
+        assert_eq!(passed, 2);
+
+        let metrics = &parser.metrics;
+        // Filtered out spots are parsed too and other lines are not counted
+        assert_eq!(metrics.parse_ok.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.parse_err.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.parse_failure_ratio(), Some(0.4));
+    }
+
+    #[test]
+    fn test_rand_sleep() {
+        let (min, max) = (Duration::from_secs(17), Duration::from_secs(34));
+        let sleeps = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let sleeps: Vec<Duration> = (0..100).map(|_| rand_sleep(&mut rng, min, max)).collect();
+            sleeps
+        };
+        let sleeps_1 = sleeps(1);
+        assert!(sleeps_1.iter().all(|sleep| (min..=max).contains(sleep)));
+        assert!(sleeps_1.iter().any(|sleep| *sleep != sleeps_1[0]));
+        assert_eq!(sleeps_1, sleeps(1));
+        assert_ne!(sleeps_1, sleeps(2));
+
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(rand_sleep(&mut rng, min, min), min);
+    }
+
+    #[tokio::test]
+    async fn test_every_subscriber_gets_every_spot() {
+        let filter = watch::channel(FilterConfig::default()).1;
+        let status = Status::new(0, 0);
+        let limits = ChannelLimits::default();
+        let state = cqgma_init(&[], filter, None, limits, CancellationToken::new(), &status).await;
+        let mut matrix = state.spots.subscribe();
+        let mut store = state.spots.subscribe();
+
+        let lines = [
+            "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z",
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+        ];
+        for line in lines {
+            state.spots.send(Arc::new(line.parse().unwrap())).unwrap();
+        }
+        for rx in [&mut matrix, &mut store] {
+            for line in lines {
+                assert_eq!(rx.recv().await.unwrap().line, line);
+            }
+            assert!(rx.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_telnet_login_and_forward() {
+        let cluster =
+            MockCluster::start(vec![Session::spots(&[SPOT_1, FOREIGN_SPOT, SPOT_2], false)]).await;
+        let shutdown = CancellationToken::new();
+        let mut telnet = telnet(&cluster, &shutdown);
+
+        assert_eq!(next_spot(&mut telnet).await, SPOT_1);
+        assert_eq!(next_spot(&mut telnet).await, SPOT_2);
+        assert_eq!(cluster.logins(), ["N0CALL"]);
+
+        shutdown.cancel();
+        telnet.handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_telnet_login_failure() {
+        let refused = Session {
+            greeting: "Sorry, cluster is full\r\n",
+            lines: Vec::new(),
+            hang_up: true,
+        };
+        let cluster = MockCluster::start(vec![refused, Session::spots(&[SPOT_1], false)]).await;
+        let shutdown = CancellationToken::new();
+
+        let telnet_1 = telnet(&cluster, &shutdown);
+        let err = telnet_1.handle.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(cluster.logins().is_empty());
+
+        // Supervisor restarts the task
+        let mut telnet_2 = telnet(&cluster, &shutdown);
+        assert_eq!(next_spot(&mut telnet_2).await, SPOT_1);
+        assert_eq!(cluster.logins(), ["N0CALL"]);
+        shutdown.cancel();
+        telnet_2.handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_telnet_reconnect() {
+        let cluster = MockCluster::start(vec![
+            Session::spots(&[SPOT_1], true),
+            Session::spots(&[SPOT_2], false),
+        ])
+        .await;
+        let shutdown = CancellationToken::new();
+        let mut telnet = telnet(&cluster, &shutdown);
+
+        assert_eq!(next_spot(&mut telnet).await, SPOT_1);
+        assert_eq!(next_spot(&mut telnet).await, SPOT_2);
+        assert_eq!(cluster.logins(), ["N0CALL", "N0CALL"]);
+
+        shutdown.cancel();
+        telnet.handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_telnet_reconnect_signal() {
+        // The cluster reads but never acts on what it's sent
+        let cluster = MockCluster::start(vec![
+            Session::spots(&[SPOT_1], false),
+            Session::spots(&[SPOT_2], false),
+        ])
+        .await;
+        let shutdown = CancellationToken::new();
+        let mut telnet = telnet(&cluster, &shutdown);
+
+        assert_eq!(next_spot(&mut telnet).await, SPOT_1);
+        telnet.reconnect.notify_waiters();
+        assert_eq!(next_spot(&mut telnet).await, SPOT_2);
+        assert_eq!(cluster.logins(), ["N0CALL", "N0CALL"]);
+
+        shutdown.cancel();
+        telnet.handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_telnet_proxy() {
+        let cluster = MockCluster::start(vec![Session::spots(&[SPOT_1], false)]).await;
+        let proxy = MockProxy::start(ProxyKind::Socks5, None).await;
+        let shutdown = CancellationToken::new();
+        let mut config = config(&cluster);
+        config.proxy = Some(ProxyConfig {
+            kind: ProxyKind::Socks5,
+            address: proxy.address.clone(),
+            username: None,
+            password: None,
+        });
+        let mut telnet = run_telnet(config, &shutdown);
+
+        assert_eq!(next_spot(&mut telnet).await, SPOT_1);
+        assert_eq!(cluster.logins(), ["N0CALL"]);
+        assert_eq!(proxy.targets(), [cluster.host.as_str()]);
+
+        shutdown.cancel();
+        telnet.handle.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut failures = Failures::new(Some(3));
+        assert!(failures.fail(&"refused").is_ok());
+        assert!(failures.lost(Duration::from_secs(10)).is_ok());
+        // A stable connection starts over
+        assert!(failures.lost(Duration::from_secs(60 * 60)).is_ok());
+        assert!(failures.fail(&"refused").is_ok());
+        assert!(failures.fail(&"refused").is_ok());
+        let err = failures.fail(&"refused").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert_eq!(
+            err.to_string(),
+            "3 failed connections in a row, last: refused"
+        );
+
+        let mut forever = Failures::new(None);
+        assert!((0..100).all(|_| forever.fail(&"refused").is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_telnet_failure_ceiling() {
+        let hang_up = Session::spots(&[], true);
+        let cluster = MockCluster::start(vec![hang_up; 5]).await;
+        let shutdown = CancellationToken::new();
+        let config = CqgmaConfig {
+            max_consecutive_failures: Some(3),
+            ..config(&cluster)
+        };
+        let telnet = run_telnet(config, &shutdown);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), telnet.handle).await;
+        let err = result.expect("gave up in time").unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert_eq!(cluster.logins().len(), 3);
+    }
 }
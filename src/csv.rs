@@ -0,0 +1,164 @@
+//! Spots as CSV for spreadsheets.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::CsvConfig;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::rotate;
+use crate::sink::{self, Sink};
+use crate::utc;
+
+/// First line of each file. Columns are only ever added to the end.
+pub const HEADER: &str = "time,reporter,dx,freq,band,mode,activity,reference,comment\n";
+
+/// `spot` received at `received` as a CSV line. Time is UTC, eg.
+/// `2024-03-01 11:46`, and frequency is in kHz.
+pub fn row(spot: &DxEntry, received: SystemTime) -> String {
+    let (year, month, day) = spot.date(received);
+    let minute = spot
+        .minute_of_day()
+        .unwrap_or_else(|| utc::minute_of_day(received));
+    let time = format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        minute / 60,
+        minute % 60
+    );
+    let fields = [
+        time,
+        spot.reporter.clone(),
+        spot.dx.clone(),
+        spot.frequency.to_string(),
+        spot.band().map_or("", |band| band.name()).to_string(),
+        spot.mode().unwrap_or_default().to_string(),
+        spot.cqgma_identifier
+            .map_or("", |(activity, _)| activity.name())
+            .to_string(),
+        spot.references().join(" "),
+        spot.info.clone(),
+    ];
+    let fields: Vec<String> = fields.iter().map(|field| quote(field)).collect();
+    fields.join(",") + "\n"
+}
+
+/// `field` in quotes if it has a comma, quote or line break.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Appends each spot to a CSV file, writing [HEADER] first if the file is
+/// new.
+pub struct CsvSink {
+    name: String,
+    path: PathBuf,
+    rotate_daily: bool,
+}
+
+impl CsvSink {
+    pub fn new(config: &CsvConfig) -> Self {
+        Self {
+            name: format!("csv {}", config.path.display()),
+            path: config.path.clone(),
+            rotate_daily: config.rotate_daily,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for CsvSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let now = SystemTime::now();
+        if self.rotate_daily {
+            if let Some(rotated) = rotate::rotate_daily(&self.path, now)? {
+                tracing::info!("Moved spots of the previous day to {}", rotated.display());
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut out = String::new();
+        if file.metadata()?.len() == 0 {
+            out.push_str(HEADER);
+        }
+        out.push_str(&row(spot, now));
+        file.write_all(out.as_bytes())
+    }
+}
+
+/// Append spots from `spots` to the file of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: CsvConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = CsvSink::new(&config);
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{row, CsvSink};
+    use crate::config::CsvConfig;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+
+    #[test]
+    fn test_row() {
+        // 2024-03-01 12:00 UTC
+        let received = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        let spot: DxEntry =
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 cw, \"QRP\"       1146Z"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            row(&spot, received),
+            "2024-03-01 11:46,OH2NOS,OH2NOS/P,3644,80m,CW,wwff,OHFF-1419,\"OHFF-1419 cw, \"\"QRP\"\"\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spots.csv");
+        let sink = CsvSink::new(&CsvConfig {
+            path: path.clone(),
+            rotate_daily: true,
+        });
+        let spot = "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z"
+            .parse()
+            .unwrap();
+        sink.send(&spot).await.unwrap();
+        sink.send(&spot).await.unwrap();
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "time,reporter,dx,freq,band,mode,activity,reference,comment"
+        );
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].ends_with(" 19:59,OH8HUB,AD6VT,14310,20m,,sota,W6/ND-101,W6/ND-101"));
+    }
+}
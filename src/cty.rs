@@ -0,0 +1,258 @@
+//! Countries of callsigns from `cty.dat`, the country file used by logging
+//! and contest programs, eg. from <https://www.country-files.com/>.
+//!
+//! The file is parsed once and [install]ed, after which
+//! [DxEntry::dxcc](crate::parser::DxEntry::dxcc) resolves spotted stations.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// Database used by [installed].
+static DATABASE: RwLock<Option<Arc<CtyDat>>> = RwLock::new(None);
+
+/// Use `database` for [DxEntry::dxcc](crate::parser::DxEntry::dxcc).
+pub fn install(database: CtyDat) {
+    *DATABASE.write().expect("cty lock") = Some(Arc::new(database));
+}
+
+/// Database given to [install], if any.
+pub fn installed() -> Option<Arc<CtyDat>> {
+    DATABASE.read().expect("cty lock").clone()
+}
+
+/// DXCC entity, or the zones and location of some of its stations when
+/// overridden for a prefix or call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dxcc {
+    /// eg. `Finland`
+    pub name: String,
+    /// Primary prefix, eg. `OH`
+    pub prefix: String,
+    pub cq_zone: u8,
+    pub itu_zone: u8,
+    /// Two letters, eg. `EU`
+    pub continent: String,
+    /// Latitude and longitude in degrees, east positive
+    pub location: (f64, f64),
+}
+
+/// Prefixes and exact calls of all entities.
+#[derive(Debug, Default)]
+pub struct CtyDat {
+    prefixes: HashMap<String, Dxcc>,
+    calls: HashMap<String, Dxcc>,
+}
+
+/// Portable and other suffixes not telling the country.
+const SUFFIXES: &[&str] = &["P", "M", "MM", "AM", "QRP", "A", "B", "LH"];
+
+impl CtyDat {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Entities are eight `:` terminated fields followed by their prefixes
+    /// separated by `,` and terminated by `;`. `=` marks an exact call and
+    /// `(cq)`, `[itu]`, `<lat/lon>` and `{continent}` override the entity.
+    pub fn parse(cty: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut database = CtyDat::default();
+        for record in cty.split(';') {
+            if record.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = record.splitn(9, ':').map(str::trim).collect();
+            let [name, cq, itu, continent, lat, lon, _utc_offset, prefix, prefixes] = fields[..]
+            else {
+                return Err(invalid(format!("entity with too few fields: {record:?}")));
+            };
+            let number = |field: &str| {
+                field
+                    .parse::<f64>()
+                    .map_err(|_| invalid(format!("{name}: expected number, got {field:?}")))
+            };
+            let zone = |field: &str| {
+                field
+                    .parse::<u8>()
+                    .map_err(|_| invalid(format!("{name}: expected zone, got {field:?}")))
+            };
+            let entity = Dxcc {
+                name: name.to_string(),
+                // `*` marks entities only on the WAE list
+                prefix: prefix.trim_start_matches('*').to_string(),
+                cq_zone: zone(cq)?,
+                itu_zone: zone(itu)?,
+                continent: continent.to_string(),
+                // West is positive in the file
+                location: (number(lat)?, -number(lon)?),
+            };
+            for prefix in prefixes.split(',') {
+                let prefix = prefix.trim();
+                if prefix.is_empty() {
+                    continue;
+                }
+                let (exact, prefix) = match prefix.strip_prefix('=') {
+                    Some(call) => (true, call),
+                    None => (false, prefix),
+                };
+                let (prefix, dxcc) = with_overrides(&entity, prefix)
+                    .ok_or_else(|| invalid(format!("{name}: invalid prefix {prefix:?}")))?;
+                let map = if exact {
+                    &mut database.calls
+                } else {
+                    &mut database.prefixes
+                };
+                map.insert(prefix.to_uppercase(), dxcc);
+            }
+        }
+        Ok(database)
+    }
+
+    /// Entity of `call`, eg. `OH2NOS/P` or `DL/OH2NOS`.
+    pub fn resolve(&self, call: &str) -> Option<&Dxcc> {
+        let call = call.trim().to_uppercase();
+        if let Some(dxcc) = self.calls.get(&call) {
+            return Some(dxcc);
+        }
+        let parts: Vec<&str> = call
+            .split('/')
+            .filter(|part| {
+                // Call area, eg. `OH2NOS/8`
+                let area = part.len() == 1 && part.starts_with(|c: char| c.is_ascii_digit());
+                !part.is_empty() && !SUFFIXES.contains(part) && !area
+            })
+            .collect();
+        // A prefix before or after the call, eg. `DL/OH2NOS`, is shorter
+        let call = parts.iter().min_by_key(|part| part.len())?;
+        if let Some(dxcc) = self.calls.get(*call) {
+            return Some(dxcc);
+        }
+        (1..=call.len())
+            .rev()
+            .filter_map(|len| call.get(..len))
+            .find_map(|prefix| self.prefixes.get(prefix))
+    }
+
+    pub fn len(&self) -> usize {
+        self.prefixes.len() + self.calls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// `prefix` without overrides and `entity` with them applied.
+fn with_overrides<'a>(entity: &Dxcc, prefix: &'a str) -> Option<(&'a str, Dxcc)> {
+    let end = prefix
+        .find(['(', '[', '<', '{', '~'])
+        .unwrap_or(prefix.len());
+    let (base, mut rest) = prefix.split_at(end);
+    let mut dxcc = entity.clone();
+    while let Some(open) = rest.chars().next() {
+        let close = match open {
+            '(' => ')',
+            '[' => ']',
+            '<' => '>',
+            '{' => '}',
+            '~' => '~',
+            _ => return None,
+        };
+        let len = rest[1..].find(close)?;
+        let value = &rest[1..1 + len];
+        match open {
+            '(' => dxcc.cq_zone = value.parse().ok()?,
+            '[' => dxcc.itu_zone = value.parse().ok()?,
+            '<' => {
+                let (lat, lon) = value.split_once('/')?;
+                dxcc.location = (lat.parse().ok()?, -lon.parse::<f64>().ok()?);
+            }
+            '{' => dxcc.continent = value.to_string(),
+            // Time zone isn't kept
+            _ => (),
+        }
+        rest = &rest[len + 2..];
+    }
+    (!base.is_empty()).then_some((base, dxcc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{install, CtyDat};
+    use crate::parser::DxEntry;
+
+    const SAMPLE: &str = "\
+Finland:                  15:  18:  EU:   63.78:   -27.08:    -2.0:  OH:
+    OF,OG,OH,OI,OJ,=OH2BH/LH;
+Aland Islands:            15:  18:  EU:   60.13:   -20.37:    -2.0:  OH0:
+    OF0,OG0,OH0,OI0,OJ0;
+United States:            05:  08:  NA:   37.53:    91.67:     5.0:  K:
+    AA,AD,K,N,W,
+    AA6(03)[06]<37.5/120>,W6(03)[06],KH6XX(31)[61]{OC},
+    =W1AW;
+Hawaii:                   31:  61:  OC:   21.12:   157.48:    10.0:  KH6:
+    AH6,KH6,WH6,=W7HI;
+Fed. Rep. of Germany:     14:  28:  EU:   51.00:   -10.00:    -1.0:  DL:
+    DA,DB,DC,DD,DE,DF,DG,DH,DJ,DK,DL,DM,DN,DO,DP,DQ,DR;
+";
+
+    #[test]
+    fn test_resolve() {
+        let cty = CtyDat::parse(SAMPLE).unwrap();
+        let name = |call: &str| cty.resolve(call).map(|dxcc| dxcc.name.as_str());
+
+        let finland = cty.resolve("oh2nos").unwrap();
+        assert_eq!(
+            (finland.prefix.as_str(), finland.cq_zone, finland.itu_zone),
+            ("OH", 15, 18)
+        );
+        assert_eq!(finland.location, (63.78, 27.08));
+        assert_eq!(name("OH0JFP"), Some("Aland Islands"));
+        assert_eq!(name("OH2NOS/P"), Some("Finland"));
+        assert_eq!(name("DL/OH2NOS"), Some("Fed. Rep. of Germany"));
+        assert_eq!(name("OH2NOS/DL"), Some("Fed. Rep. of Germany"));
+        assert_eq!(name("OH2BH/LH"), Some("Finland"));
+        assert_eq!(name("XX9XX"), None);
+
+        // Exact calls win over prefixes
+        assert_eq!(name("W7HI"), Some("Hawaii"));
+        assert_eq!(name("W1AW"), Some("United States"));
+
+        // Overridden zones, location and continent
+        let w6 = cty.resolve("W6ABC").unwrap();
+        assert_eq!(
+            (w6.name.as_str(), w6.cq_zone, w6.itu_zone),
+            ("United States", 3, 6)
+        );
+        assert_eq!(w6.location, (37.53, -91.67));
+        let aa6 = cty.resolve("AA6BB").unwrap();
+        assert_eq!(aa6.location, (37.5, -120.0));
+        let kh6xx = cty.resolve("KH6XXA").unwrap();
+        assert_eq!(
+            (kh6xx.name.as_str(), kh6xx.continent.as_str()),
+            ("United States", "OC")
+        );
+        assert_eq!(name("KH6ABC"), Some("Hawaii"));
+        assert_eq!(cty.resolve("W2XYZ").unwrap().cq_zone, 5);
+    }
+
+    #[test]
+    fn test_dxentry_dxcc() {
+        install(CtyDat::parse(SAMPLE).unwrap());
+        let spot: DxEntry =
+            "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z"
+                .parse()
+                .unwrap();
+        let dxcc = spot.dxcc().unwrap();
+        assert_eq!((dxcc.name.as_str(), dxcc.cq_zone), ("United States", 5));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(CtyDat::parse("Finland: 15: 18: EU: 63.78: -27.08: -2.0: OH: OH(x);").is_err());
+        assert!(CtyDat::parse("Finland: 15: 18: EU;").is_err());
+        assert!(CtyDat::parse("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+//! Lines looking like spots which couldn't be parsed. Kept in a file so
+//! changes in the cluster format are noticed and can be turned into parser
+//! tests.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::config::DeadLetterConfig;
+
+pub struct DeadLetter {
+    path: PathBuf,
+    max_bytes: u64,
+    /// Held while writing, so lines from different clusters don't mix
+    lock: Mutex<()>,
+}
+
+impl DeadLetter {
+    pub fn new(config: &DeadLetterConfig) -> Self {
+        Self {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Append `line` and why it couldn't be parsed, separated by a tab. A
+    /// file which would grow over the size limit is first moved aside to
+    /// [DeadLetter::old_path], replacing the previous one.
+    pub fn record(&self, line: &str, err: &str) -> io::Result<()> {
+        let record = format!("{line}\t{err}\n");
+        let _lock = self.lock.lock().unwrap_or_else(|err| err.into_inner());
+        let size = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        if size > 0 && size + record.len() as u64 > self.max_bytes {
+            std::fs::rename(&self.path, self.old_path())?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(record.as_bytes())
+    }
+
+    /// Where older lines are moved when the file is full.
+    pub fn old_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".old");
+        path.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeadLetter;
+    use crate::config::DeadLetterConfig;
+
+    #[test]
+    fn test_size_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let dead_letter = DeadLetter::new(&DeadLetterConfig {
+            path: dir.path().join("dead_letter.log"),
+            max_bytes: 64,
+        });
+        let line = "DX de OH8HUB: this is not a spot";
+        for _ in 0..5 {
+            dead_letter.record(line, "found 't'").unwrap();
+        }
+
+        let read = |path| std::fs::read_to_string(path).unwrap();
+        let current = read(dead_letter.path.clone());
+        assert_eq!(current, format!("{line}\tfound 't'\n"));
+        assert_eq!(read(dead_letter.old_path()), current);
+    }
+}
@@ -0,0 +1,131 @@
+//! Suppress repeated spots of the same activation.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::parser::DedupKey;
+
+/// Remembers recently seen [DedupKey]s for `window` amount of time.
+#[derive(Debug)]
+pub struct Dedup {
+    window: Duration,
+    seen: HashMap<DedupKey, SystemTime>,
+}
+
+impl Dedup {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    /// Returns `true` if `key` was already seen within the window. Otherwise
+    /// the key is remembered from `now` on.
+    pub fn is_duplicate(&mut self, key: DedupKey, now: SystemTime) -> bool {
+        let window = self.window;
+        let expired = |seen: &SystemTime| now.duration_since(*seen).unwrap_or_default() >= window;
+        self.seen.retain(|_, seen| !expired(seen));
+
+        if self.seen.contains_key(&key) {
+            return true;
+        }
+        self.seen.insert(key, now);
+        false
+    }
+
+    /// Load keys saved by [Dedup::save]. Keys already expired at `now` are
+    /// skipped and a missing file gives an empty [Dedup].
+    pub fn load(path: &Path, window: Duration, now: SystemTime) -> io::Result<Self> {
+        let mut dedup = Dedup::new(window);
+        let s = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(dedup),
+            Err(err) => return Err(err),
+        };
+        for line in s.lines() {
+            let mut fields = line.split('\t');
+            let (Some(seen), Some(frequency), Some(dx)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let (Ok(seen), Ok(frequency)) = (seen.parse(), frequency.parse()) else {
+                continue;
+            };
+            let seen = UNIX_EPOCH + Duration::from_secs(seen);
+            if now.duration_since(seen).unwrap_or_default() < window {
+                let key = DedupKey {
+                    dx: dx.to_string(),
+                    frequency,
+                };
+                dedup.seen.insert(key, seen);
+            }
+        }
+        Ok(dedup)
+    }
+
+    /// Save keys to `path`, one per line as `unix time<TAB>kHz<TAB>callsign`.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut s = String::new();
+        for (key, seen) in &self.seen {
+            let seen = seen.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            s.push_str(&format!("{seen}\t{}\t{}\n", key.frequency, key.dx));
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, s)?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::Dedup;
+    use crate::parser::DedupKey;
+
+    #[test]
+    fn test_dedup_window() {
+        let key = DedupKey {
+            dx: "OH2NOS/P".to_string(),
+            frequency: 3644,
+        };
+        let now = SystemTime::now();
+        let mut dedup = Dedup::new(Duration::from_secs(60));
+
+        assert!(!dedup.is_duplicate(key.clone(), now));
+        assert!(dedup.is_duplicate(key.clone(), now + Duration::from_secs(59)));
+        assert!(!dedup.is_duplicate(key, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_dedup_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dedup");
+        let key = |dx: &str| DedupKey {
+            dx: dx.to_string(),
+            frequency: 3644,
+        };
+        let window = Duration::from_secs(60);
+        let now = SystemTime::now();
+
+        let mut dedup = Dedup::load(&path, window, now).unwrap();
+        assert!(!dedup.is_duplicate(key("OH2NOS/P"), now - Duration::from_secs(90)));
+        assert!(!dedup.is_duplicate(key("OH8HUB"), now));
+        dedup.save(&path).unwrap();
+
+        // Restarted
+        let later = now + Duration::from_secs(30);
+        let mut dedup = Dedup::load(&path, window, later).unwrap();
+        assert_eq!(dedup.seen.len(), 1);
+        assert!(dedup.is_duplicate(key("OH8HUB"), later));
+        assert!(!dedup.is_duplicate(key("OH2NOS/P"), later));
+    }
+}
@@ -0,0 +1,284 @@
+//! Posting spots to Discord channels through webhooks.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, StatusCode, Url};
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{DiscordConfig, DEFAULT_DISCORD_TEMPLATE};
+use crate::metrics::Metrics;
+use crate::parser::{Activity, DxEntry};
+use crate::sink::{self, Sink};
+use crate::template::Template;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How many times a rate limited post is tried again.
+const RATE_LIMIT_RETRIES: u32 = 3;
+/// Longest wait for rate limit. Longer waits fail the post instead.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+pub struct DiscordSink {
+    name: String,
+    client: Client,
+    url: Url,
+    username: Option<String>,
+    template: Template,
+    color: Option<u32>,
+    /// No posts before this, as told by the rate limit headers
+    ready_at: Mutex<Option<Instant>>,
+}
+
+impl DiscordSink {
+    /// Sink of `config`, the `index`th in the config.
+    pub fn new(config: &DiscordConfig, index: usize) -> io::Result<Self> {
+        // The error would contain the secret URL
+        let url = Url::parse(&config.webhook_url)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid webhook_url"))?;
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let template = config.template.clone().unwrap_or_else(|| {
+            Template::parse(DEFAULT_DISCORD_TEMPLATE).expect("default template is valid")
+        });
+        Ok(Self {
+            // Same host for every webhook, so told apart by the index
+            name: format!("discord[{index}]"),
+            client,
+            url,
+            username: config.username.clone(),
+            template,
+            color: config.color,
+            ready_at: Mutex::new(None),
+        })
+    }
+
+    /// Webhook message with an embed for `spot`.
+    fn payload(&self, spot: &DxEntry) -> serde_json::Value {
+        let activity = spot.cqgma_identifier.map(|(activity, _)| activity);
+        let references = spot.references();
+        let mut fields = vec![
+            json!({ "name": "Frequency", "value": spot.frequency_mhz_string(), "inline": true }),
+            json!({ "name": "Spotter", "value": spot.reporter, "inline": true }),
+        ];
+        if !references.is_empty() {
+            let links: Vec<String> = references
+                .iter()
+                .map(|reference| format!("[{reference}]({})", reference_url(activity, reference)))
+                .collect();
+            fields.push(json!({ "name": "Reference", "value": links.join(" "), "inline": true }));
+        }
+        let mut embed = json!({
+            "title": spot.dx,
+            "description": self.template.render(spot),
+            "fields": fields,
+            "footer": { "text": format!("{}Z", spot.timestamp) },
+        });
+        if let Some(reference) = references.first() {
+            embed["url"] = reference_url(activity, reference).into();
+        }
+        if let Some(color) = self.color {
+            embed["color"] = color.into();
+        }
+        let mut payload = json!({ "embeds": [embed] });
+        if let Some(username) = &self.username {
+            payload["username"] = username.as_str().into();
+        }
+        payload
+    }
+
+    /// Wait if the previous response told that the bucket is empty.
+    async fn wait_until_ready(&self) {
+        let ready_at = self.ready_at.lock().expect("ready_at lock").take();
+        if let Some(ready_at) = ready_at {
+            tokio::time::sleep_until(ready_at).await;
+        }
+    }
+
+    fn update_rate_limit(&self, headers: &HeaderMap) {
+        let remaining = header_str(headers, "x-ratelimit-remaining");
+        if remaining == Some("0") {
+            if let Some(reset_after) = header_secs(headers, "x-ratelimit-reset-after") {
+                let ready_at = Instant::now() + reset_after.min(MAX_RETRY_AFTER);
+                *self.ready_at.lock().expect("ready_at lock") = Some(ready_at);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for DiscordSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let payload = self.payload(spot);
+        let mut retries = RATE_LIMIT_RETRIES;
+        loop {
+            self.wait_until_ready().await;
+            let response = self
+                .client
+                .post(self.url.clone())
+                .json(&payload)
+                .send()
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.without_url()))?;
+            self.update_rate_limit(response.headers());
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            if status != StatusCode::TOO_MANY_REQUESTS || retries == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("HTTP {status}"),
+                ));
+            }
+            let retry_after =
+                header_secs(response.headers(), "retry-after").unwrap_or(Duration::from_secs(1));
+            if retry_after > MAX_RETRY_AFTER {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("rate limited for {} seconds", retry_after.as_secs()),
+                ));
+            }
+            tracing::debug!(
+                "Rate limited by Discord. Retrying in {} seconds.",
+                retry_after.as_secs_f32()
+            );
+            tokio::time::sleep(retry_after).await;
+            retries -= 1;
+        }
+    }
+}
+
+/// Page of `reference` on the site of the program, or on CQGMA which knows
+/// most programs.
+fn reference_url(activity: Option<Activity>, reference: &str) -> String {
+    match activity {
+        Some(Activity::Wwff) => format!("https://wwff.co/directory/?showRef={reference}"),
+        Some(Activity::Sota) => format!("https://www.sotadata.org.uk/en/summit/{reference}"),
+        _ => format!("https://www.cqgma.org/zinfo.php?ref={reference}"),
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name)?.to_str().ok()
+}
+
+/// Header value in seconds, eg. `1.5`.
+fn header_secs(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    let secs: f64 = header_str(headers, name)?.parse().ok()?;
+    (secs.is_finite() && secs >= 0.0).then(|| Duration::from_secs_f64(secs))
+}
+
+/// Post spots from `spots` to the channel of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: DiscordConfig,
+    index: usize,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = DiscordSink::new(&config, index)?;
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use axum::Router;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::DiscordSink;
+    use crate::config::DiscordConfig;
+    use crate::sink::Sink;
+
+    fn config(webhook_url: String) -> DiscordConfig {
+        DiscordConfig {
+            webhook_url,
+            username: Some("puskapupu".to_string()),
+            template: None,
+            color: Some(0x2e7d32),
+        }
+    }
+
+    #[test]
+    fn test_payload() {
+        let sink =
+            DiscordSink::new(&config("https://discord.com/api/webhooks/1/x".into()), 0).unwrap();
+        let spot = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            sink.payload(&spot),
+            json!({
+                "username": "puskapupu",
+                "embeds": [{
+                    "title": "OH2NOS/P",
+                    "url": "https://wwff.co/directory/?showRef=OHFF-1419",
+                    "description": "OHFF-1419 New one!",
+                    "color": 0x2e7d32,
+                    "fields": [
+                        { "name": "Frequency", "value": "3.644 MHz", "inline": true },
+                        { "name": "Spotter", "value": "OH2NOS", "inline": true },
+                        {
+                            "name": "Reference",
+                            "value": "[OHFF-1419](https://wwff.co/directory/?showRef=OHFF-1419)",
+                            "inline": true
+                        },
+                    ],
+                    "footer": { "text": "1146Z" },
+                }],
+            })
+        );
+        assert_eq!(sink.name(), "discord[0]");
+    }
+
+    /// Rate limits the first post.
+    async fn webhook(State(posts): State<Arc<AtomicUsize>>) -> impl IntoResponse {
+        if posts.fetch_add(1, Ordering::Relaxed) == 0 {
+            (StatusCode::TOO_MANY_REQUESTS, [("retry-after", "0.2")])
+        } else {
+            (StatusCode::NO_CONTENT, [("x-ratelimit-remaining", "1")])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let posts = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/api/webhooks/1/x", post(webhook))
+            .with_state(posts.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let sink = DiscordSink::new(&config(format!("http://{addr}/api/webhooks/1/x")), 0).unwrap();
+        let spot = "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z"
+            .parse()
+            .unwrap();
+        let started = Instant::now();
+        sink.send(&spot).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(posts.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,499 @@
+//! Email alerts through SMTP when a watched reference or a `New one!` is
+//! spotted.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{EmailConfig, SmtpSecurity};
+use crate::lookup::Lookup;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+use crate::template::Template;
+use crate::{tls, utc};
+
+/// Time for connecting and sending one message.
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// Subject of each message after `Spotted: `.
+const SUBJECT_TEMPLATE: &str = "{dx} {reference}";
+
+/// Decides which spots are alerted. A reference alerted once isn't alerted
+/// again until the cooldown has passed.
+#[derive(Debug)]
+pub struct Alerts {
+    /// Uppercased, like [DxEntry::references]
+    watch: Vec<String>,
+    new_one: bool,
+    cooldown: Duration,
+    alerted: HashMap<String, SystemTime>,
+}
+
+impl Alerts {
+    pub fn new(config: &EmailConfig) -> Self {
+        Self {
+            watch: config.watch.iter().map(|r| r.to_uppercase()).collect(),
+            new_one: config.new_one,
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            alerted: HashMap::new(),
+        }
+    }
+
+    /// Key of the alert if `spot` should be alerted at `now`: the watched
+    /// or new reference, or the callsign if there's no reference.
+    pub fn check(&mut self, spot: &DxEntry, now: SystemTime) -> Option<String> {
+        let references = spot.references();
+        let key = match references.iter().find(|r| self.watch.contains(r)) {
+            Some(watched) => watched.clone(),
+            None if self.new_one && spot.is_new_one() => references
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| spot.dx.to_uppercase()),
+            None => return None,
+        };
+        let cooldown = self.cooldown;
+        self.alerted
+            .retain(|_, at| now.duration_since(*at).unwrap_or_default() < cooldown);
+        if self.alerted.contains_key(&key) {
+            tracing::debug!("Alerted {key} recently, not emailing");
+            return None;
+        }
+        self.alerted.insert(key.clone(), now);
+        Some(key)
+    }
+
+    /// Forget the alert of `key`, eg. when it couldn't be sent.
+    pub fn forget(&mut self, key: &str) {
+        self.alerted.remove(key);
+    }
+}
+
+pub struct EmailSink {
+    name: String,
+    server: String,
+    security: SmtpSecurity,
+    /// Username and password
+    login: Option<(String, String)>,
+    from: String,
+    to: Vec<String>,
+    subject: Template,
+    alerts: Mutex<Alerts>,
+    tls: TlsConnector,
+    /// References of alerted spots are looked up if given
+    lookup: Option<Arc<Lookup>>,
+}
+
+impl EmailSink {
+    pub fn new(config: &EmailConfig, lookup: Option<Arc<Lookup>>) -> Self {
+        Self {
+            name: format!("email {}", config.server),
+            server: config.server.clone(),
+            security: config.security,
+            login: config.username.clone().zip(config.password.clone()),
+            from: config.from.clone(),
+            to: config.to.clone(),
+            subject: Template::parse(SUBJECT_TEMPLATE).expect("subject template is valid"),
+            alerts: Mutex::new(Alerts::new(config)),
+            tls: tls::connector(&[]),
+            lookup,
+        }
+    }
+
+    /// Message of `spot` sent at `now`, with headers and CR LF line ends.
+    fn message(&self, spot: &DxEntry, now: SystemTime) -> String {
+        let subject = format!("Spotted: {}", self.subject.render(spot).trim());
+        let band = spot
+            .band()
+            .map_or(String::new(), |band| format!(" ({})", band.name()));
+        let mut body = format!("Callsign: {}\n", spot.dx);
+        let _ = writeln!(body, "Frequency: {}{band}", spot.frequency_mhz_string());
+        match &spot.reference_info {
+            Some(info) => {
+                let _ = writeln!(body, "Reference: {info}");
+            }
+            None => {
+                if let Some(reference) = spot.references().first() {
+                    let _ = writeln!(body, "Reference: {reference}");
+                }
+            }
+        }
+        let _ = writeln!(body, "Info: {}", spot.info);
+        let _ = writeln!(body, "Spotted by {} at {}Z", spot.reporter, spot.timestamp);
+
+        let mut message = String::new();
+        let headers = [
+            ("From", self.from.clone()),
+            ("To", self.to.join(", ")),
+            ("Subject", encode_word(&subject)),
+            ("Date", utc::rfc5322(now)),
+            ("MIME-Version", "1.0".to_string()),
+            ("Content-Type", "text/plain; charset=utf-8".to_string()),
+            ("Content-Transfer-Encoding", "8bit".to_string()),
+        ];
+        for (name, value) in headers {
+            let _ = write!(message, "{name}: {value}\r\n");
+        }
+        message.push_str("\r\n");
+        for line in body.lines() {
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message
+    }
+
+    /// Connect to the server and send `message` to all recipients.
+    async fn submit(&self, message: &str) -> io::Result<()> {
+        let host = self.server.rsplit_once(':').map_or("", |(host, _)| host);
+        let server_name = || {
+            ServerName::try_from(host)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+        };
+        let stream = TcpStream::connect(&self.server).await?;
+        match self.security {
+            SmtpSecurity::None => {
+                let mut stream = BufReader::new(stream);
+                greet(&mut stream, &self.from).await?;
+                self.transaction(&mut stream, message).await
+            }
+            SmtpSecurity::Tls => {
+                let stream = self.tls.connect(server_name()?, stream).await?;
+                let mut stream = BufReader::new(stream);
+                greet(&mut stream, &self.from).await?;
+                self.transaction(&mut stream, message).await
+            }
+            SmtpSecurity::Starttls => {
+                let mut stream = BufReader::new(stream);
+                greet(&mut stream, &self.from).await?;
+                command(&mut stream, "STARTTLS", 220).await?;
+                let stream = self
+                    .tls
+                    .connect(server_name()?, stream.into_inner())
+                    .await?;
+                let mut stream = BufReader::new(stream);
+                command(
+                    &mut stream,
+                    &format!("EHLO {}", helo_domain(&self.from)),
+                    250,
+                )
+                .await?;
+                self.transaction(&mut stream, message).await
+            }
+        }
+    }
+
+    /// Log in if configured, send `message` and quit.
+    async fn transaction<S>(&self, stream: &mut BufReader<S>, message: &str) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Some((username, password)) = &self.login {
+            let credentials = STANDARD.encode(format!("\0{username}\0{password}"));
+            command(stream, &format!("AUTH PLAIN {credentials}"), 235).await?;
+        }
+        command(stream, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        for to in &self.to {
+            command(stream, &format!("RCPT TO:<{to}>"), 250).await?;
+        }
+        command(stream, "DATA", 354).await?;
+        // Lines starting with a dot get another, so they don't end the data
+        let mut data = String::with_capacity(message.len() + 8);
+        for line in message.split_inclusive("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+        }
+        data.push_str(".\r\n");
+        stream.get_mut().write_all(data.as_bytes()).await?;
+        reply(stream, 250).await?;
+        command(stream, "QUIT", 221).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for EmailSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Spots which aren't alerted are dropped.
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let now = SystemTime::now();
+        let Some(key) = self.alerts.lock().expect("alerts lock").check(spot, now) else {
+            return Ok(());
+        };
+        tracing::info!("Emailing alert of {key} to {}", self.to.join(", "));
+        let message = match &self.lookup {
+            Some(lookup) => self.message(&lookup.enrich(spot).await, now),
+            None => self.message(spot, now),
+        };
+        let result = tokio::time::timeout(TIMEOUT, self.submit(&message))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "SMTP timed out")));
+        if result.is_err() {
+            // Alerted again when spotted again
+            self.alerts.lock().expect("alerts lock").forget(&key);
+        }
+        result
+    }
+}
+
+/// Read the greeting and introduce ourselves.
+async fn greet<S>(stream: &mut BufReader<S>, from: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    reply(stream, 220).await?;
+    command(stream, &format!("EHLO {}", helo_domain(from)), 250).await?;
+    Ok(())
+}
+
+/// Domain of the sender address, used to introduce ourselves.
+fn helo_domain(from: &str) -> &str {
+    from.rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain)
+}
+
+/// Send `line` and read the reply, which must be of the same class as
+/// `expected`, eg. any 2xx for 250.
+async fn command<S>(stream: &mut BufReader<S>, line: &str, expected: u16) -> io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if line.starts_with("AUTH ") {
+        tracing::trace!("smtp tx: ^AUTH ...$");
+    } else {
+        tracing::trace!("smtp tx: ^{line}$");
+    }
+    stream
+        .get_mut()
+        .write_all(format!("{line}\r\n").as_bytes())
+        .await?;
+    reply(stream, expected).await
+}
+
+/// Read a reply, which may span lines like `250-...` up to `250 ...`.
+async fn reply<S>(stream: &mut BufReader<S>, expected: u16) -> io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "SMTP server closed the connection",
+            ));
+        }
+        tracing::trace!("smtp rx: ^{}$", line.trim_end());
+        reply.push_str(&line);
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+    }
+    let code: Option<u16> = reply.get(..3).and_then(|code| code.parse().ok());
+    match code {
+        Some(code) if code / 100 == expected / 100 => Ok(reply),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("SMTP server answered: {}", reply.trim_end()),
+        )),
+    }
+}
+
+/// `text` as is if it's ASCII, else as an RFC 2047 encoded word.
+fn encode_word(text: &str) -> String {
+    if text.is_ascii() {
+        text.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(text))
+    }
+}
+
+/// Email alerts of spots from `spots` as configured in `config` until
+/// `shutdown` is cancelled.
+pub async fn run(
+    config: EmailConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    lookup: Option<Arc<Lookup>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = EmailSink::new(&config, lookup);
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::{Alerts, EmailSink};
+    use crate::config::{EmailConfig, SmtpSecurity};
+    use crate::lookup::ReferenceInfo;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+    use crate::testutil;
+
+    fn config(server: String) -> EmailConfig {
+        EmailConfig {
+            server,
+            security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "puskapupu@example.org".to_string(),
+            to: vec!["oh8hub@example.org".to_string()],
+            watch: vec!["ohff-1419".to_string()],
+            new_one: true,
+            cooldown_secs: 3600,
+        }
+    }
+
+    fn spot(line: &str) -> DxEntry {
+        line.parse().unwrap()
+    }
+
+    #[test]
+    fn test_message() {
+        let sink = EmailSink::new(&config("localhost:25".to_string()), None);
+        let mut entry =
+            spot("DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z");
+        // 2024-03-01 12:00 UTC
+        let now = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        let message = sink.message(&entry, now);
+        assert_eq!(
+            message,
+            "From: puskapupu@example.org\r\n\
+             To: oh8hub@example.org\r\n\
+             Subject: Spotted: OH2NOS/P OHFF-1419\r\n\
+             Date: Fri, 01 Mar 2024 12:00:00 +0000\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\
+             \r\n\
+             Callsign: OH2NOS/P\r\n\
+             Frequency: 3.644 MHz (80m)\r\n\
+             Reference: OHFF-1419\r\n\
+             Info: OHFF-1419 New one!\r\n\
+             Spotted by OH2NOS at 1146Z\r\n"
+        );
+
+        entry.reference_info = Some(ReferenceInfo {
+            code: "OHFF-1419".to_string(),
+            name: "Kuusijärvi".to_string(),
+            details: None,
+            location: None,
+        });
+        let message = sink.message(&entry, now);
+        // "Spotted: OH2NOS/P OHFF-1419 Kuusijärvi"
+        assert!(message.contains(
+            "Subject: =?UTF-8?B?U3BvdHRlZDogT0gyTk9TL1AgT0hGRi0xNDE5IEt1dXNpasOkcnZp?=\r\n"
+        ));
+        assert!(message.contains("Reference: OHFF-1419 Kuusijärvi\r\n"));
+    }
+
+    #[test]
+    fn test_alerts() {
+        let mut alerts = Alerts::new(&config("localhost:25".to_string()));
+        let now = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        let watched =
+            spot("DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 CW            1150Z");
+        let new_one =
+            spot("DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-0001 New one!        1146Z");
+        let other =
+            spot("DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z");
+
+        assert_eq!(alerts.check(&watched, now), Some("OHFF-1419".to_string()));
+        assert_eq!(alerts.check(&new_one, now), Some("OHFF-0001".to_string()));
+        assert_eq!(alerts.check(&other, now), None);
+
+        // Cooling down
+        let later = now + Duration::from_secs(3599);
+        assert_eq!(alerts.check(&watched, later), None);
+        assert_eq!(alerts.check(&new_one, later), None);
+        alerts.forget("OHFF-0001");
+        assert_eq!(alerts.check(&new_one, later), Some("OHFF-0001".to_string()));
+
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(alerts.check(&watched, later), Some("OHFF-1419".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let lookup = testutil::lookup(vec![ReferenceInfo {
+            code: "OHFF-1419".to_string(),
+            name: "Kuusijärvi".to_string(),
+            details: None,
+            location: None,
+        }]);
+        let sink = EmailSink::new(
+            &config(listener.local_addr().unwrap().to_string()),
+            Some(lookup),
+        );
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            let mut received = Vec::new();
+            socket
+                .write_all(b"220 mail.example.org ESMTP\r\n")
+                .await
+                .unwrap();
+            loop {
+                let mut line = String::new();
+                socket.read_line(&mut line).await.unwrap();
+                let answer: &[u8] = match line.trim_end() {
+                    "DATA" => b"354 Go ahead\r\n",
+                    "." => b"250 Queued\r\n",
+                    "QUIT" => b"221 Bye\r\n",
+                    line if line.starts_with("EHLO ") => {
+                        b"250-mail.example.org\r\n250 8BITMIME\r\n"
+                    }
+                    line if line.starts_with("MAIL ") || line.starts_with("RCPT ") => b"250 OK\r\n",
+                    _ => b"",
+                };
+                received.push(line.trim_end().to_string());
+                socket.write_all(answer).await.unwrap();
+                if line.starts_with("QUIT") {
+                    return received;
+                }
+            }
+        });
+
+        sink.send(&spot(
+            "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z",
+        ))
+        .await
+        .unwrap();
+        sink.send(&spot(
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+        ))
+        .await
+        .unwrap();
+        let received = server.await.unwrap();
+        assert_eq!(received[0], "EHLO example.org");
+        assert_eq!(received[1], "MAIL FROM:<puskapupu@example.org>");
+        assert_eq!(received[2], "RCPT TO:<oh8hub@example.org>");
+        assert_eq!(received[3], "DATA");
+        // Looked up by the sink
+        assert!(received.contains(&"Reference: OHFF-1419 Kuusijärvi".to_string()));
+        assert_eq!(received[received.len() - 2], ".");
+    }
+}
@@ -0,0 +1,679 @@
+//! Example config for new users to start from.
+//!
+//! The example is serialized from [Config] so it can't drift from the
+//! structs. Comments are added from [COMMENTS] by the dotted path of each
+//! table and key.
+
+use matrix_sdk::ruma::{device_id, owned_room_id, owned_user_id};
+
+use crate::band::Band;
+use crate::config::{
+    ActivitiesConfig, AdifConfig, AprsConfig, Config, CqgmaConfig, CsvConfig, DeadLetterConfig,
+    DedupConfig, DiscordConfig, EmailConfig, HttpConfig, InfluxdbConfig, JsonlConfig, KafkaConfig,
+    LoggingConfig, LookupConfig, MatrixConfig, MqttConfig, NostrConfig, NtfyConfig, OverflowPolicy,
+    PotaSpotsConfig, QuietHours, RouteConfig, SmtpSecurity, SotawatchConfig, StoreConfig,
+    TelegramConfig, WatchdogConfig, WebhookConfig, XmppConfig, DEFAULT_APRS_SERVER,
+    DEFAULT_APRS_TEMPLATE, DEFAULT_CHANNEL_CAPACITY, DEFAULT_DEAD_LETTER_MAX_BYTES,
+    DEFAULT_DEDUP_WINDOW_SECS, DEFAULT_DISCORD_TEMPLATE, DEFAULT_EMAIL_COOLDOWN_SECS,
+    DEFAULT_INFLUXDB_BATCH_SIZE, DEFAULT_INFLUXDB_FLUSH_SECS, DEFAULT_KAFKA_ACKS,
+    DEFAULT_KAFKA_CLIENT_ID, DEFAULT_LIVE_BACKLOG, DEFAULT_LOOKUP_CACHE_SECS,
+    DEFAULT_LOOKUP_TIMEOUT_SECS, DEFAULT_MAP_MINUTES, DEFAULT_MQTT_CLIENT_ID, DEFAULT_MQTT_TOPIC,
+    DEFAULT_NOSTR_TEMPLATE, DEFAULT_NTFY_NEW_ONE_PRIORITY, DEFAULT_NTFY_SERVER,
+    DEFAULT_NTFY_TEMPLATE, DEFAULT_POTA_SPOTS_COOLDOWN_SECS, DEFAULT_RECONNECT_MAX_SECS,
+    DEFAULT_RECONNECT_MIN_SECS, DEFAULT_RETENTION_DAYS, DEFAULT_STALL_SECS,
+    DEFAULT_TELEGRAM_TEMPLATE, DEFAULT_WEBHOOK_RETRIES, DEFAULT_WEBHOOK_TIMEOUT_SECS,
+    DEFAULT_XMPP_NICK,
+};
+use crate::filter::FilterConfig;
+use crate::parser::Activity;
+use crate::template::{Format, Template};
+
+/// Put in place of secrets. Must be replaced before use.
+pub const PLACEHOLDER_SECRET: &str = "CHANGE-ME";
+
+const COMMENTS: &[(&str, &str)] = &[
+    ("home_grid", "Maidenhead locator of the operator, used for distances to looked up references"),
+    (
+        "cty_path",
+        "Country file from https://www.country-files.com/ for countries of callsigns",
+    ),
+    (
+        "matrix",
+        "Matrix account posting the spots. Repeat [[matrix]] for more accounts.",
+    ),
+    ("matrix.homeserver", "Homeserver URL of the account"),
+    (
+        "matrix.access_token",
+        "Secret. Can also be given in secrets_path, or for the first account in PUSKAPUPU_MATRIX_ACCESS_TOKEN.",
+    ),
+    ("matrix.user_id", "User of the bot"),
+    ("matrix.device_id", "Device of the bot"),
+    ("matrix.rooms", "Rooms where spots are posted"),
+    (
+        "matrix.sync_token_path",
+        "Sync token is kept here over restarts, so old messages are not handled again",
+    ),
+    (
+        "matrix.dedup_window_secs",
+        "Don't post the same spot again within this many seconds",
+    ),
+    (
+        "matrix.dedup_path",
+        "Recently posted spots are kept here, so they are not posted again after a restart",
+    ),
+    ("matrix.admins", "Users allowed to change the filter with !filter"),
+    (
+        "matrix.template",
+        "Message for each spot. Placeholders: {dx} {frequency} {band} {info} {reporter} {time} {grid}. {{ and }} are braces.",
+    ),
+    (
+        "matrix.format",
+        "Markup of the template: plain or html. Values of placeholders are escaped for it.",
+    ),
+    ("matrix.quiet_hours", "No spots are posted between these times (UTC)"),
+    ("matrix.quiet_hours.start", "HH:MM"),
+    ("matrix.quiet_hours.end", "HH:MM, may be past midnight"),
+    (
+        "cqgma",
+        "Cluster where spots are read from. Repeat [[cqgma]] for more clusters.",
+    ),
+    (
+        "cqgma.host",
+        "host:port of the telnet cluster. Add proxy = { type = \"socks5\", address = \"localhost:1080\" } \
+         or type = \"http\" to connect through a proxy.",
+    ),
+    (
+        "cqgma.username",
+        "Your callsign. Add password = \"...\" if the cluster asks for one.",
+    ),
+    (
+        "cqgma.reconnect_min_secs",
+        "Wait randomly between min and max seconds before reconnecting",
+    ),
+    ("cqgma.reconnect_max_secs", ""),
+    (
+        "cqgma.max_consecutive_failures",
+        "Give up reconnecting after this many failures in a row. Leave out to retry forever.",
+    ),
+    (
+        "channel_capacity",
+        "Spots kept for the slowest sink. More takes memory, less loses spots sooner.",
+    ),
+    (
+        "overflow_policy",
+        "When a sink is that far behind: drop_oldest, drop_newest or block reading clusters",
+    ),
+    ("filter", "Which spots are forwarded"),
+    (
+        "filter.spotter_prefixes",
+        "Spots reported by these callsign prefixes...",
+    ),
+    ("filter.references", "...or spots mentioning these references"),
+    ("filter.bands", "Only these bands. Empty means all."),
+    (
+        "filter.activities",
+        "Only these: wwff, iota, cota, sota, gma, lighthouses, rda, agcw. Empty means all.",
+    ),
+    ("filter.modes", "Only spots mentioning these modes. Empty means all."),
+    (
+        "filter.frequencies",
+        "Only these ranges, eg. [{ min_khz = 7000.0, max_khz = 7040.0 }]. Empty means all.",
+    ),
+    (
+        "filter.dedup_window_secs",
+        "Drop spots of the same activation seen within this many seconds",
+    ),
+    ("filter.max_age_secs", "Drop spots older than this many seconds"),
+    (
+        "activities",
+        "Turn programs on or off as a whole. Spots of those off are never forwarded.",
+    ),
+    ("activities.wwff", "World Flora & Fauna"),
+    ("activities.iota", "Islands on the Air"),
+    ("activities.cota", "Castles on the Air"),
+    ("activities.sota", "Summits on the Air"),
+    ("activities.gma", "Global Mountain Activity"),
+    ("activities.lighthouses", ""),
+    ("activities.rda", "Russian District Award"),
+    ("activities.agcw", ""),
+    (
+        "route",
+        "Send spots passing the filter only to these sinks. Sinks in no [[route]] get all spots.",
+    ),
+    (
+        "route.sinks",
+        "Names as in logs, eg. mqtt, webhook[0] or matrix @puskapupu:example.org",
+    ),
+    (
+        "route.filter",
+        "Like [filter], but unset fields let all spots through and without dedup_window_secs",
+    ),
+    ("route.filter.spotter_prefixes", ""),
+    ("route.filter.references", ""),
+    ("route.filter.bands", ""),
+    ("route.filter.activities", ""),
+    ("route.filter.modes", ""),
+    ("route.filter.frequencies", ""),
+    (
+        "dedup",
+        "Stricter deduplication for these sinks, on top of filter.dedup_window_secs",
+    ),
+    ("dedup.sinks", "Names like in route.sinks"),
+    (
+        "dedup.window_secs",
+        "Each sink gets a spot of the same activation only once within this many seconds",
+    ),
+    (
+        "logging",
+        "Logging to stdout, or to syslog with [logging.syslog]. RUST_LOG overrides the level.",
+    ),
+    ("logging.level", "Level or directives, eg. \"info,matrix_sdk=warn\""),
+    ("logging.format", "text or json"),
+    (
+        "store",
+        "Keep history of forwarded spots in SQLite. Leave out to disable.",
+    ),
+    ("store.path", "Database file"),
+    ("store.retention_days", "Spots older than this are deleted"),
+    (
+        "mqtt",
+        "Publish spots as JSON to MQTT broker. Needs the mqtt feature. Leave out to disable.",
+    ),
+    (
+        "mqtt.broker",
+        "mqtt://host:port. Add username and password if the broker asks for them.",
+    ),
+    ("mqtt.client_id", "Client identifier, unique on the broker"),
+    ("mqtt.topic", "Topic of each spot, with placeholders like in matrix.template"),
+    ("mqtt.qos", "0 at most once, 1 at least once or 2 exactly once"),
+    (
+        "mqtt.format",
+        "Markup of template, the payload of each spot: plain or json. Spots are published as JSON without a template.",
+    ),
+    (
+        "webhook",
+        "POST each spot as JSON to a URL. Needs the webhook feature. Repeat [[webhook]] for more.",
+    ),
+    ("webhook.url", "http:// or https:// URL"),
+    ("webhook.timeout_secs", "Give up on a request after this many seconds"),
+    (
+        "webhook.retries",
+        "Retry this many times after timeouts, connection errors and 5xx responses",
+    ),
+    ("webhook.headers", "Sent with every request, eg. for authentication"),
+    ("webhook.headers.Authorization", ""),
+    (
+        "discord",
+        "Post spots to Discord channel. Needs the discord feature. Repeat [[discord]] for more.",
+    ),
+    (
+        "discord.webhook_url",
+        "Secret. From channel settings, Integrations, Webhooks.",
+    ),
+    ("discord.username", "Poster name instead of the name of the webhook"),
+    ("discord.template", "Body of the message, with placeholders like in matrix.template"),
+    ("discord.color", "Color of the message, eg. 0x2e7d32 for green"),
+    (
+        "telegram",
+        "Send spots to Telegram chat. Needs the telegram feature. Repeat [[telegram]] for more.",
+    ),
+    ("telegram.bot_token", "Secret. Token of the bot from @BotFather."),
+    ("telegram.chat_id", "Numeric id of the chat or @name of a channel"),
+    (
+        "telegram.template",
+        "Message after the callsign, with placeholders like in matrix.template",
+    ),
+    ("telegram.format", "Markup of the template: plain, html or markdown (MarkdownV2)"),
+    (
+        "ntfy",
+        "Push notifications through ntfy, titled with the callsign and reference. Needs the ntfy feature.",
+    ),
+    ("ntfy.server", "ntfy server, public or your own"),
+    (
+        "ntfy.topic",
+        "Topic to publish to. Anyone knowing the name of an open topic can subscribe.",
+    ),
+    ("ntfy.token", "Access token for topics with access control. Optional."),
+    (
+        "ntfy.template",
+        "Message under the title, with placeholders like in matrix.template",
+    ),
+    (
+        "ntfy.new_one_priority",
+        "Priority of New one! spots from 1 (min) to 5 (max). Others have the default 3.",
+    ),
+    (
+        "email",
+        "Email alerts when a watched reference or a New one! is spotted. Needs the email feature.",
+    ),
+    ("email.server", "SMTP server as host:port"),
+    (
+        "email.security",
+        "tls (usually port 465), starttls (port 587) or none (eg. a relay on localhost)",
+    ),
+    ("email.username", "Login, if the server wants one"),
+    ("email.password", "Password of the login"),
+    ("email.from", "Sender address"),
+    ("email.to", "One or more recipient addresses"),
+    ("email.watch", "References to alert on whenever spotted"),
+    ("email.new_one", "Alert on spots marked New one!"),
+    (
+        "email.cooldown_secs",
+        "The same reference is alerted again only after this many seconds",
+    ),
+    (
+        "aprs",
+        "Send spots as APRS messages through APRS-IS. Needs the aprs feature. Leave out to disable.",
+    ),
+    ("aprs.server", "APRS-IS server as host:port"),
+    ("aprs.callsign", "Your callsign and SSID the messages are sent from"),
+    ("aprs.passcode", "APRS-IS passcode of the callsign"),
+    ("aprs.to", "Callsign or bulletin the messages are addressed to"),
+    (
+        "aprs.template",
+        "Message text, with placeholders like in matrix.template. Cut to 67 characters.",
+    ),
+    (
+        "nostr",
+        "Publish spots as Nostr notes tagged with band and activity. Needs the nostr feature. Leave out to disable.",
+    ),
+    ("nostr.relays", "Relays the notes are sent to"),
+    (
+        "nostr.secret_key",
+        "Key the notes are signed with, as hex or nsec1... Generate one of your own.",
+    ),
+    (
+        "nostr.template",
+        "Text of the notes, with placeholders like in matrix.template",
+    ),
+    (
+        "xmpp",
+        "Post spots to an XMPP multi-user chat room. Needs the xmpp feature. Leave out to disable.",
+    ),
+    ("xmpp.jid", "Account the spots are posted from"),
+    ("xmpp.password", "Password of the account"),
+    (
+        "xmpp.server",
+        "Server as host:port with direct TLS, not STARTTLS. Defaults to the domain of the jid and port 5223.",
+    ),
+    ("xmpp.room", "Room the bot joins and posts to"),
+    ("xmpp.nick", "Nickname of the bot in the room"),
+    (
+        "xmpp.template",
+        "Message, with placeholders like in matrix.template",
+    ),
+    (
+        "influxdb",
+        "Write spots to InfluxDB 2 in line protocol for dashboards. Needs the influxdb feature. Leave out to disable.",
+    ),
+    ("influxdb.url", "Base URL of the server"),
+    ("influxdb.org", "Organization of the bucket"),
+    ("influxdb.bucket", "Bucket the spots are written to"),
+    ("influxdb.token", "API token with write access to the bucket"),
+    ("influxdb.batch_size", "Most spots written in one request"),
+    (
+        "influxdb.flush_secs",
+        "Spots are written at least this often even if the batch isn't full",
+    ),
+    (
+        "kafka",
+        "Produce spots as JSON keyed by callsign to a Kafka topic. Needs the kafka feature. Leave out to disable.",
+    ),
+    ("kafka.brokers", "Brokers asked for the leaders of the topic"),
+    ("kafka.topic", ""),
+    ("kafka.client_id", ""),
+    (
+        "kafka.acks",
+        "Wait for acknowledgement of the leader (1) or all in-sync replicas (-1)",
+    ),
+    (
+        "sotawatch",
+        "Post SOTA spots from the cluster which aren't on SOTAwatch there. Needs the sotawatch feature. Leave out to disable.",
+    ),
+    (
+        "sotawatch.enabled",
+        "Spots are posted under your SOTA account only when this is true",
+    ),
+    ("sotawatch.username", "SOTA account, usually your callsign"),
+    ("sotawatch.password", ""),
+    ("sotawatch.callsign", "Spotter of the posted spots. Defaults to the username."),
+    (
+        "pota_spots",
+        "Post spots of POTA parks to the POTA site unless already there. Needs the pota_spots feature. Leave out to disable.",
+    ),
+    (
+        "pota_spots.enabled",
+        "Spots are posted under your POTA account only when this is true",
+    ),
+    ("pota_spots.callsign", "Spotter of the posted spots"),
+    ("pota_spots.token", "API token of your POTA account"),
+    (
+        "pota_spots.cooldown_secs",
+        "The same park is posted again only after this many seconds",
+    ),
+    ("adif", "Append spots to ADIF file for logging programs. Leave out to disable."),
+    ("adif.path", "The header is written when the file is created"),
+    ("jsonl", "Append spots as JSON, one per line. Leave out to disable."),
+    (
+        "jsonl.path",
+        "Moved to <path>.YYYY-MM-DD when the first spot of a new day is written",
+    ),
+    ("csv", "Append spots to CSV file for spreadsheets. Leave out to disable."),
+    ("csv.path", "The header is written when the file is created"),
+    ("csv.rotate_daily", "Move the file to <path>.YYYY-MM-DD on a new day"),
+    (
+        "lookup",
+        "Look up details of references for {reference} in Matrix templates. Leave out to disable.",
+    ),
+    ("lookup.sota", "Summit names, altitudes and points from the SOTA API"),
+    (
+        "lookup.pota",
+        "Park names and locations from the POTA API, also used for distances",
+    ),
+    (
+        "lookup.wwff_path",
+        "WWFF directory from https://wwff.co/wwff-data/wwff_directory.csv, read at start",
+    ),
+    ("lookup.cache_secs", "Answers are remembered this long"),
+    (
+        "lookup.timeout_secs",
+        "Spots are sent without details if the directory doesn't answer in time",
+    ),
+    (
+        "http",
+        "HTTP server for /healthz, /readyz, /metrics, /spots, /spots.rss and /spots.html from the store and /spots/live. Leave out to disable.",
+    ),
+    ("http.listen", "Address and port to listen on"),
+    (
+        "http.live_backlog",
+        "Recent spots sent to WebSocket clients of /spots/live when they connect",
+    ),
+    (
+        "http.map_minutes",
+        "Spots of this many last minutes are drawn on /spots/map.svg (no coastlines, only a grid)",
+    ),
+    (
+        "watchdog",
+        "Reconnect clusters if no spots are forwarded for a while. Leave out to disable.",
+    ),
+    (
+        "watchdog.stall_secs",
+        "Reconnect after this many seconds without spots and exit after as long again",
+    ),
+    (
+        "watchdog.active_hours",
+        "Spots are expected only between these times (UTC). Leave out for always.",
+    ),
+    ("watchdog.active_hours.start", "HH:MM"),
+    ("watchdog.active_hours.end", "HH:MM, may be past midnight"),
+    (
+        "dead_letter",
+        "Cluster lines which look like spots but couldn't be parsed. Leave out to disable.",
+    ),
+    ("dead_letter.path", "Lines and parse errors are appended here"),
+    (
+        "dead_letter.max_bytes",
+        "When full, the file is moved to <path>.old and a new one is started",
+    ),
+];
+
+/// Config with every setting filled with a sensible or placeholder value.
+pub fn example_config() -> Config {
+    Config {
+        matrix: vec![MatrixConfig {
+            homeserver: "https://matrix.example.org".parse().expect("valid url"),
+            access_token: PLACEHOLDER_SECRET.to_string(),
+            refresh_token: None,
+            user_id: owned_user_id!("@puskapupu:example.org"),
+            device_id: device_id!("puskapupu").to_owned(),
+            rooms: vec![owned_room_id!("!spots:example.org")],
+            quiet_hours: Some(QuietHours {
+                start: "22:00".to_string().try_into().expect("valid time"),
+                end: "06:00".to_string().try_into().expect("valid time"),
+            }),
+            sync_token_path: Some("/var/lib/puskapupu/sync_token".into()),
+            dedup_window_secs: DEFAULT_DEDUP_WINDOW_SECS,
+            dedup_path: Some("/var/lib/puskapupu/dedup".into()),
+            admins: vec![owned_user_id!("@oh8hub:example.org")],
+            template: Some(Template::default()),
+            format: Format::Plain,
+        }],
+        cqgma: vec![CqgmaConfig {
+            host: "www.cqgma.org:7300".to_string(),
+            username: "N0CALL".to_string(),
+            password: None,
+            filter: None,
+            reconnect_min_secs: DEFAULT_RECONNECT_MIN_SECS,
+            reconnect_max_secs: DEFAULT_RECONNECT_MAX_SECS,
+            max_consecutive_failures: Some(20),
+            proxy: None,
+        }],
+        home_grid: Some("KP20le".to_string()),
+        cty_path: Some("/var/lib/puskapupu/cty.dat".into()),
+        channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+        overflow_policy: OverflowPolicy::DropOldest,
+        filter: FilterConfig {
+            bands: vec![Band::B40m, Band::B20m],
+            dedup_window_secs: Some(5 * 60),
+            max_age_secs: Some(30 * 60),
+            ..FilterConfig::default()
+        },
+        activities: ActivitiesConfig {
+            rda: false,
+            agcw: false,
+            ..ActivitiesConfig::default()
+        },
+        route: vec![RouteConfig {
+            sinks: vec!["mqtt".to_string()],
+            filter: FilterConfig {
+                activities: vec![Activity::Wwff],
+                ..FilterConfig::all()
+            },
+        }],
+        dedup: vec![DedupConfig {
+            sinks: vec!["email".to_string()],
+            window_secs: 60 * 60,
+        }],
+        logging: LoggingConfig::default(),
+        store: Some(StoreConfig {
+            path: "/var/lib/puskapupu/spots.sqlite".into(),
+            retention_days: DEFAULT_RETENTION_DAYS,
+        }),
+        mqtt: Some(MqttConfig {
+            broker: "mqtt://localhost:1883".to_string(),
+            client_id: DEFAULT_MQTT_CLIENT_ID.to_string(),
+            username: None,
+            password: None,
+            topic: Template::parse(DEFAULT_MQTT_TOPIC).expect("valid topic"),
+            qos: 0,
+            template: None,
+            format: Format::Json,
+        }),
+        webhook: vec![WebhookConfig {
+            url: "https://example.org/spots".to_string(),
+            headers: [(
+                "Authorization".to_string(),
+                format!("Bearer {PLACEHOLDER_SECRET}"),
+            )]
+            .into(),
+            timeout_secs: DEFAULT_WEBHOOK_TIMEOUT_SECS,
+            retries: DEFAULT_WEBHOOK_RETRIES,
+        }],
+        discord: vec![DiscordConfig {
+            webhook_url: format!("https://discord.com/api/webhooks/{PLACEHOLDER_SECRET}"),
+            username: Some("puskapupu".to_string()),
+            template: Some(Template::parse(DEFAULT_DISCORD_TEMPLATE).expect("valid template")),
+            color: Some(0x2e7d32),
+        }],
+        telegram: vec![TelegramConfig {
+            bot_token: format!("123456:{PLACEHOLDER_SECRET}"),
+            chat_id: "@ohffspots".to_string(),
+            template: Some(Template::parse(DEFAULT_TELEGRAM_TEMPLATE).expect("valid template")),
+            format: Format::Plain,
+        }],
+        ntfy: vec![NtfyConfig {
+            server: DEFAULT_NTFY_SERVER.to_string(),
+            topic: "ohff_spots".to_string(),
+            token: Some(format!("tk_{PLACEHOLDER_SECRET}")),
+            template: Some(Template::parse(DEFAULT_NTFY_TEMPLATE).expect("valid template")),
+            new_one_priority: DEFAULT_NTFY_NEW_ONE_PRIORITY,
+        }],
+        email: Some(EmailConfig {
+            server: "smtp.example.org:587".to_string(),
+            security: SmtpSecurity::Starttls,
+            username: Some("puskapupu@example.org".to_string()),
+            password: Some(PLACEHOLDER_SECRET.to_string()),
+            from: "puskapupu@example.org".to_string(),
+            to: vec!["oh8hub@example.org".to_string()],
+            watch: vec!["OHFF-1419".to_string()],
+            new_one: true,
+            cooldown_secs: DEFAULT_EMAIL_COOLDOWN_SECS,
+        }),
+        aprs: Some(AprsConfig {
+            server: DEFAULT_APRS_SERVER.to_string(),
+            callsign: "N0CALL-10".to_string(),
+            passcode: 13023,
+            to: "BLN1DX".to_string(),
+            template: Some(Template::parse(DEFAULT_APRS_TEMPLATE).expect("valid template")),
+        }),
+        nostr: Some(NostrConfig {
+            relays: vec!["wss://relay.damus.io".to_string()],
+            secret_key: "0123456789abcdef".repeat(4),
+            template: Some(Template::parse(DEFAULT_NOSTR_TEMPLATE).expect("valid template")),
+        }),
+        xmpp: Some(XmppConfig {
+            jid: "puskapupu@example.org".to_string(),
+            password: PLACEHOLDER_SECRET.to_string(),
+            server: None,
+            room: "spots@conference.example.org".to_string(),
+            nick: DEFAULT_XMPP_NICK.to_string(),
+            template: Some(Template::default()),
+        }),
+        influxdb: Some(InfluxdbConfig {
+            url: "http://localhost:8086".to_string(),
+            org: "oh8hub".to_string(),
+            bucket: "spots".to_string(),
+            token: PLACEHOLDER_SECRET.to_string(),
+            batch_size: DEFAULT_INFLUXDB_BATCH_SIZE,
+            flush_secs: DEFAULT_INFLUXDB_FLUSH_SECS,
+        }),
+        kafka: Some(KafkaConfig {
+            brokers: vec!["localhost:9092".to_string()],
+            topic: "spots".to_string(),
+            client_id: DEFAULT_KAFKA_CLIENT_ID.to_string(),
+            acks: DEFAULT_KAFKA_ACKS,
+        }),
+        sotawatch: Some(SotawatchConfig {
+            enabled: false,
+            username: "OH8HUB".to_string(),
+            password: PLACEHOLDER_SECRET.to_string(),
+            callsign: None,
+        }),
+        pota_spots: Some(PotaSpotsConfig {
+            enabled: false,
+            callsign: "OH8HUB".to_string(),
+            token: PLACEHOLDER_SECRET.to_string(),
+            cooldown_secs: DEFAULT_POTA_SPOTS_COOLDOWN_SECS,
+        }),
+        adif: Some(AdifConfig {
+            path: "/var/lib/puskapupu/spots.adi".into(),
+        }),
+        jsonl: Some(JsonlConfig {
+            path: "/var/lib/puskapupu/spots.jsonl".into(),
+        }),
+        csv: Some(CsvConfig {
+            path: "/var/lib/puskapupu/spots.csv".into(),
+            rotate_daily: false,
+        }),
+        lookup: Some(LookupConfig {
+            sota: true,
+            pota: true,
+            wwff_path: Some("/var/lib/puskapupu/wwff_directory.csv".into()),
+            cache_secs: DEFAULT_LOOKUP_CACHE_SECS,
+            timeout_secs: DEFAULT_LOOKUP_TIMEOUT_SECS,
+        }),
+        http: Some(HttpConfig {
+            listen: ([127, 0, 0, 1], 8080).into(),
+            live_backlog: DEFAULT_LIVE_BACKLOG,
+            map_minutes: DEFAULT_MAP_MINUTES,
+            map_region: None,
+        }),
+        watchdog: Some(WatchdogConfig {
+            stall_secs: DEFAULT_STALL_SECS,
+            active_hours: Some(QuietHours {
+                start: "05:00".to_string().try_into().expect("valid time"),
+                end: "20:00".to_string().try_into().expect("valid time"),
+            }),
+        }),
+        dead_letter: Some(DeadLetterConfig {
+            path: "/var/lib/puskapupu/dead_letter.log".into(),
+            max_bytes: DEFAULT_DEAD_LETTER_MAX_BYTES,
+        }),
+        secrets_path: None,
+    }
+}
+
+/// [example_config] as commented TOML.
+pub fn example_toml() -> String {
+    let toml = toml::to_string_pretty(&example_config()).expect("example config serializes");
+
+    let mut out = String::from("# Example puskapupu config\n\n");
+    let mut table = String::new();
+    for line in toml.lines() {
+        let path = if let Some(header) = line.strip_prefix('[') {
+            table = header.trim_matches(|c| c == '[' || c == ']').to_string();
+            Some(table.clone())
+        } else {
+            key(line).map(|key| match table.as_str() {
+                "" => key.to_string(),
+                table => format!("{table}.{key}"),
+            })
+        };
+        let comment = path.and_then(|path| COMMENTS.iter().find(|(p, _)| *p == path));
+        if let Some((_, comment)) = comment {
+            if !comment.is_empty() {
+                out.push_str(&format!("# {comment}\n"));
+            }
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Key of `key = value` line.
+fn key(line: &str) -> Option<&str> {
+    let (key, _) = line.split_once(" = ")?;
+    key.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        .then_some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{example_config, example_toml, key, COMMENTS};
+    use crate::config::Config;
+
+    #[test]
+    fn test_example_config_parses() {
+        let example = example_toml();
+        let config: Config = toml::from_str(&example).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config, example_config());
+
+        // Every setting is explained
+        let mut table = "";
+        for line in example.lines() {
+            if let Some(header) = line.strip_prefix('[') {
+                table = header.trim_matches(|c| c == '[' || c == ']');
+                assert!(COMMENTS.iter().any(|(p, _)| *p == table), "{table}");
+            } else if let Some(key) = key(line) {
+                let path = match table {
+                    "" => key.to_string(),
+                    table => format!("{table}.{key}"),
+                };
+                assert!(COMMENTS.iter().any(|(p, _)| *p == path), "{path}");
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Recent spots as an RSS feed and a plain web page, for feed readers and
+//! browsers without JavaScript. Served by the HTTP server from the store
+//! as `/spots.rss` and `/spots.html`.
+
+use std::fmt::Write;
+#[cfg(feature = "sqlite")]
+use std::io;
+#[cfg(feature = "sqlite")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "sqlite")]
+use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "sqlite")]
+use tokio::time::Instant;
+
+#[cfg(feature = "sqlite")]
+use crate::config::StoreConfig;
+use crate::parser::DxEntry;
+#[cfg(feature = "sqlite")]
+use crate::store::{self, SpotQuery};
+use crate::utc;
+
+/// How many of the most recent spots are in the feed.
+pub const FEED_LEN: usize = 50;
+/// How long a rendered feed is served before asking the store again.
+#[cfg(feature = "sqlite")]
+const CACHE_FOR: Duration = Duration::from_secs(60);
+const TITLE: &str = "puskapupu spots";
+
+/// RSS 2.0 feed of spots and when each was received, most recent first.
+pub fn rss<'a>(spots: impl IntoIterator<Item = (SystemTime, &'a DxEntry)>) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<rss version=\"2.0\">\n<channel>\n");
+    let _ = writeln!(out, "<title>{TITLE}</title>");
+    let _ = writeln!(out, "<link>{}</link>", escape(env!("CARGO_PKG_REPOSITORY")));
+    out.push_str("<description>Spots forwarded from DX clusters</description>\n");
+    for (received, spot) in spots {
+        let secs = received
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let references = spot.references().join(" ");
+        let title = format!("{} {} {references}", spot.dx, spot.frequency_mhz_string());
+        let description = format!(
+            "{} de {} {}Z: {}",
+            band_frequency(spot),
+            spot.reporter,
+            spot.timestamp,
+            spot.info
+        );
+        out.push_str("<item>\n");
+        let _ = writeln!(out, "<title>{}</title>", escape(title.trim()));
+        let _ = writeln!(out, "<description>{}</description>", escape(&description));
+        if let Some((activity, _)) = spot.cqgma_identifier {
+            let _ = writeln!(out, "<category>{}</category>", activity.name());
+        }
+        let _ = writeln!(
+            out,
+            "<guid isPermaLink=\"false\">{secs}-{}-{}</guid>",
+            escape(&spot.dx),
+            spot.frequency
+        );
+        let _ = writeln!(out, "<pubDate>{}</pubDate>", utc::rfc5322(received));
+        out.push_str("</item>\n");
+    }
+    out.push_str("</channel>\n</rss>\n");
+    out
+}
+
+/// Web page with a table of spots and when each was received, most recent
+/// first.
+pub fn html<'a>(spots: impl IntoIterator<Item = (SystemTime, &'a DxEntry)>) -> String {
+    let mut out = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(out, "<title>{TITLE}</title>");
+    out.push_str("<link rel=\"alternate\" type=\"application/rss+xml\" href=\"spots.rss\">\n");
+    out.push_str("</head>\n<body>\n");
+    let _ = writeln!(out, "<h1>{TITLE}</h1>");
+    out.push_str("<table>\n<tr><th>Time</th><th>Callsign</th><th>Frequency</th>");
+    out.push_str("<th>Reference</th><th>Info</th><th>Spotter</th></tr>\n");
+    for (_, spot) in spots {
+        let cells = [
+            format!("{}Z", spot.timestamp),
+            spot.dx.clone(),
+            band_frequency(spot),
+            spot.references().join(" "),
+            spot.info.clone(),
+            spot.reporter.clone(),
+        ];
+        out.push_str("<tr>");
+        for cell in cells {
+            let _ = write!(out, "<td>{}</td>", escape(&cell));
+        }
+        out.push_str("</tr>\n");
+    }
+    out.push_str("</table>\n</body>\n</html>\n");
+    out
+}
+
+/// eg. `3.644 MHz (80m)`
+fn band_frequency(spot: &DxEntry) -> String {
+    match spot.band() {
+        Some(band) => format!("{} ({})", spot.frequency_mhz_string(), band.name()),
+        None => spot.frequency_mhz_string(),
+    }
+}
+
+/// `s` as XML or HTML text or attribute value. Control characters XML
+/// doesn't allow are dropped. Also used for XMPP stanzas and SVG maps.
+pub(crate) fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\'' => out.push_str("&apos;"),
+            '"' => out.push_str("&quot;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c.is_control() => (),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// [rss] and [html] of the same spots.
+#[cfg(feature = "sqlite")]
+pub struct Rendered {
+    pub rss: String,
+    pub html: String,
+}
+
+/// Renders the latest spots of the store, remembering the result for a
+/// while so busy readers don't keep the store busy.
+#[cfg(feature = "sqlite")]
+pub struct Feed {
+    store: StoreConfig,
+    cached: Mutex<Option<(Instant, Arc<Rendered>)>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Feed {
+    pub fn new(store: StoreConfig) -> Self {
+        Self {
+            store,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub async fn rendered(&self) -> io::Result<Arc<Rendered>> {
+        if let Some((at, rendered)) = &*self.cached.lock().expect("feed lock") {
+            if at.elapsed() < CACHE_FOR {
+                return Ok(rendered.clone());
+            }
+        }
+        let query = SpotQuery {
+            limit: Some(FEED_LEN),
+            ..SpotQuery::default()
+        };
+        let spots = store::query_received(self.store.clone(), query).await?;
+        let spots = || spots.iter().map(|(received, spot)| (*received, spot));
+        let rendered = Arc::new(Rendered {
+            rss: rss(spots()),
+            html: html(spots()),
+        });
+        *self.cached.lock().expect("feed lock") = Some((Instant::now(), rendered.clone()));
+        Ok(rendered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{escape, html, rss};
+    use crate::parser::DxEntry;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape("<a href=\"x\">R&R's</a>\u{7}\n"),
+            "&lt;a href=&quot;x&quot;&gt;R&amp;R&apos;s&lt;/a&gt;\n"
+        );
+    }
+
+    /// Whether `xml` is well-formed enough for feed readers: tags nest and
+    /// close, and `&` only starts an entity. Returns the text of each
+    /// element by its path, eg. `rss/channel/item/title`.
+    fn parse(xml: &str) -> Result<Vec<(String, String)>, String> {
+        let mut stack: Vec<&str> = Vec::new();
+        let mut texts = Vec::new();
+        let mut rest = xml
+            .strip_prefix("<?xml version=\"1.0\" encoding=\"UTF-8\"?>")
+            .ok_or("no XML declaration")?;
+        while let Some(start) = rest.find('<') {
+            let text = &rest[..start];
+            for (i, _) in text.match_indices('&') {
+                let entity = &text[i..text[i..].find(';').map_or(i, |end| i + end + 1)];
+                if !["&amp;", "&lt;", "&gt;", "&quot;", "&apos;"].contains(&entity) {
+                    return Err(format!("bad entity in {text:?}"));
+                }
+            }
+            if !text.trim().is_empty() {
+                texts.push((stack.join("/"), text.to_string()));
+            }
+            let end = rest[start..].find('>').ok_or("unclosed tag")? + start;
+            let tag = &rest[start + 1..end];
+            if let Some(name) = tag.strip_prefix('/') {
+                if stack.pop() != Some(name) {
+                    return Err(format!("mismatched </{name}>"));
+                }
+            } else if !tag.ends_with('/') {
+                stack.push(tag.split(' ').next().unwrap_or_default());
+            }
+            rest = &rest[end + 1..];
+        }
+        if !stack.is_empty() || !rest.trim().is_empty() {
+            return Err(format!("unclosed {stack:?}"));
+        }
+        Ok(texts)
+    }
+
+    fn spots() -> Vec<DxEntry> {
+        [
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+            "DX de HB9BIN:    14044.0  HB9BIN/P     x04s HB/BL-001 <QRT> & QSY     1049Z",
+        ]
+        .iter()
+        .map(|line| line.parse().unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn test_rss() {
+        // 2024-03-01 12:00 UTC
+        let received = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        let spots = spots();
+        let feed = rss(spots.iter().map(|spot| (received, spot)));
+        let texts = parse(&feed).unwrap();
+        let items: Vec<&str> = texts
+            .iter()
+            .filter(|(path, _)| path == "rss/channel/item/title")
+            .map(|(_, text)| text.as_str())
+            .collect();
+        assert_eq!(
+            items,
+            [
+                "OH2NOS/P 3.644 MHz OHFF-1419",
+                "HB9BIN/P 14.044 MHz HB/BL-001"
+            ]
+        );
+        let text = |path: &str| {
+            texts
+                .iter()
+                .find(|(p, _)| p == path)
+                .map(|(_, text)| text.as_str())
+        };
+        assert_eq!(
+            text("rss/channel/item/description"),
+            Some("3.644 MHz (80m) de OH2NOS 1146Z: OHFF-1419 New one!")
+        );
+        assert_eq!(text("rss/channel/item/category"), Some("wwff"));
+        assert_eq!(
+            text("rss/channel/item/pubDate"),
+            Some("Fri, 01 Mar 2024 12:00:00 +0000")
+        );
+        assert!(feed.contains("<description>14.044 MHz (20m) de HB9BIN 1049Z: HB/BL-001 &lt;QRT&gt; &amp; QSY</description>"));
+    }
+
+    #[test]
+    fn test_html() {
+        let spots = spots();
+        let page = html(spots.iter().map(|spot| (UNIX_EPOCH, spot)));
+        assert!(page.contains(
+            "<tr><td>1146Z</td><td>OH2NOS/P</td><td>3.644 MHz (80m)</td>\
+             <td>OHFF-1419</td><td>OHFF-1419 New one!</td><td>OH2NOS</td></tr>"
+        ));
+        assert!(page.contains("<td>HB/BL-001 &lt;QRT&gt; &amp; QSY</td>"));
+    }
+}
@@ -0,0 +1,306 @@
+//! Which spots are interesting.
+
+use std::fmt;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::band::Band;
+use crate::parser::{Activity, DxEntry};
+use crate::utc::{minute_of_day, MINUTES_PER_DAY};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Spots reported by stations with these callsign prefixes. A prefix not
+    /// ending in a digit must be followed by one, so `OH` matches `OH2NOS`
+    /// but not `OHFF`.
+    pub spotter_prefixes: Vec<String>,
+    /// Spots mentioning any of these references, eg. `OHFF-` or `OH-`.
+    /// Either a spotter prefix or a reference must match, unless both lists
+    /// are empty.
+    pub references: Vec<String>,
+    /// Only spots on these bands. Empty means all bands.
+    pub bands: Vec<Band>,
+    /// Only spots of these activities. Empty means all activities.
+    pub activities: Vec<Activity>,
+    /// Only spots mentioning one of these modes, eg. `CW` or `FT8`. Empty
+    /// means all modes.
+    pub modes: Vec<String>,
+    /// Only spots within one of these frequency ranges. Empty means all
+    /// frequencies.
+    pub frequencies: Vec<FrequencyRange>,
+    /// Drop spots of the same activation seen within this many seconds
+    pub dedup_window_secs: Option<u64>,
+    /// Drop spots older than this many seconds
+    pub max_age_secs: Option<u64>,
+    /// Never spots of these activities, whatever `activities` says. Set
+    /// from the activities turned off in `[activities]`.
+    #[serde(skip)]
+    pub excluded_activities: Vec<Activity>,
+}
+
+/// Spots from Finnish stations and of Finnish WWFF and POTA references.
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            spotter_prefixes: vec!["OH".to_string(), "OG".to_string()],
+            references: vec!["OHFF-".to_string(), "OH-".to_string()],
+            bands: Vec::new(),
+            activities: Vec::new(),
+            modes: Vec::new(),
+            frequencies: Vec::new(),
+            dedup_window_secs: None,
+            max_age_secs: None,
+            excluded_activities: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct FrequencyRange {
+    pub min_khz: f32,
+    pub max_khz: f32,
+}
+
+impl FrequencyRange {
+    pub fn contains(&self, khz: f32) -> bool {
+        self.min_khz <= khz && khz <= self.max_khz
+    }
+}
+
+impl FilterConfig {
+    /// Filter letting every spot through.
+    pub fn all() -> Self {
+        Self {
+            spotter_prefixes: Vec::new(),
+            references: Vec::new(),
+            ..Self::default()
+        }
+    }
+
+    /// Cheap check on the raw cluster line before parsing it.
+    pub fn matches_line(&self, line: &str) -> bool {
+        let line = line.to_lowercase();
+        let Some(rest) = line.strip_prefix("dx de ") else {
+            return false;
+        };
+        if self.spotter_prefixes.is_empty() && self.references.is_empty() {
+            return true;
+        }
+
+        let spotter = rest.split(':').next().unwrap_or_default().trim();
+        self.spotter_prefixes
+            .iter()
+            .any(|prefix| prefix_matches(spotter, &prefix.to_lowercase()))
+            || self
+                .references
+                .iter()
+                .any(|reference| line.contains(&reference.to_lowercase()))
+    }
+
+    pub fn matches(&self, entry: &DxEntry, now: SystemTime) -> bool {
+        if !self.bands.is_empty() {
+            match entry.band() {
+                Some(band) if self.bands.contains(&band) => (),
+                _ => return false,
+            }
+        }
+        if let Some((activity, _)) = &entry.cqgma_identifier {
+            if self.excluded_activities.contains(activity) {
+                return false;
+            }
+        }
+        if !self.activities.is_empty() {
+            match &entry.cqgma_identifier {
+                Some((activity, _)) if self.activities.contains(activity) => (),
+                _ => return false,
+            }
+        }
+        if !self.modes.is_empty() {
+            let mut words = entry.info.split(|c: char| !c.is_ascii_alphanumeric());
+            if !words.any(|word| {
+                self.modes
+                    .iter()
+                    .any(|mode| mode.eq_ignore_ascii_case(word))
+            }) {
+                return false;
+            }
+        }
+        if !self.frequencies.is_empty()
+            && !self.frequencies.iter().any(|r| r.contains(entry.frequency))
+        {
+            return false;
+        }
+        if let (Some(max_age), Some(spotted)) = (self.max_age_secs, entry.minute_of_day()) {
+            // Spots only have time of day, so anything "in the future" is
+            // from yesterday.
+            let age = (minute_of_day(now) + MINUTES_PER_DAY - spotted) % MINUTES_PER_DAY;
+            if u64::from(age) * 60 > max_age {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn prefix_matches(callsign: &str, prefix: &str) -> bool {
+    let Some(rest) = callsign.strip_prefix(prefix) else {
+        return false;
+    };
+    prefix.ends_with(|c: char| c.is_ascii_digit()) || rest.starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Compact summary for humans.
+impl fmt::Display for FilterConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("bands: ")?;
+        if self.bands.is_empty() {
+            f.write_str("all")?;
+        } else {
+            let bands: Vec<&str> = self.bands.iter().map(Band::name).collect();
+            f.write_str(&bands.join(","))?;
+        }
+        if !self.activities.is_empty() {
+            let activities: Vec<&str> = self.activities.iter().map(Activity::name).collect();
+            write!(f, ", activities: {}", activities.join(","))?;
+        }
+        if !self.excluded_activities.is_empty() {
+            let excluded: Vec<&str> = self
+                .excluded_activities
+                .iter()
+                .map(Activity::name)
+                .collect();
+            write!(f, ", never: {}", excluded.join(","))?;
+        }
+        if !self.modes.is_empty() {
+            write!(f, ", modes: {}", self.modes.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{FilterConfig, FrequencyRange};
+    use crate::band::Band;
+    use crate::parser::{Activity, DxEntry};
+
+    fn entry() -> DxEntry {
+        "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 CW New one!     1146Z"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_band_filter() {
+        let entry = entry();
+        let now = SystemTime::now();
+
+        let mut filter = FilterConfig::default();
+        assert!(filter.matches(&entry, now));
+        assert_eq!(filter.to_string(), "bands: all");
+
+        filter.bands = vec![Band::B40m, Band::B20m];
+        assert!(!filter.matches(&entry, now));
+        assert_eq!(filter.to_string(), "bands: 40m,20m");
+
+        filter.bands.push(Band::B80m);
+        assert!(filter.matches(&entry, now));
+    }
+
+    #[test]
+    fn test_excluded_activities() {
+        let entry = entry();
+        let sota: DxEntry =
+            "DX de OH8HUB:    14285.0  OH2NOS/P     x04s OH/KI-001 SSB             1150Z"
+                .parse()
+                .unwrap();
+        let plain: DxEntry =
+            "DX de OH8HUB:    14025.0  JA1ABC       cq cq                          1150Z"
+                .parse()
+                .unwrap();
+        let now = SystemTime::now();
+
+        let mut filter = FilterConfig {
+            excluded_activities: vec![Activity::Wwff],
+            ..FilterConfig::default()
+        };
+        assert!(!filter.matches(&entry, now));
+        assert!(filter.matches(&sota, now));
+        // Spots of no activity aren't excluded
+        assert!(filter.matches(&plain, now));
+        assert_eq!(filter.to_string(), "bands: all, never: wwff");
+
+        // Even if listed in the activities
+        filter.activities = vec![Activity::Wwff, Activity::Sota];
+        assert!(!filter.matches(&entry, now));
+        assert!(filter.matches(&sota, now));
+    }
+
+    #[test]
+    fn test_spot_filter() {
+        let entry = entry();
+        // 11:50 UTC
+        let now = UNIX_EPOCH + Duration::from_secs((11 * 60 + 50) * 60);
+
+        let mut filter = FilterConfig {
+            activities: vec![Activity::Sota],
+            ..FilterConfig::default()
+        };
+        assert!(!filter.matches(&entry, now));
+        filter.activities.push(Activity::Wwff);
+        assert!(filter.matches(&entry, now));
+
+        filter.modes = vec!["ssb".to_string()];
+        assert!(!filter.matches(&entry, now));
+        filter.modes.push("cw".to_string());
+        assert!(filter.matches(&entry, now));
+        assert_eq!(
+            filter.to_string(),
+            "bands: all, activities: sota,wwff, modes: ssb,cw"
+        );
+
+        filter.frequencies = vec![FrequencyRange {
+            min_khz: 7000.0,
+            max_khz: 7040.0,
+        }];
+        assert!(!filter.matches(&entry, now));
+        filter.frequencies[0].min_khz = 3500.0;
+        assert!(filter.matches(&entry, now));
+
+        filter.max_age_secs = Some(3 * 60);
+        assert!(!filter.matches(&entry, now));
+        filter.max_age_secs = Some(4 * 60);
+        assert!(filter.matches(&entry, now));
+    }
+
+    #[test]
+    fn test_matches_line() {
+        let filter = FilterConfig::default();
+        assert!(filter.matches_line(
+            "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z"
+        ));
+        assert!(!filter.matches_line(
+            "DX de OHFF:      14310.0  AD6VT        x04s W6/ND-101                 1959Z"
+        ));
+        assert!(filter.matches_line(
+            "DX de AD6VT:      7044.0  OH2NOS/P     x01f OHFF-1419                 1959Z"
+        ));
+        assert!(!filter.matches_line("OH8HUB de OH2NOS"));
+
+        let filter = FilterConfig {
+            spotter_prefixes: vec!["OH2".to_string()],
+            references: Vec::new(),
+            ..FilterConfig::default()
+        };
+        assert!(filter.matches_line(
+            "DX de OH2NOS:    14310.0  AD6VT        x04s W6/ND-101                 1959Z"
+        ));
+        assert!(!filter.matches_line(
+            "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z"
+        ));
+    }
+}
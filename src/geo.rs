@@ -0,0 +1,164 @@
+//! Maidenhead locators and great circle math.
+
+/// Mean radius of Earth in kilometers.
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Each pair of a locator divides the square of the previous pair into this
+/// many parts: fields `A`-`R`, squares `0`-`9`, subsquares `A`-`X` and
+/// extended squares `0`-`9`.
+const DIVISIONS: [u8; 4] = [18, 10, 24, 10];
+
+/// Convert 4, 6 or 8 character Maidenhead locator into (latitude,
+/// longitude) of the center of the square.
+pub fn grid_to_latlon(grid: &str) -> Option<(f64, f64)> {
+    let grid = grid.as_bytes();
+    if !matches!(grid.len(), 4 | 6 | 8) {
+        return None;
+    }
+
+    let (mut lat, mut lon) = (-90.0, -180.0);
+    let (mut height, mut width) = (180.0, 360.0);
+    for (pair, divisions) in grid.chunks(2).zip(DIVISIONS) {
+        let digit = |c: u8| {
+            let value = match c.to_ascii_uppercase() {
+                c @ b'A'..=b'Z' if divisions != 10 => c - b'A',
+                c @ b'0'..=b'9' if divisions == 10 => c - b'0',
+                _ => return None,
+            };
+            (value < divisions).then(|| f64::from(value))
+        };
+        width /= f64::from(divisions);
+        height /= f64::from(divisions);
+        lon += digit(pair[0])? * width;
+        lat += digit(pair[1])? * height;
+    }
+
+    Some((lat + height / 2.0, lon + width / 2.0))
+}
+
+/// Locator of `len` characters (2, 4, 6 or 8) for the square containing
+/// `lat` and `lon`, eg. `KP20le`.
+pub fn latlon_to_grid(lat: f64, lon: f64, len: usize) -> Option<String> {
+    if !matches!(len, 2 | 4 | 6 | 8) || !(-90.0..=90.0).contains(&lat) {
+        return None;
+    }
+    if !(-180.0..=180.0).contains(&lon) {
+        return None;
+    }
+
+    // Fractions of the way around, kept below 1 so the poles and the
+    // antimeridian stay in the last square
+    let mut lat = ((lat + 90.0) / 180.0).min(1.0 - f64::EPSILON);
+    let mut lon = ((lon + 180.0) / 360.0).min(1.0 - f64::EPSILON);
+    let mut grid = String::with_capacity(len);
+    for (i, divisions) in DIVISIONS.into_iter().take(len / 2).enumerate() {
+        let digit = |fraction: &mut f64| {
+            let scaled = *fraction * f64::from(divisions);
+            let value = (scaled.floor() as u8).min(divisions - 1);
+            *fraction = scaled - f64::from(value);
+            match i {
+                0 => b'A' + value,
+                2 => b'a' + value,
+                _ => b'0' + value,
+            }
+        };
+        grid.push(char::from(digit(&mut lon)));
+        grid.push(char::from(digit(&mut lat)));
+    }
+    Some(grid)
+}
+
+/// Great circle distance between two points using haversine formula.
+pub fn distance_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (a.0.to_radians(), b.0.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.1 - a.1).to_radians();
+
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+/// Initial bearing in degrees [0, 360) when heading from `a` to `b`.
+pub fn bearing_deg(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (a.0.to_radians(), b.0.to_radians());
+    let dlon = (b.1 - a.1).to_radians();
+
+    let y = dlon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
+
+/// Eight point compass direction of the bearing.
+pub fn compass_point(bearing: f64) -> &'static str {
+    const POINTS: [&str; 8] = ["N", "NE", "E", "SE", "S", "SW", "W", "NW"];
+    let idx = ((bearing.rem_euclid(360.0) + 22.5) / 45.0) as usize % POINTS.len();
+    POINTS[idx]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bearing_deg, compass_point, distance_km, grid_to_latlon, latlon_to_grid};
+
+    const HELSINKI: (f64, f64) = (60.1699, 24.9384);
+    const LONDON: (f64, f64) = (51.5074, -0.1278);
+    const NEW_YORK: (f64, f64) = (40.7128, -74.0060);
+    const SYDNEY: (f64, f64) = (-33.8688, 151.2093);
+
+    #[test]
+    fn test_distance_km() {
+        let close = |km: f64, expected: f64| (km - expected).abs() < expected * 0.005;
+        assert!(close(distance_km(HELSINKI, LONDON), 1_820.0));
+        assert!(close(distance_km(LONDON, NEW_YORK), 5_570.0));
+        assert!(close(distance_km(NEW_YORK, SYDNEY), 15_990.0));
+        assert_eq!(distance_km(HELSINKI, HELSINKI), 0.0);
+        assert_eq!(distance_km(LONDON, NEW_YORK), distance_km(NEW_YORK, LONDON));
+        // Half way around
+        assert!(close(distance_km((0.0, 0.0), (0.0, 180.0)), 20_015.0));
+    }
+
+    #[test]
+    fn test_bearing_deg() {
+        assert!((bearing_deg(LONDON, NEW_YORK) - 288.3).abs() < 0.5);
+        assert!((bearing_deg(HELSINKI, LONDON) - 249.3).abs() < 0.5);
+        assert_eq!(bearing_deg((0.0, 0.0), (10.0, 0.0)), 0.0);
+        assert_eq!(bearing_deg((0.0, 0.0), (0.0, -10.0)), 270.0);
+
+        assert_eq!(compass_point(0.0), "N");
+        assert_eq!(compass_point(22.4), "N");
+        assert_eq!(compass_point(22.5), "NE");
+        assert_eq!(compass_point(180.0), "S");
+        assert_eq!(compass_point(288.3), "W");
+        assert_eq!(compass_point(350.0), "N");
+        assert_eq!(compass_point(-45.0), "NW");
+    }
+
+    #[test]
+    fn test_grid_to_latlon() {
+        assert_eq!(grid_to_latlon("JO10"), Some((50.5, 3.0)));
+        assert_eq!(grid_to_latlon("FN43"), Some((43.5, -71.0)));
+        let (lat, lon) = grid_to_latlon("KP20le").unwrap();
+        assert!((lat - 60.1875).abs() < 1e-9 && (lon - 24.958_333).abs() < 1e-6);
+        let (lat, lon) = grid_to_latlon("kp20le20").unwrap();
+        assert!((lat - 60.168_75).abs() < 1e-9 && (lon - 24.937_5).abs() < 1e-9);
+
+        for grid in ["JO1", "JO10l", "SA00", "JOAA", "JO10yy", "JO10le2a", ""] {
+            assert_eq!(grid_to_latlon(grid), None, "{grid}");
+        }
+    }
+
+    #[test]
+    fn test_latlon_to_grid() {
+        // Helsinki
+        assert_eq!(latlon_to_grid(60.17, 24.94, 6).as_deref(), Some("KP20le"));
+        assert_eq!(latlon_to_grid(60.17, 24.94, 8).as_deref(), Some("KP20le20"));
+        assert_eq!(latlon_to_grid(-90.0, -180.0, 4).as_deref(), Some("AA00"));
+        assert_eq!(latlon_to_grid(90.0, 180.0, 4).as_deref(), Some("RR99"));
+        assert_eq!(latlon_to_grid(91.0, 0.0, 4), None);
+        assert_eq!(latlon_to_grid(0.0, 0.0, 5), None);
+
+        for grid in ["JO10", "FN43", "KP20le", "PM95vr", "FN31pr42"] {
+            let (lat, lon) = grid_to_latlon(grid).unwrap();
+            assert_eq!(latlon_to_grid(lat, lon, grid.len()).as_deref(), Some(grid));
+        }
+    }
+}
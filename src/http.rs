@@ -0,0 +1,187 @@
+//! Optional HTTP server for health checks, metrics and recent spots.
+
+use std::io;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[cfg(feature = "sqlite")]
+use axum::extract::Query;
+use axum::extract::State;
+#[cfg(feature = "sqlite")]
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+#[cfg(feature = "sqlite")]
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+#[cfg(feature = "sqlite")]
+use axum::Json;
+use axum::Router;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "sqlite")]
+use crate::adif;
+use crate::config::HttpConfig;
+#[cfg(feature = "sqlite")]
+use crate::config::StoreConfig;
+#[cfg(feature = "sqlite")]
+use crate::feed::{Feed, Rendered};
+#[cfg(feature = "sqlite")]
+use crate::parser::DxEntry;
+use crate::status::Status;
+#[cfg(feature = "sqlite")]
+use crate::store::{self, SpotQuery};
+
+pub fn router(status: Arc<Status>) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .with_state(status)
+}
+
+/// `/spots` returning recent spots from the store as JSON, and `/spots.adi`
+/// as ADIF. Query parameters are the fields of [SpotQuery], eg.
+/// `/spots?band=20m&activity=wwff`. The latest spots are also in the
+/// [feed](crate::feed) `/spots.rss` and the page `/spots.html`.
+#[cfg(feature = "sqlite")]
+pub fn spots_router(store: StoreConfig) -> Router {
+    let feed = Router::new()
+        .route("/spots.rss", get(spots_rss))
+        .route("/spots.html", get(spots_html))
+        .with_state(Arc::new(Feed::new(store.clone())));
+    Router::new()
+        .route("/spots", get(spots))
+        .route("/spots.adi", get(spots_adif))
+        .with_state(Arc::new(store))
+        .merge(feed)
+}
+
+/// Serve `app` until `shutdown` is cancelled.
+pub async fn serve(config: HttpConfig, app: Router, shutdown: CancellationToken) -> io::Result<()> {
+    let listener = TcpListener::bind(config.listen).await?;
+    tracing::info!("HTTP server listening on {}", config.listen);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await
+}
+
+/// The process is alive.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Spots are flowing from a cluster to Matrix.
+async fn readyz(State(status): State<Arc<Status>>) -> (StatusCode, &'static str) {
+    if status.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// Counters in Prometheus text format.
+async fn metrics(State(status): State<Arc<Status>>) -> String {
+    status.metrics.render(SystemTime::now())
+}
+
+#[cfg(feature = "sqlite")]
+async fn spots(
+    State(store): State<Arc<StoreConfig>>,
+    Query(query): Query<SpotQuery>,
+) -> Result<Json<Vec<DxEntry>>, (StatusCode, String)> {
+    match store::query(StoreConfig::clone(&store), query).await {
+        Ok(entries) => Ok(Json(entries)),
+        Err(err) => {
+            tracing::warn!("Couldn't query spots: {err}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+async fn spots_adif(
+    State(store): State<Arc<StoreConfig>>,
+    Query(query): Query<SpotQuery>,
+) -> Result<String, (StatusCode, String)> {
+    adif::export_store(StoreConfig::clone(&store), query)
+        .await
+        .map_err(|err| {
+            tracing::warn!("Couldn't export spots: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        })
+}
+
+#[cfg(feature = "sqlite")]
+async fn spots_rss(State(feed): State<Arc<Feed>>) -> Response {
+    rendered(&feed, "application/rss+xml; charset=utf-8", |r| &r.rss).await
+}
+
+#[cfg(feature = "sqlite")]
+async fn spots_html(State(feed): State<Arc<Feed>>) -> Response {
+    rendered(&feed, "text/html; charset=utf-8", |r| &r.html).await
+}
+
+#[cfg(feature = "sqlite")]
+async fn rendered(
+    feed: &Feed,
+    content_type: &'static str,
+    part: fn(&Rendered) -> &String,
+) -> Response {
+    match feed.rendered().await {
+        Ok(rendered) => ([(CONTENT_TYPE, content_type)], part(&rendered).clone()).into_response(),
+        Err(err) => {
+            tracing::warn!("Couldn't render feed: {err}");
+            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::router;
+    use crate::status::Status;
+
+    async fn get(status: &Arc<Status>, uri: &str) -> StatusCode {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        router(status.clone())
+            .oneshot(request)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let status = Arc::new(Status::new(1, 1));
+        assert_eq!(get(&status, "/healthz").await, StatusCode::OK);
+        assert_eq!(
+            get(&status, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        status.clusters[0].store(true, Ordering::Relaxed);
+        assert_eq!(
+            get(&status, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        status.matrix[0].store(true, Ordering::Relaxed);
+        assert_eq!(get(&status, "/readyz").await, StatusCode::OK);
+
+        status.clusters[0].store(false, Ordering::Relaxed);
+        assert_eq!(
+            get(&status, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(get(&status, "/healthz").await, StatusCode::OK);
+        assert_eq!(get(&status, "/metrics").await, StatusCode::OK);
+        assert_eq!(get(&status, "/nope").await, StatusCode::NOT_FOUND);
+    }
+}
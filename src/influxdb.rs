@@ -0,0 +1,399 @@
+//! Writing spots to InfluxDB 2 in line protocol, for time-series dashboards.
+//!
+//! Each spot is a point of measurement `spots` tagged with its band,
+//! activity and mode, eg.
+//! `spots,band=20m,activity=wwff,mode=CW frequency=14062,count=1i 1709294400`.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, StatusCode, Url};
+use tokio::sync::{broadcast, Mutex, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::config::InfluxdbConfig;
+use crate::metrics::{Metrics, SinkHealth};
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+
+/// Time for one write request.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Spots kept while InfluxDB is unreachable, in batches. The oldest are
+/// dropped when more arrive.
+const MAX_PENDING_BATCHES: usize = 10;
+
+/// Point of `spot` received at `time`, without a newline.
+pub fn line(spot: &DxEntry, time: SystemTime) -> String {
+    let mut line = "spots".to_string();
+    let tags = [
+        ("band", spot.band().map(|band| band.name())),
+        (
+            "activity",
+            spot.cqgma_identifier.map(|(activity, _)| activity.name()),
+        ),
+        ("mode", spot.mode()),
+    ];
+    for (key, value) in tags {
+        if let Some(value) = value {
+            let _ = write!(line, ",{key}={}", escape_tag(value));
+        }
+    }
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let _ = write!(line, " frequency={},count=1i {secs}", spot.frequency);
+    line
+}
+
+/// Commas, spaces and equal signs in tag values are escaped with `\`.
+fn escape_tag(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | ' ' | '=') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Why a write failed and whether the spots are worth trying again.
+struct Failure {
+    transient: bool,
+    message: String,
+}
+
+/// Lines not yet written.
+#[derive(Default)]
+struct Pending {
+    lines: VecDeque<String>,
+    /// When the oldest of [Pending::lines] arrived
+    since: Option<Instant>,
+}
+
+/// Collects spots into batches, written by [flush_periodically] when full
+/// or after the flush interval. If a write fails the spots are kept for the
+/// next one. The counters of the sink count written spots, not queued ones.
+pub struct InfluxdbSink {
+    name: String,
+    client: Client,
+    /// Write endpoint with org, bucket and precision in the query
+    url: Url,
+    token: HeaderValue,
+    batch_size: usize,
+    flush_interval: Duration,
+    pending: Mutex<Pending>,
+    /// Wakes the flusher when a batch is full
+    flush: Notify,
+    health: Arc<SinkHealth>,
+}
+
+impl InfluxdbSink {
+    pub fn new(config: &InfluxdbConfig, metrics: &Metrics) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut url = Url::parse(&config.url).map_err(|err| invalid(format!("url: {err}")))?;
+        url.path_segments_mut()
+            .map_err(|_| invalid("url: can't be a base".to_string()))?
+            .pop_if_empty()
+            .extend(["api", "v2", "write"]);
+        url.query_pairs_mut()
+            .append_pair("org", &config.org)
+            .append_pair("bucket", &config.bucket)
+            .append_pair("precision", "s");
+        let mut token = HeaderValue::try_from(format!("Token {}", config.token))
+            .map_err(|err| invalid(format!("token: {err}")))?;
+        token.set_sensitive(true);
+        let client = Client::builder()
+            .timeout(WRITE_TIMEOUT)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let name = format!(
+            "influxdb {}/{}",
+            url.host_str().unwrap_or_default(),
+            config.bucket
+        );
+        Ok(Self {
+            health: metrics.sink(&name),
+            name,
+            client,
+            url,
+            token,
+            batch_size: config.batch_size,
+            flush_interval: Duration::from_secs(config.flush_secs),
+            pending: Mutex::default(),
+            flush: Notify::new(),
+        })
+    }
+
+    async fn post(&self, body: String) -> Result<(), Failure> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .header(AUTHORIZATION, self.token.clone())
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body)
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                let text = response.text().await.unwrap_or_default();
+                Err(Failure {
+                    transient: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                    message: format!("HTTP {status} {}", text.trim()),
+                })
+            }
+            Err(err) => Err(Failure {
+                transient: true,
+                message: err.without_url().to_string(),
+            }),
+        }
+    }
+
+    /// Write all pending lines, a batch at a time. Lines InfluxDB refuses
+    /// for good are dropped, others are kept for the next try.
+    async fn write_pending(&self, pending: &mut Pending) -> io::Result<()> {
+        while !pending.lines.is_empty() {
+            let n = pending.lines.len().min(self.batch_size);
+            let body = pending
+                .lines
+                .iter()
+                .take(n)
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join("\n");
+            match self.post(body).await {
+                Ok(()) => {
+                    pending.lines.drain(..n);
+                    self.health.sent.fetch_add(n as u64, Ordering::Relaxed);
+                    self.health.consecutive_failures.store(0, Ordering::Relaxed);
+                }
+                Err(failure) => {
+                    self.health.failed.fetch_add(n as u64, Ordering::Relaxed);
+                    self.health
+                        .consecutive_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                    if !failure.transient {
+                        tracing::error!("InfluxDB refused {n} spots: {}", failure.message);
+                        pending.lines.drain(..n);
+                    }
+                    return Err(io::Error::new(io::ErrorKind::Other, failure.message));
+                }
+            }
+        }
+        pending.since = None;
+        Ok(())
+    }
+
+    /// Write pending lines if a batch is full or the oldest has waited for
+    /// the flush interval.
+    async fn flush_if_due(&self, now: Instant) -> io::Result<()> {
+        let mut pending = self.pending.lock().await;
+        if self.is_due(&pending, now) {
+            self.write_pending(&mut pending).await
+        } else {
+            Ok(())
+        }
+    }
+
+    fn is_due(&self, pending: &Pending, now: Instant) -> bool {
+        pending.lines.len() >= self.batch_size
+            || pending.since.map_or(false, |since| {
+                now.duration_since(since) >= self.flush_interval
+            })
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxdbSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queue `spot` for the flusher. Never fails; failed writes are logged
+    /// and counted by the flusher.
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let line = line(spot, SystemTime::now());
+        let mut pending = self.pending.lock().await;
+        if pending.lines.len() >= self.batch_size * MAX_PENDING_BATCHES {
+            pending.lines.pop_front();
+            tracing::warn!("Too many spots waiting for InfluxDB. Dropped the oldest.");
+        }
+        pending.lines.push_back(line);
+        let now = Instant::now();
+        pending.since.get_or_insert(now);
+        if self.is_due(&pending, now) {
+            self.flush.notify_one();
+        }
+        Ok(())
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        let mut pending = self.pending.lock().await;
+        self.write_pending(&mut pending).await
+    }
+}
+
+/// Write full batches and what has waited long enough, even if no more
+/// spots arrive.
+async fn flush_periodically(sink: Arc<InfluxdbSink>, shutdown: CancellationToken) {
+    let mut interval = tokio::time::interval(sink.flush_interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = sink.flush.notified() => (),
+            _ = shutdown.cancelled() => return,
+        }
+        if let Err(err) = sink.flush_if_due(Instant::now()).await {
+            tracing::warn!("Couldn't write spots to {}: {err}", sink.name);
+        }
+    }
+}
+
+/// Write spots from `spots` to the bucket of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: InfluxdbConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = Arc::new(InfluxdbSink::new(&config, &metrics)?);
+    // Sending only queues, so the sink counts the writes itself
+    let health = Arc::new(SinkHealth::default());
+    let flusher = flush_periodically(sink.clone(), shutdown.clone());
+    let flusher = tokio::spawn(flusher.in_current_span());
+    let result = sink::run(Box::new(sink), spots, health, shutdown).await;
+    flusher.abort();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use axum::extract::{Query, State};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use tokio::net::TcpListener;
+
+    use super::{line, InfluxdbSink};
+    use crate::config::InfluxdbConfig;
+    use crate::metrics::Metrics;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+
+    type Received = Arc<Mutex<Vec<String>>>;
+
+    fn spot(line: &str) -> DxEntry {
+        line.parse().unwrap()
+    }
+
+    #[test]
+    fn test_line() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        let entry =
+            spot("DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 CW            1150Z");
+        assert_eq!(
+            line(&entry, time),
+            "spots,band=20m,activity=wwff,mode=CW frequency=14062,count=1i 1709294400"
+        );
+
+        let mut entry =
+            spot("DX de AD6VT:     14310.5  AD6VT        x04s W6/ND-101              1959Z");
+        entry.cqgma_identifier = None;
+        entry.info = "FT8, or=so".to_string();
+        assert_eq!(
+            line(&entry, time),
+            "spots,band=20m,mode=FT8 frequency=14310.5,count=1i 1709294400"
+        );
+        assert_eq!(super::escape_tag("a b,c=d"), r"a\ b\,c\=d");
+    }
+
+    /// Fails the first write with 503 and records the rest.
+    async fn receive(
+        State(received): State<Received>,
+        Query(query): Query<Vec<(String, String)>>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        assert_eq!(
+            query,
+            [("org", "oh8hub"), ("bucket", "spots"), ("precision", "s")]
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+        assert_eq!(headers["authorization"], "Token hunter2");
+        let mut received = received.lock().unwrap();
+        received.push(body);
+        if received.len() == 1 {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::NO_CONTENT
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches() {
+        let received = Received::default();
+        let app = Router::new()
+            .route("/api/v2/write", post(receive))
+            .with_state(received.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let metrics = Metrics::default();
+        let sink = InfluxdbSink::new(
+            &InfluxdbConfig {
+                url: format!("http://{addr}/"),
+                org: "oh8hub".to_string(),
+                bucket: "spots".to_string(),
+                token: "hunter2".to_string(),
+                batch_size: 2,
+                flush_secs: 60,
+            },
+            &metrics,
+        )
+        .unwrap();
+        let lines = [
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+            "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z",
+            "DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 CW            1150Z",
+        ];
+        sink.send(&spot(lines[0])).await.unwrap();
+        sink.flush_if_due(Instant::now()).await.unwrap();
+        assert!(received.lock().unwrap().is_empty());
+        // Sending only queues. The first write fails, and the spots are
+        // written with the next.
+        sink.send(&spot(lines[1])).await.unwrap();
+        assert!(sink.flush_if_due(Instant::now()).await.is_err());
+        let health = metrics.sink(&sink.name);
+        assert_eq!(health.failed.load(Ordering::Relaxed), 2);
+        assert!(!health.is_up());
+        sink.send(&spot(lines[2])).await.unwrap();
+        sink.close().await.unwrap();
+        assert_eq!(health.sent.load(Ordering::Relaxed), 3);
+        assert!(health.is_up());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3, "{received:?}");
+        assert_eq!(received[0], received[1]);
+        let batch: Vec<&str> = received[1].lines().collect();
+        assert_eq!(batch.len(), 2);
+        assert!(batch[0].starts_with("spots,band=80m,activity=wwff frequency=3644,"));
+        assert!(batch[1].starts_with("spots,band=20m,activity=sota frequency=14310,"));
+        assert!(received[2].starts_with("spots,band=20m,activity=wwff,mode=CW "));
+    }
+}
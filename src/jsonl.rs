@@ -0,0 +1,119 @@
+//! Archive of spots as JSON lines, one file per day.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::JsonlConfig;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::rotate;
+use crate::sink::{self, Sink};
+
+/// Appends each spot as one JSON object per line. The file is rotated with
+/// [rotate::rotate_daily].
+pub struct JsonlSink {
+    name: String,
+    path: PathBuf,
+}
+
+impl JsonlSink {
+    pub fn new(config: &JsonlConfig) -> Self {
+        Self {
+            name: format!("jsonl {}", config.path.display()),
+            path: config.path.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for JsonlSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        if let Some(rotated) = rotate::rotate_daily(&self.path, SystemTime::now())? {
+            tracing::info!("Moved spots of the previous day to {}", rotated.display());
+        }
+        let mut line = serde_json::to_vec(spot)?;
+        line.push(b'\n');
+        // One write, so a failure doesn't leave half a line behind
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)
+    }
+}
+
+/// Append spots from `spots` to the file of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: JsonlConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = JsonlSink::new(&config);
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonlSink;
+    use crate::config::JsonlConfig;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spots.jsonl");
+        let sink = JsonlSink::new(&JsonlConfig { path: path.clone() });
+        let spots: Vec<DxEntry> = [
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+            "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z",
+        ]
+        .iter()
+        .map(|line| line.parse().unwrap())
+        .collect();
+        for spot in &spots {
+            sink.send(spot).await.unwrap();
+        }
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let read: Vec<DxEntry> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(read.len(), spots.len());
+        for (read, spot) in read.iter().zip(&spots) {
+            assert_eq!(
+                serde_json::to_value(read).unwrap(),
+                serde_json::to_value(spot).unwrap()
+            );
+        }
+        assert_eq!(read[1].cqgma_identifier, spots[1].cqgma_identifier);
+    }
+
+    #[tokio::test]
+    async fn test_write_error() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory can't be opened for appending
+        let sink = JsonlSink::new(&JsonlConfig {
+            path: dir.path().to_path_buf(),
+        });
+        let spot = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+            .parse()
+            .unwrap();
+        assert!(sink.send(&spot).await.is_err());
+    }
+}
@@ -0,0 +1,756 @@
+//! Producing spots to a Kafka topic, for data platforms.
+//!
+//! Only the little of the Kafka protocol needed is spoken: metadata for
+//! the leaders of the partitions and produce requests of one record each.
+//! Records are keyed by the callsign of the activator, so spots of an
+//! activator stay in order in one partition, and the value is the spot as
+//! JSON.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Mutex};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::KafkaConfig;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+
+/// Time for producing one spot, including connecting and metadata.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest response accepted from a broker.
+const MAX_RESPONSE_LEN: usize = 1 << 20;
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+
+/// Key and value of the record of `spot`.
+pub fn record(spot: &DxEntry) -> (Vec<u8>, Vec<u8>) {
+    let key = spot.dx.to_uppercase().into_bytes();
+    let value = serde_json::to_vec(spot).expect("spot serializes");
+    (key, value)
+}
+
+/// Partition of `key` like the default partitioner of the Java client, so
+/// other producers put the same callsigns in the same partitions.
+pub fn partition(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+/// MurmurHash2 with the seed of the Java client.
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h ^= u32::from(*byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+/// CRC-32C (Castagnoli) of record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn put_i16(out: &mut Vec<u8>, value: i16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i64(out: &mut Vec<u8>, value: i64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(out: &mut Vec<u8>, value: &str) {
+    put_i16(out, value.len() as i16);
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Zigzag encoded variable length integer of records.
+fn put_varint(out: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Record batch of format version 2 with one uncompressed record.
+fn record_batch(key: &[u8], value: &[u8], timestamp_ms: i64) -> Vec<u8> {
+    let mut record = vec![0]; // attributes
+    put_varint(&mut record, 0); // timestamp delta
+    put_varint(&mut record, 0); // offset delta
+    put_varint(&mut record, key.len() as i64);
+    record.extend_from_slice(key);
+    put_varint(&mut record, value.len() as i64);
+    record.extend_from_slice(value);
+    put_varint(&mut record, 0); // headers
+
+    // Covered by the CRC
+    let mut tail = Vec::new();
+    put_i16(&mut tail, 0); // attributes: no compression
+    put_i32(&mut tail, 0); // last offset delta
+    put_i64(&mut tail, timestamp_ms); // first timestamp
+    put_i64(&mut tail, timestamp_ms); // max timestamp
+    put_i64(&mut tail, -1); // producer id
+    put_i16(&mut tail, -1); // producer epoch
+    put_i32(&mut tail, -1); // base sequence
+    put_i32(&mut tail, 1); // records
+    put_varint(&mut tail, record.len() as i64);
+    tail.extend_from_slice(&record);
+
+    let mut batch = Vec::with_capacity(tail.len() + 21);
+    put_i64(&mut batch, 0); // base offset
+    put_i32(&mut batch, (4 + 1 + 4 + tail.len()) as i32); // length after this
+    put_i32(&mut batch, -1); // partition leader epoch
+    batch.push(2); // magic
+    batch.extend_from_slice(&crc32c(&tail).to_be_bytes());
+    batch.extend_from_slice(&tail);
+    batch
+}
+
+/// Reads fields of a response.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Kafka response ended too early",
+            ));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn i8(&mut self) -> io::Result<i8> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        let bytes = self.take(2)?;
+        Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        let (high, low) = (self.i32()?, self.i32()?);
+        Ok(i64::from(high) << 32 | i64::from(low as u32))
+    }
+
+    /// Length of an array, 0 for null.
+    fn len(&mut self) -> io::Result<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+
+    /// Nullable string, empty for null.
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.i16()?.max(0) as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+fn broker_error(what: &str, code: i16) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("Kafka broker answered {what} with error code {code}"),
+    )
+}
+
+/// What the brokers told of the topic.
+#[derive(Debug, Default)]
+struct Metadata {
+    /// host:port of each broker by node id
+    brokers: HashMap<i32, String>,
+    /// Leader of each partition of the topic, by partition index
+    leaders: Vec<i32>,
+}
+
+/// Metadata response of version 1 about `topic`.
+fn parse_metadata(response: &[u8], topic: &str) -> io::Result<Metadata> {
+    let mut d = Decoder(response);
+    let mut metadata = Metadata::default();
+    for _ in 0..d.len()? {
+        let node = d.i32()?;
+        let host = d.string()?;
+        let port = d.i32()?;
+        let _rack = d.string()?;
+        metadata.brokers.insert(node, format!("{host}:{port}"));
+    }
+    let _controller = d.i32()?;
+    for _ in 0..d.len()? {
+        let error = d.i16()?;
+        let name = d.string()?;
+        let _internal = d.i8()?;
+        let mut leaders = Vec::new();
+        for _ in 0..d.len()? {
+            let _error = d.i16()?;
+            let index = d.i32()?;
+            let leader = d.i32()?;
+            for _ in 0..d.len()? {
+                d.i32()?; // replicas
+            }
+            for _ in 0..d.len()? {
+                d.i32()?; // in-sync replicas
+            }
+            leaders.push((index, leader));
+        }
+        if name != topic {
+            continue;
+        }
+        if error != 0 {
+            return Err(broker_error(&format!("metadata of topic {topic}"), error));
+        }
+        leaders.sort_unstable();
+        metadata.leaders = leaders.into_iter().map(|(_, leader)| leader).collect();
+    }
+    if metadata.leaders.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Kafka topic {topic} has no partitions"),
+        ));
+    }
+    Ok(metadata)
+}
+
+/// Produce response of version 3, which has one partition.
+fn parse_produce(response: &[u8]) -> io::Result<()> {
+    let mut d = Decoder(response);
+    for _ in 0..d.len()? {
+        let _topic = d.string()?;
+        for _ in 0..d.len()? {
+            let _index = d.i32()?;
+            let error = d.i16()?;
+            let _base_offset = d.i64()?;
+            let _log_append_time = d.i64()?;
+            if error != 0 {
+                return Err(broker_error("produce", error));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Connections and what's known of the brokers. Forgotten after errors, so
+/// the next spot reconnects and asks the metadata again.
+#[derive(Default)]
+struct State {
+    correlation_id: i32,
+    metadata: Option<Metadata>,
+    connections: HashMap<i32, TcpStream>,
+}
+
+pub struct KafkaSink {
+    name: String,
+    config: KafkaConfig,
+    state: Mutex<State>,
+}
+
+impl KafkaSink {
+    pub fn new(config: &KafkaConfig) -> Self {
+        Self {
+            name: format!("kafka {}", config.topic),
+            config: config.clone(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Send request `body` on `stream` and read the response after its
+    /// correlation id.
+    async fn call(
+        &self,
+        stream: &mut TcpStream,
+        correlation_id: i32,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let mut request = Vec::with_capacity(body.len() + 64);
+        put_i32(&mut request, 0); // size, filled below
+        put_i16(&mut request, api_key);
+        put_i16(&mut request, api_version);
+        put_i32(&mut request, correlation_id);
+        put_string(&mut request, &self.config.client_id);
+        request.extend_from_slice(body);
+        let size = (request.len() - 4) as i32;
+        request[..4].copy_from_slice(&size.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let size = stream.read_i32().await?;
+        if !(4..=MAX_RESPONSE_LEN as i32).contains(&size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Kafka response of {size} bytes"),
+            ));
+        }
+        let mut response = vec![0; size as usize];
+        stream.read_exact(&mut response).await?;
+        if response[..4] != correlation_id.to_be_bytes() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Kafka response to another request",
+            ));
+        }
+        response.drain(..4);
+        Ok(response)
+    }
+
+    /// Ask the configured brokers in turn for the metadata of the topic.
+    async fn metadata(&self, state: &mut State) -> io::Result<Metadata> {
+        let mut body = Vec::new();
+        put_i32(&mut body, 1);
+        put_string(&mut body, &self.config.topic);
+        let mut last_err = None;
+        for broker in &self.config.brokers {
+            state.correlation_id = state.correlation_id.wrapping_add(1);
+            let id = state.correlation_id;
+            let result = async {
+                let mut stream = TcpStream::connect(broker).await?;
+                let response = self.call(&mut stream, id, API_METADATA, 1, &body).await?;
+                parse_metadata(&response, &self.config.topic)
+            };
+            match result.await {
+                Ok(metadata) => {
+                    tracing::debug!("Metadata of {} from {broker}: {metadata:?}", self.name);
+                    return Ok(metadata);
+                }
+                Err(err) => {
+                    tracing::debug!(
+                        "Couldn't get metadata of {} from {broker}: {err}",
+                        self.name
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no brokers")))
+    }
+
+    /// Produce a record of `key` and `value` to the leader of its partition
+    /// and wait for the acknowledgement.
+    async fn produce(&self, state: &mut State, key: &[u8], value: &[u8]) -> io::Result<()> {
+        if state.metadata.is_none() {
+            state.metadata = Some(self.metadata(state).await?);
+        }
+        let metadata = state.metadata.as_ref().expect("metadata was just set");
+        let partition = partition(key, metadata.leaders.len());
+        let leader = metadata.leaders[partition];
+        let addr = metadata.brokers.get(&leader).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no leader for partition {partition}"),
+            )
+        })?;
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+        let batch = record_batch(key, value, timestamp_ms);
+        let mut body = Vec::with_capacity(batch.len() + 64);
+        put_i16(&mut body, -1); // no transactional id
+        put_i16(&mut body, self.config.acks);
+        put_i32(&mut body, TIMEOUT.as_millis() as i32);
+        put_i32(&mut body, 1); // topics
+        put_string(&mut body, &self.config.topic);
+        put_i32(&mut body, 1); // partitions
+        put_i32(&mut body, partition as i32);
+        put_i32(&mut body, batch.len() as i32);
+        body.extend_from_slice(&batch);
+
+        state.correlation_id = state.correlation_id.wrapping_add(1);
+        let id = state.correlation_id;
+        let stream = match state.connections.entry(leader) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                tracing::debug!("Connecting {} to broker {leader} at {addr}", self.name);
+                entry.insert(TcpStream::connect(&addr).await?)
+            }
+        };
+        let response = self.call(stream, id, API_PRODUCE, 3, &body).await?;
+        parse_produce(&response)
+    }
+}
+
+#[async_trait]
+impl Sink for KafkaSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Tried again once after an error, with new connections and metadata
+    /// in case the leader has moved.
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let (key, value) = record(spot);
+        let mut state = self.state.lock().await;
+        let mut result = Ok(());
+        for attempt in 0..2 {
+            let produced = tokio::time::timeout(TIMEOUT, self.produce(&mut state, &key, &value));
+            result = produced.await.unwrap_or_else(|_| {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Kafka timed out"))
+            });
+            let Err(err) = &result else {
+                break;
+            };
+            if attempt == 0 {
+                tracing::debug!("Couldn't produce to {}: {err}. Trying again.", self.name);
+            }
+            state.metadata = None;
+            state.connections.clear();
+        }
+        result
+    }
+}
+
+/// Produce spots from `spots` to the topic of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: KafkaConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = KafkaSink::new(&config);
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{
+        crc32c, murmur2, parse_metadata, parse_produce, partition, put_i16, put_i32, put_i64,
+        put_string, put_varint, record, record_batch, Decoder, KafkaSink, API_METADATA,
+        API_PRODUCE,
+    };
+    use crate::config::KafkaConfig;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+
+    /// Error code of a topic or partition without a leader.
+    const LEADER_NOT_AVAILABLE: i16 = 5;
+    const NOT_LEADER_FOR_PARTITION: i16 = 6;
+
+    /// Request as the broker saw it: api key, version, correlation id,
+    /// client id and the rest of the body.
+    type Request = (i16, i16, i32, String, Vec<u8>);
+    /// Api key and body of responses not yet sent.
+    type Responses = Arc<Mutex<VecDeque<(i16, Vec<u8>)>>>;
+
+    /// Broker answering requests with canned responses in order.
+    struct MockBroker {
+        address: String,
+        port: i32,
+        responses: Responses,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl MockBroker {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let broker = Self {
+                address: address.to_string(),
+                port: i32::from(address.port()),
+                responses: Arc::default(),
+                requests: Arc::default(),
+            };
+            let responses = broker.responses.clone();
+            let requests = broker.requests.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, responses.clone(), requests.clone()));
+                }
+            });
+            broker
+        }
+
+        /// Answer the next request, which must be of `api_key`, with `body`.
+        fn respond(&self, api_key: i16, body: Vec<u8>) {
+            self.responses.lock().unwrap().push_back((api_key, body));
+        }
+
+        fn requests(&self) -> Vec<Request> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    async fn serve(
+        mut stream: TcpStream,
+        responses: Responses,
+        requests: Arc<Mutex<Vec<Request>>>,
+    ) {
+        while let Ok(size) = stream.read_i32().await {
+            let mut request = vec![0; size as usize];
+            stream.read_exact(&mut request).await.unwrap();
+            let mut d = Decoder(&request);
+            let api_key = d.i16().unwrap();
+            let version = d.i16().unwrap();
+            let id = d.i32().unwrap();
+            let client_id = d.string().unwrap();
+            let body = d.0.to_vec();
+            requests
+                .lock()
+                .unwrap()
+                .push((api_key, version, id, client_id, body));
+
+            let (expected, body) = responses.lock().unwrap().pop_front().unwrap();
+            assert_eq!(api_key, expected);
+            let mut response = Vec::new();
+            put_i32(&mut response, body.len() as i32 + 4);
+            put_i32(&mut response, id);
+            response.extend_from_slice(&body);
+            stream.write_all(&response).await.unwrap();
+        }
+    }
+
+    /// Metadata response of version 1 with broker 1 at `port` leading both
+    /// partitions of `spots`, or an error for the topic.
+    fn metadata_response(port: i32, error: i16) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_i32(&mut out, 1); // brokers
+        put_i32(&mut out, 1);
+        put_string(&mut out, "127.0.0.1");
+        put_i32(&mut out, port);
+        put_i16(&mut out, -1); // no rack
+        put_i32(&mut out, 1); // controller
+        put_i32(&mut out, 2); // topics
+                              // Other topics are skipped
+        put_i16(&mut out, 0);
+        put_string(&mut out, "other");
+        out.push(0);
+        put_i32(&mut out, 0);
+        put_i16(&mut out, error);
+        put_string(&mut out, "spots");
+        out.push(0); // not internal
+        let partitions: &[i32] = if error == 0 { &[1, 0] } else { &[] };
+        put_i32(&mut out, partitions.len() as i32);
+        for &index in partitions {
+            put_i16(&mut out, 0);
+            put_i32(&mut out, index);
+            put_i32(&mut out, 1); // leader
+            put_i32(&mut out, 1); // replicas
+            put_i32(&mut out, 1);
+            put_i32(&mut out, 1); // in-sync replicas
+            put_i32(&mut out, 1);
+        }
+        out
+    }
+
+    /// Produce response of version 3 for one partition.
+    fn produce_response(error: i16) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_i32(&mut out, 1);
+        put_string(&mut out, "spots");
+        put_i32(&mut out, 1);
+        put_i32(&mut out, 0);
+        put_i16(&mut out, error);
+        put_i64(&mut out, 42); // base offset
+        put_i64(&mut out, -1); // log append time
+        put_i32(&mut out, 0); // throttle time
+        out
+    }
+
+    fn sink(broker: &MockBroker) -> KafkaSink {
+        KafkaSink::new(&KafkaConfig {
+            brokers: vec![broker.address.clone()],
+            topic: "spots".to_string(),
+            client_id: "puskapupu".to_string(),
+            acks: -1,
+        })
+    }
+
+    fn spot() -> DxEntry {
+        "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        let spot: DxEntry =
+            "DX de OH2NOS:     3644.0  oh2nos/p     x01f OHFF-1419 New one!        1146Z"
+                .parse()
+                .unwrap();
+        let (key, value) = record(&spot);
+        assert_eq!(key, b"OH2NOS/P");
+        let value: serde_json::Value = serde_json::from_slice(&value).unwrap();
+        assert_eq!(value["dx"], "oh2nos/p");
+        assert_eq!(value["reporter"], "OH2NOS");
+        assert_eq!(value["info"], "OHFF-1419 New one!");
+        assert_eq!(value["frequency"], 3644.0);
+    }
+
+    #[test]
+    fn test_partition() {
+        // From the tests of the Java client
+        for (data, hash) in [
+            (&b"21"[..], -973932308),
+            (b"foobar", -790332482),
+            (b"a-little-bit-long-string", -985981536),
+            (b"a-little-bit-longer-string", -1486304829),
+            (
+                b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8",
+                -58897971,
+            ),
+            (b"abc", 479470107),
+        ] {
+            assert_eq!(murmur2(data) as i32, hash, "{data:?}");
+        }
+        assert_eq!(partition(b"abc", 10), 479470107 % 10);
+        assert_eq!(
+            partition(b"21", 3),
+            (-973932308i32 & 0x7fff_ffff) as usize % 3
+        );
+    }
+
+    #[test]
+    fn test_record_batch() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        let varints = |values: &[i64]| {
+            let mut out = Vec::new();
+            for value in values {
+                put_varint(&mut out, *value);
+            }
+            out
+        };
+        assert_eq!(
+            varints(&[0, -1, 1, 63, 64, 300]),
+            [0, 1, 2, 126, 128, 1, 216, 4]
+        );
+
+        let batch = record_batch(b"OH2NOS/P", b"{}", 1_709_294_400_000);
+        let mut d = Decoder(&batch);
+        assert_eq!(d.i64().unwrap(), 0);
+        assert_eq!(d.i32().unwrap() as usize, batch.len() - 12);
+        assert_eq!(d.i32().unwrap(), -1);
+        assert_eq!(d.i8().unwrap(), 2);
+        let crc = d.i32().unwrap() as u32;
+        assert_eq!(crc, crc32c(&batch[21..]));
+        assert_eq!(d.i16().unwrap(), 0);
+        assert_eq!(d.i32().unwrap(), 0);
+        assert_eq!(d.i64().unwrap(), 1_709_294_400_000);
+        assert_eq!(d.i64().unwrap(), 1_709_294_400_000);
+        assert!(batch.ends_with(b"\x10OH2NOS/P\x04{}\x00"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let metadata = parse_metadata(&metadata_response(9092, 0), "spots").unwrap();
+        assert_eq!(metadata.brokers[&1], "127.0.0.1:9092");
+        assert_eq!(metadata.leaders, [1, 1]);
+        let err = parse_metadata(&metadata_response(9092, 0), "nothere").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        let err =
+            parse_metadata(&metadata_response(9092, LEADER_NOT_AVAILABLE), "spots").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Kafka broker answered metadata of topic spots with error code 5"
+        );
+        let truncated = metadata_response(9092, 0);
+        let err = parse_metadata(&truncated[..truncated.len() - 2], "spots").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        parse_produce(&produce_response(0)).unwrap();
+        let err = parse_produce(&produce_response(NOT_LEADER_FOR_PARTITION)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Kafka broker answered produce with error code 6"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_broker() {
+        let broker = MockBroker::start().await;
+        // Just created topic without a leader yet, so tried again
+        broker.respond(
+            API_METADATA,
+            metadata_response(broker.port, LEADER_NOT_AVAILABLE),
+        );
+        broker.respond(API_METADATA, metadata_response(broker.port, 0));
+        broker.respond(API_PRODUCE, produce_response(0));
+        let sink = sink(&broker);
+        sink.send(&spot()).await.unwrap();
+
+        let requests = broker.requests();
+        assert_eq!(requests.len(), 3);
+        let ids: Vec<i32> = requests.iter().map(|request| request.2).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        for (api_key, version, _, client_id, body) in &requests[..2] {
+            assert_eq!((*api_key, *version), (API_METADATA, 1));
+            assert_eq!(client_id, "puskapupu");
+            assert_eq!(body, b"\0\0\0\x01\0\x05spots");
+        }
+        let (api_key, version, _, _, body) = &requests[2];
+        assert_eq!((*api_key, *version), (API_PRODUCE, 3));
+        let mut d = Decoder(body);
+        assert_eq!(d.i16().unwrap(), -1); // no transactional id
+        assert_eq!(d.i16().unwrap(), -1); // acks
+        assert_eq!(d.i32().unwrap(), 30_000);
+        assert_eq!(d.len().unwrap(), 1);
+        assert_eq!(d.string().unwrap(), "spots");
+        assert_eq!(d.len().unwrap(), 1);
+        assert_eq!(d.i32().unwrap(), partition(b"OH2NOS/P", 2) as i32);
+        assert_eq!(d.len().unwrap(), d.0.len());
+
+        // The connection to the leader is kept for the next spot
+        broker.respond(API_PRODUCE, produce_response(0));
+        sink.send(&spot()).await.unwrap();
+        assert_eq!(broker.requests().len(), 4);
+
+        // Errors of the leader fail the spot after one more try
+        broker.respond(API_PRODUCE, produce_response(NOT_LEADER_FOR_PARTITION));
+        broker.respond(API_METADATA, metadata_response(broker.port, 0));
+        broker.respond(API_PRODUCE, produce_response(NOT_LEADER_FOR_PARTITION));
+        let err = sink.send(&spot()).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Kafka broker answered produce with error code 6"
+        );
+        assert_eq!(broker.requests().len(), 7);
+    }
+}
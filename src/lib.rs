@@ -1,4 +1,93 @@
+//! Spots from DX clusters to Matrix rooms.
+//!
+//! Programs wanting just the spots of clusters start with
+//! `stream::SpotStream`, which needs the `cqgma` feature but no sinks.
+//!
+//! Parsing and filtering spots ([parser], [filter] etc.) don't depend on any
+//! feature. Reading clusters needs the `cqgma` feature and posting to Matrix
+//! the `matrix` feature. Other outputs have features of their own, eg.
+//! `mqtt`, `webhook`, `discord`, `telegram`, `ntfy`, `email`, `aprs`,
+//! `nostr`, `xmpp`, `influxdb`, `kafka`, `sotawatch` and `pota_spots`, and
+//! live spots of the HTTP server need `websocket`. Lookups of reference details are in [lookup],
+//! with the API directories behind the `sota` and `pota` features.
+
+pub mod adif;
+#[cfg(feature = "aprs")]
+pub mod aprs;
+pub mod band;
+pub mod channel;
+#[cfg(feature = "matrix")]
+pub mod command;
 pub mod config;
+#[cfg(feature = "cqgma")]
 pub mod cqgma;
+pub mod csv;
+pub mod cty;
+pub mod dead_letter;
+pub mod dedup;
+#[cfg(feature = "discord")]
+pub mod discord;
+#[cfg(feature = "email")]
+pub mod email;
+#[cfg(feature = "matrix")]
+pub mod example;
+pub mod feed;
+pub mod filter;
+pub mod geo;
+pub mod http;
+#[cfg(feature = "influxdb")]
+pub mod influxdb;
+pub mod jsonl;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "websocket")]
+pub mod live;
+pub mod logging;
+pub mod lookup;
+pub mod map;
+#[cfg(feature = "matrix")]
 pub mod matrix;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "nostr")]
+pub mod nostr;
+#[cfg(feature = "ntfy")]
+pub mod ntfy;
 pub mod parser;
+#[cfg(feature = "pota")]
+pub mod pota;
+#[cfg(feature = "pota_spots")]
+pub mod pota_spots;
+#[cfg(feature = "cqgma")]
+mod proxy;
+#[cfg(feature = "matrix")]
+pub mod reload;
+pub mod rotate;
+pub mod route;
+pub mod sink;
+#[cfg(feature = "sota")]
+pub mod sota;
+#[cfg(feature = "sotawatch")]
+pub mod sotawatch;
+pub mod status;
+pub mod stdout;
+#[cfg(feature = "sqlite")]
+pub mod store;
+#[cfg(feature = "cqgma")]
+pub mod stream;
+pub mod supervisor;
+#[cfg(feature = "telegram")]
+pub mod telegram;
+pub mod template;
+#[cfg(test)]
+mod testutil;
+#[cfg(any(feature = "email", feature = "xmpp"))]
+mod tls;
+pub mod utc;
+pub mod watchdog;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+pub mod wwff;
+#[cfg(feature = "xmpp")]
+pub mod xmpp;
@@ -0,0 +1,255 @@
+//! Live spots for browser dashboards: `/spots/live` on the HTTP server
+//! streams spots as JSON messages to WebSocket clients.
+//!
+//! On connect a client gets the last spots first, then new ones as they
+//! arrive. Query parameters are the fields of [LiveQuery], eg.
+//! `/spots/live?band=20m&activity=sota`.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+use crate::band::Band;
+use crate::parser::{Activity, DxEntry};
+
+/// How many spots are kept for slow clients before they lose the oldest.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Which spots a client gets. Empty fields match all spots.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LiveQuery {
+    pub band: Option<Band>,
+    pub activity: Option<Activity>,
+    /// Callsign of the activator, case insensitive
+    pub callsign: Option<String>,
+    /// Program reference, eg. `OHFF-1419`, case insensitive
+    pub reference: Option<String>,
+}
+
+impl LiveQuery {
+    pub fn matches(&self, spot: &DxEntry) -> bool {
+        self.band.map_or(true, |band| spot.band() == Some(band))
+            && self.activity.map_or(
+                true,
+                |activity| matches!(spot.cqgma_identifier, Some((a, _)) if a == activity),
+            )
+            && self
+                .callsign
+                .as_ref()
+                .map_or(true, |callsign| spot.dx.eq_ignore_ascii_case(callsign))
+            && self.reference.as_ref().map_or(true, |reference| {
+                spot.references().contains(&reference.to_uppercase())
+            })
+    }
+}
+
+/// Recent spots and the channel of new ones for the clients.
+pub struct Live {
+    backlog_len: usize,
+    /// Oldest first. Locked also while subscribing, so a client gets each
+    /// spot once either from here or from the channel.
+    backlog: Mutex<VecDeque<Arc<DxEntry>>>,
+    spots: broadcast::Sender<Arc<DxEntry>>,
+    /// Clients are disconnected when cancelled
+    shutdown: CancellationToken,
+}
+
+impl Live {
+    /// Clients get up to `backlog_len` last spots when they connect.
+    pub fn new(backlog_len: usize, shutdown: CancellationToken) -> Self {
+        Self {
+            backlog_len,
+            backlog: Mutex::new(VecDeque::with_capacity(backlog_len)),
+            spots: broadcast::channel(CHANNEL_CAPACITY).0,
+            shutdown,
+        }
+    }
+
+    fn push(&self, spot: Arc<DxEntry>) {
+        let mut backlog = self.backlog.lock().expect("backlog lock");
+        if self.backlog_len > 0 {
+            if backlog.len() == self.backlog_len {
+                backlog.pop_front();
+            }
+            backlog.push_back(spot.clone());
+        }
+        // No clients connected
+        let _ = self.spots.send(spot);
+    }
+
+    fn subscribe(&self) -> (Vec<Arc<DxEntry>>, broadcast::Receiver<Arc<DxEntry>>) {
+        let backlog = self.backlog.lock().expect("backlog lock");
+        (backlog.iter().cloned().collect(), self.spots.subscribe())
+    }
+}
+
+/// `/spots/live` for WebSocket clients.
+pub fn router(live: Arc<Live>) -> Router {
+    Router::new()
+        .route("/spots/live", get(upgrade))
+        .with_state(live)
+}
+
+/// Keep spots from `spots` for the clients until shutdown.
+pub async fn run(live: Arc<Live>, mut spots: broadcast::Receiver<Arc<DxEntry>>) -> io::Result<()> {
+    loop {
+        let received = tokio::select! {
+            received = spots.recv() => received,
+            _ = live.shutdown.cancelled() => return Ok(()),
+        };
+        match received {
+            Ok(spot) => live.push(spot),
+            Err(RecvError::Lagged(n)) => {
+                tracing::warn!("Live spots are too slow to keep up. Skipped {n} spots.");
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn upgrade(
+    State(live): State<Arc<Live>>,
+    Query(query): Query<LiveQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_failed_upgrade(|err| tracing::warn!("Couldn't upgrade to WebSocket: {err}"))
+        .on_upgrade(move |ws| async move {
+            if let Err(err) = stream(ws, &live, &query).await {
+                tracing::debug!("Live spots client went away: {err}");
+            }
+        })
+}
+
+/// Send the backlog and then new spots matching `query` until the client
+/// disconnects or shutdown.
+async fn stream(mut ws: WebSocket, live: &Live, query: &LiveQuery) -> Result<(), axum::Error> {
+    tracing::debug!("Live spots client connected with {query:?}");
+    let (backlog, mut spots) = live.subscribe();
+    for spot in backlog.iter().filter(|spot| query.matches(spot)) {
+        ws.feed(message(spot)).await?;
+    }
+    ws.flush().await?;
+    loop {
+        tokio::select! {
+            received = spots.recv() => match received {
+                Ok(spot) if query.matches(&spot) => ws.send(message(&spot)).await?,
+                Ok(_) => (),
+                Err(RecvError::Lagged(n)) => {
+                    tracing::debug!("Live spots client is too slow. Skipped {n} spots.");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // Pings are answered while reading
+            received = ws.next() => match received {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => (),
+                Some(Err(err)) => return Err(err),
+            },
+            _ = live.shutdown.cancelled() => break,
+        }
+    }
+    ws.close().await
+}
+
+fn message(spot: &DxEntry) -> Message {
+    Message::Text(serde_json::to_string(spot).expect("spot serializes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use futures::StreamExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+    use tokio_util::sync::CancellationToken;
+    use tower::ServiceExt;
+
+    use super::{router, run, Live};
+    use crate::parser::DxEntry;
+
+    fn spot(line: &str) -> Arc<DxEntry> {
+        Arc::new(DxEntry::parse_line(line).unwrap())
+    }
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Callsign of the next spot from `ws`.
+    async fn dx(ws: &mut Client) -> String {
+        let message = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let spot: DxEntry = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        spot.dx
+    }
+
+    #[tokio::test]
+    async fn test_live_spots() {
+        let shutdown = CancellationToken::new();
+        let live = Arc::new(Live::new(1, shutdown.clone()));
+        let (spots, _) = broadcast::channel(16);
+        tokio::spawn(run(live.clone(), spots.subscribe()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(live.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        spots
+            .send(spot(
+                "DX de HB9BIN:    14044.0  HB9BIN/P     x04s HB/BL-001                 1049Z",
+            ))
+            .unwrap();
+        while live.subscribe().0.is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let url = format!("ws://{addr}/spots/live");
+        let (mut all, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut wwff, _) = tokio_tungstenite::connect_async(format!("{url}?activity=wwff"))
+            .await
+            .unwrap();
+        assert_eq!(dx(&mut all).await, "HB9BIN/P");
+        while live.spots.receiver_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        spots
+            .send(spot(
+                "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+            ))
+            .unwrap();
+        assert_eq!(dx(&mut all).await, "OH2NOS/P");
+        // The backlog had no WWFF spots
+        assert_eq!(dx(&mut wwff).await, "OH2NOS/P");
+
+        shutdown.cancel();
+        let closed = tokio::time::timeout(Duration::from_secs(5), all.next()).await;
+        assert!(matches!(closed, Ok(Some(Ok(Message::Close(_))))));
+    }
+
+    #[tokio::test]
+    async fn test_not_websocket() {
+        let live = Arc::new(Live::new(1, CancellationToken::new()));
+        let request = Request::get("/spots/live").body(Body::empty()).unwrap();
+        let response = router(live).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
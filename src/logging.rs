@@ -0,0 +1,249 @@
+//! Log output as configured in `[logging]`.
+
+use std::io;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+
+use tracing::{Level, Metadata, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::{LogFormat, LoggingConfig, SyslogConfig};
+
+/// Socket of the local syslog daemon.
+#[cfg(unix)]
+const DEV_LOG: &str = "/dev/log";
+
+/// Build the subscriber writing log lines to `writer`. `rust_log`, ie. the
+/// `RUST_LOG` environment variable, overrides the configured level.
+///
+/// In JSON format each line is an object with `timestamp`, `level`,
+/// `target` and `fields` of the event, the current `span` and the list of
+/// all entered `spans` with their fields, eg. the `host` of a cluster
+/// connection.
+pub fn subscriber<W>(
+    config: &LoggingConfig,
+    rust_log: Option<&str>,
+    writer: W,
+) -> io::Result<Box<dyn Subscriber + Send + Sync>>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(config.env_filter(rust_log)?)
+        .with_writer(writer);
+    Ok(match config.format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .finish(),
+        ),
+    })
+}
+
+/// Build the subscriber for `config` sending log lines to syslog if
+/// configured and to stdout otherwise. With `stderr` they go to stderr
+/// instead of stdout, so stdout is left for spots.
+pub fn init_subscriber(
+    config: &LoggingConfig,
+    rust_log: Option<&str>,
+    stderr: bool,
+) -> io::Result<Box<dyn Subscriber + Send + Sync>> {
+    let Some(syslog) = &config.syslog else {
+        return if stderr {
+            subscriber(config, rust_log, io::stderr)
+        } else {
+            subscriber(config, rust_log, io::stdout)
+        };
+    };
+    let writer = Syslog::connect(syslog)?;
+    // Syslog adds time of its own
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(config.env_filter(rust_log)?)
+        .with_writer(writer)
+        .with_ansi(false)
+        .without_time();
+    Ok(match config.format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .finish(),
+        ),
+    })
+}
+
+#[derive(Debug)]
+enum Socket {
+    #[cfg(unix)]
+    Local(UnixDatagram),
+    Remote(UdpSocket),
+}
+
+/// Sends each log line as a syslog message in the BSD format of RFC 3164,
+/// eg. `<30>puskapupu[1234]: Connected`.
+#[derive(Debug, Clone)]
+pub struct Syslog {
+    socket: Arc<Socket>,
+    facility: u8,
+    pid: u32,
+}
+
+impl Syslog {
+    /// Local syslog is only on Unix. Elsewhere `host` must be given.
+    pub fn connect(config: &SyslogConfig) -> io::Result<Self> {
+        let socket = match &config.host {
+            Some(host) => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                socket.connect(host.as_str())?;
+                Socket::Remote(socket)
+            }
+            #[cfg(unix)]
+            None => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(DEV_LOG)?;
+                Socket::Local(socket)
+            }
+            #[cfg(not(unix))]
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "no local syslog on this system; set logging.syslog.host",
+                ));
+            }
+        };
+        Ok(Self {
+            socket: Arc::new(socket),
+            facility: config.facility as u8,
+            pid: std::process::id(),
+        })
+    }
+
+    fn line(&self, severity: u8) -> SyslogLine<'_> {
+        SyslogLine {
+            syslog: self,
+            severity,
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogLine<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.line(6)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let severity = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        self.line(severity)
+    }
+}
+
+/// Writer of one event. The formatter writes each event at once.
+pub struct SyslogLine<'a> {
+    syslog: &'a Syslog,
+    severity: u8,
+}
+
+impl io::Write for SyslogLine<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = buf.strip_suffix(b"\n").unwrap_or(buf);
+        let priority = self.syslog.facility * 8 + self.severity;
+        let mut message = format!("<{priority}>puskapupu[{}]: ", self.syslog.pid).into_bytes();
+        message.extend_from_slice(text);
+        match self.syslog.socket.as_ref() {
+            #[cfg(unix)]
+            Socket::Local(socket) => socket.send(&message)?,
+            Socket::Remote(socket) => socket.send(&message)?,
+        };
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use super::{init_subscriber, subscriber};
+    use crate::config::{Facility, LogFormat, LoggingConfig, SyslogConfig};
+    use crate::testutil::Capture;
+
+    #[test]
+    fn test_json_log_line() {
+        let config = LoggingConfig {
+            level: "debug".to_string(),
+            format: LogFormat::Json,
+            syslog: None,
+        };
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = subscriber(&config, None, move || writer.clone()).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("manage_telnet", host = "www.cqgma.org:7300");
+            let _entered = span.enter();
+            tracing::info!(spots = 3, "Connected");
+            tracing::trace!("Not logged");
+        });
+
+        let output = capture.contents();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 1, "{output}");
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert!(line["timestamp"].is_string());
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "puskapupu::logging::tests");
+        assert_eq!(line["fields"]["message"], "Connected");
+        assert_eq!(line["fields"]["spots"], 3);
+        assert_eq!(line["span"]["name"], "manage_telnet");
+        assert_eq!(line["span"]["host"], "www.cqgma.org:7300");
+        assert_eq!(line["spans"][0]["host"], "www.cqgma.org:7300");
+    }
+
+    #[test]
+    fn test_syslog() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let config = LoggingConfig {
+            syslog: Some(SyslogConfig {
+                facility: Facility::Local3,
+                host: Some(server.local_addr().unwrap().to_string()),
+            }),
+            ..Default::default()
+        };
+        let subscriber = init_subscriber(&config, None, false).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("Reconnecting");
+            tracing::debug!("Not logged");
+        });
+
+        let mut buf = [0; 1024];
+        let len = server.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        // local3 is 19, warning 4
+        let prefix = format!("<156>puskapupu[{}]: ", std::process::id());
+        assert!(message.starts_with(&prefix), "{message}");
+        assert!(message.ends_with("Reconnecting"), "{message}");
+        assert!(message.contains(" WARN "), "{message}");
+    }
+}
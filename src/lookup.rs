@@ -0,0 +1,233 @@
+//! Details of program references, eg. summit names, from the directories of
+//! the programs. Answers are cached so the services aren't asked about the
+//! same reference again for every spot.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::config::LookupConfig;
+use crate::parser::DxEntry;
+
+/// How long a failed lookup waits before the reference is asked again.
+const RETRY_AFTER_ERROR: Duration = Duration::from_secs(5 * 60);
+
+/// What a directory tells about a reference.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ReferenceInfo {
+    /// Reference as known by the directory, eg. `HB/BL-001`
+    pub code: String,
+    /// eg. `Chasseral`
+    pub name: String,
+    /// Short facts, eg. `1607m, 8pts`
+    pub details: Option<String>,
+    /// Latitude and longitude in degrees
+    pub location: Option<(f64, f64)>,
+}
+
+/// eg. `HB/BL-001 Chasseral (1607m, 8pts)`
+impl fmt::Display for ReferenceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.name)?;
+        if let Some(details) = &self.details {
+            write!(f, " ({details})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Directory of one program.
+#[async_trait]
+pub trait Directory: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Whether `reference` of `spot` could be in this directory.
+    fn handles(&self, spot: &DxEntry, reference: &str) -> bool;
+
+    /// `Ok(None)` if the directory doesn't know `reference`.
+    async fn fetch(&self, reference: &str) -> io::Result<Option<ReferenceInfo>>;
+}
+
+/// Asks directories about references and remembers the answers.
+pub struct Lookup {
+    directories: Vec<Box<dyn Directory>>,
+    cache_for: Duration,
+    cache: Mutex<HashMap<String, (Instant, Option<ReferenceInfo>)>>,
+}
+
+impl Lookup {
+    pub fn new(directories: Vec<Box<dyn Directory>>, cache_for: Duration) -> Self {
+        Self {
+            directories,
+            cache_for,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Directories enabled in `config`, when built with their features.
+    pub fn from_config(config: &LookupConfig) -> Self {
+        let mut directories: Vec<Box<dyn Directory>> = Vec::new();
+        // Local file before the APIs, which might know the same references
+        if let Some(path) = &config.wwff_path {
+            match crate::wwff::WwffDirectory::load(path) {
+                Ok(wwff) => {
+                    tracing::info!(
+                        "Read {} WWFF references from {}",
+                        wwff.len(),
+                        path.display()
+                    );
+                    directories.push(Box::new(wwff));
+                }
+                Err(err) => {
+                    tracing::warn!("Couldn't read WWFF directory {}: {err}", path.display())
+                }
+            }
+        }
+        if config.sota {
+            #[cfg(feature = "sota")]
+            match crate::sota::SotaDirectory::new(
+                crate::sota::API_URL,
+                Duration::from_secs(config.timeout_secs),
+            ) {
+                Ok(sota) => directories.push(Box::new(sota)),
+                Err(err) => tracing::warn!("Couldn't set up SOTA lookups: {err}"),
+            }
+            #[cfg(not(feature = "sota"))]
+            tracing::warn!("Ignoring lookup.sota: built without the sota feature");
+        }
+        if config.pota {
+            #[cfg(feature = "pota")]
+            match crate::pota::PotaDirectory::new(
+                crate::pota::API_URL,
+                Duration::from_secs(config.timeout_secs),
+            ) {
+                Ok(pota) => directories.push(Box::new(pota)),
+                Err(err) => tracing::warn!("Couldn't set up POTA lookups: {err}"),
+            }
+            #[cfg(not(feature = "pota"))]
+            tracing::warn!("Ignoring lookup.pota: built without the pota feature");
+        }
+        Self::new(directories, Duration::from_secs(config.cache_secs))
+    }
+
+    /// Info of the first reference of `spot` some directory knows. Failed
+    /// lookups are logged and give `None`, so spots are never held back
+    /// by a directory being down.
+    pub async fn reference_info(&self, spot: &DxEntry) -> Option<ReferenceInfo> {
+        for reference in spot.references() {
+            for directory in &self.directories {
+                if !directory.handles(spot, &reference) {
+                    continue;
+                }
+                if let Some(info) = self.lookup(directory.as_ref(), &reference).await {
+                    return Some(info);
+                }
+            }
+        }
+        None
+    }
+
+    /// `spot` with [DxEntry::reference_info] filled in.
+    pub async fn enrich(&self, spot: &DxEntry) -> DxEntry {
+        let mut spot = spot.clone();
+        spot.reference_info = self.reference_info(&spot).await;
+        spot
+    }
+
+    async fn lookup(&self, directory: &dyn Directory, reference: &str) -> Option<ReferenceInfo> {
+        let key = format!("{} {reference}", directory.name());
+        let now = Instant::now();
+        if let Some((expires, info)) = self.cache.lock().expect("cache lock").get(&key) {
+            if *expires > now {
+                return info.clone();
+            }
+        }
+        let (info, cache_for) = match directory.fetch(reference).await {
+            Ok(info) => (info, self.cache_for),
+            Err(err) => {
+                tracing::warn!(
+                    "Couldn't look up {reference} from {}: {err}",
+                    directory.name()
+                );
+                (None, RETRY_AFTER_ERROR.min(self.cache_for))
+            }
+        };
+        let mut cache = self.cache.lock().expect("cache lock");
+        cache.retain(|_, (expires, _)| *expires > now);
+        cache.insert(key, (now + cache_for, info.clone()));
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use super::{Directory, Lookup, ReferenceInfo};
+    use crate::parser::DxEntry;
+
+    /// Knows only `HB/BL-001` and fails with `DM/NS-107`.
+    struct Summits(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Directory for Summits {
+        fn name(&self) -> &str {
+            "summits"
+        }
+
+        fn handles(&self, _spot: &DxEntry, reference: &str) -> bool {
+            reference.contains('/')
+        }
+
+        async fn fetch(&self, reference: &str) -> io::Result<Option<ReferenceInfo>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            match reference {
+                "HB/BL-001" => Ok(Some(ReferenceInfo {
+                    code: reference.to_string(),
+                    name: "Chasseral".to_string(),
+                    details: Some("1607m, 8pts".to_string()),
+                    location: None,
+                })),
+                "DM/NS-107" => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+                _ => Ok(None),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let lookup = Lookup::new(
+            vec![Box::new(Summits(fetches.clone()))],
+            Duration::from_secs(60),
+        );
+        let spot: DxEntry =
+            "DX de HB9BIN:    14044.0  HB9BIN/P     x04s HB/BL-001                 1049Z"
+                .parse()
+                .unwrap();
+        let info = lookup.enrich(&spot).await.reference_info.unwrap();
+        assert_eq!(info.to_string(), "HB/BL-001 Chasseral (1607m, 8pts)");
+        assert!(lookup.reference_info(&spot).await.is_some());
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+
+        // Failures aren't fatal and aren't retried right away either
+        let spot: DxEntry =
+            "DX de DL1CR:      3567.0  DL1CR/P      x04s DM/NS-107                 1052Z"
+                .parse()
+                .unwrap();
+        assert_eq!(lookup.reference_info(&spot).await, None);
+        assert_eq!(lookup.reference_info(&spot).await, None);
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,344 @@
+//! Map of recent spots for a quick look at what's on the air: served by
+//! the HTTP server as the SVG image `/spots/map.svg`.
+//!
+//! Spots are placed by the location of their reference, a locator in the
+//! info, or the country of the callsign, and colored by band. There are no
+//! coastlines or borders, only lines of latitude and longitude, so the map
+//! is easiest to read of a familiar region.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+use crate::band::Band;
+use crate::config::MapRegion;
+use crate::feed::escape;
+use crate::geo;
+use crate::lookup::Lookup;
+use crate::parser::DxEntry;
+
+/// Width of the image in pixels. The height follows from the region.
+const WIDTH: f64 = 720.0;
+/// Most spots drawn, the oldest are dropped first.
+const MAX_SPOTS: usize = 1000;
+const TITLE: &str = "puskapupu spots";
+
+/// Position of `location` (latitude, longitude) on a `width` × `height`
+/// image of `region` in equirectangular projection, from the top left
+/// corner. `None` if it's outside the region.
+pub fn pixel(
+    location: (f64, f64),
+    region: &MapRegion,
+    width: f64,
+    height: f64,
+) -> Option<(f64, f64)> {
+    let (lat, lon) = location;
+    if !(region.south..=region.north).contains(&lat) || !(region.west..=region.east).contains(&lon)
+    {
+        return None;
+    }
+    let x = (lon - region.west) / (region.east - region.west) * width;
+    let y = (region.north - lat) / (region.north - region.south) * height;
+    Some((x, y))
+}
+
+/// Where `spot` is: at its reference if looked up, at a 6 or 8 character
+/// locator in the info, or in the country of the callsign.
+pub fn location(spot: &DxEntry) -> Option<(f64, f64)> {
+    spot.reference_info
+        .as_ref()
+        .and_then(|info| info.location)
+        .or_else(|| {
+            spot.info
+                .split_whitespace()
+                .filter(|word| word.len() >= 6)
+                .find_map(geo::grid_to_latlon)
+        })
+        .or_else(|| spot.dxcc().map(|dxcc| dxcc.location))
+}
+
+/// Color of the points of `band`.
+fn band_color(band: Option<Band>) -> &'static str {
+    match band {
+        Some(Band::B160m) => "#8b4513",
+        Some(Band::B80m) => "#e6194b",
+        Some(Band::B60m) => "#f58231",
+        Some(Band::B40m) => "#ffe119",
+        Some(Band::B30m) => "#bfef45",
+        Some(Band::B20m) => "#3cb44b",
+        Some(Band::B17m) => "#42d4f4",
+        Some(Band::B15m) => "#4363d8",
+        Some(Band::B12m) => "#911eb4",
+        Some(Band::B10m) => "#f032e6",
+        Some(Band::B6m) => "#fabed4",
+        Some(Band::B4m | Band::B2m | Band::B70cm | Band::B23cm) => "#ffffff",
+        None => "#a9a9a9",
+    }
+}
+
+/// SVG image of `region` with a point for each of `spots` at its location,
+/// drawn in order so the last ones are on top.
+pub fn svg<'a>(
+    spots: impl IntoIterator<Item = ((f64, f64), &'a DxEntry)>,
+    region: &MapRegion,
+) -> String {
+    let (lat_span, lon_span) = (region.north - region.south, region.east - region.west);
+    let (width, height) = (WIDTH, (WIDTH * lat_span / lon_span).round());
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+         viewBox=\"0 0 {width} {height}\">"
+    );
+    let _ = writeln!(out, "<title>{TITLE}</title>");
+    out.push_str("<rect width=\"100%\" height=\"100%\" fill=\"#0b2340\"/>\n");
+
+    // Lines of latitude and longitude, closer together on smaller maps
+    let step = if lon_span.max(lat_span) > 90.0 {
+        30.0
+    } else {
+        10.0
+    };
+    out.push_str("<g stroke=\"#2c4a6e\" stroke-width=\"1\">\n");
+    let mut lat = (region.south / step).ceil() * step;
+    while lat <= region.north {
+        let y = (region.north - lat) / lat_span * height;
+        let _ = writeln!(
+            out,
+            "<line x1=\"0\" y1=\"{y:.1}\" x2=\"{width}\" y2=\"{y:.1}\"/>"
+        );
+        lat += step;
+    }
+    let mut lon = (region.west / step).ceil() * step;
+    while lon <= region.east {
+        let x = (lon - region.west) / lon_span * width;
+        let _ = writeln!(
+            out,
+            "<line x1=\"{x:.1}\" y1=\"0\" x2=\"{x:.1}\" y2=\"{height}\"/>"
+        );
+        lon += step;
+    }
+    out.push_str("</g>\n");
+
+    let mut bands = BTreeSet::new();
+    for (location, spot) in spots {
+        let Some((x, y)) = pixel(location, region, width, height) else {
+            continue;
+        };
+        bands.insert(spot.band());
+        let references = spot.references().join(" ");
+        let title = format!("{} {} {references}", spot.dx, spot.frequency_mhz_string());
+        let _ = writeln!(
+            out,
+            "<circle cx=\"{x:.1}\" cy=\"{y:.1}\" r=\"4\" fill=\"{}\"><title>{}</title></circle>",
+            band_color(spot.band()),
+            escape(title.trim())
+        );
+    }
+
+    // Legend of the bands drawn
+    for (i, band) in bands.into_iter().enumerate() {
+        let y = height - 10.0 - 16.0 * i as f64;
+        let _ = writeln!(
+            out,
+            "<circle cx=\"14\" cy=\"{y}\" r=\"4\" fill=\"{}\"/>\
+             <text x=\"24\" y=\"{}\" fill=\"#ffffff\" font-family=\"sans-serif\" font-size=\"12\">{}</text>",
+            band_color(band),
+            y + 4.0,
+            band.map_or("other", |band| band.name())
+        );
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+struct Located {
+    received: SystemTime,
+    /// Latitude and longitude
+    location: (f64, f64),
+    spot: DxEntry,
+}
+
+/// Located spots of the last minutes.
+pub struct SpotMap {
+    window: Duration,
+    region: MapRegion,
+    /// References are looked up for their location if given
+    lookup: Option<Arc<Lookup>>,
+    /// Oldest first
+    spots: Mutex<VecDeque<Located>>,
+}
+
+impl SpotMap {
+    /// Map of `region` with spots of the last `window`.
+    pub fn new(window: Duration, region: MapRegion, lookup: Option<Arc<Lookup>>) -> Self {
+        Self {
+            window,
+            region,
+            lookup,
+            spots: Mutex::default(),
+        }
+    }
+
+    async fn push(&self, spot: &DxEntry, now: SystemTime) {
+        let spot = match &self.lookup {
+            Some(lookup) => lookup.enrich(spot).await,
+            None => spot.clone(),
+        };
+        let Some(location) = location(&spot) else {
+            return;
+        };
+        let mut spots = self.spots.lock().expect("map lock");
+        if spots.len() == MAX_SPOTS {
+            spots.pop_front();
+        }
+        spots.push_back(Located {
+            received: now,
+            location,
+            spot,
+        });
+    }
+
+    /// [svg] of the spots within the window at `now`.
+    pub fn render(&self, now: SystemTime) -> String {
+        let mut spots = self.spots.lock().expect("map lock");
+        while let Some(oldest) = spots.front() {
+            match now.duration_since(oldest.received) {
+                Ok(age) if age > self.window => spots.pop_front(),
+                _ => break,
+            };
+        }
+        svg(
+            spots
+                .iter()
+                .map(|located| (located.location, &located.spot)),
+            &self.region,
+        )
+    }
+}
+
+/// `/spots/map.svg`
+pub fn router(map: Arc<SpotMap>) -> Router {
+    Router::new()
+        .route("/spots/map.svg", get(map_svg))
+        .with_state(map)
+}
+
+async fn map_svg(State(map): State<Arc<SpotMap>>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "image/svg+xml")],
+        map.render(SystemTime::now()),
+    )
+}
+
+/// Keep spots from `spots` for the map until `shutdown` is cancelled.
+pub async fn run(
+    map: Arc<SpotMap>,
+    mut spots: broadcast::Receiver<Arc<DxEntry>>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    loop {
+        let received = tokio::select! {
+            received = spots.recv() => received,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        match received {
+            Ok(spot) => map.push(&spot, SystemTime::now()).await,
+            Err(RecvError::Lagged(n)) => {
+                tracing::warn!("Spot map is too slow to keep up. Skipped {n} spots.");
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{location, pixel, SpotMap};
+    use crate::config::MapRegion;
+    use crate::lookup::ReferenceInfo;
+    use crate::parser::DxEntry;
+
+    #[test]
+    fn test_pixel() {
+        let world = MapRegion::default();
+        assert_eq!(
+            pixel((0.0, 0.0), &world, 720.0, 360.0),
+            Some((360.0, 180.0))
+        );
+        assert_eq!(
+            pixel((90.0, -180.0), &world, 720.0, 360.0),
+            Some((0.0, 0.0))
+        );
+        assert_eq!(
+            pixel((-90.0, 180.0), &world, 720.0, 360.0),
+            Some((720.0, 360.0))
+        );
+        // Helsinki
+        let (x, y) = pixel((60.17, 24.94), &world, 720.0, 360.0).unwrap();
+        assert!((x - 409.88).abs() < 0.01 && (y - 59.66).abs() < 0.01);
+
+        let nordic = MapRegion {
+            south: 55.0,
+            west: 5.0,
+            north: 72.0,
+            east: 32.0,
+        };
+        let (x, y) = pixel((60.17, 24.94), &nordic, 540.0, 340.0).unwrap();
+        assert!((x - 398.8).abs() < 0.01 && (y - 236.6).abs() < 0.01);
+        // New York isn't on it
+        assert_eq!(pixel((40.71, -74.01), &nordic, 540.0, 340.0), None);
+    }
+
+    #[test]
+    fn test_location() {
+        let mut spot = DxEntry::parse_line(
+            "DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 KP20le CW    1150Z",
+        )
+        .unwrap();
+        let (lat, lon) = location(&spot).unwrap();
+        assert!((lat - 60.19).abs() < 0.01 && (lon - 24.96).abs() < 0.01);
+        spot.reference_info = Some(ReferenceInfo {
+            code: "OHFF-1419".to_string(),
+            name: "Kuusijärvi".to_string(),
+            details: None,
+            location: Some((60.3063, 25.1089)),
+        });
+        assert_eq!(location(&spot), Some((60.3063, 25.1089)));
+    }
+
+    #[tokio::test]
+    async fn test_render() {
+        let map = SpotMap::new(Duration::from_secs(60 * 60), MapRegion::default(), None);
+        let spot = DxEntry::parse_line(
+            "DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 KP20le CW    1150Z",
+        )
+        .unwrap();
+        let received = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        map.push(&spot, received).await;
+        let svg = map.render(received + Duration::from_secs(60));
+        assert!(svg
+            .starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"720\" height=\"360\""));
+        assert!(svg.contains(
+            "<circle cx=\"409.9\" cy=\"59.6\" r=\"4\" fill=\"#3cb44b\">\
+             <title>OH2NOS/P 14.062 MHz OHFF-1419</title></circle>"
+        ));
+        assert!(svg.contains(">20m</text>"));
+
+        // Older than the window
+        let svg = map.render(received + Duration::from_secs(2 * 60 * 60));
+        assert!(!svg.contains("OH2NOS/P"));
+    }
+}
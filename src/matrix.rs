@@ -1,20 +1,64 @@
+use std::fmt;
+use std::future::Future;
 use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
+use async_trait::async_trait;
 use futures::stream::StreamExt;
 use matrix_sdk::config::SyncSettings;
 use matrix_sdk::matrix_auth::{MatrixSession, MatrixSessionTokens};
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::{Client, SessionMeta};
-use tokio::sync::mpsc::UnboundedReceiver;
+use matrix_sdk::ruma::events::room::message::{
+    MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent,
+};
+use matrix_sdk::{Client, Room, SessionMeta};
+use tokio::sync::broadcast;
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
-use tracing::instrument;
+use tokio_util::sync::CancellationToken;
+use tracing::{instrument, Instrument};
 
-use crate::config::MatrixConfig;
+use crate::command::{self, Command, CommandState, Pause};
+use crate::config::{MatrixConfig, QuietHours};
+use crate::dedup::Dedup;
+use crate::filter::FilterConfig;
+use crate::lookup::Lookup;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+use crate::template::{Format, Template};
+use crate::{geo, utc};
 
-#[instrument(skip(room_rx))]
+/// How many times joining the room is tried before giving up.
+const JOIN_ATTEMPTS: u32 = 5;
+/// Wait time after first failed join. Doubled after each failure.
+const JOIN_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Posted to rooms when shutting down.
+pub const SHUTDOWN_NOTICE: &str = "Shutting down. Spots will be back later.";
+
+/// Settings not needing reconnection, like quiet hours and message template,
+/// are applied from `updates` while running. When `shutdown` is cancelled,
+/// spots already received are sent and [SHUTDOWN_NOTICE] is posted before
+/// the forward task finishes. Cancel it only after clusters have stopped,
+/// see [shut_down](crate::supervisor::shut_down), so no spots are lost.
+/// `synced` tells if syncing with the homeserver works. Failed posts are
+/// counted in `metrics` like for any [Sink]. Spots are enriched with
+/// `lookup`, if given, for the `{reference}` placeholder.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(room_rx, filter, updates, shutdown, synced, metrics, lookup))]
 pub async fn matrix_init(
     config: &MatrixConfig,
-    mut room_rx: UnboundedReceiver<String>,
+    home_grid: Option<&str>,
+    room_rx: broadcast::Receiver<Arc<DxEntry>>,
+    filter: Arc<watch::Sender<FilterConfig>>,
+    updates: watch::Receiver<MatrixConfig>,
+    shutdown: CancellationToken,
+    synced: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    lookup: Option<Arc<Lookup>>,
 ) -> anyhow::Result<Vec<JoinHandle<io::Result<()>>>> {
     let client = Client::new(config.homeserver.clone()).await?;
 
@@ -25,51 +69,533 @@ pub async fn matrix_init(
         },
         tokens: MatrixSessionTokens {
             access_token: config.access_token.to_owned(),
-            refresh_token: None,
+            refresh_token: config.refresh_token.clone(),
         },
     };
 
     client.restore_session(session).await?;
 
+    let sync_token_path = config.sync_token_path.clone();
+    let sync_token = sync_token_path.as_deref().and_then(load_sync_token);
+
     tracing::debug!("Doing first sync");
-    if let Err(err) = client.sync_once(SyncSettings::default()).await {
-        tracing::error!("Client::sync_once() error: {:?}", err);
+    let mut res = match sync_token {
+        Some(token) => client.sync_once(SyncSettings::default().token(token)).await,
+        None => client.sync_once(SyncSettings::default()).await,
+    };
+    if let Err(err) = &res {
+        tracing::error!("Client::sync_once() error: {:?}. Trying full sync.", err);
+        res = client.sync_once(SyncSettings::default()).await;
+    }
+    match res {
+        Ok(resp) => {
+            synced.store(true, Ordering::Relaxed);
+            if let Some(path) = &sync_token_path {
+                if let Err(err) = save_sync_token(path, &resp.next_batch) {
+                    tracing::warn!("Couldn't save sync token to {path:?}: {err}");
+                }
+            }
+        }
+        Err(err) => tracing::error!("Client::sync_once() error: {:?}", err),
     }
     tracing::debug!("First sync done");
 
+    let mut rooms = Vec::new();
+    for room_id in &config.rooms {
+        let room = retry_with_backoff(JOIN_ATTEMPTS, JOIN_BACKOFF, || {
+            client.join_room_by_id(room_id)
+        })
+        .await
+        .map_err(|err| anyhow::anyhow!("couldn't join room {room_id}: {err}"))?;
+        rooms.push(room);
+    }
+
+    let pause = Arc::new(Pause::default());
+    let state = CommandState {
+        pause: pause.clone(),
+        filter,
+        admins: config.admins.clone(),
+        metrics: metrics.clone(),
+    };
+    register_command_handler(&client, config, Arc::new(state));
+
     let mut handles = Vec::new();
-    if let Ok(resp) = client.join_room_by_id(&config.room_id).await {
-        if let Some(room) = client.get_room(resp.room_id()) {
-            let handle = tokio::spawn(async move {
-                while let Some(line) = room_rx.recv().await {
-                    tracing::info!("matrix tx: ^{line}$");
-                    let content = RoomMessageEventContent::notice_plain(line);
-                    let resp = room.send(content).await;
-                    tracing::debug!("Room message send response: {resp:?}");
+    let sink = MatrixSink {
+        name: format!("matrix {}", config.user_id),
+        rooms,
+        forwarder: Mutex::new(Forwarder::new(config, home_grid, pause)),
+        updates: Mutex::new(updates),
+        lookup,
+    };
+    let health = metrics.sink(&sink.name);
+    let handle =
+        tokio::spawn(sink::run(Box::new(sink), room_rx, health, shutdown).in_current_span());
+    handles.push(handle);
+
+    let handle = tokio::spawn(
+        async move {
+            let mut sync_stream = Box::pin(client.sync_stream(SyncSettings::default()).await);
+            while let Some(res) = sync_stream.next().await {
+                match res {
+                    Ok(resp) => {
+                        synced.store(true, Ordering::Relaxed);
+                        if let Some(path) = &sync_token_path {
+                            if let Err(err) = save_sync_token(path, &resp.next_batch) {
+                                tracing::warn!("Couldn't save sync token to {path:?}: {err}");
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        tracing::error!("sync_stream returned error: {err}");
+                        synced.store(false, Ordering::Relaxed);
+                        return Err(io::Error::new(io::ErrorKind::Interrupted, err));
+                    }
                 }
-                Ok(())
-            });
-            handles.push(handle);
+            }
+            synced.store(false, Ordering::Relaxed);
+            Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "sync_stream died",
+            ))
         }
+        .in_current_span(),
+    );
+    handles.push(handle);
+
+    Ok(handles)
+}
+
+/// Posts spots let through by [Forwarder] to the rooms of one account.
+struct MatrixSink {
+    name: String,
+    rooms: Vec<Room>,
+    forwarder: Mutex<Forwarder>,
+    /// Settings applied to [MatrixSink::forwarder] while running
+    updates: Mutex<watch::Receiver<MatrixConfig>>,
+    lookup: Option<Arc<Lookup>>,
+}
+
+#[async_trait]
+impl Sink for MatrixSink {
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    let handle = tokio::spawn(async move {
-        let mut sync_stream = Box::pin(client.sync_stream(SyncSettings::default()).await);
-        while let Some(res) = sync_stream.next().await {
-            match res {
-                Ok(_) => (),
-                Err(err) => {
-                    tracing::error!("sync_stream returned error: {err}");
-                    return Err(io::Error::new(io::ErrorKind::Interrupted, err));
-                }
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let enriched;
+        let spot = match &self.lookup {
+            Some(lookup) => {
+                enriched = lookup.enrich(spot).await;
+                &enriched
+            }
+            None => spot,
+        };
+        let message = {
+            let mut forwarder = self.forwarder.lock().expect("forwarder lock");
+            let mut updates = self.updates.lock().expect("updates lock");
+            if updates.has_changed().unwrap_or(false) {
+                forwarder.update(&updates.borrow_and_update());
+                tracing::info!("Applied new settings");
+            }
+            let message = forwarder.process(spot, SystemTime::now());
+            message.map(|message| (message, forwarder.format))
+        };
+        match message {
+            Some((message, format)) => send_notice(&self.rooms, &message, format).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        send_notice(&self.rooms, SHUTDOWN_NOTICE, Format::Plain).await
+    }
+}
+
+/// Post `message` in `format` to every room. Returns the last error if
+/// posting to any of them failed.
+async fn send_notice(rooms: &[Room], message: &str, format: Format) -> io::Result<()> {
+    tracing::info!("matrix tx: ^{message}$");
+    let mut result = Ok(());
+    for room in rooms {
+        let content = match format {
+            Format::Html => RoomMessageEventContent::notice_html(html_to_plain(message), message),
+            _ => RoomMessageEventContent::notice_plain(message),
+        };
+        let resp = room.send(content).await;
+        tracing::debug!("Room message send response: {resp:?}");
+        if let Err(err) = resp {
+            result = Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("room {}: {err}", room.room_id()),
+            ));
+        }
+    }
+    result
+}
+
+/// Listen for commands given in the room.
+fn register_command_handler(client: &Client, config: &MatrixConfig, state: Arc<CommandState>) {
+    let room_ids = config.rooms.clone();
+    let own_user_id = config.user_id.clone();
+
+    let span = tracing::Span::current();
+
+    client.add_event_handler(move |ev: OriginalSyncRoomMessageEvent, room: Room| {
+        let state = state.clone();
+        let room_ids = room_ids.clone();
+        let own_user_id = own_user_id.clone();
+        let span = span.clone();
+        async move {
+            if !room_ids.iter().any(|id| id == room.room_id()) || ev.sender == own_user_id {
+                return;
+            }
+            let MessageType::Text(text) = ev.content.msgtype else {
+                return;
+            };
+            let word = text.body.split_whitespace().next().unwrap_or_default();
+            if !command::COMMANDS.contains(&word) {
+                return;
+            }
+
+            tracing::info!("Command from {}: {}", ev.sender, text.body);
+            let reply = match text.body.parse::<Command>() {
+                Ok(cmd) => command::execute(cmd, &ev.sender, &state, SystemTime::now()),
+                Err(err) => err,
+            };
+            let content = RoomMessageEventContent::notice_plain(reply);
+            if let Err(err) = room.send(content).await {
+                tracing::error!("Couldn't reply to command: {err}");
             }
         }
-        Err(io::Error::new(
-            io::ErrorKind::Interrupted,
-            "sync_stream died",
-        ))
+        .instrument(span)
     });
-    handles.push(handle);
+}
 
-    Ok(handles)
+/// Call `f` until it succeeds, at most `attempts` times. Returns the last
+/// error if all attempts fail.
+async fn retry_with_backoff<F, Fut, T, E>(
+    attempts: u32,
+    backoff: Duration,
+    mut f: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: fmt::Display,
+{
+    let mut sleep_for = backoff;
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(err) if attempt < attempts => {
+                tracing::error!(
+                    "Attempt {attempt}/{attempts} failed: {err}. Retrying in {} seconds.",
+                    sleep_for.as_secs_f32()
+                );
+                tokio::time::sleep(sleep_for).await;
+                sleep_for *= 2;
+                attempt += 1;
+            }
+            Err(err) => {
+                tracing::error!("Attempt {attempt}/{attempts} failed: {err}. Giving up.");
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Decides which lines received from the cluster are posted to the room.
+struct Forwarder {
+    pause: Arc<Pause>,
+    quiet_hours: Option<QuietHours>,
+    dedup: Dedup,
+    /// Where [Forwarder::dedup] is saved
+    dedup_path: Option<PathBuf>,
+    /// Operator's location for distance calculations
+    home: Option<(f64, f64)>,
+    template: Template,
+    format: Format,
+}
+
+impl Forwarder {
+    fn new(config: &MatrixConfig, home_grid: Option<&str>, pause: Arc<Pause>) -> Self {
+        let window = Duration::from_secs(config.dedup_window_secs);
+        let dedup = match &config.dedup_path {
+            Some(path) => Dedup::load(path, window, SystemTime::now()).unwrap_or_else(|err| {
+                tracing::warn!("Couldn't load recent spots from {path:?}: {err}");
+                Dedup::new(window)
+            }),
+            None => Dedup::new(window),
+        };
+        Self {
+            pause,
+            quiet_hours: config.quiet_hours,
+            dedup,
+            dedup_path: config.dedup_path.clone(),
+            home: home_grid.and_then(geo::grid_to_latlon),
+            template: config.template.clone().unwrap_or_default(),
+            format: config.format,
+        }
+    }
+
+    /// Apply settings which can be changed while running.
+    fn update(&mut self, config: &MatrixConfig) {
+        self.quiet_hours = config.quiet_hours;
+        self.dedup
+            .set_window(Duration::from_secs(config.dedup_window_secs));
+        self.template = config.template.clone().unwrap_or_default();
+        self.format = config.format;
+    }
+
+    /// Returns the message to be sent to the room at `now` or `None` if the
+    /// `entry` shouldn't be sent.
+    fn process(&mut self, entry: &DxEntry, now: SystemTime) -> Option<String> {
+        let line = &entry.line;
+        if self.pause.is_paused(now) {
+            tracing::debug!("Paused, not sending: ^{line}$");
+            return None;
+        }
+
+        if let Some(quiet) = self.quiet_hours {
+            if quiet.contains(utc::minute_of_day(now)) {
+                tracing::debug!("Quiet hours, not sending: ^{line}$");
+                return None;
+            }
+        }
+
+        if self.dedup.is_duplicate(entry.dedup_key(), now) {
+            tracing::debug!("Duplicate spot, not sending: ^{line}$");
+            return None;
+        }
+        if let Some(path) = &self.dedup_path {
+            if let Err(err) = self.dedup.save(path) {
+                tracing::warn!("Couldn't save recent spots to {path:?}: {err}");
+            }
+        }
+
+        let message = self.template.render_as(entry, self.format);
+        // Only to the reference, the grid of the line is the reporter's
+        let there = entry.reference_info.as_ref().and_then(|info| info.location);
+        let distance = self
+            .home
+            .zip(there)
+            .map(|(home, there)| distance_to(home, there));
+        match distance {
+            Some(distance) => Some(format!("{message} {distance}")),
+            None => Some(message),
+        }
+    }
+}
+
+/// Text of `html` for clients not showing HTML: tags dropped and entities
+/// decoded.
+fn html_to_plain(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = rest[start..]
+            .find('>')
+            .map_or("", |end| &rest[start + end + 1..]);
+    }
+    out.push_str(rest);
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// Distance and direction from `home` to `there`, eg. "(342 km, NE)".
+fn distance_to(home: (f64, f64), there: (f64, f64)) -> String {
+    let km = geo::distance_km(home, there);
+    let direction = geo::compass_point(geo::bearing_deg(home, there));
+    format!("({km:.0} km, {direction})")
+}
+
+/// Read sync token saved by earlier run. Missing or garbled token means we
+/// start with a full sync.
+fn load_sync_token(path: &Path) -> Option<String> {
+    let token = match std::fs::read_to_string(path) {
+        Ok(token) => token,
+        Err(err) => {
+            tracing::info!("No sync token in {path:?} ({err}). Doing full sync.");
+            return None;
+        }
+    };
+    let token = token.trim();
+    if token.is_empty() || !token.chars().all(|c| c.is_ascii_graphic()) {
+        tracing::warn!("Invalid sync token in {path:?}. Doing full sync.");
+        return None;
+    }
+    Some(token.to_string())
+}
+
+/// Save sync token by writing into temporary file and renaming it over the
+/// old one so that a crash can't leave half written token behind.
+fn save_sync_token(path: &Path, token: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, token)?;
+    std::fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use std::cell::Cell;
+
+    use super::{
+        distance_to, html_to_plain, load_sync_token, retry_with_backoff, save_sync_token, Forwarder,
+    };
+    use crate::dedup::Dedup;
+    use crate::lookup::ReferenceInfo;
+    use crate::parser::DxEntry;
+    use crate::template::{Format, Template};
+
+    #[test]
+    fn test_forwarder_dedup() {
+        let mut forwarder = Forwarder {
+            pause: Default::default(),
+            quiet_hours: None,
+            dedup: Dedup::new(Duration::from_secs(600)),
+            dedup_path: None,
+            home: None,
+            template: Default::default(),
+            format: Format::Plain,
+        };
+        let now = SystemTime::now();
+        let spots = [
+            "DX de OH2NOS:    14046.0  OH2NOS/P     x01f OHFF-1419 New one!        1254Z",
+            "DX de OK1VEI:    14046.0  OH2NOS/P     x01d ohff-1419                 1255Z",
+        ];
+
+        let sent = spots
+            .iter()
+            .filter_map(|line| forwarder.process(&line.parse().unwrap(), now))
+            .count();
+        assert_eq!(sent, 1);
+    }
+
+    #[test]
+    fn test_forwarder_html() {
+        let mut forwarder = Forwarder {
+            pause: Default::default(),
+            quiet_hours: None,
+            dedup: Dedup::new(Duration::from_secs(600)),
+            dedup_path: None,
+            home: None,
+            template: Template::parse("<b>{dx}</b> {frequency} {info}").unwrap(),
+            format: Format::Html,
+        };
+        let entry = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 <New one!>      1146Z"
+            .parse()
+            .unwrap();
+        let message = forwarder.process(&entry, SystemTime::now()).unwrap();
+        assert_eq!(
+            message,
+            "<b>OH2NOS/P</b> 3.644 MHz OHFF-1419 &lt;New one!&gt;"
+        );
+        assert_eq!(
+            html_to_plain(&message),
+            "OH2NOS/P 3.644 MHz OHFF-1419 <New one!>"
+        );
+    }
+
+    #[test]
+    fn test_distance_to() {
+        let grid = |grid| crate::geo::grid_to_latlon(grid).unwrap();
+        assert_eq!(distance_to(grid("KP20"), grid("KP32")), "(246 km, NE)");
+        assert_eq!(distance_to(grid("KP20"), grid("JO65")), "(897 km, SW)");
+    }
+
+    #[test]
+    fn test_forwarder_appends_distance() {
+        let mut forwarder = Forwarder {
+            pause: Default::default(),
+            quiet_hours: None,
+            dedup: Dedup::new(Duration::from_secs(600)),
+            dedup_path: None,
+            home: crate::geo::grid_to_latlon("JO10"),
+            template: Default::default(),
+            format: Format::Plain,
+        };
+        let entry =
+            "DX de ON4AVT:     7143.0  OT8S         bca on-2672                    0657Z JO10"
+                .parse()
+                .unwrap();
+        // The grid is the reporter's, not where OT8S is
+        assert_eq!(
+            forwarder.process(&entry, SystemTime::now()).as_deref(),
+            Some("OT8S 7.143 MHz bca on-2672 (de ON4AVT 0657Z)")
+        );
+
+        let entry = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            forwarder.process(&entry, SystemTime::now()).as_deref(),
+            Some("OH2NOS/P 3.644 MHz OHFF-1419 New one! (de OH2NOS 1146Z)")
+        );
+
+        let mut entry: DxEntry =
+            "DX de ON4AVT:     7144.0  OT8S         bca on-2672                    0712Z JO10"
+                .parse()
+                .unwrap();
+        entry.reference_info = Some(ReferenceInfo {
+            code: "ON-2672".to_string(),
+            name: "Hoge Kempen".to_string(),
+            details: None,
+            location: Some((51.0, 5.6)),
+        });
+        assert_eq!(
+            forwarder.process(&entry, SystemTime::now()).as_deref(),
+            Some("OT8S 7.144 MHz bca on-2672 (de ON4AVT 0712Z) (191 km, E)")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_backoff() {
+        let calls = Cell::new(0);
+        let res: Result<(), String> = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls.set(calls.get() + 1);
+            async { Err("M_FORBIDDEN".to_string()) }
+        })
+        .await;
+        assert_eq!(res, Err("M_FORBIDDEN".to_string()));
+        assert_eq!(calls.get(), 3);
+
+        calls.set(0);
+        let res: Result<u32, String> = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls.set(calls.get() + 1);
+            let n = calls.get();
+            async move {
+                if n < 2 {
+                    Err("timeout".to_string())
+                } else {
+                    Ok(n)
+                }
+            }
+        })
+        .await;
+        assert_eq!(res, Ok(2));
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_sync_token_save_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sync_token");
+
+        assert_eq!(load_sync_token(&path), None);
+
+        save_sync_token(&path, "s72594_4483_1934").unwrap();
+        assert_eq!(load_sync_token(&path).as_deref(), Some("s72594_4483_1934"));
+
+        save_sync_token(&path, "s72595_4483_1935").unwrap();
+        assert_eq!(load_sync_token(&path).as_deref(), Some("s72595_4483_1935"));
+
+        std::fs::write(&path, "not a\ntoken").unwrap();
+        assert_eq!(load_sync_token(&path), None);
+    }
 }
@@ -0,0 +1,411 @@
+//! Counters for spotting problems early, eg. a rising parse failure rate
+//! when a cluster changes its format.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use tokio_util::sync::CancellationToken;
+
+/// How often [log_periodically] logs the counters.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Spots of this long ago are counted in [ClusterStats::spots_per_minute].
+const SPOT_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Cluster lines parsed as spots
+    pub parse_ok: AtomicU64,
+    /// Cluster lines looking like spots but failing to parse
+    pub parse_err: AtomicU64,
+    clusters: Mutex<Vec<Arc<ClusterStats>>>,
+    sinks: Mutex<Vec<Arc<SinkHealth>>>,
+}
+
+/// Connection of one cluster.
+#[derive(Debug, Default)]
+pub struct ClusterStats {
+    /// host:port of the cluster
+    pub name: String,
+    /// Connections after the first one
+    pub reconnects: AtomicU64,
+    /// Spots passed on from the cluster
+    pub spots: AtomicU64,
+    connection: Mutex<Connection>,
+}
+
+#[derive(Debug, Default)]
+struct Connection {
+    /// `None` while disconnected
+    since: Option<SystemTime>,
+    /// Has there been a connection before
+    ever: bool,
+    last_error: Option<String>,
+    /// When the spots of the last [SPOT_RATE_WINDOW] came, oldest first
+    recent: VecDeque<SystemTime>,
+}
+
+impl ClusterStats {
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("cluster stats lock")
+    }
+
+    /// Connected, or reconnected, at `now`.
+    pub fn connected(&self, now: SystemTime) {
+        let mut connection = self.connection();
+        if connection.ever {
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        connection.ever = true;
+        connection.since = Some(now);
+    }
+
+    pub fn disconnected(&self) {
+        self.connection().since = None;
+    }
+
+    /// Connecting failed or the connection was lost because of `error`.
+    pub fn failed(&self, error: &dyn fmt::Display) {
+        let mut connection = self.connection();
+        connection.since = None;
+        connection.last_error = Some(error.to_string());
+    }
+
+    /// A spot was passed on at `now`.
+    pub fn spot(&self, now: SystemTime) {
+        self.spots.fetch_add(1, Ordering::Relaxed);
+        let mut connection = self.connection();
+        connection.recent.push_back(now);
+        forget_older(&mut connection.recent, now);
+    }
+
+    /// How long the connection has been up at `now`, or `None` while
+    /// disconnected.
+    pub fn uptime(&self, now: SystemTime) -> Option<Duration> {
+        let since = self.connection().since?;
+        Some(now.duration_since(since).unwrap_or_default())
+    }
+
+    /// Spots passed on during the minute before `now`.
+    pub fn spots_per_minute(&self, now: SystemTime) -> usize {
+        let mut connection = self.connection();
+        forget_older(&mut connection.recent, now);
+        connection.recent.len()
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.connection().last_error.clone()
+    }
+}
+
+/// Drop the times of `recent` older than [SPOT_RATE_WINDOW] at `now`.
+fn forget_older(recent: &mut VecDeque<SystemTime>, now: SystemTime) {
+    while let Some(oldest) = recent.front() {
+        match now.duration_since(*oldest) {
+            Ok(age) if age >= SPOT_RATE_WINDOW => recent.pop_front(),
+            _ => break,
+        };
+    }
+}
+
+/// Counters of one [Sink](crate::sink::Sink).
+#[derive(Debug, Default)]
+pub struct SinkHealth {
+    pub name: String,
+    /// Spots sent successfully
+    pub sent: AtomicU64,
+    /// Spots the sink failed to send
+    pub failed: AtomicU64,
+    /// Failures since the last successful send
+    pub consecutive_failures: AtomicU64,
+}
+
+impl SinkHealth {
+    /// The last send succeeded or nothing has been sent yet.
+    pub fn is_up(&self) -> bool {
+        self.consecutive_failures.load(Ordering::Relaxed) == 0
+    }
+}
+
+impl Metrics {
+    /// Stats of the cluster at `name`, kept over restarts like
+    /// [Metrics::sink].
+    pub fn cluster(&self, name: &str) -> Arc<ClusterStats> {
+        let mut clusters = self.clusters.lock().expect("clusters lock");
+        if let Some(stats) = clusters.iter().find(|stats| stats.name == name) {
+            return stats.clone();
+        }
+        let stats = Arc::new(ClusterStats {
+            name: name.to_string(),
+            ..ClusterStats::default()
+        });
+        clusters.push(stats.clone());
+        stats
+    }
+
+    /// Stats of all clusters in the order they were first asked for.
+    pub fn clusters(&self) -> Vec<Arc<ClusterStats>> {
+        self.clusters.lock().expect("clusters lock").clone()
+    }
+
+    /// Counters of the sink called `name`. The same counters are returned
+    /// for the same name, so a restarted sink continues where it left off.
+    pub fn sink(&self, name: &str) -> Arc<SinkHealth> {
+        let mut sinks = self.sinks.lock().expect("sinks lock");
+        if let Some(health) = sinks.iter().find(|health| health.name == name) {
+            return health.clone();
+        }
+        let health = Arc::new(SinkHealth {
+            name: name.to_string(),
+            ..SinkHealth::default()
+        });
+        sinks.push(health.clone());
+        health
+    }
+
+    /// Share of lines failing to parse, or `None` before any lines.
+    pub fn parse_failure_ratio(&self) -> Option<f64> {
+        let ok = self.parse_ok.load(Ordering::Relaxed);
+        let err = self.parse_err.load(Ordering::Relaxed);
+        (ok + err > 0).then(|| err as f64 / (ok + err) as f64)
+    }
+
+    /// Counters in Prometheus text format, with uptimes and rates at `now`.
+    pub fn render(&self, now: SystemTime) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "puskapupu_{name} {value}");
+        };
+        metric(
+            "parse_ok_total",
+            "counter",
+            "Cluster lines parsed as spots",
+            &self.parse_ok.load(Ordering::Relaxed),
+        );
+        metric(
+            "parse_err_total",
+            "counter",
+            "Cluster lines looking like spots but failing to parse",
+            &self.parse_err.load(Ordering::Relaxed),
+        );
+        metric(
+            "parse_failure_ratio",
+            "gauge",
+            "Share of cluster lines failing to parse",
+            &self.parse_failure_ratio().unwrap_or(0.0),
+        );
+
+        let clusters = self.clusters();
+        if !clusters.is_empty() {
+            type Value<'a> = &'a dyn Fn(&ClusterStats) -> u64;
+            let per_cluster: [(&str, &str, &str, Value); 5] = [
+                ("cluster_up", "gauge", "Is each cluster connected", &|s| {
+                    u64::from(s.uptime(now).is_some())
+                }),
+                (
+                    "cluster_uptime_seconds",
+                    "gauge",
+                    "How long the connection of each cluster has been up",
+                    &|s| s.uptime(now).unwrap_or_default().as_secs(),
+                ),
+                (
+                    "cluster_reconnects_total",
+                    "counter",
+                    "Reconnections to each cluster",
+                    &|s| s.reconnects.load(Ordering::Relaxed),
+                ),
+                (
+                    "cluster_spots_total",
+                    "counter",
+                    "Spots passed on from each cluster",
+                    &|s| s.spots.load(Ordering::Relaxed),
+                ),
+                (
+                    "cluster_spots_per_minute",
+                    "gauge",
+                    "Spots passed on from each cluster during the last minute",
+                    &|s| s.spots_per_minute(now) as u64,
+                ),
+            ];
+            for (name, kind, help, value) in per_cluster {
+                header(&mut out, name, kind, help);
+                for stats in &clusters {
+                    let _ = writeln!(
+                        out,
+                        "puskapupu_{name}{{cluster=\"{}\"}} {}",
+                        label(&stats.name),
+                        value(stats)
+                    );
+                }
+            }
+        }
+
+        let sinks = self.sinks.lock().expect("sinks lock");
+        if sinks.is_empty() {
+            return out;
+        }
+        type Value = fn(&SinkHealth) -> u64;
+        let per_sink: [(&str, &str, &str, Value); 3] = [
+            (
+                "sink_sent_total",
+                "counter",
+                "Spots sent by each sink",
+                |h| h.sent.load(Ordering::Relaxed),
+            ),
+            (
+                "sink_failed_total",
+                "counter",
+                "Spots each sink failed to send",
+                |h| h.failed.load(Ordering::Relaxed),
+            ),
+            (
+                "sink_up",
+                "gauge",
+                "Did the last send of each sink succeed",
+                |h| u64::from(h.is_up()),
+            ),
+        ];
+        for (name, kind, help, value) in per_sink {
+            header(&mut out, name, kind, help);
+            for health in sinks.iter() {
+                let _ = writeln!(
+                    out,
+                    "puskapupu_{name}{{sink=\"{}\"}} {}",
+                    label(&health.name),
+                    value(health)
+                );
+            }
+        }
+        out
+    }
+}
+
+/// `value` escaped for a Prometheus label.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP puskapupu_{name} {help}");
+    let _ = writeln!(out, "# TYPE puskapupu_{name} {kind}");
+}
+
+/// Log the counters every `interval` until `shutdown` is cancelled.
+pub async fn log_periodically(
+    metrics: Arc<Metrics>,
+    interval: Duration,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick is immediate and there's nothing to tell yet
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+        let ok = metrics.parse_ok.load(Ordering::Relaxed);
+        let err = metrics.parse_err.load(Ordering::Relaxed);
+        let ratio = metrics.parse_failure_ratio().unwrap_or(0.0);
+        tracing::info!(
+            parse_ok = ok,
+            parse_err = err,
+            "Parsed {ok} spots, {err} failed ({:.1} %)",
+            ratio * 100.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::Metrics;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let now = SystemTime::now();
+        assert_eq!(metrics.parse_failure_ratio(), None);
+        assert!(metrics
+            .render(now)
+            .contains("\npuskapupu_parse_failure_ratio 0\n"));
+
+        metrics.parse_ok.fetch_add(3, Ordering::Relaxed);
+        metrics.parse_err.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(metrics.parse_failure_ratio(), Some(0.25));
+        let rendered = metrics.render(now);
+        assert!(rendered
+            .contains("# TYPE puskapupu_parse_ok_total counter\npuskapupu_parse_ok_total 3\n"));
+        assert!(rendered.contains("\npuskapupu_parse_err_total 1\n"));
+        assert!(rendered.contains("\npuskapupu_parse_failure_ratio 0.25\n"));
+        assert!(!rendered.contains("sink"));
+        assert!(!rendered.contains("puskapupu_cluster"));
+
+        let health = metrics.sink("matrix @puskapupu:example.org");
+        health.sent.fetch_add(2, Ordering::Relaxed);
+        metrics
+            .sink("mqtt")
+            .consecutive_failures
+            .store(1, Ordering::Relaxed);
+        assert!(Arc::ptr_eq(
+            &health,
+            &metrics.sink("matrix @puskapupu:example.org")
+        ));
+        let rendered = metrics.render(now);
+        assert!(rendered.contains(
+            "# TYPE puskapupu_sink_sent_total counter\n\
+             puskapupu_sink_sent_total{sink=\"matrix @puskapupu:example.org\"} 2\n\
+             puskapupu_sink_sent_total{sink=\"mqtt\"} 0\n"
+        ));
+        assert!(rendered.contains("\npuskapupu_sink_up{sink=\"mqtt\"} 0\n"));
+    }
+
+    #[test]
+    fn test_cluster_stats() {
+        let metrics = Metrics::default();
+        let start = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        let minutes = |n: u64| start + Duration::from_secs(n * 60);
+        let stats = metrics.cluster("www.cqgma.org:7300");
+        assert_eq!(stats.uptime(start), None);
+
+        stats.failed(&"connection refused");
+        stats.connected(minutes(1));
+        assert_eq!(stats.reconnects.load(Ordering::Relaxed), 0);
+        stats.spot(minutes(2));
+        stats.spot(minutes(3));
+        stats.spot(minutes(3) + Duration::from_secs(30));
+        assert_eq!(stats.uptime(minutes(4)), Some(Duration::from_secs(3 * 60)));
+        assert_eq!(stats.spots_per_minute(minutes(4)), 1);
+        assert_eq!(stats.spots.load(Ordering::Relaxed), 3);
+
+        stats.failed(&"connection lost");
+        assert_eq!(stats.uptime(minutes(5)), None);
+        stats.connected(minutes(6));
+        assert_eq!(stats.reconnects.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.last_error().as_deref(), Some("connection lost"));
+        assert!(Arc::ptr_eq(&stats, &metrics.cluster("www.cqgma.org:7300")));
+
+        metrics.cluster("dxc.example.org:7300");
+        let rendered = metrics.render(minutes(16));
+        assert!(rendered.contains(
+            "# TYPE puskapupu_cluster_up gauge\n\
+             puskapupu_cluster_up{cluster=\"www.cqgma.org:7300\"} 1\n\
+             puskapupu_cluster_up{cluster=\"dxc.example.org:7300\"} 0\n"
+        ));
+        assert!(rendered
+            .contains("\npuskapupu_cluster_uptime_seconds{cluster=\"www.cqgma.org:7300\"} 600\n"));
+        assert!(rendered
+            .contains("\npuskapupu_cluster_reconnects_total{cluster=\"www.cqgma.org:7300\"} 1\n"));
+        assert!(rendered
+            .contains("\npuskapupu_cluster_spots_total{cluster=\"www.cqgma.org:7300\"} 3\n"));
+        assert!(rendered
+            .contains("\npuskapupu_cluster_spots_per_minute{cluster=\"www.cqgma.org:7300\"} 0\n"));
+    }
+}
@@ -0,0 +1,304 @@
+//! Publishing spots as JSON to MQTT broker.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Outgoing, Packet, QoS};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::MqttConfig;
+use crate::metrics::{Metrics, SinkHealth};
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+use crate::template::{Format, Template};
+
+/// Spots waiting to be sent, eg. while reconnecting. Spots are dropped when
+/// the queue is full.
+const QUEUE_CAPACITY: usize = 64;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Wait time after the connection fails. Doubled after each failure.
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(5 * 60);
+
+pub struct MqttSink {
+    name: String,
+    client: AsyncClient,
+    topic: Template,
+    qos: QoS,
+    /// Spots are serialized as JSON without a template
+    payload: Option<(Template, Format)>,
+    /// Counts spots the broker has acknowledged, see [drive]
+    health: Arc<SinkHealth>,
+}
+
+impl MqttSink {
+    /// The sink and the connection which must be polled for anything to be
+    /// sent, see [drive].
+    pub fn new(config: &MqttConfig, metrics: &Metrics) -> io::Result<(Self, EventLoop)> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+        let (host, port) = config
+            .broker_addr()
+            .ok_or_else(|| invalid("invalid broker URL"))?;
+        let mut options = MqttOptions::new(&config.client_id, host, port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let qos = rumqttc::qos(config.qos).map_err(|_| invalid("invalid QoS"))?;
+        let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        let name = format!("mqtt {}", config.broker);
+        let sink = Self {
+            health: metrics.sink(&name),
+            name,
+            client,
+            topic: config.topic.clone(),
+            qos,
+            payload: config
+                .template
+                .clone()
+                .map(|template| (template, config.format)),
+        };
+        Ok((sink, eventloop))
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queue `spot` for [drive], which counts it as sent once the broker
+    /// has it. Fails if the queue is full.
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let payload = match &self.payload {
+            Some((template, format)) => template.render_as(spot, *format).into_bytes(),
+            None => serde_json::to_vec(spot)?,
+        };
+        let result = self
+            .client
+            .try_publish(self.topic.render(spot), self.qos, false, payload)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        if result.is_err() {
+            self.health.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        self.client
+            .try_disconnect()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+}
+
+/// Publish spots from `spots` to the broker of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: MqttConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let (sink, eventloop) = MqttSink::new(&config, &metrics)?;
+    let (qos, sent) = (sink.qos, sink.health.clone());
+    // Sending only queues, so the counters are kept by the sink and drive
+    let health = Arc::new(SinkHealth::default());
+    let closed = CancellationToken::new();
+    let publish = async {
+        let result = sink::run(Box::new(sink), spots, health, shutdown).await;
+        closed.cancel();
+        result
+    };
+    let driven = drive(eventloop, qos, &sent, closed.clone());
+    let (result, ()) = tokio::join!(publish, driven);
+    result
+}
+
+/// Keep the connection to the broker up, reconnecting with backoff when it
+/// is lost. Returns after disconnecting once `closed` is cancelled.
+///
+/// Spots published with `qos` are counted as sent in `health` once the
+/// broker acknowledges them, or for QoS 0 once they are written to it. A
+/// lost connection counts as a failure.
+pub async fn drive(
+    mut eventloop: EventLoop,
+    qos: QoS,
+    health: &SinkHealth,
+    closed: CancellationToken,
+) {
+    let sent = || {
+        health.sent.fetch_add(1, Ordering::Relaxed);
+        health.consecutive_failures.store(0, Ordering::Relaxed);
+    };
+    let mut backoff = RECONNECT_MIN;
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker");
+                backoff = RECONNECT_MIN;
+            }
+            Ok(Event::Outgoing(Outgoing::Publish(_))) if qos == QoS::AtMostOnce => sent(),
+            Ok(Event::Incoming(Packet::PubAck(_))) if qos == QoS::AtLeastOnce => sent(),
+            Ok(Event::Incoming(Packet::PubComp(_))) if qos == QoS::ExactlyOnce => sent(),
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => return,
+            Ok(_) => (),
+            Err(err) if closed.is_cancelled() => {
+                tracing::warn!("MQTT connection failed while shutting down: {err}");
+                return;
+            }
+            Err(err) => {
+                health.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(
+                    "MQTT connection failed: {err}. Reconnecting in {} seconds.",
+                    backoff.as_secs()
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => (),
+                    _ = closed.cancelled() => return,
+                }
+                backoff = (backoff * 2).min(RECONNECT_MAX);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+    use tokio_util::sync::CancellationToken;
+
+    use super::run;
+    use crate::config::MqttConfig;
+    use crate::metrics::Metrics;
+    use crate::template::{Format, Template};
+
+    const CONNECT: u8 = 1;
+    const PUBLISH: u8 = 3;
+    const PUBACK: u8 = 4;
+    const DISCONNECT: u8 = 14;
+    const CONNACK: [u8; 4] = [0x20, 2, 0, 0];
+
+    /// Type and body of the next MQTT packet.
+    async fn read_packet(socket: &mut TcpStream) -> (u8, Vec<u8>) {
+        let kind = socket.read_u8().await.unwrap() >> 4;
+        let (mut len, mut shift) = (0, 0);
+        loop {
+            let byte = socket.read_u8().await.unwrap();
+            len |= usize::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        socket.read_exact(&mut body).await.unwrap();
+        (kind, body)
+    }
+
+    /// Topic and JSON payload of a QoS 0 publish.
+    async fn read_publish(socket: &mut TcpStream) -> (String, serde_json::Value) {
+        let (kind, body) = read_packet(socket).await;
+        assert_eq!(kind, PUBLISH);
+        let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+        let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+        (topic, serde_json::from_slice(&body[2 + len..]).unwrap())
+    }
+
+    async fn accept(listener: &TcpListener) -> TcpStream {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        assert_eq!(read_packet(&mut socket).await.0, CONNECT);
+        socket.write_all(&CONNACK).await.unwrap();
+        socket
+    }
+
+    fn config(listener: &TcpListener, qos: u8) -> MqttConfig {
+        MqttConfig {
+            broker: format!("mqtt://{}", listener.local_addr().unwrap()),
+            client_id: "test".to_string(),
+            username: None,
+            password: None,
+            topic: Template::parse("puskapupu/spots/{band}").unwrap(),
+            qos,
+            template: None,
+            format: Format::Json,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(&listener, 0);
+        let (tx, rx) = broadcast::channel(16);
+        let shutdown = CancellationToken::new();
+        let metrics = Arc::new(Metrics::default());
+        let handle = tokio::spawn(run(config, rx, metrics.clone(), shutdown.clone()));
+
+        let mut socket = accept(&listener).await;
+        let line = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z";
+        tx.send(Arc::new(line.parse().unwrap())).unwrap();
+        let (topic, spot) = read_publish(&mut socket).await;
+        assert_eq!(topic, "puskapupu/spots/80m");
+        assert_eq!(spot["dx"], "OH2NOS/P");
+        assert_eq!(spot["frequency"], 3644.0);
+
+        // Broker goes away and comes back
+        drop(socket);
+        let mut socket = accept(&listener).await;
+        let line = "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z";
+        tx.send(Arc::new(line.parse().unwrap())).unwrap();
+        let (topic, spot) = read_publish(&mut socket).await;
+        assert_eq!(topic, "puskapupu/spots/20m");
+        assert_eq!(spot["dx"], "AD6VT");
+
+        shutdown.cancel();
+        assert_eq!(read_packet(&mut socket).await.0, DISCONNECT);
+        handle.await.unwrap().unwrap();
+        let health = metrics.sink(&format!("mqtt mqtt://{}", listener.local_addr().unwrap()));
+        assert_eq!(health.sent.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sent_when_acknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(&listener, 1);
+        let name = format!("mqtt {}", config.broker);
+        let (tx, rx) = broadcast::channel(16);
+        let shutdown = CancellationToken::new();
+        let metrics = Arc::new(Metrics::default());
+        let handle = tokio::spawn(run(config, rx, metrics.clone(), shutdown.clone()));
+
+        let mut socket = accept(&listener).await;
+        let line = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z";
+        tx.send(Arc::new(line.parse().unwrap())).unwrap();
+        let (kind, body) = read_packet(&mut socket).await;
+        assert_eq!(kind, PUBLISH);
+        let health = metrics.sink(&name);
+        assert_eq!(health.sent.load(Ordering::Relaxed), 0);
+
+        // Packet id follows the topic
+        let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+        let id = &body[2 + len..4 + len];
+        socket
+            .write_all(&[PUBACK << 4, 2, id[0], id[1]])
+            .await
+            .unwrap();
+        while health.sent.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        shutdown.cancel();
+        assert_eq!(read_packet(&mut socket).await.0, DISCONNECT);
+        handle.await.unwrap().unwrap();
+        assert_eq!(health.sent.load(Ordering::Relaxed), 1);
+    }
+}
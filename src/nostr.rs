@@ -0,0 +1,442 @@
+//! Publishing spots as Nostr notes (NIP-01) to relays.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt};
+use k256::schnorr::SigningKey;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{NostrConfig, DEFAULT_NOSTR_TEMPLATE};
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+use crate::template::Template;
+
+/// Kind of short text notes.
+const KIND_TEXT_NOTE: u32 = 1;
+/// Time for connecting to a relay.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time for a relay to tell if it accepted an event.
+const OK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before connecting again to a relay after the first failure.
+/// Doubled after each failure.
+const RECONNECT_MIN: Duration = Duration::from_secs(5);
+/// Longest wait between connection attempts to a relay.
+const RECONNECT_MAX: Duration = Duration::from_secs(10 * 60);
+
+type Connection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Signed event as sent to relays.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+impl Event {
+    /// Text note of `content` signed with `key`. `aux_rand` is mixed into
+    /// the signature as BIP-340 recommends and should be random.
+    pub fn note(
+        key: &SigningKey,
+        created_at: u64,
+        tags: Vec<Vec<String>>,
+        content: String,
+        aux_rand: &[u8; 32],
+    ) -> io::Result<Self> {
+        let pubkey = hex(&key.verifying_key().to_bytes());
+        let serialized = json!([0, pubkey, created_at, KIND_TEXT_NOTE, tags, content]).to_string();
+        let id = Sha256::digest(serialized.as_bytes());
+        let sig = key
+            .sign_raw(&id, aux_rand)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(Self {
+            id: hex(&id),
+            pubkey,
+            created_at,
+            kind: KIND_TEXT_NOTE,
+            tags,
+            content,
+            sig: hex(&sig.to_bytes()),
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Secret key as 64 hex digits or `nsec1...` as in NIP-19.
+pub fn parse_secret_key(key: &str) -> Option<SigningKey> {
+    let bytes = match key.strip_prefix("nsec1") {
+        Some(_) => bech32_data(key, "nsec")?,
+        None if key.len() == 64 => (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(key.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?,
+        None => return None,
+    };
+    SigningKey::from_bytes(&bytes).ok()
+}
+
+/// Data of bech32 string `s` with human readable part `hrp`, see BIP-173.
+fn bech32_data(s: &str, hrp: &str) -> Option<Vec<u8>> {
+    const CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const GENERATOR: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+    let s = s.to_ascii_lowercase();
+    let (prefix, data) = s.rsplit_once('1')?;
+    if prefix != hrp || data.len() < 6 {
+        return None;
+    }
+    let values = data
+        .bytes()
+        .map(|c| CHARSET.iter().position(|&d| d == c).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()?;
+
+    let mut checksum = 1u32;
+    let expanded = hrp.bytes().map(|c| c >> 5).chain([0]);
+    let expanded = expanded.chain(hrp.bytes().map(|c| c & 31));
+    for value in expanded.chain(values.iter().copied()) {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x01ff_ffff) << 5) ^ u32::from(value);
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    if checksum != 1 {
+        return None;
+    }
+
+    // From 5 to 8 bits, leftover bits must be zero padding
+    let (mut bytes, mut acc, mut bits) = (Vec::new(), 0u32, 0);
+    for value in &values[..values.len() - 6] {
+        acc = (acc << 5) | u32::from(*value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    (bits < 5 && acc & ((1 << bits) - 1) == 0).then_some(bytes)
+}
+
+/// Connection to one relay, reconnected with backoff.
+struct Relay {
+    url: String,
+    connection: Option<Connection>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+impl Relay {
+    /// Connected relay, unless it failed recently.
+    async fn connection(&mut self) -> Option<&mut Connection> {
+        if self.connection.is_none() {
+            if self.retry_at.map_or(false, |at| Instant::now() < at) {
+                return None;
+            }
+            let connect = tokio_tungstenite::connect_async(self.url.as_str());
+            match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+                Ok(Ok((connection, _))) => {
+                    tracing::info!("Connected to Nostr relay {}", self.url);
+                    self.connection = Some(connection);
+                    self.retry_at = None;
+                    self.backoff = RECONNECT_MIN;
+                }
+                result => {
+                    let err = match result {
+                        Ok(Err(err)) => err.to_string(),
+                        _ => "timed out".to_string(),
+                    };
+                    tracing::warn!(
+                        "Couldn't connect to Nostr relay {}: {err}. Trying again in {} seconds.",
+                        self.url,
+                        self.backoff.as_secs()
+                    );
+                    self.failed();
+                }
+            }
+        }
+        self.connection.as_mut()
+    }
+
+    fn failed(&mut self) {
+        self.connection = None;
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(RECONNECT_MAX);
+    }
+
+    /// Send `event` and wait for the relay to answer.
+    async fn publish(&mut self, event: &Event) -> io::Result<()> {
+        let not_connected = || io::Error::new(io::ErrorKind::NotConnected, "not connected");
+        let connection = self.connection().await.ok_or_else(not_connected)?;
+        let message = json!(["EVENT", event]).to_string();
+        let answer = async {
+            connection.send(Message::Text(message)).await?;
+            while let Some(message) = connection.next().await {
+                let Message::Text(text) = message? else {
+                    continue;
+                };
+                // ["OK", <event id>, <accepted>, <message>]
+                let answer: Value = serde_json::from_str(&text).unwrap_or_default();
+                if answer[0] == "OK" && answer[1] == event.id.as_str() {
+                    return Ok(answer);
+                }
+            }
+            Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed)
+        };
+        match tokio::time::timeout(OK_TIMEOUT, answer).await {
+            Ok(Ok(answer)) => {
+                if answer[2] != true {
+                    tracing::warn!("Nostr relay {} rejected spot: {}", self.url, answer[3]);
+                }
+                Ok(())
+            }
+            Ok(Err(err)) => {
+                self.failed();
+                Err(io::Error::new(io::ErrorKind::Other, err))
+            }
+            Err(_) => {
+                self.failed();
+                Err(io::Error::new(io::ErrorKind::TimedOut, "no answer"))
+            }
+        }
+    }
+}
+
+pub struct NostrSink {
+    name: String,
+    key: SigningKey,
+    template: Template,
+    relays: Mutex<Vec<Relay>>,
+}
+
+impl NostrSink {
+    pub fn new(config: &NostrConfig) -> io::Result<Self> {
+        let key = parse_secret_key(&config.secret_key).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid Nostr secret key")
+        })?;
+        let template = config.template.clone().unwrap_or_else(|| {
+            Template::parse(DEFAULT_NOSTR_TEMPLATE).expect("default template is valid")
+        });
+        let relays = config
+            .relays
+            .iter()
+            .map(|url| Relay {
+                url: url.clone(),
+                connection: None,
+                retry_at: None,
+                backoff: RECONNECT_MIN,
+            })
+            .collect();
+        Ok(Self {
+            name: format!("nostr {}", hex(&key.verifying_key().to_bytes())),
+            key,
+            template,
+            relays: Mutex::new(relays),
+        })
+    }
+
+    /// Note of `spot` tagged with its band and activity, eg. `#20m` and
+    /// `#sota`.
+    fn event(&self, spot: &DxEntry, created_at: u64, aux_rand: &[u8; 32]) -> io::Result<Event> {
+        let band = spot.band().map(|band| band.name());
+        let activity = spot.cqgma_identifier.map(|(activity, _)| activity.name());
+        let tags = band
+            .into_iter()
+            .chain(activity)
+            .map(|tag| vec!["t".to_string(), tag.to_string()])
+            .collect();
+        Event::note(
+            &self.key,
+            created_at,
+            tags,
+            self.template.render(spot),
+            aux_rand,
+        )
+    }
+}
+
+#[async_trait]
+impl Sink for NostrSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Fails only if no relay got the spot.
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let event = self.event(spot, now, &rand::random())?;
+        let mut relays = self.relays.lock().await;
+        let mut result = Err(io::Error::new(io::ErrorKind::NotConnected, "no relays"));
+        for relay in relays.iter_mut() {
+            match relay.publish(&event).await {
+                Ok(()) => result = Ok(()),
+                Err(err) => {
+                    tracing::debug!("Couldn't publish spot to {}: {err}", relay.url);
+                    if result.is_err() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        for relay in self.relays.lock().await.iter_mut() {
+            if let Some(mut connection) = relay.connection.take() {
+                let _ = connection.close(None).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Publish spots from `spots` to the relays of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: NostrConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = NostrSink::new(&config)?;
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use k256::schnorr::{Signature, VerifyingKey};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::{parse_secret_key, NostrSink};
+    use crate::config::NostrConfig;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+
+    /// Key of the NIP-19 example, `nsec1vl029mgpspedva04g90vltkh6fvh240zqtv9k0t9af8935ke9laqsnlfe5`
+    const SECRET_KEY: &str = "67dea2ed018072d675f5415ecfaed7d2597555e202d85b3d65ea4e58d2d92ffa";
+
+    fn config(relays: Vec<String>) -> NostrConfig {
+        NostrConfig {
+            relays,
+            secret_key: SECRET_KEY.to_string(),
+            template: None,
+        }
+    }
+
+    fn spot() -> DxEntry {
+        "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_signed_event() {
+        let nsec = "nsec1vl029mgpspedva04g90vltkh6fvh240zqtv9k0t9af8935ke9laqsnlfe5";
+        assert_eq!(
+            parse_secret_key(nsec).unwrap().to_bytes(),
+            parse_secret_key(SECRET_KEY).unwrap().to_bytes()
+        );
+        assert!(parse_secret_key(&nsec.replace('5', "6")).is_none());
+        assert!(parse_secret_key("67dea2ed").is_none());
+
+        let sink = NostrSink::new(&config(Vec::new())).unwrap();
+        let event = sink.event(&spot(), 1_709_294_400, &[0; 32]).unwrap();
+        assert_eq!(
+            event.pubkey,
+            "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e"
+        );
+        assert_eq!(event.kind, 1);
+        assert_eq!(event.tags, [["t", "20m"], ["t", "sota"]]);
+        assert_eq!(event.content, "AD6VT 14.310 MHz W6/ND-101 de OH8HUB 1959Z");
+        // Same key, time and randomness give the same event
+        assert_eq!(sink.event(&spot(), 1_709_294_400, &[0; 32]).unwrap(), event);
+
+        let id: Vec<u8> = (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&event.id[i..i + 2], 16).unwrap())
+            .collect();
+        let sig: Vec<u8> = (0..128)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&event.sig[i..i + 2], 16).unwrap())
+            .collect();
+        let key = VerifyingKey::from_bytes(
+            &parse_secret_key(SECRET_KEY)
+                .unwrap()
+                .verifying_key()
+                .to_bytes(),
+        )
+        .unwrap();
+        key.verify_raw(&id, &Signature::try_from(sig.as_slice()).unwrap())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let Some(Ok(Message::Text(text))) = ws.next().await else {
+                panic!("no event");
+            };
+            let message: Value = serde_json::from_str(&text).unwrap();
+            let id = message[1]["id"].clone();
+            let ok = json!(["OK", id, true, ""]).to_string();
+            ws.send(Message::Text(ok)).await.unwrap();
+            message
+        });
+
+        // Nothing listens at the first relay
+        let sink = NostrSink::new(&config(vec![
+            "ws://127.0.0.1:1".to_string(),
+            format!("ws://{addr}"),
+        ]))
+        .unwrap();
+        sink.send(&spot()).await.unwrap();
+        let message = relay.await.unwrap();
+        assert_eq!(message[0], "EVENT");
+        assert_eq!(message[1]["kind"], 1);
+        assert_eq!(
+            message[1]["content"],
+            "AD6VT 14.310 MHz W6/ND-101 de OH8HUB 1959Z"
+        );
+        sink.close().await.unwrap();
+
+        // Relay gone and the other one waiting before trying again
+        assert!(sink.send(&spot()).await.is_err());
+    }
+}
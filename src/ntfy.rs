@@ -0,0 +1,298 @@
+//! Push notifications of spots through ntfy, eg. to phones.
+
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Client, Request, Url};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{NtfyConfig, DEFAULT_NTFY_TEMPLATE};
+use crate::lookup::Lookup;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, other, Sink};
+use crate::template::Template;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Priority of spots which aren't `New one!`s, the default of ntfy.
+const DEFAULT_PRIORITY: u8 = 3;
+/// Title of each notification.
+const TITLE_TEMPLATE: &str = "{dx} {reference}";
+
+pub struct NtfySink {
+    name: String,
+    client: Client,
+    url: Url,
+    token: Option<HeaderValue>,
+    title: Template,
+    template: Template,
+    new_one_priority: u8,
+    /// References are looked up for the title if given
+    lookup: Option<Arc<Lookup>>,
+}
+
+impl NtfySink {
+    /// Sink of `config`, the `index`th in the config.
+    pub fn new(config: &NtfyConfig, index: usize, lookup: Option<Arc<Lookup>>) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let mut url =
+            Url::parse(&config.server).map_err(|err| invalid(format!("server: {err}")))?;
+        url.path_segments_mut()
+            .map_err(|_| invalid("server: can't be a base".to_string()))?
+            .pop_if_empty()
+            .push(&config.topic);
+        let token = match &config.token {
+            Some(token) => {
+                let mut token = HeaderValue::try_from(format!("Bearer {token}"))
+                    .map_err(|err| invalid(format!("token: {err}")))?;
+                token.set_sensitive(true);
+                Some(token)
+            }
+            None => None,
+        };
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let template = config.template.clone().unwrap_or_else(|| {
+            Template::parse(DEFAULT_NTFY_TEMPLATE).expect("default template is valid")
+        });
+        Ok(Self {
+            // Logs and metrics must not reveal the topic
+            name: format!("ntfy[{index}]"),
+            client,
+            url,
+            token,
+            title: Template::parse(TITLE_TEMPLATE).expect("title template is valid"),
+            template,
+            new_one_priority: config.new_one_priority,
+            lookup,
+        })
+    }
+
+    /// Priority of `spot`: higher for `New one!`s.
+    fn priority(&self, spot: &DxEntry) -> u8 {
+        if spot.is_new_one() {
+            self.new_one_priority
+        } else {
+            DEFAULT_PRIORITY
+        }
+    }
+
+    /// Request publishing `spot`, titled with the callsign and reference.
+    fn request(&self, spot: &DxEntry) -> io::Result<Request> {
+        let mut headers = HeaderMap::new();
+        let title = encode_header(self.title.render(spot).trim());
+        headers.insert("Title", HeaderValue::try_from(title).map_err(other)?);
+        headers.insert(
+            "Priority",
+            HeaderValue::from(u16::from(self.priority(spot))),
+        );
+        let tags: Vec<&str> = spot
+            .band()
+            .map(|band| band.name())
+            .into_iter()
+            .chain(spot.cqgma_identifier.map(|(activity, _)| activity.name()))
+            .collect();
+        if !tags.is_empty() {
+            headers.insert(
+                "Tags",
+                HeaderValue::try_from(tags.join(",")).map_err(other)?,
+            );
+        }
+        if let Some(token) = &self.token {
+            headers.insert(AUTHORIZATION, token.clone());
+        }
+        self.client
+            .post(self.url.clone())
+            .headers(headers)
+            .body(self.template.render(spot))
+            .build()
+            .map_err(other)
+    }
+}
+
+/// `value` as is if it's printable ASCII, else as an RFC 2047 encoded word
+/// which ntfy decodes, eg. `=?UTF-8?Q?Kuusij=C3=A4rvi?=`.
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        return value.to_string();
+    }
+    let mut out = "=?UTF-8?Q?".to_string();
+    for byte in value.bytes() {
+        match byte {
+            b' ' => out.push('_'),
+            b if b.is_ascii_alphanumeric() || b"-./".contains(&b) => out.push(char::from(b)),
+            b => {
+                let _ = write!(out, "={b:02X}");
+            }
+        }
+    }
+    out.push_str("?=");
+    out
+}
+
+#[async_trait]
+impl Sink for NtfySink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let request = match &self.lookup {
+            Some(lookup) => self.request(&lookup.enrich(spot).await)?,
+            None => self.request(spot)?,
+        };
+        let response = self
+            .client
+            .execute(request)
+            .await
+            .map_err(|err| other(err.without_url()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let text = response.text().await.unwrap_or_default();
+        Err(other(format!("HTTP {status} {}", text.trim())))
+    }
+}
+
+/// Publish spots from `spots` to the topic of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: NtfyConfig,
+    index: usize,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    lookup: Option<Arc<Lookup>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = NtfySink::new(&config, index, lookup)?;
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::{encode_header, NtfySink};
+    use crate::config::NtfyConfig;
+    use crate::lookup::ReferenceInfo;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+    use crate::testutil;
+
+    fn config(token: Option<&str>) -> NtfyConfig {
+        NtfyConfig {
+            server: "https://ntfy.example.org/".to_string(),
+            topic: "ohff_spots".to_string(),
+            token: token.map(str::to_string),
+            template: None,
+            new_one_priority: 5,
+        }
+    }
+
+    fn body(request: &reqwest::Request) -> &str {
+        let bytes = request.body().unwrap().as_bytes().unwrap();
+        std::str::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_request() {
+        let sink = NtfySink::new(&config(Some("tk_hunter2")), 0, None).unwrap();
+        assert_eq!(sink.name(), "ntfy[0]");
+        let mut spot: DxEntry =
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+                .parse()
+                .unwrap();
+        let request = sink.request(&spot).unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(
+            request.url().as_str(),
+            "https://ntfy.example.org/ohff_spots"
+        );
+        let headers = request.headers();
+        assert_eq!(headers["title"], "OH2NOS/P OHFF-1419");
+        assert_eq!(headers["priority"], "5");
+        assert_eq!(headers["tags"], "80m,wwff");
+        assert_eq!(headers["authorization"], "Bearer tk_hunter2");
+        assert_eq!(body(&request), "3.644 MHz (80m) de OH2NOS 1146Z");
+
+        spot.info = "OHFF-1419 CW".to_string();
+        spot.reference_info = Some(ReferenceInfo {
+            code: "OHFF-1419".to_string(),
+            name: "Kuusijärvi".to_string(),
+            details: None,
+            location: None,
+        });
+        let request = sink.request(&spot).unwrap();
+        assert_eq!(request.headers()["priority"], "3");
+        assert_eq!(
+            request.headers()["title"],
+            "=?UTF-8?Q?OH2NOS/P_OHFF-1419_Kuusij=C3=A4rvi?="
+        );
+
+        let sink = NtfySink::new(&config(None), 0, None).unwrap();
+        assert!(!sink
+            .request(&spot)
+            .unwrap()
+            .headers()
+            .contains_key("authorization"));
+    }
+
+    #[tokio::test]
+    async fn test_send_looks_up_reference() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = config(None);
+        config.server = format!("http://{}/", listener.local_addr().unwrap());
+        let lookup = testutil::lookup(vec![ReferenceInfo {
+            code: "OHFF-1419".to_string(),
+            name: "Kuusijärvi".to_string(),
+            details: None,
+            location: None,
+        }]);
+        let sink = NtfySink::new(&config, 0, Some(lookup)).unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                socket.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                headers.push(line.trim_end().to_lowercase());
+            }
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            headers
+        });
+
+        let spot: DxEntry =
+            "DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 CW              1150Z"
+                .parse()
+                .unwrap();
+        sink.send(&spot).await.unwrap();
+        let headers = server.await.unwrap();
+        assert!(
+            headers.contains(&"title: =?utf-8?q?oh2nos/p_ohff-1419_kuusij=c3=a4rvi?=".to_string()),
+            "{headers:?}"
+        );
+    }
+
+    #[test]
+    fn test_encode_header() {
+        assert_eq!(encode_header("AD6VT W6/ND-101"), "AD6VT W6/ND-101");
+        assert_eq!(encode_header("Ä=?"), "=?UTF-8?Q?=C3=84=3D=3F?=");
+    }
+}
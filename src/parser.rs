@@ -1,8 +1,20 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use chumsky::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+use crate::band::Band;
+use crate::utc;
+
+/// Modes recognized in spot info by [DxEntry::mode].
+pub const MODES: &[&str] = &[
+    "CW", "SSB", "USB", "LSB", "FM", "AM", "FT8", "FT4", "JS8", "RTTY", "PSK31",
+];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct DxEntry {
     pub reporter: String,
     pub frequency: f32,
@@ -10,13 +22,160 @@ pub struct DxEntry {
     pub cqgma_identifier: Option<(Activity, Source)>,
     pub info: String,
     pub timestamp: String,
+    /// Maidenhead locator of the reporter, if given
+    pub grid: Option<String>,
+    /// Details of the reference from [crate::lookup], if looked up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference_info: Option<crate::lookup::ReferenceInfo>,
+    /// Line as received from the cluster
+    #[serde(skip)]
+    pub line: String,
+}
+
+impl DxEntry {
+    pub fn band(&self) -> Option<Band> {
+        Band::from_khz(self.frequency)
+    }
+
+    /// Frequency in MHz, eg. `14.044 MHz`. At least three decimals are shown
+    /// and a fourth one if there are hundreds of Hz.
+    pub fn frequency_mhz_string(&self) -> String {
+        let hundreds_hz = (f64::from(self.frequency) * 10.0).round() as u64;
+        let (mhz, rest) = (hundreds_hz / 10_000, hundreds_hz % 10_000);
+        if rest % 10 == 0 {
+            format!("{mhz}.{:03} MHz", rest / 10)
+        } else {
+            format!("{mhz}.{rest:04} MHz")
+        }
+    }
+
+    /// Country of the spotted station from the [installed](crate::cty::install)
+    /// `cty.dat`.
+    pub fn dxcc(&self) -> Option<crate::cty::Dxcc> {
+        crate::cty::installed()?.resolve(&self.dx).cloned()
+    }
+
+    /// Parse a cluster line like [str::parse], but tell what went wrong.
+    pub fn parse_line(s: &str) -> Result<Self, String> {
+        let mut entry = dxspider_parser().parse(s).map_err(|errs| {
+            let errs: Vec<String> = errs.iter().map(ToString::to_string).collect();
+            errs.join("; ")
+        })?;
+        entry.line = s.to_string();
+        Ok(entry)
+    }
+
+    /// Span for following the spot in logs. Each copy of the spot has the
+    /// same [id](DxEntry::id), so its way through every sink can be found.
+    pub fn span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "spot",
+            id = %self.id(),
+            dx = %self.dx,
+            frequency = self.frequency,
+            reporter = %self.reporter
+        )
+    }
+
+    /// Short hash of the spot for [DxEntry::span], eg. `5f0e2a1c`.
+    pub fn id(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        (&self.reporter, &self.dx, &self.info, &self.timestamp).hash(&mut hasher);
+        self.frequency.to_bits().hash(&mut hasher);
+        format!("{:08x}", hasher.finish() as u32)
+    }
+
+    /// Minutes since UTC midnight when the spot was made.
+    pub fn minute_of_day(&self) -> Option<u16> {
+        if self.timestamp.len() != 4 || !self.timestamp.is_ascii() {
+            return None;
+        }
+        let (hours, minutes) = self.timestamp.split_at(2);
+        let (hours, minutes): (u16, u16) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+    }
+
+    /// UTC date of the spot received at `received`. A spot timed later in
+    /// the day than it was received was made before midnight.
+    pub fn date(&self, received: SystemTime) -> (i64, u32, u32) {
+        let late = self
+            .minute_of_day()
+            .map_or(false, |minute| minute > utc::minute_of_day(received));
+        if late {
+            utc::date(received - Duration::from_secs(utc::SECS_PER_DAY))
+        } else {
+            utc::date(received)
+        }
+    }
+
+    /// First of [MODES] mentioned in info, eg. `CW`.
+    pub fn mode(&self) -> Option<&'static str> {
+        self.info
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .find_map(|word| MODES.iter().find(|mode| mode.eq_ignore_ascii_case(word)))
+            .copied()
+    }
+
+    /// Whether the reporter marked the spot as a new reference for them,
+    /// eg. `OHFF-1419 New one!`.
+    pub fn is_new_one(&self) -> bool {
+        self.info.to_ascii_lowercase().contains("new one")
+    }
+
+    /// Program references mentioned in info, eg. `OHFF-1419`, `OH-0123` or
+    /// `HB/BL-001`, uppercased.
+    pub fn references(&self) -> Vec<String> {
+        self.info
+            .split(|c: char| c.is_whitespace() || c == ',' || c == '.')
+            .filter(|word| is_reference(word))
+            .map(str::to_uppercase)
+            .collect()
+    }
+
+    /// Key identifying spots of the same activation. Different reporters
+    /// spotting the same station on the same frequency share this key.
+    pub fn dedup_key(&self) -> DedupKey {
+        DedupKey {
+            dx: self.dx.to_uppercase(),
+            frequency: self.frequency.round() as u32,
+        }
+    }
+}
+
+/// Program prefix and number separated by `-`, eg. `OHFF-1419`.
+fn is_reference(word: &str) -> bool {
+    let Some((program, number)) = word.rsplit_once('-') else {
+        return false;
+    };
+    program.starts_with(|c: char| c.is_ascii_alphabetic())
+        && program
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '/')
+        && (3..=5).contains(&number.len())
+        && number.chars().all(|c| c.is_ascii_digit())
+}
+
+/// See [DxEntry::dedup_key].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DedupKey {
+    pub dx: String,
+    /// Frequency in whole kHz
+    pub frequency: u32,
+}
+
+/// Longest part of `callsign`, eg. `OH2NOS` of `OH2NOS/P`.
+pub fn base_callsign(callsign: &str) -> &str {
+    callsign
+        .split('/')
+        .max_by_key(|part| part.len())
+        .unwrap_or(callsign)
 }
 
 impl FromStr for DxEntry {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        dxspider_parser().parse(s).map_err(|_| ())
+        DxEntry::parse_line(s).map_err(|_| ())
     }
 }
 
@@ -31,7 +190,8 @@ impl FromStr for DxEntry {
 // X07 = RDA                   |
 // x08 = AGCW                  |
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Activity {
     /// Flora & Fauna
     Wwff,
@@ -49,6 +209,22 @@ pub enum Activity {
     Agcw,
 }
 
+impl Activity {
+    /// Name as used in config files.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Activity::Wwff => "wwff",
+            Activity::Iota => "iota",
+            Activity::Cota => "cota",
+            Activity::Sota => "sota",
+            Activity::Gma => "gma",
+            Activity::Lighthouses => "lighthouses",
+            Activity::Rda => "rda",
+            Activity::Agcw => "agcw",
+        }
+    }
+}
+
 impl FromStr for Activity {
     type Err = ();
 
@@ -67,7 +243,8 @@ impl FromStr for Activity {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Source {
     DxCluster,
     SmartWwff,
@@ -148,6 +325,16 @@ fn dxspider_parser() -> impl Parser<char, DxEntry, Error = Simple<char>> {
 
     let timestamp = text::digits(10).then_ignore(just("Z"));
 
+    let grid = filter(|c: &char| c.is_ascii_alphanumeric())
+        .repeated()
+        .at_least(4)
+        .at_most(6)
+        .collect()
+        .try_map(|s: String, span| match crate::geo::grid_to_latlon(&s) {
+            Some(_) => Ok(s),
+            None => Err(Simple::custom(span, "invalid grid")),
+        });
+
     just("DX de")
         .ignored()
         .then(callsign.padded())
@@ -157,7 +344,9 @@ fn dxspider_parser() -> impl Parser<char, DxEntry, Error = Simple<char>> {
         .then(cqgma_identifier.padded().or_not())
         .then(info.padded())
         .then(timestamp.padded())
+        .then(grid.padded().or_not())
         .map(|value| {
+            let (value, grid) = value;
             let (value, timestamp) = value;
             let (value, info) = value;
             let (value, cqgma_identifier) = value;
@@ -172,13 +361,18 @@ fn dxspider_parser() -> impl Parser<char, DxEntry, Error = Simple<char>> {
                 cqgma_identifier,
                 info,
                 timestamp,
+                grid,
+                reference_info: None,
+                line: String::new(),
             }
         })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::dxspider_parser;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{dxspider_parser, DxEntry};
     use chumsky::Parser;
 
     const TEST: &[&str] = &[
@@ -283,4 +477,71 @@ mod tests {
             dbg!(entry);
         }
     }
+
+    #[test]
+    fn test_frequency_mhz_string() {
+        let mhz = |line: &str| line.parse::<DxEntry>().unwrap().frequency_mhz_string();
+        assert_eq!(mhz(TEST[0]), "14.044 MHz");
+        assert_eq!(mhz(TEST[1]), "14.0741 MHz");
+        assert_eq!(mhz(TEST[2]), "3.567 MHz");
+        assert_eq!(mhz(TEST[3]), "10.119 MHz");
+        assert_eq!(mhz(TEST[8]), "145.525 MHz");
+        assert_eq!(mhz(TEST[37]), "7.090 MHz");
+        assert_eq!(mhz(TEST[40]), "7.0245 MHz");
+    }
+
+    #[test]
+    fn test_references() {
+        let refs = |line: &str| line.parse::<DxEntry>().unwrap().references();
+        assert_eq!(refs(TEST[0]), vec!["HB/BL-001"]);
+        assert_eq!(refs(TEST[1]), vec!["OC-001"]);
+        assert_eq!(refs(TEST[3]), vec!["DLFF-0794", "DA/SX-398"]);
+        assert_eq!(refs(TEST[35]), vec!["OHFF-1419"]);
+        let entry: DxEntry =
+            "DX de OH8HUB:     7090.0  OH8HUB       ft8 -12 dB 1234 Hz            1147Z"
+                .parse()
+                .unwrap();
+        assert!(entry.references().is_empty());
+    }
+
+    #[test]
+    fn test_mode_and_date() {
+        let entry: DxEntry =
+            "DX de OH8HUB:     7090.0  OH8HUB       ft8 -12 dB 1234 Hz            2359Z"
+                .parse()
+                .unwrap();
+        assert_eq!(entry.mode(), Some("FT8"));
+        // 2024-03-01 00:01 UTC
+        let received = UNIX_EPOCH + Duration::from_secs(1_709_251_260);
+        assert_eq!(entry.date(received), (2024, 2, 29));
+        assert!(!entry.is_new_one());
+        let entry: DxEntry = TEST[35].parse().unwrap();
+        assert_eq!(entry.mode(), None);
+        assert!(entry.is_new_one());
+        assert_eq!(
+            entry.date(received + Duration::from_secs(12 * 60 * 60)),
+            (2024, 3, 1)
+        );
+    }
+
+    #[test]
+    fn test_dedup_key() {
+        let a: DxEntry = TEST[35].parse().unwrap();
+        let b: DxEntry =
+            "DX de OK1VEI:     3644.2  oh2nos/p     x01d ohff-1419                 1147Z"
+                .parse()
+                .unwrap();
+        let c: DxEntry = TEST[48].parse().unwrap();
+        assert_eq!(a.dedup_key(), b.dedup_key());
+        assert_ne!(a.dedup_key(), c.dedup_key());
+    }
+
+    #[test]
+    fn test_id() {
+        let a: DxEntry = TEST[35].parse().unwrap();
+        let b: DxEntry = TEST[48].parse().unwrap();
+        assert_eq!(a.id(), a.clone().id());
+        assert_eq!(a.id().len(), 8);
+        assert_ne!(a.id(), b.id());
+    }
 }
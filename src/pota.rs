@@ -0,0 +1,168 @@
+//! Park names and locations from the POTA API.
+
+use std::io;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+
+use crate::lookup::{Directory, ReferenceInfo};
+use crate::parser::{Activity, DxEntry};
+
+pub const API_URL: &str = "https://api.pota.app";
+
+/// Park as returned by `/park/{reference}`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Park {
+    reference: String,
+    name: String,
+    parktype_desc: Option<String>,
+    location_desc: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+impl From<Park> for ReferenceInfo {
+    fn from(park: Park) -> Self {
+        let name = match park.parktype_desc {
+            Some(kind) if !kind.is_empty() => format!("{} {kind}", park.name),
+            _ => park.name,
+        };
+        ReferenceInfo {
+            code: park.reference,
+            name,
+            details: park.location_desc.filter(|location| !location.is_empty()),
+            location: park.latitude.zip(park.longitude),
+        }
+    }
+}
+
+pub struct PotaDirectory {
+    client: Client,
+    url: String,
+}
+
+impl PotaDirectory {
+    /// Directory at `url`, usually [API_URL].
+    pub fn new(url: &str, timeout: Duration) -> io::Result<Self> {
+        let client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Directory for PotaDirectory {
+    fn name(&self) -> &str {
+        "pota"
+    }
+
+    /// Parks have no `/` in them, eg. `OH-0001` or `KFF-5750`.
+    fn handles(&self, spot: &DxEntry, reference: &str) -> bool {
+        let activity = spot.cqgma_identifier.map(|(activity, _)| activity);
+        matches!(activity, None | Some(Activity::Wwff)) && !reference.contains('/')
+    }
+
+    async fn fetch(&self, reference: &str) -> io::Result<Option<ReferenceInfo>> {
+        let to_io = |err: reqwest::Error| io::Error::new(io::ErrorKind::Other, err);
+        let response = self
+            .client
+            .get(format!("{}/park/{reference}", self.url))
+            .send()
+            .await
+            .map_err(to_io)?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::NO_CONTENT => return Ok(None),
+            status if !status.is_success() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("HTTP {status}"),
+                ))
+            }
+            _ => (),
+        }
+        // Unknown parks are `null`
+        let park: Option<Park> = response.json().await.map_err(to_io)?;
+        Ok(park.map(ReferenceInfo::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::extract::{Path, State};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use super::PotaDirectory;
+    use crate::lookup::Lookup;
+    use crate::parser::DxEntry;
+
+    async fn park(
+        State(requests): State<Arc<AtomicUsize>>,
+        Path(park): Path<String>,
+    ) -> Json<Value> {
+        requests.fetch_add(1, Ordering::Relaxed);
+        if park != "OH-0001" {
+            return Json(Value::Null);
+        }
+        Json(json!({
+            "parkId": 4650,
+            "reference": "OH-0001",
+            "name": "Archipelago",
+            "latitude": 59.9167,
+            "longitude": 21.8,
+            "grid6": "KP09tw",
+            "parktypeDesc": "National Park",
+            "locationDesc": "FI-VS",
+            "active": 1,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_lookup_park() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/park/:park", get(park))
+            .with_state(requests.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let pota = PotaDirectory::new(&format!("http://{addr}"), Duration::from_secs(5)).unwrap();
+        let lookup = Lookup::new(vec![Box::new(pota)], Duration::from_secs(60));
+        let spot: DxEntry =
+            "DX de OH6BG:      7032.0  OH6BG/P      OH-0001 cw                     0815Z"
+                .parse()
+                .unwrap();
+        let info = lookup.enrich(&spot).await.reference_info.unwrap();
+        assert_eq!(
+            info.to_string(),
+            "OH-0001 Archipelago National Park (FI-VS)"
+        );
+        assert_eq!(info.location, Some((59.9167, 21.8)));
+        assert!(lookup.reference_info(&spot).await.is_some());
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Unknown references are remembered too
+        let spot: DxEntry =
+            "DX de K1ABC:     14062.0  W1AW         KFF-5750 cw                    1402Z"
+                .parse()
+                .unwrap();
+        assert_eq!(lookup.reference_info(&spot).await, None);
+        assert_eq!(lookup.reference_info(&spot).await, None);
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,379 @@
+//! Posting spots of POTA parks from the cluster to the POTA spotting API,
+//! so hunters following the POTA site see them too.
+//!
+//! Spots are posted with the token of the operator. A park isn't posted
+//! again until its cooldown has passed, nor when POTA already has a spot of
+//! the activator in the park on the same frequency.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::{Client, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::PotaSpotsConfig;
+use crate::metrics::Metrics;
+use crate::parser::{base_callsign, Activity, DxEntry};
+use crate::sink::{self, other, Sink};
+
+pub const API_URL: &str = "https://api.pota.app";
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Shortest pause between posts, to stay well below the rate limit.
+const MIN_INTERVAL: Duration = Duration::from_secs(10);
+/// Longest pause asked by a rate limited response which is respected.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(15 * 60);
+/// Spots this close in frequency, in kHz, are of the same activation.
+const SAME_FREQUENCY_KHZ: f64 = 2.0;
+/// Shown as the source of the posted spots.
+const SOURCE: &str = "puskapupu";
+
+/// Spot as posted to `/spot` and returned by `/spot/activator`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PotaSpot {
+    pub activator: String,
+    #[serde(default)]
+    pub spotter: String,
+    /// In kHz, eg. `14062.5`
+    pub frequency: String,
+    /// Park, eg. `OH-0001`
+    pub reference: String,
+    #[serde(default)]
+    pub mode: String,
+    #[serde(default)]
+    pub source: String,
+    #[serde(default)]
+    pub comments: String,
+}
+
+impl PotaSpot {
+    /// Whether this is a spot of the same activator in the same park on
+    /// the same frequency as `other`.
+    fn same_activation(&self, other: &PotaSpot) -> bool {
+        let frequency = |spot: &PotaSpot| spot.frequency.trim().parse::<f64>().ok();
+        base_callsign(&self.activator).eq_ignore_ascii_case(base_callsign(&other.activator))
+            && self.reference.eq_ignore_ascii_case(&other.reference)
+            && match (frequency(self), frequency(other)) {
+                (Some(a), Some(b)) => (a - b).abs() < SAME_FREQUENCY_KHZ,
+                // Can't tell, so rather don't post
+                _ => true,
+            }
+    }
+}
+
+/// Whether `reference` looks like a park, eg. `OH-0001` or `US-12345`.
+/// WWFF references like `KFF-5750` and summits like `HB/BL-001` aren't.
+pub fn is_park(reference: &str) -> bool {
+    let Some((prefix, number)) = reference.split_once('-') else {
+        return false;
+    };
+    (1..=3).contains(&prefix.len())
+        && prefix.chars().all(|c| c.is_ascii_alphanumeric())
+        && prefix.chars().any(|c| c.is_ascii_alphabetic())
+        && !(prefix.len() == 3 && prefix.to_ascii_uppercase().ends_with("FF"))
+        && (4..=5).contains(&number.len())
+        && number.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Spot to post of `spot`, spotted by `spotter`, if it mentions a park.
+/// Spots of other programs are left alone even if they mention one.
+pub fn payload(spot: &DxEntry, spotter: &str) -> Option<PotaSpot> {
+    if matches!(spot.cqgma_identifier, Some((activity, _)) if activity != Activity::Wwff) {
+        return None;
+    }
+    let reference = spot.references().into_iter().find(|r| is_park(r))?;
+    let mode = match spot.mode() {
+        Some("USB" | "LSB") => "SSB",
+        Some(mode) => mode,
+        None => "",
+    };
+    Some(PotaSpot {
+        activator: spot.dx.to_uppercase(),
+        spotter: spotter.to_string(),
+        frequency: spot.frequency.to_string(),
+        reference,
+        mode: mode.to_string(),
+        source: SOURCE.to_string(),
+        comments: format!("{} de {}", spot.info.trim(), spot.reporter),
+    })
+}
+
+/// Lets through spots of parks whose cooldown has passed and which aren't
+/// on POTA yet.
+pub struct Cooldown {
+    cooldown: Duration,
+    /// When each park was last posted
+    posted: HashMap<String, SystemTime>,
+}
+
+impl Cooldown {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            posted: HashMap::new(),
+        }
+    }
+
+    /// Whether `spot` should be posted at `now`: its park wasn't posted
+    /// within the cooldown and none of the `current` spots of POTA are of
+    /// the same activation. If so, the park is remembered as posted.
+    pub fn check(&mut self, spot: &PotaSpot, current: &[PotaSpot], now: SystemTime) -> bool {
+        let cooldown = self.cooldown;
+        self.posted.retain(|_, at| {
+            now.duration_since(*at)
+                .map_or(true, |elapsed| elapsed < cooldown)
+        });
+        if self.posted.contains_key(&spot.reference)
+            || current.iter().any(|other| spot.same_activation(other))
+        {
+            return false;
+        }
+        self.posted.insert(spot.reference.clone(), now);
+        true
+    }
+
+    /// Forget that the park of `spot` was posted, eg. when posting failed.
+    pub fn forget(&mut self, spot: &PotaSpot) {
+        self.posted.remove(&spot.reference);
+    }
+}
+
+pub struct PotaSpotsSink {
+    client: Client,
+    url: String,
+    token: HeaderValue,
+    callsign: String,
+    cooldown: Mutex<Cooldown>,
+    /// No posts before this
+    ready_at: Mutex<Option<Instant>>,
+}
+
+impl PotaSpotsSink {
+    /// Sink posting to the API at `url`, usually [API_URL].
+    pub fn new(config: &PotaSpotsConfig, url: &str) -> io::Result<Self> {
+        let mut token = HeaderValue::try_from(format!("Bearer {}", config.token))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("token: {err}")))?;
+        token.set_sensitive(true);
+        let client = Client::builder().timeout(TIMEOUT).build().map_err(other)?;
+        Ok(Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+            token,
+            callsign: config.callsign.to_uppercase(),
+            cooldown: Mutex::new(Cooldown::new(Duration::from_secs(config.cooldown_secs))),
+            ready_at: Mutex::new(None),
+        })
+    }
+
+    /// Request posting `spot`.
+    fn request(&self, spot: &PotaSpot) -> io::Result<Request> {
+        self.client
+            .post(format!("{}/spot", self.url))
+            .header(AUTHORIZATION, self.token.clone())
+            .json(spot)
+            .build()
+            .map_err(other)
+    }
+
+    /// Spots currently on POTA.
+    async fn current(&self) -> io::Result<Vec<PotaSpot>> {
+        let response = self
+            .client
+            .get(format!("{}/spot/activator", self.url))
+            .send()
+            .await
+            .map_err(other)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(other(format!("HTTP {status}")));
+        }
+        response.json().await.map_err(other)
+    }
+
+    async fn post(&self, spot: &PotaSpot) -> io::Result<()> {
+        let ready_at = *self.ready_at.lock().expect("ready_at lock");
+        if let Some(ready_at) = ready_at {
+            tokio::time::sleep_until(ready_at).await;
+        }
+        let response = self
+            .client
+            .execute(self.request(spot)?)
+            .await
+            .map_err(other)?;
+        let status = response.status();
+        let wait = if status == StatusCode::TOO_MANY_REQUESTS {
+            response
+                .headers()
+                .get("retry-after")
+                .and_then(|value| value.to_str().ok()?.parse().ok())
+                .map_or(MAX_RETRY_AFTER, |secs| {
+                    Duration::from_secs(secs).min(MAX_RETRY_AFTER)
+                })
+        } else {
+            MIN_INTERVAL
+        };
+        *self.ready_at.lock().expect("ready_at lock") = Some(Instant::now() + wait);
+        if status.is_success() {
+            return Ok(());
+        }
+        let text = response.text().await.unwrap_or_default();
+        Err(other(format!("HTTP {status} {}", text.trim())))
+    }
+}
+
+#[async_trait]
+impl Sink for PotaSpotsSink {
+    fn name(&self) -> &str {
+        "pota_spots"
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let Some(pota_spot) = payload(spot, &self.callsign) else {
+            return Ok(());
+        };
+        let current = self.current().await?;
+        let new = self.cooldown.lock().expect("cooldown lock").check(
+            &pota_spot,
+            &current,
+            SystemTime::now(),
+        );
+        if !new {
+            tracing::debug!(
+                "{} in {} is already on POTA or cooling down",
+                pota_spot.activator,
+                pota_spot.reference
+            );
+            return Ok(());
+        }
+        let posted = self.post(&pota_spot).await;
+        if posted.is_err() {
+            self.cooldown
+                .lock()
+                .expect("cooldown lock")
+                .forget(&pota_spot);
+        }
+        posted
+    }
+}
+
+/// Post spots of parks from `spots` which aren't on POTA until `shutdown`
+/// is cancelled.
+pub async fn run(
+    config: PotaSpotsConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = PotaSpotsSink::new(&config, API_URL)?;
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{is_park, payload, Cooldown, PotaSpot, PotaSpotsSink};
+    use crate::config::PotaSpotsConfig;
+    use crate::parser::DxEntry;
+
+    fn spot(line: &str) -> DxEntry {
+        DxEntry::parse_line(line).unwrap()
+    }
+
+    #[test]
+    fn test_is_park() {
+        assert!(is_park("OH-0001"));
+        assert!(is_park("US-12345"));
+        assert!(is_park("K-0817"));
+        assert!(!is_park("KFF-5750"));
+        assert!(!is_park("OHFF-1419"));
+        assert!(!is_park("HB/BL-001"));
+        assert!(!is_park("OH-001"));
+    }
+
+    #[test]
+    fn test_request() {
+        let config = PotaSpotsConfig {
+            enabled: true,
+            callsign: "oh8hub".to_string(),
+            token: "hunter2".to_string(),
+            cooldown_secs: 600,
+        };
+        let sink = PotaSpotsSink::new(&config, "https://api.pota.example.org/").unwrap();
+        let pota_spot = payload(
+            &spot("DX de OH2NOS:    14062.5  OH2NOS/P     OH-0001 OHFF-1419 usb          1146Z"),
+            &sink.callsign,
+        )
+        .unwrap();
+        let request = sink.request(&pota_spot).unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.url().as_str(), "https://api.pota.example.org/spot");
+        assert_eq!(request.headers()["authorization"], "Bearer hunter2");
+        let body = request.body().unwrap().as_bytes().unwrap();
+        let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "activator": "OH2NOS/P",
+                "spotter": "OH8HUB",
+                "frequency": "14062.5",
+                "reference": "OH-0001",
+                "mode": "SSB",
+                "source": "puskapupu",
+                "comments": "OH-0001 OHFF-1419 usb de OH2NOS",
+            })
+        );
+
+        // Not a park
+        assert!(payload(
+            &spot("DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"),
+            "OH8HUB"
+        )
+        .is_none());
+        // Summit
+        assert!(payload(
+            &spot("DX de HB9BIN:    14044.0  HB9BIN/P     x04s HB/BL-001 OH-0001         1049Z"),
+            "OH8HUB"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let pota_spot = |activator: &str, reference: &str, frequency: &str| PotaSpot {
+            activator: activator.to_string(),
+            spotter: "OH8HUB".to_string(),
+            frequency: frequency.to_string(),
+            reference: reference.to_string(),
+            mode: "CW".to_string(),
+            source: "puskapupu".to_string(),
+            comments: String::new(),
+        };
+        let now = SystemTime::now();
+        let mut cooldown = Cooldown::new(Duration::from_secs(600));
+        let current = [pota_spot("OH2NOS", "OH-0001", "14061")];
+
+        // POTA has it already, also without the /P
+        assert!(!cooldown.check(&pota_spot("OH2NOS/P", "OH-0001", "14062"), &current, now));
+        assert!(cooldown.check(&pota_spot("OH2NOS/P", "OH-0001", "7032"), &current, now));
+        // The park is cooling down, even for another activator
+        assert!(!cooldown.check(&pota_spot("OH8HUB/P", "OH-0001", "3544"), &[], now));
+        assert!(cooldown.check(&pota_spot("OH8HUB/P", "OH-0002", "3544"), &[], now));
+        let later = now + Duration::from_secs(601);
+        assert!(cooldown.check(&pota_spot("OH8HUB/P", "OH-0001", "3544"), &[], later));
+
+        // Posting failed, so it's tried again
+        let failed = pota_spot("OH2NOS/P", "OH-0003", "7032");
+        assert!(cooldown.check(&failed, &[], now));
+        cooldown.forget(&failed);
+        assert!(cooldown.check(&failed, &[], now));
+    }
+}
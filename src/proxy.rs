@@ -0,0 +1,282 @@
+//! Tunneling cluster connections through a SOCKS5 (RFC 1928) or HTTP
+//! `CONNECT` proxy.
+
+use std::io;
+use std::net::IpAddr;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::{ProxyConfig, ProxyKind};
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+/// RFC 1929
+const USERNAME_PASSWORD: u8 = 2;
+/// Version of the RFC 1929 subnegotiation
+const AUTH_VERSION: u8 = 1;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+/// Longest response headers accepted from an HTTP proxy.
+const MAX_HTTP_HEADERS: usize = 8 * 1024;
+
+/// Ask the proxy at the other end of `stream` to connect to `target`
+/// (host:port). Afterwards `stream` is connected to the target.
+pub async fn tunnel<S>(stream: &mut S, proxy: &ProxyConfig, target: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (host, port) = split_target(target)?;
+    match proxy.kind {
+        ProxyKind::Socks5 => socks5(stream, proxy, host, port).await,
+        ProxyKind::Http => http_connect(stream, proxy, target).await,
+    }
+}
+
+fn split_target(target: &str) -> io::Result<(&str, u16)> {
+    target
+        .rsplit_once(':')
+        .and_then(|(host, port)| {
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            Some((host, port.parse().ok()?))
+        })
+        .ok_or_else(|| invalid_input(format!("expected host:port; got '{target}'")))
+}
+
+async fn socks5<S>(stream: &mut S, proxy: &ProxyConfig, host: &str, port: u16) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = match proxy.username {
+        Some(_) => USERNAME_PASSWORD,
+        None => NO_AUTH,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    match reply {
+        [SOCKS_VERSION, chosen] if chosen == method => (),
+        [SOCKS_VERSION, NO_ACCEPTABLE_METHODS] => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS proxy didn't accept the authentication method",
+            ));
+        }
+        _ => return Err(invalid_data(format!("unexpected SOCKS reply {reply:?}"))),
+    }
+
+    if let Some(username) = &proxy.username {
+        let password = proxy.password.as_deref().unwrap_or_default();
+        let mut auth = vec![AUTH_VERSION];
+        for field in [username.as_bytes(), password.as_bytes()] {
+            let len = u8::try_from(field.len())
+                .map_err(|_| invalid_input("SOCKS username or password too long"))?;
+            auth.push(len);
+            auth.extend_from_slice(field);
+        }
+        stream.write_all(&auth).await?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        match reply {
+            [AUTH_VERSION, 0] => (),
+            [AUTH_VERSION, _] => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS proxy rejected the username or password",
+                ));
+            }
+            _ => return Err(invalid_data(format!("unexpected SOCKS reply {reply:?}"))),
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| invalid_input("host name too long"))?;
+            request.extend_from_slice(&[DOMAIN, len]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(invalid_data(format!("unexpected SOCKS reply {reply:?}")));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("SOCKS proxy couldn't connect: {}", socks_error(reply[1])),
+        ));
+    }
+    // Address the proxy connected from, not needed
+    let len = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => usize::from(stream.read_u8().await?),
+        other => return Err(invalid_data(format!("unknown SOCKS address type {other}"))),
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn socks_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+async fn http_connect<S>(stream: &mut S, proxy: &ProxyConfig, target: &str) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some(username) = &proxy.username {
+        let password = proxy.password.as_deref().unwrap_or_default();
+        let credentials = STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Byte at a time, so nothing the cluster sends after is read here
+    let mut headers = Vec::new();
+    while !headers.ends_with(b"\r\n\r\n") {
+        if headers.len() == MAX_HTTP_HEADERS {
+            return Err(invalid_data("too long response from HTTP proxy"));
+        }
+        headers.push(stream.read_u8().await?);
+    }
+    let headers = String::from_utf8_lossy(&headers);
+    let status = headers.lines().next().unwrap_or_default();
+    let code = status.split_whitespace().nth(1);
+    if !status.starts_with("HTTP/1.") || code.map_or(true, |code| !code.starts_with('2')) {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("HTTP proxy couldn't connect: {status}"),
+        ));
+    }
+    Ok(())
+}
+
+fn invalid_input(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{split_target, tunnel};
+    use crate::config::{ProxyConfig, ProxyKind};
+    use crate::testutil::MockProxy;
+
+    fn proxy(kind: ProxyKind, address: String, username: Option<&str>) -> ProxyConfig {
+        ProxyConfig {
+            kind,
+            address,
+            username: username.map(str::to_string),
+            password: username.map(|_| "hunter2".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_split_target() {
+        assert_eq!(
+            split_target("www.cqgma.org:7300").unwrap(),
+            ("www.cqgma.org", 7300)
+        );
+        assert_eq!(split_target("[::1]:7300").unwrap(), ("::1", 7300));
+        assert!(split_target("www.cqgma.org").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let mock = MockProxy::start(ProxyKind::Socks5, Some(("N0CALL", "hunter2"))).await;
+        let config = proxy(ProxyKind::Socks5, mock.address.clone(), Some("N0CALL"));
+        let mut stream = tokio::net::TcpStream::connect(&mock.address).await.unwrap();
+        tunnel(&mut stream, &config, "www.cqgma.org:7300")
+            .await
+            .unwrap();
+        assert_eq!(mock.targets(), ["www.cqgma.org:7300"]);
+
+        // Wrong password
+        let mut config = config;
+        config.password = Some("hunter3".to_string());
+        let mut stream = tokio::net::TcpStream::connect(&mock.address).await.unwrap();
+        let err = tunnel(&mut stream, &config, "www.cqgma.org:7300")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_auth_version() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            let mut greeting = [0; 3];
+            server.read_exact(&mut greeting).await?;
+            server.write_all(&[5, 2]).await?;
+            let mut auth = [0; 16];
+            server.read_exact(&mut auth).await?;
+            // SOCKS version instead of the subnegotiation version
+            server.write_all(&[5, 0]).await?;
+            std::io::Result::Ok(server)
+        });
+        let config = proxy(ProxyKind::Socks5, "proxy:1080".to_string(), Some("N0CALL"));
+        let err = tunnel(&mut client, &config, "www.cqgma.org:7300")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "unexpected SOCKS reply [5, 0]");
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let mock = MockProxy::start(ProxyKind::Http, Some(("N0CALL", "hunter2"))).await;
+        let config = proxy(ProxyKind::Http, mock.address.clone(), Some("N0CALL"));
+        let mut stream = tokio::net::TcpStream::connect(&mock.address).await.unwrap();
+        tunnel(&mut stream, &config, "127.0.0.1:7300")
+            .await
+            .unwrap();
+        assert_eq!(mock.targets(), ["127.0.0.1:7300"]);
+
+        // Without credentials
+        let config = proxy(ProxyKind::Http, mock.address.clone(), None);
+        let mut stream = tokio::net::TcpStream::connect(&mock.address).await.unwrap();
+        let err = tunnel(&mut stream, &config, "127.0.0.1:7300")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "HTTP proxy couldn't connect: HTTP/1.1 407 Proxy Authentication Required"
+        );
+    }
+}
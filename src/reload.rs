@@ -0,0 +1,284 @@
+//! Decide which config changes can be applied while running.
+
+use crate::config::{Config, MatrixConfig};
+use crate::filter::FilterConfig;
+
+#[derive(Debug, Default)]
+pub struct Reload {
+    /// New filter, if it changed
+    pub filter: Option<FilterConfig>,
+    /// New settings for each Matrix account, if they changed. Only the
+    /// settings not needing reconnection are applied.
+    pub matrix: Vec<Option<MatrixConfig>>,
+    /// Changed settings which need a restart to take effect
+    pub restart_required: Vec<String>,
+}
+
+impl Reload {
+    /// Copy the settings applied while running into `config`, the one
+    /// running. Settings needing a restart keep their old values, so the
+    /// next reload still reports them.
+    pub fn apply_to(&self, config: &mut Config) {
+        if let Some(filter) = &self.filter {
+            config.filter = filter.clone();
+        }
+        for (old, new) in config.matrix.iter_mut().zip(&self.matrix) {
+            if let Some(new) = new {
+                old.quiet_hours = new.quiet_hours;
+                old.dedup_window_secs = new.dedup_window_secs;
+                old.template = new.template.clone();
+                old.format = new.format;
+            }
+        }
+    }
+}
+
+/// Compare `old` and `new` config.
+pub fn diff(old: &Config, new: &Config) -> Reload {
+    let mut reload = Reload::default();
+    let mut restart = |field: String| reload.restart_required.push(field);
+
+    if old.cqgma.len() != new.cqgma.len() {
+        restart("cqgma".to_string());
+    }
+    for (i, (old, new)) in old.cqgma.iter().zip(&new.cqgma).enumerate() {
+        let fields = [
+            ("host", old.host != new.host),
+            ("username", old.username != new.username),
+            ("password", old.password != new.password),
+            ("filter", old.filter != new.filter),
+            (
+                "reconnect_min_secs",
+                old.reconnect_min_secs != new.reconnect_min_secs,
+            ),
+            (
+                "reconnect_max_secs",
+                old.reconnect_max_secs != new.reconnect_max_secs,
+            ),
+            (
+                "max_consecutive_failures",
+                old.max_consecutive_failures != new.max_consecutive_failures,
+            ),
+            ("proxy", old.proxy != new.proxy),
+        ];
+        for (field, changed) in fields {
+            if changed {
+                restart(format!("cqgma[{i}].{field}"));
+            }
+        }
+    }
+    if old.home_grid != new.home_grid {
+        restart("home_grid".to_string());
+    }
+    if old.cty_path != new.cty_path {
+        restart("cty_path".to_string());
+    }
+    if old.secrets_path != new.secrets_path {
+        restart("secrets_path".to_string());
+    }
+    if old.channel_capacity != new.channel_capacity {
+        restart("channel_capacity".to_string());
+    }
+    if old.overflow_policy != new.overflow_policy {
+        restart("overflow_policy".to_string());
+    }
+    if old.route != new.route {
+        restart("route".to_string());
+    }
+    if old.dedup != new.dedup {
+        restart("dedup".to_string());
+    }
+    if old.store != new.store {
+        restart("store".to_string());
+    }
+    if old.mqtt != new.mqtt {
+        restart("mqtt".to_string());
+    }
+    if old.webhook != new.webhook {
+        restart("webhook".to_string());
+    }
+    if old.discord != new.discord {
+        restart("discord".to_string());
+    }
+    if old.telegram != new.telegram {
+        restart("telegram".to_string());
+    }
+    if old.ntfy != new.ntfy {
+        restart("ntfy".to_string());
+    }
+    if old.email != new.email {
+        restart("email".to_string());
+    }
+    if old.aprs != new.aprs {
+        restart("aprs".to_string());
+    }
+    if old.nostr != new.nostr {
+        restart("nostr".to_string());
+    }
+    if old.xmpp != new.xmpp {
+        restart("xmpp".to_string());
+    }
+    if old.influxdb != new.influxdb {
+        restart("influxdb".to_string());
+    }
+    if old.kafka != new.kafka {
+        restart("kafka".to_string());
+    }
+    if old.sotawatch != new.sotawatch {
+        restart("sotawatch".to_string());
+    }
+    if old.pota_spots != new.pota_spots {
+        restart("pota_spots".to_string());
+    }
+    if old.adif != new.adif {
+        restart("adif".to_string());
+    }
+    if old.jsonl != new.jsonl {
+        restart("jsonl".to_string());
+    }
+    if old.csv != new.csv {
+        restart("csv".to_string());
+    }
+    if old.lookup != new.lookup {
+        restart("lookup".to_string());
+    }
+    if old.watchdog != new.watchdog {
+        restart("watchdog".to_string());
+    }
+    if old.dead_letter != new.dead_letter {
+        restart("dead_letter".to_string());
+    }
+    if old.http != new.http {
+        restart("http".to_string());
+    }
+    if old.logging != new.logging {
+        restart("logging".to_string());
+    }
+    if old.matrix.len() != new.matrix.len() {
+        restart("matrix".to_string());
+    }
+
+    for (i, (old, new)) in old.matrix.iter().zip(&new.matrix).enumerate() {
+        let connection = [
+            ("homeserver", old.homeserver != new.homeserver),
+            ("access_token", old.access_token != new.access_token),
+            ("refresh_token", old.refresh_token != new.refresh_token),
+            ("user_id", old.user_id != new.user_id),
+            ("device_id", old.device_id != new.device_id),
+            ("rooms", old.rooms != new.rooms),
+            (
+                "sync_token_path",
+                old.sync_token_path != new.sync_token_path,
+            ),
+            ("admins", old.admins != new.admins),
+            ("dedup_path", old.dedup_path != new.dedup_path),
+        ];
+        for (field, changed) in connection {
+            if changed {
+                restart(format!("matrix[{i}].{field}"));
+            }
+        }
+    }
+
+    reload.matrix = old
+        .matrix
+        .iter()
+        .zip(&new.matrix)
+        .map(|(old, new)| {
+            let live_changed = old.quiet_hours != new.quiet_hours
+                || old.dedup_window_secs != new.dedup_window_secs
+                || old.template != new.template
+                || old.format != new.format;
+            live_changed.then(|| new.clone())
+        })
+        .collect();
+
+    if old.filter != new.filter {
+        reload.filter = Some(new.filter.clone());
+    }
+
+    reload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::diff;
+    use crate::band::Band;
+    use crate::config::Config;
+
+    const CONFIG: &str = r##"
+        [matrix]
+        homeserver = "https://matrix.pikaviestin.fi:8448"
+        access_token = "abcdefghijklmnopqrstuvwxyz12345678901234567890"
+        user_id = "@puskapupu:pikaviestin.fi"
+        device_id = "puskapupu"
+        room_id = "!hVUOVQnjnxUgSTCdCJ:pikaviestin.fi"
+
+        [cqgma]
+        host = "www.cqgma.org:7300"
+        username = "oh9xxx-4"
+        "##;
+
+    #[test]
+    fn test_reload_diff() {
+        let old: Config = toml::from_str(CONFIG).unwrap();
+
+        let reload = diff(&old, &toml::from_str(CONFIG).unwrap());
+        assert!(reload.filter.is_none());
+        assert!(reload.matrix.iter().all(Option::is_none));
+        assert!(reload.restart_required.is_empty());
+
+        let raw = CONFIG.replace(
+            "room_id",
+            "template = \"{dx} {frequency}\"\n        room_id",
+        ) + "[filter]\nbands = [\"20m\"]\n";
+        let reload = diff(&old, &toml::from_str(&raw).unwrap());
+        assert_eq!(reload.filter.unwrap().bands, vec![Band::B20m]);
+        assert_eq!(
+            reload.matrix[0]
+                .as_ref()
+                .unwrap()
+                .template
+                .as_ref()
+                .unwrap()
+                .to_string(),
+            "{dx} {frequency}"
+        );
+        assert!(reload.restart_required.is_empty());
+
+        let raw = CONFIG
+            .replace("www.cqgma.org:7300", "www.cqgma.org:7373")
+            .replace("abcdefghijklmnopqrstuvwxyz", "zyxwvutsrqponmlkjihgfedcba");
+        let reload = diff(&old, &toml::from_str(&raw).unwrap());
+        assert!(reload.filter.is_none());
+        assert!(reload.matrix[0].is_none());
+        assert_eq!(
+            reload.restart_required,
+            vec!["cqgma[0].host", "matrix[0].access_token"]
+        );
+    }
+
+    #[test]
+    fn test_reload_apply() {
+        let mut running: Config = toml::from_str(CONFIG).unwrap();
+        let raw = CONFIG
+            .replace("www.cqgma.org:7300", "www.cqgma.org:7373")
+            .replace(
+                "room_id",
+                "template = \"{dx} {frequency}\"\n        room_id",
+            )
+            + "[filter]\nbands = [\"20m\"]\n";
+        let new: Config = toml::from_str(&raw).unwrap();
+        let reload = diff(&running, &new);
+        reload.apply_to(&mut running);
+        assert_eq!(running.filter.bands, vec![Band::B20m]);
+        assert!(running.matrix[0].template.is_some());
+        assert_eq!(running.cqgma[0].host, "www.cqgma.org:7300");
+
+        // Still waiting for a restart
+        let reload = diff(&running, &new);
+        assert!(reload.filter.is_none());
+        assert!(reload.matrix[0].is_none());
+        assert_eq!(reload.restart_required, vec!["cqgma[0].host"]);
+    }
+}
@@ -0,0 +1,59 @@
+//! Files moved aside when a new day starts, so archives come in pieces of
+//! one day.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::utc;
+
+/// Where the file at `path` written on the day of `t` is moved,
+/// `<path>.YYYY-MM-DD`.
+pub fn rotated_path(path: &Path, t: SystemTime) -> PathBuf {
+    let (year, month, day) = utc::date(t);
+    let mut rotated = path.to_path_buf().into_os_string();
+    rotated.push(format!(".{year:04}-{month:02}-{day:02}"));
+    rotated.into()
+}
+
+/// Move the file at `path` aside if it was last written on an earlier UTC
+/// day than `now`. Returns where the file was moved.
+pub fn rotate_daily(path: &Path, now: SystemTime) -> io::Result<Option<PathBuf>> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let modified = metadata.modified()?;
+    if metadata.len() == 0 || utc::date(modified) >= utc::date(now) {
+        return Ok(None);
+    }
+    let rotated = rotated_path(path, modified);
+    std::fs::rename(path, &rotated)?;
+    Ok(Some(rotated))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{rotate_daily, rotated_path};
+
+    #[test]
+    fn test_rotate_daily() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spots.jsonl");
+        let now = SystemTime::now();
+        assert_eq!(rotate_daily(&path, now).unwrap(), None);
+
+        std::fs::write(&path, "{}\n").unwrap();
+        assert_eq!(rotate_daily(&path, now).unwrap(), None);
+        assert!(path.exists());
+
+        let tomorrow = now + Duration::from_secs(24 * 60 * 60);
+        let rotated = rotate_daily(&path, tomorrow).unwrap();
+        assert_eq!(rotated, Some(rotated_path(&path, now)));
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_to_string(rotated.unwrap()).unwrap(), "{}\n");
+    }
+}
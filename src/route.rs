@@ -0,0 +1,298 @@
+//! Rules sending spots only to some sinks, eg. SOTA spots to Matrix and
+//! WWFF spots to MQTT, as configured in `[[route]]`, and deduplicating
+//! spots of some sinks more strictly, as configured in `[[dedup]]`.
+//!
+//! Each sink named in a rule gets a channel of its own, fed with the spots
+//! of the rules naming it. Other sinks keep getting every spot.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+use crate::channel::ChannelLimits;
+use crate::config::{DedupConfig, RouteConfig};
+use crate::dedup::Dedup;
+use crate::parser::DxEntry;
+
+pub struct Router {
+    routes: Vec<RouteConfig>,
+    /// Every spot
+    spots: broadcast::Sender<Arc<DxEntry>>,
+    /// Routed spots by the name of the sink
+    routed: HashMap<String, broadcast::Sender<Arc<DxEntry>>>,
+    /// Of the routed channels, like for all spots
+    limits: ChannelLimits,
+    /// Sinks getting every spot through their own channel, for dedup
+    unrouted: Vec<String>,
+    /// Spots already sent to each sink with a dedup window of its own
+    dedup: Mutex<HashMap<String, Dedup>>,
+}
+
+impl Router {
+    /// Router of `spots` by `routes`, deduplicated for the sinks of
+    /// `dedups`. Channels of routed spots are sized by `limits`.
+    pub fn new(
+        routes: &[RouteConfig],
+        dedups: &[DedupConfig],
+        spots: broadcast::Sender<Arc<DxEntry>>,
+        limits: ChannelLimits,
+    ) -> Self {
+        let routed: HashMap<_, _> = routes
+            .iter()
+            .flat_map(|route| &route.sinks)
+            .chain(dedups.iter().flat_map(|dedup| &dedup.sinks))
+            .map(|sink| (sink.clone(), limits.channel()))
+            .collect();
+        let unrouted = routed
+            .keys()
+            .filter(|sink| !routes.iter().any(|route| route.sinks.contains(sink)))
+            .cloned()
+            .collect();
+        let dedup = dedups
+            .iter()
+            .flat_map(|dedup| {
+                let window = Duration::from_secs(dedup.window_secs);
+                dedup
+                    .sinks
+                    .iter()
+                    .map(move |sink| (sink.clone(), Dedup::new(window)))
+            })
+            .collect();
+        Self {
+            routes: routes.to_vec(),
+            spots,
+            routed,
+            limits,
+            unrouted,
+            dedup: Mutex::new(dedup),
+        }
+    }
+
+    /// Spots for sink `name`: only the routed ones if a rule names the
+    /// sink, else all of them.
+    pub fn spots(&self, name: &str) -> broadcast::Sender<Arc<DxEntry>> {
+        self.routed.get(name).unwrap_or(&self.spots).clone()
+    }
+
+    /// Whether any spots are routed or deduplicated, so [run] is needed.
+    pub fn is_empty(&self) -> bool {
+        self.routed.is_empty()
+    }
+
+    /// Sinks of all rules whose filter `spot` passes at `now`, and the
+    /// deduplicated sinks named in no rule.
+    pub fn destinations(&self, spot: &DxEntry, now: SystemTime) -> BTreeSet<&str> {
+        self.routes
+            .iter()
+            .filter(|route| {
+                route.filter.matches_line(&spot.line) && route.filter.matches(spot, now)
+            })
+            .flat_map(|route| &route.sinks)
+            .chain(&self.unrouted)
+            .map(String::as_str)
+            .collect()
+    }
+
+    async fn route(&self, spot: Arc<DxEntry>, now: SystemTime, shutdown: &CancellationToken) {
+        let destinations: Vec<_> = {
+            let mut dedup = self.dedup.lock().expect("dedup lock");
+            self.destinations(&spot, now)
+                .into_iter()
+                .filter(|sink| {
+                    let duplicate = dedup
+                        .get_mut(*sink)
+                        .map_or(false, |dedup| dedup.is_duplicate(spot.dedup_key(), now));
+                    if duplicate {
+                        tracing::debug!("Duplicate spot not sent to {sink}");
+                    }
+                    !duplicate
+                })
+                .collect()
+        };
+        for sink in destinations {
+            let spots = &self.routed[sink];
+            self.limits.send(spots, spot.clone(), shutdown).await;
+        }
+    }
+}
+
+/// Route every spot to the sinks of matching rules until `shutdown` is
+/// cancelled. Spots already received are routed before returning.
+pub async fn run(router: Arc<Router>, shutdown: CancellationToken) -> io::Result<()> {
+    let mut spots = router.spots.subscribe();
+    loop {
+        let received = tokio::select! {
+            received = spots.recv() => received,
+            _ = shutdown.cancelled() => break,
+        };
+        match received {
+            Ok(spot) => router.route(spot, SystemTime::now(), &shutdown).await,
+            Err(RecvError::Lagged(n)) => {
+                tracing::warn!("Routing is too slow to keep up with spots. Skipped {n} spots.");
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+    while let Ok(spot) = spots.try_recv() {
+        router.route(spot, SystemTime::now(), &shutdown).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use tokio::sync::broadcast;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{run, Router};
+    use crate::channel::ChannelLimits;
+    use crate::config::{DedupConfig, RouteConfig};
+    use crate::filter::FilterConfig;
+    use crate::parser::{Activity, DxEntry};
+
+    const SOTA: &str =
+        "DX de HB9BIN:    14044.0  HB9BIN/P     x04s HB/BL-001                 1049Z";
+    const WWFF: &str =
+        "DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 CW              1150Z";
+    const NEW_ONE: &str =
+        "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-0001 New one!        1146Z";
+
+    fn route(sinks: &[&str], activities: Vec<Activity>, references: &[&str]) -> RouteConfig {
+        RouteConfig {
+            sinks: sinks.iter().map(|s| s.to_string()).collect(),
+            filter: FilterConfig {
+                references: references.iter().map(|r| r.to_string()).collect(),
+                activities,
+                ..FilterConfig::all()
+            },
+        }
+    }
+
+    fn routes() -> Vec<RouteConfig> {
+        vec![
+            route(
+                &["matrix @puskapupu:example.org"],
+                vec![Activity::Sota],
+                &[],
+            ),
+            route(&["mqtt"], vec![Activity::Wwff], &[]),
+            route(&["email", "mqtt"], Vec::new(), &["New one!"]),
+        ]
+    }
+
+    fn spot(line: &str) -> Arc<DxEntry> {
+        Arc::new(DxEntry::parse_line(line).unwrap())
+    }
+
+    #[test]
+    fn test_destinations() {
+        let (spots, _) = broadcast::channel(16);
+        let mut routes = routes();
+        // Spots of WWFF references are alerted too
+        routes[2].filter.references.push("OHFF-".to_string());
+        let router = Router::new(&routes, &[], spots, ChannelLimits::default());
+        let now = SystemTime::now();
+        let destinations = |line| router.destinations(&spot(line), now);
+
+        assert_eq!(
+            destinations(SOTA),
+            BTreeSet::from(["matrix @puskapupu:example.org"])
+        );
+        assert_eq!(destinations(WWFF), BTreeSet::from(["email", "mqtt"]));
+        assert_eq!(destinations(NEW_ONE), BTreeSet::from(["email", "mqtt"]));
+        assert!(destinations(
+            "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z"
+        )
+        .contains("matrix @puskapupu:example.org"));
+        assert!(destinations(
+            "DX de KG5ED:     14074.1  VK3ACE       x02d ccc vk3* iota oc-001      1051Z"
+        )
+        .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let (spots, _) = broadcast::channel(16);
+        let router = Arc::new(Router::new(
+            &routes(),
+            &[],
+            spots.clone(),
+            ChannelLimits::default(),
+        ));
+        assert!(!router.is_empty());
+        let mut matrix = router.spots("matrix @puskapupu:example.org").subscribe();
+        let mut mqtt = router.spots("mqtt").subscribe();
+        let mut email = router.spots("email").subscribe();
+        let mut csv = router.spots("csv").subscribe();
+
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(run(router.clone(), shutdown.clone()));
+        // Subscribed before spots are sent
+        while spots.receiver_count() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        for line in [SOTA, WWFF, NEW_ONE] {
+            spots.send(spot(line)).unwrap();
+        }
+        shutdown.cancel();
+        task.await.unwrap().unwrap();
+
+        let received = |rx: &mut broadcast::Receiver<Arc<DxEntry>>| {
+            let mut calls = Vec::new();
+            while let Ok(spot) = rx.try_recv() {
+                calls.push(spot.info.clone());
+            }
+            calls
+        };
+        assert_eq!(received(&mut matrix), ["HB/BL-001"]);
+        assert_eq!(received(&mut mqtt), ["OHFF-1419 CW", "OHFF-0001 New one!"]);
+        assert_eq!(received(&mut email), ["OHFF-0001 New one!"]);
+        // Not routed, so gets every spot
+        assert_eq!(received(&mut csv).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_dedup() {
+        let (spots, _) = broadcast::channel(16);
+        let dedups = [
+            DedupConfig {
+                sinks: vec!["email".to_string()],
+                window_secs: 60 * 60,
+            },
+            DedupConfig {
+                sinks: vec!["ntfy[0]".to_string(), "mqtt".to_string()],
+                window_secs: 10 * 60,
+            },
+        ];
+        let router = Router::new(&routes(), &dedups, spots, ChannelLimits::default());
+        assert!(!router.is_empty());
+        let mut email = router.spots("email").subscribe();
+        let mut ntfy = router.spots("ntfy[0]").subscribe();
+        let mut csv = router.spots("csv").subscribe();
+
+        // The same activation every 15 minutes
+        let start = UNIX_EPOCH + Duration::from_secs(1_709_294_400);
+        let shutdown = CancellationToken::new();
+        for i in 0..4 {
+            let now = start + Duration::from_secs(i * 15 * 60);
+            router.route(spot(NEW_ONE), now, &shutdown).await;
+        }
+        let count = |rx: &mut broadcast::Receiver<Arc<DxEntry>>| {
+            std::iter::from_fn(|| rx.try_recv().ok()).count()
+        };
+        // Routed and deduplicated
+        assert_eq!(count(&mut email), 1);
+        // Not routed, so gets every spot passing its own window
+        assert_eq!(count(&mut ntfy), 4);
+        // Neither routed nor deduplicated, so not sent through the router
+        assert_eq!(count(&mut csv), 0);
+    }
+}
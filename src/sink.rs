@@ -0,0 +1,201 @@
+//! Outputs where forwarded spots are sent.
+//!
+//! Each sink runs in its own task with its own subscription to the spots, so
+//! a sink which is down only delays itself.
+
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::metrics::{Metrics, SinkHealth};
+use crate::parser::DxEntry;
+
+/// Pause after the first failed send. Doubled after each failure.
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// Longest pause between failed sends.
+const BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Output for spots, eg. Matrix rooms.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Name of the sink in logs and metrics.
+    fn name(&self) -> &str;
+
+    /// Send `spot`. Sinks may drop spots they don't want, eg. duplicates.
+    async fn send(&self, spot: &DxEntry) -> io::Result<()>;
+
+    /// Called once after the last spot when shutting down.
+    async fn close(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Shared sink, eg. one also flushed by a task of its own.
+#[async_trait]
+impl<S: Sink + ?Sized> Sink for Arc<S> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        (**self).send(spot).await
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        (**self).close().await
+    }
+}
+
+/// Run each of `sinks` in a task of its own, subscribed to `spots`.
+pub fn spawn(
+    sinks: Vec<Box<dyn Sink>>,
+    spots: &broadcast::Sender<Arc<DxEntry>>,
+    metrics: &Metrics,
+    shutdown: CancellationToken,
+) -> Vec<JoinHandle<io::Result<()>>> {
+    sinks
+        .into_iter()
+        .map(|sink| {
+            let health = metrics.sink(sink.name());
+            let future = run(sink, spots.subscribe(), health, shutdown.clone());
+            tokio::spawn(future.in_current_span())
+        })
+        .collect()
+}
+
+/// Send every spot from `spots` to `sink`. After a failed send the sink is
+/// paused for a while, skipping spots if it falls too far behind, so a sink
+/// which keeps failing is only logged. When `shutdown` is cancelled, spots
+/// already received are sent before closing the sink.
+pub async fn run(
+    sink: Box<dyn Sink>,
+    mut spots: broadcast::Receiver<Arc<DxEntry>>,
+    health: Arc<SinkHealth>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let name = sink.name();
+    let mut backoff = BACKOFF_MIN;
+    loop {
+        let received = tokio::select! {
+            received = spots.recv() => received,
+            _ = shutdown.cancelled() => break,
+        };
+        let entry = match received {
+            Ok(entry) => entry,
+            Err(RecvError::Lagged(n)) => {
+                tracing::warn!("{name} is too slow to keep up with spots. Skipped {n} spots.");
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        if let Err(err) = send(&*sink, &entry, &health).await {
+            tracing::warn!(
+                "Couldn't send spot to {name}: {err}. Pausing it for {} seconds.",
+                backoff.as_secs()
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => (),
+                _ = shutdown.cancelled() => break,
+            }
+            if backoff < BACKOFF_MAX && backoff * 2 >= BACKOFF_MAX {
+                tracing::error!("{name} keeps failing. Skipping spots until it recovers.");
+            }
+            backoff = (backoff * 2).min(BACKOFF_MAX);
+        } else {
+            backoff = BACKOFF_MIN;
+        }
+    }
+
+    tracing::info!("Shutting down. Sending pending spots to {name}.");
+    while let Ok(entry) = spots.try_recv() {
+        if let Err(err) = send(&*sink, &entry, &health).await {
+            tracing::warn!("Couldn't send pending spots to {name}: {err}");
+            break;
+        }
+    }
+    if let Err(err) = sink.close().await {
+        tracing::warn!("Couldn't close {name}: {err}");
+    }
+    Ok(())
+}
+
+async fn send(sink: &dyn Sink, entry: &DxEntry, health: &SinkHealth) -> io::Result<()> {
+    let result = sink.send(entry).instrument(entry.span()).await;
+    match result {
+        Ok(()) => {
+            health.sent.fetch_add(1, Ordering::Relaxed);
+            health.consecutive_failures.store(0, Ordering::Relaxed);
+        }
+        Err(_) => {
+            health.failed.fetch_add(1, Ordering::Relaxed);
+            health.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    result
+}
+
+/// Error of a sink from any error, eg. of HTTP requests.
+pub fn other<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::broadcast;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{spawn, Sink};
+    use crate::metrics::Metrics;
+    use crate::testutil::RecordingSink;
+
+    /// Time is paused, so the sleeps below only let the sinks run until
+    /// they wait, without racing the clock.
+    #[tokio::test(start_paused = true)]
+    async fn test_failing_sink_doesnt_stop_others() {
+        let failing = Arc::new(RecordingSink::failing("mqtt"));
+        let working = Arc::new(RecordingSink::new("matrix"));
+        let sinks: Vec<Box<dyn Sink>> = vec![Box::new(failing.clone()), Box::new(working.clone())];
+
+        let (tx, _) = broadcast::channel(16);
+        let metrics = Metrics::default();
+        let shutdown = CancellationToken::new();
+        let handles = spawn(sinks, &tx, &metrics, shutdown.clone());
+        let lines = [
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+            "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z",
+            "DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 OH-0123      1150Z",
+        ];
+        for line in lines {
+            tx.send(Arc::new(line.parse().unwrap())).unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The failing sink is paused after its first failure
+        assert_eq!(working.sent(), ["OH2NOS/P", "AD6VT", "OH2NOS/P"]);
+        shutdown.cancel();
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+
+        assert!(working.is_closed());
+        assert!(failing.sent().is_empty());
+        assert!(failing.is_closed());
+        assert_eq!(metrics.sink("matrix").sent.load(Ordering::Relaxed), 3);
+        assert!(metrics.sink("matrix").is_up());
+        assert_eq!(metrics.sink("mqtt").failed.load(Ordering::Relaxed), 2);
+        assert!(!metrics.sink("mqtt").is_up());
+    }
+}
@@ -0,0 +1,171 @@
+//! Summit details from the SOTA API.
+
+use std::io;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+
+use crate::lookup::{Directory, ReferenceInfo};
+use crate::parser::{Activity, DxEntry};
+
+pub const API_URL: &str = "https://api2.sota.org.uk";
+
+/// Summit as returned by `/api/summits/{code}`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Summit {
+    summit_code: String,
+    name: String,
+    alt_m: Option<i32>,
+    points: Option<u32>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+impl From<Summit> for ReferenceInfo {
+    fn from(summit: Summit) -> Self {
+        let details: Vec<String> = [
+            summit.alt_m.map(|alt| format!("{alt}m")),
+            summit.points.map(|points| format!("{points}pts")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        ReferenceInfo {
+            code: summit.summit_code,
+            name: summit.name,
+            details: (!details.is_empty()).then(|| details.join(", ")),
+            location: summit.latitude.zip(summit.longitude),
+        }
+    }
+}
+
+pub struct SotaDirectory {
+    client: Client,
+    url: String,
+}
+
+impl SotaDirectory {
+    /// Directory at `url`, usually [API_URL].
+    pub fn new(url: &str, timeout: Duration) -> io::Result<Self> {
+        let client = Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Directory for SotaDirectory {
+    fn name(&self) -> &str {
+        "sota"
+    }
+
+    /// SOTA spots and references looking like summits, eg. `HB/BL-001`.
+    fn handles(&self, spot: &DxEntry, reference: &str) -> bool {
+        let activity = spot.cqgma_identifier.map(|(activity, _)| activity);
+        activity == Some(Activity::Sota) || (activity.is_none() && reference.contains('/'))
+    }
+
+    async fn fetch(&self, reference: &str) -> io::Result<Option<ReferenceInfo>> {
+        let to_io = |err: reqwest::Error| io::Error::new(io::ErrorKind::Other, err);
+        let response = self
+            .client
+            .get(format!("{}/api/summits/{reference}", self.url))
+            .send()
+            .await
+            .map_err(to_io)?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::NO_CONTENT => return Ok(None),
+            status if !status.is_success() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("HTTP {status}"),
+                ))
+            }
+            _ => (),
+        }
+        let summit: Option<Summit> = response.json().await.map_err(to_io)?;
+        Ok(summit.map(ReferenceInfo::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::extract::{Path, State};
+    use axum::http::StatusCode;
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::SotaDirectory;
+    use crate::lookup::Lookup;
+    use crate::parser::DxEntry;
+
+    async fn summit(
+        State(requests): State<Arc<AtomicUsize>>,
+        Path((association, region)): Path<(String, String)>,
+    ) -> Response {
+        requests.fetch_add(1, Ordering::Relaxed);
+        if format!("{association}/{region}") != "HB/BL-001" {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Json(json!({
+            "summitCode": "HB/BL-001",
+            "name": "Chasseral",
+            "shortCode": "BL-001",
+            "altM": 1607,
+            "altFt": 5272,
+            "points": 8,
+            "latitude": 47.1327,
+            "longitude": 7.0599,
+        }))
+        .into_response()
+    }
+
+    #[tokio::test]
+    async fn test_lookup_summit() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/api/summits/:association/:region", get(summit))
+            .with_state(requests.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let sota = SotaDirectory::new(&format!("http://{addr}"), Duration::from_secs(5)).unwrap();
+        let lookup = Lookup::new(vec![Box::new(sota)], Duration::from_secs(60));
+        let spot: DxEntry =
+            "DX de HB9BIN:    14044.0  HB9BIN/P     x04s HB/BL-001                 1049Z"
+                .parse()
+                .unwrap();
+        let info = lookup.reference_info(&spot).await.unwrap();
+        assert_eq!(info.to_string(), "HB/BL-001 Chasseral (1607m, 8pts)");
+        assert_eq!(info.location, Some((47.1327, 7.0599)));
+        assert!(lookup.reference_info(&spot).await.is_some());
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        let spot: DxEntry =
+            "DX de DL1CR:      3567.0  DL1CR/P      x04s DM/NS-107                 1052Z"
+                .parse()
+                .unwrap();
+        assert_eq!(lookup.reference_info(&spot).await, None);
+
+        // Nothing listening
+        let offline = SotaDirectory::new("http://127.0.0.1:1", Duration::from_secs(1)).unwrap();
+        let lookup = Lookup::new(vec![Box::new(offline)], Duration::from_secs(60));
+        assert_eq!(lookup.reference_info(&spot).await, None);
+    }
+}
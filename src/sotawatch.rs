@@ -0,0 +1,446 @@
+//! Cross-posting SOTA spots from the cluster to SOTAwatch, so activators
+//! spotted only on the cluster show up for chasers following SOTAwatch.
+//!
+//! Spots are posted with the SOTA account of the operator. Only SOTA spots
+//! with a valid summit reference which didn't come from SOTAwatch are
+//! posted, and not when SOTAwatch already has a spot of the activator on
+//! the same summit and frequency.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::SotawatchConfig;
+use crate::metrics::Metrics;
+use crate::parser::{base_callsign, Activity, DxEntry, Source};
+use crate::sink::{self, other, Sink};
+
+const API_URL: &str = "https://api2.sota.org.uk";
+/// Token endpoint of the SOTA single sign-on.
+const TOKEN_URL: &str = "https://sso.sota.org.uk/auth/realms/SOTA/protocol/openid-connect/token";
+const CLIENT_ID: &str = "sotawatch";
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Spots posted by us aren't posted again for this long, even if they
+/// aren't yet or anymore among the recent spots of SOTAwatch.
+const POSTED_FOR: Duration = Duration::from_secs(60 * 60);
+/// Spots this close in frequency are of the same activation.
+const SAME_FREQUENCY_MHZ: f64 = 0.002;
+
+/// Spot as posted to and returned by `/api/spots`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpotPost {
+    pub activator_callsign: String,
+    /// eg. `HB`
+    pub association_code: String,
+    /// eg. `BL-001`
+    pub summit_code: String,
+    /// In MHz, eg. `14.044`
+    pub frequency: String,
+    pub mode: String,
+    pub comments: String,
+    /// Spotter
+    pub callsign: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+impl SpotPost {
+    /// Whether this is a spot of the same activator on the same summit and
+    /// frequency as `other`.
+    fn same_activation(&self, other: &SpotPost) -> bool {
+        let frequency = |post: &SpotPost| post.frequency.trim().parse::<f64>().ok();
+        base_callsign(&self.activator_callsign)
+            .eq_ignore_ascii_case(base_callsign(&other.activator_callsign))
+            && self
+                .association_code
+                .eq_ignore_ascii_case(&other.association_code)
+            && self.summit_code.eq_ignore_ascii_case(&other.summit_code)
+            && match (frequency(self), frequency(other)) {
+                (Some(a), Some(b)) => (a - b).abs() < SAME_FREQUENCY_MHZ,
+                // Can't tell, so rather don't post
+                _ => true,
+            }
+    }
+}
+
+/// Spot of SOTAwatch as returned by `/api/spots`. Only the fields compared
+/// with [SpotPost]s.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecentSpot {
+    activator_callsign: String,
+    association_code: String,
+    summit_code: String,
+    frequency: String,
+}
+
+impl From<RecentSpot> for SpotPost {
+    fn from(spot: RecentSpot) -> Self {
+        SpotPost {
+            activator_callsign: spot.activator_callsign,
+            association_code: spot.association_code,
+            summit_code: spot.summit_code,
+            frequency: spot.frequency,
+            mode: String::new(),
+            comments: String::new(),
+            callsign: String::new(),
+            kind: String::new(),
+        }
+    }
+}
+
+/// Association and summit code of `reference` if it's a valid summit
+/// reference, eg. `HB` and `BL-001` of `HB/BL-001`.
+pub fn summit(reference: &str) -> Option<(&str, &str)> {
+    let (association, summit) = reference.split_once('/')?;
+    let association_ok = (1..=4).contains(&association.len())
+        && association.chars().all(|c| c.is_ascii_alphanumeric())
+        && association.chars().any(|c| c.is_ascii_alphabetic());
+    let summit_ok = summit.len() == 6
+        && summit.bytes().enumerate().all(|(i, b)| match i {
+            0 | 1 => b.is_ascii_alphabetic(),
+            2 => b == b'-',
+            _ => b.is_ascii_digit(),
+        });
+    (association_ok && summit_ok).then_some((association, summit))
+}
+
+/// SOTAwatch mode of `spot`, eg. `data` for FT8.
+fn mode(spot: &DxEntry) -> &'static str {
+    match spot.mode() {
+        Some("CW") => "cw",
+        Some("SSB" | "USB" | "LSB") => "ssb",
+        Some("FM") => "fm",
+        Some("AM") => "am",
+        Some(_) => "data",
+        None => "other",
+    }
+}
+
+/// Spot to post of `spot`, spotted by `callsign`, if it's a SOTA spot from
+/// elsewhere than SOTAwatch with a valid summit reference.
+pub fn payload(spot: &DxEntry, callsign: &str) -> Option<SpotPost> {
+    match spot.cqgma_identifier {
+        Some((Activity::Sota, source)) if source != Source::SotaWatchRss => (),
+        _ => return None,
+    }
+    let references = spot.references();
+    let (association, summit_code) = references.iter().find_map(|r| summit(r))?;
+    let info: Vec<&str> = spot
+        .info
+        .split_whitespace()
+        .filter(|word| !word.eq_ignore_ascii_case(&format!("{association}/{summit_code}")))
+        .collect();
+    let comments = format!("{} de {}", info.join(" "), spot.reporter);
+    let frequency = spot.frequency_mhz_string();
+    Some(SpotPost {
+        activator_callsign: spot.dx.to_uppercase(),
+        association_code: association.to_string(),
+        summit_code: summit_code.to_string(),
+        frequency: frequency.trim_end_matches(" MHz").to_string(),
+        mode: mode(spot).to_string(),
+        comments: comments.trim().to_string(),
+        callsign: callsign.to_string(),
+        kind: "NORMAL".to_string(),
+    })
+}
+
+/// Lets through spots which aren't on SOTAwatch yet.
+#[derive(Default)]
+pub struct Gate {
+    /// Spots posted by us and when
+    posted: Vec<(SystemTime, SpotPost)>,
+}
+
+impl Gate {
+    /// Whether `post` should be posted at `now`: neither the `recent` spots
+    /// of SOTAwatch nor those posted by us lately have the same activation.
+    /// If so, `post` is remembered as posted.
+    pub fn check(&mut self, post: &SpotPost, recent: &[SpotPost], now: SystemTime) -> bool {
+        self.posted.retain(|(at, _)| {
+            now.duration_since(*at)
+                .map_or(true, |elapsed| elapsed < POSTED_FOR)
+        });
+        let posted = self.posted.iter().map(|(_, posted)| posted);
+        if recent
+            .iter()
+            .chain(posted)
+            .any(|spot| post.same_activation(spot))
+        {
+            return false;
+        }
+        self.posted.push((now, post.clone()));
+        true
+    }
+
+    /// Forget that `post` was posted, eg. when posting failed.
+    pub fn forget(&mut self, post: &SpotPost) {
+        self.posted.retain(|(_, posted)| posted != post);
+    }
+}
+
+/// Access and ID token of the SOTA account.
+#[derive(Clone, Deserialize)]
+struct Token {
+    access_token: String,
+    id_token: String,
+    expires_in: u64,
+}
+
+pub struct SotawatchSink {
+    client: Client,
+    username: String,
+    password: String,
+    callsign: String,
+    gate: Mutex<Gate>,
+    token: Mutex<Option<(Instant, Token)>>,
+}
+
+impl SotawatchSink {
+    pub fn new(config: &SotawatchConfig) -> io::Result<Self> {
+        let client = Client::builder().timeout(TIMEOUT).build().map_err(other)?;
+        Ok(Self {
+            client,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            callsign: config
+                .callsign
+                .clone()
+                .unwrap_or_else(|| config.username.clone())
+                .to_uppercase(),
+            gate: Mutex::new(Gate::default()),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// Spots of SOTAwatch from the last hour.
+    async fn recent(&self) -> io::Result<Vec<SpotPost>> {
+        let response = self
+            .client
+            .get(format!("{API_URL}/api/spots/-1/all"))
+            .send()
+            .await
+            .map_err(other)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(other(format!("HTTP {status}")));
+        }
+        let spots: Vec<RecentSpot> = response.json().await.map_err(other)?;
+        Ok(spots.into_iter().map(SpotPost::from).collect())
+    }
+
+    /// Token of the account, logging in again when it's about to expire.
+    async fn token(&self) -> io::Result<Token> {
+        if let Some((expires, token)) = &*self.token.lock().expect("token lock") {
+            if Instant::now() + TIMEOUT < *expires {
+                return Ok(token.clone());
+            }
+        }
+        let form = [
+            ("grant_type", "password"),
+            ("client_id", CLIENT_ID),
+            ("scope", "openid"),
+            ("username", &self.username),
+            ("password", &self.password),
+        ];
+        let response = self
+            .client
+            .post(TOKEN_URL)
+            .form(&form)
+            .send()
+            .await
+            .map_err(other)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(other(format!("logging in failed with HTTP {status}")));
+        }
+        let token: Token = response.json().await.map_err(other)?;
+        let expires = Instant::now() + Duration::from_secs(token.expires_in);
+        *self.token.lock().expect("token lock") = Some((expires, token.clone()));
+        Ok(token)
+    }
+
+    async fn post(&self, post: &SpotPost) -> io::Result<()> {
+        let token = self.token().await?;
+        let response = self
+            .client
+            .post(format!("{API_URL}/api/spots"))
+            .bearer_auth(&token.access_token)
+            .header("id_token", &token.id_token)
+            .json(post)
+            .send()
+            .await
+            .map_err(other)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        if status == reqwest::StatusCode::UNAUTHORIZED {
+            // Log in again for the next try
+            *self.token.lock().expect("token lock") = None;
+        }
+        let text = response.text().await.unwrap_or_default();
+        Err(other(format!("HTTP {status} {}", text.trim())))
+    }
+}
+
+#[async_trait]
+impl Sink for SotawatchSink {
+    fn name(&self) -> &str {
+        "sotawatch"
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let Some(post) = payload(spot, &self.callsign) else {
+            return Ok(());
+        };
+        let recent = self.recent().await?;
+        let new = self
+            .gate
+            .lock()
+            .expect("gate lock")
+            .check(&post, &recent, SystemTime::now());
+        if !new {
+            tracing::debug!(
+                "{} on {}/{} is already on SOTAwatch",
+                post.activator_callsign,
+                post.association_code,
+                post.summit_code
+            );
+            return Ok(());
+        }
+        let posted = self.post(&post).await;
+        if posted.is_err() {
+            self.gate.lock().expect("gate lock").forget(&post);
+        }
+        posted
+    }
+}
+
+/// Post SOTA spots from `spots` which aren't on SOTAwatch until `shutdown`
+/// is cancelled.
+pub async fn run(
+    config: SotawatchConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = SotawatchSink::new(&config)?;
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{payload, summit, Gate, SpotPost};
+    use crate::parser::DxEntry;
+
+    fn spot(line: &str) -> DxEntry {
+        DxEntry::parse_line(line).unwrap()
+    }
+
+    #[test]
+    fn test_payload() {
+        let post = payload(
+            &spot("DX de OH8HUB:    14044.0  HB9BIN/p     x04d HB/BL-001 CW 599          1049Z"),
+            "OH8HUB",
+        )
+        .unwrap();
+        assert_eq!(
+            post,
+            SpotPost {
+                activator_callsign: "HB9BIN/P".to_string(),
+                association_code: "HB".to_string(),
+                summit_code: "BL-001".to_string(),
+                frequency: "14.044".to_string(),
+                mode: "cw".to_string(),
+                comments: "CW 599 de OH8HUB".to_string(),
+                callsign: "OH8HUB".to_string(),
+                kind: "NORMAL".to_string(),
+            }
+        );
+        let json = serde_json::to_value(&post).unwrap();
+        assert_eq!(json["activatorCallsign"], "HB9BIN/P");
+        assert_eq!(json["summitCode"], "BL-001");
+        assert_eq!(json["type"], "NORMAL");
+
+        let post = payload(
+            &spot("DX de AD6VT:     14074.5  AD6VT        x04r W6/ND-101 FT8            1959Z"),
+            "OH8HUB",
+        )
+        .unwrap();
+        assert_eq!(
+            (post.frequency.as_str(), post.mode.as_str()),
+            ("14.0745", "data")
+        );
+
+        // Already on SOTAwatch
+        assert!(payload(
+            &spot("DX de HB9BIN:    14044.0  HB9BIN/P     x04s HB/BL-001                 1049Z"),
+            "OH8HUB"
+        )
+        .is_none());
+        // Not SOTA
+        assert!(payload(
+            &spot("DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"),
+            "OH8HUB"
+        )
+        .is_none());
+        // No valid summit reference
+        assert!(payload(
+            &spot("DX de OH8HUB:    14044.0  HB9BIN/P     x04d HB/BL-01 CW                1049Z"),
+            "OH8HUB"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_summit() {
+        assert_eq!(summit("HB/BL-001"), Some(("HB", "BL-001")));
+        assert_eq!(summit("W6/ND-101"), Some(("W6", "ND-101")));
+        assert_eq!(summit("HB/BL-01"), None);
+        assert_eq!(summit("OHFF-1419"), None);
+        assert_eq!(summit("12/AB-123"), None);
+    }
+
+    #[test]
+    fn test_gate() {
+        let post = |activator: &str, frequency: &str| SpotPost {
+            activator_callsign: activator.to_string(),
+            association_code: "HB".to_string(),
+            summit_code: "BL-001".to_string(),
+            frequency: frequency.to_string(),
+            mode: "cw".to_string(),
+            comments: String::new(),
+            callsign: "OH8HUB".to_string(),
+            kind: "NORMAL".to_string(),
+        };
+        let now = SystemTime::now();
+        let mut gate = Gate::default();
+        let recent = [post("HB9BIN", "14.0445")];
+
+        // SOTAwatch has it already, also without the /P
+        assert!(!gate.check(&post("HB9BIN/P", "14.044"), &recent, now));
+        // On another band it's news
+        assert!(gate.check(&post("HB9BIN/P", "7.032"), &recent, now));
+        // Posted already by us, not yet on SOTAwatch
+        assert!(!gate.check(&post("HB9BIN/P", "7.0325"), &[], now));
+        let later = now + Duration::from_secs(2 * 60 * 60);
+        assert!(gate.check(&post("HB9BIN/P", "7.032"), &[], later));
+
+        // Posting failed, so it's tried again
+        assert!(gate.check(&post("OH2NOS/P", "7.032"), &[], now));
+        gate.forget(&post("OH2NOS/P", "7.032"));
+        assert!(gate.check(&post("OH2NOS/P", "7.032"), &[], now));
+    }
+}
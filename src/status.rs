@@ -0,0 +1,55 @@
+//! Connection status of clusters and Matrix accounts, for readiness checks,
+//! and the [Metrics] served next to them.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::metrics::Metrics;
+
+/// One flag for each cluster and Matrix account. Tasks set their own flag
+/// and the HTTP server reads them all.
+#[derive(Debug, Default)]
+pub struct Status {
+    /// Is the telnet connection of each cluster up
+    pub clusters: Vec<Arc<AtomicBool>>,
+    /// Has each Matrix account synced with its homeserver
+    pub matrix: Vec<Arc<AtomicBool>>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Status {
+    pub fn new(clusters: usize, matrix: usize) -> Self {
+        let flags = |n| (0..n).map(|_| Arc::new(AtomicBool::new(false))).collect();
+        Self {
+            clusters: flags(clusters),
+            matrix: flags(matrix),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// At least one cluster is connected and all Matrix accounts have synced.
+    pub fn is_ready(&self) -> bool {
+        let is_set = |flag: &Arc<AtomicBool>| flag.load(Ordering::Relaxed);
+        self.clusters.iter().any(is_set) && self.matrix.iter().all(is_set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::Status;
+
+    #[test]
+    fn test_ready() {
+        let status = Status::new(2, 2);
+        assert!(!status.is_ready());
+        status.clusters[1].store(true, Ordering::Relaxed);
+        status.matrix[0].store(true, Ordering::Relaxed);
+        assert!(!status.is_ready());
+        status.matrix[1].store(true, Ordering::Relaxed);
+        assert!(status.is_ready());
+        status.clusters[1].store(false, Ordering::Relaxed);
+        assert!(!status.is_ready());
+    }
+}
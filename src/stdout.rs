@@ -0,0 +1,96 @@
+//! Spots as JSON lines to stdout, for piping into other tools.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+
+/// Writes each spot as one JSON object per line, like [crate::jsonl].
+pub struct StdoutSink {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        Self::with_writer(io::stdout())
+    }
+
+    /// Write to `writer` instead of stdout.
+    pub fn with_writer(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Sink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(spot)?;
+        line.push(b'\n');
+        // Flushed line by line, so readers of a pipe see each spot at once
+        let mut writer = self.writer.lock().unwrap_or_else(|err| err.into_inner());
+        writer.write_all(&line)?;
+        writer.flush()
+    }
+}
+
+/// Print spots from `spots` to stdout until `shutdown` is cancelled.
+pub async fn run(
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = StdoutSink::new();
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StdoutSink;
+    use crate::parser::DxEntry;
+    use crate::sink::Sink;
+    use crate::testutil::Capture;
+
+    #[tokio::test]
+    async fn test_one_line_per_spot() {
+        let capture = Capture::default();
+        let sink = StdoutSink::with_writer(capture.clone());
+        let spots: Vec<DxEntry> = [
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+            "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z",
+        ]
+        .iter()
+        .map(|line| line.parse().unwrap())
+        .collect();
+        for spot in &spots {
+            sink.send(spot).await.unwrap();
+        }
+
+        let output = capture.contents();
+        assert!(output.ends_with('\n'));
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), spots.len(), "{output}");
+        for (line, spot) in lines.iter().zip(&spots) {
+            let read: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(read, serde_json::to_value(spot).unwrap());
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! History of forwarded spots in SQLite database.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+use crate::band::Band;
+use crate::config::StoreConfig;
+use crate::parser::{Activity, DxEntry};
+
+/// How often old spots are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Most spots returned by [SpotStore::query].
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS spots (
+        id INTEGER PRIMARY KEY,
+        received INTEGER NOT NULL,
+        callsign TEXT NOT NULL,
+        band TEXT,
+        activity TEXT,
+        frequency REAL NOT NULL,
+        refs TEXT NOT NULL,
+        reporter TEXT NOT NULL,
+        spot_time TEXT NOT NULL,
+        info TEXT NOT NULL,
+        line TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS spots_received ON spots (received);
+";
+
+/// Columns added to [SCHEMA] later, added to older databases on open.
+/// Spots stored before `line` was added can't be queried.
+const ADDED_COLUMNS: &[(&str, &str)] =
+    &[("activity", "TEXT"), ("line", "TEXT NOT NULL DEFAULT ''")];
+
+/// Which spots [SpotStore::query] returns. Empty fields match all spots.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SpotQuery {
+    pub band: Option<Band>,
+    pub activity: Option<Activity>,
+    /// Callsign of the activator, case insensitive
+    pub callsign: Option<String>,
+    /// Program reference, eg. `OHFF-1419`, case insensitive
+    pub reference: Option<String>,
+    /// Received at or after this UNIX time
+    pub since: Option<i64>,
+    /// Received before this UNIX time
+    pub until: Option<i64>,
+    /// At most this many spots, up to [MAX_QUERY_LIMIT]
+    pub limit: Option<usize>,
+}
+
+pub struct SpotStore {
+    conn: Connection,
+    retention: Duration,
+}
+
+impl SpotStore {
+    /// Open or create database at `path`. Spots older than `retention` are
+    /// deleted by [SpotStore::prune].
+    pub fn open(path: &Path, retention: Duration) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?, retention)
+    }
+
+    pub fn open_in_memory(retention: Duration) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?, retention)
+    }
+
+    fn with_connection(conn: Connection, retention: Duration) -> rusqlite::Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        migrate(&conn)?;
+        Ok(Self { conn, retention })
+    }
+
+    pub fn insert(&self, entry: &DxEntry, received: SystemTime) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO spots
+                (received, callsign, band, activity, frequency, refs, reporter, spot_time, info, line)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                unix_secs(received),
+                entry.dx,
+                entry.band().map(|band| band.name()),
+                entry.cqgma_identifier.as_ref().map(|(activity, _)| activity.name()),
+                f64::from(entry.frequency),
+                entry.references().join(","),
+                entry.reporter,
+                entry.timestamp,
+                entry.info,
+                entry.line,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Delete spots received before the retention period. Returns how many
+    /// were deleted.
+    pub fn prune(&self, now: SystemTime) -> rusqlite::Result<usize> {
+        let oldest = unix_secs(now).saturating_sub(self.retention.as_secs() as i64);
+        self.conn
+            .execute("DELETE FROM spots WHERE received < ?1", params![oldest])
+    }
+
+    /// Spots matching `query`, most recently received first.
+    pub fn query(&self, query: &SpotQuery) -> rusqlite::Result<Vec<DxEntry>> {
+        let spots = self.query_received(query)?;
+        Ok(spots.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Like [SpotStore::query] but also when each spot was received.
+    pub fn query_received(
+        &self,
+        query: &SpotQuery,
+    ) -> rusqlite::Result<Vec<(SystemTime, DxEntry)>> {
+        let mut sql = String::from("SELECT received, line FROM spots WHERE 1 = 1");
+        let mut values = Vec::new();
+        let mut and = |condition: &str, value: Value| {
+            sql.push_str(" AND ");
+            sql.push_str(condition);
+            values.push(value);
+        };
+        if let Some(band) = query.band {
+            and("band = ?", band.name().to_string().into());
+        }
+        if let Some(activity) = query.activity {
+            and("activity = ?", activity.name().to_string().into());
+        }
+        if let Some(callsign) = &query.callsign {
+            and("callsign = ? COLLATE NOCASE", callsign.clone().into());
+        }
+        if let Some(reference) = &query.reference {
+            let pattern = format!("%,{},%", escape_like(&reference.to_uppercase()));
+            and("',' || refs || ',' LIKE ? ESCAPE '\\'", pattern.into());
+        }
+        if let Some(since) = query.since {
+            and("received >= ?", since.into());
+        }
+        if let Some(until) = query.until {
+            and("received < ?", until.into());
+        }
+        let limit = query.limit.unwrap_or(MAX_QUERY_LIMIT).min(MAX_QUERY_LIMIT);
+        sql.push_str(&format!(" ORDER BY received DESC, id DESC LIMIT {limit}"));
+
+        let mut statement = self.conn.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (received, line) = row?;
+            if let Ok(entry) = line.parse() {
+                let received = UNIX_EPOCH + Duration::from_secs(received.max(0) as u64);
+                entries.push((received, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn count(&self) -> rusqlite::Result<usize> {
+        self.conn
+            .query_row("SELECT COUNT(*) FROM spots", [], |row| row.get(0))
+    }
+}
+
+/// Add [ADDED_COLUMNS] missing from the spots table.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let mut statement = conn.prepare("PRAGMA table_info(spots)")?;
+    let columns = statement
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (name, definition) in ADDED_COLUMNS {
+        if !columns.iter().any(|column| column == name) {
+            conn.execute_batch(&format!("ALTER TABLE spots ADD COLUMN {name} {definition}"))?;
+            tracing::info!("Added column {name} to the spot store");
+        }
+    }
+    Ok(())
+}
+
+/// `s` matching itself in `LIKE ... ESCAPE '\'`.
+fn escape_like(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn unix_secs(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn to_io(err: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+/// [SpotStore::query] without blocking the runtime.
+pub async fn query(config: StoreConfig, query: SpotQuery) -> io::Result<Vec<DxEntry>> {
+    tokio::task::spawn_blocking(move || {
+        let store = SpotStore::open(&config.path, config.retention())?;
+        store.query(&query)
+    })
+    .await?
+    .map_err(to_io)
+}
+
+/// [SpotStore::query_received] without blocking the runtime.
+pub async fn query_received(
+    config: StoreConfig,
+    query: SpotQuery,
+) -> io::Result<Vec<(SystemTime, DxEntry)>> {
+    tokio::task::spawn_blocking(move || {
+        let store = SpotStore::open(&config.path, config.retention())?;
+        store.query_received(&query)
+    })
+    .await?
+    .map_err(to_io)
+}
+
+/// Store spots from `spots` until `shutdown` is cancelled.
+pub async fn run(
+    config: StoreConfig,
+    mut spots: broadcast::Receiver<Arc<DxEntry>>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let store = SpotStore::open(&config.path, config.retention()).map_err(to_io)?;
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            received = spots.recv() => match received {
+                Ok(entry) => store.insert(&entry, SystemTime::now()).map_err(to_io)?,
+                Err(RecvError::Lagged(n)) => tracing::warn!("Store skipped {n} spots."),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = prune.tick() => {
+                let deleted = store.prune(SystemTime::now()).map_err(to_io)?;
+                tracing::debug!("Deleted {deleted} old spots");
+            }
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use rusqlite::Connection;
+
+    use super::{unix_secs, SpotQuery, SpotStore};
+    use crate::band::Band;
+    use crate::parser::{Activity, DxEntry};
+
+    const LINE: &str =
+        "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z";
+
+    #[test]
+    fn test_insert_and_prune() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let store = SpotStore::open_in_memory(7 * day).unwrap();
+        let entry: DxEntry = LINE.parse().unwrap();
+        let now = SystemTime::now();
+
+        store.insert(&entry, now - 10 * day).unwrap();
+        store.insert(&entry, now - 6 * day).unwrap();
+        store.insert(&entry, now).unwrap();
+        assert_eq!(store.count().unwrap(), 3);
+
+        let (callsign, band, refs): (String, String, String) = store
+            .conn
+            .query_row(
+                "SELECT callsign, band, refs FROM spots ORDER BY received DESC",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (callsign.as_str(), band.as_str(), refs.as_str()),
+            ("OH2NOS/P", "80m", "OHFF-1419")
+        );
+
+        assert_eq!(store.prune(now).unwrap(), 1);
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(store.prune(now).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_query() {
+        let store = SpotStore::open_in_memory(Duration::from_secs(60 * 60)).unwrap();
+        let now = SystemTime::now();
+        let lines = [
+            (LINE, now - Duration::from_secs(120)),
+            (
+                "DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 OH-0123      1150Z",
+                now - Duration::from_secs(60),
+            ),
+            (
+                "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101              1959Z",
+                now,
+            ),
+        ];
+        for (line, received) in lines {
+            store.insert(&line.parse().unwrap(), received).unwrap();
+        }
+        let dx = |query: SpotQuery| -> Vec<(String, f32)> {
+            let entries = store.query(&query).unwrap();
+            entries.into_iter().map(|e| (e.dx, e.frequency)).collect()
+        };
+
+        assert_eq!(dx(SpotQuery::default()).len(), 3);
+        assert_eq!(
+            dx(SpotQuery {
+                band: Some(Band::B20m),
+                ..SpotQuery::default()
+            }),
+            [
+                ("AD6VT".to_string(), 14310.0),
+                ("OH2NOS/P".to_string(), 14062.0)
+            ]
+        );
+        assert_eq!(
+            dx(SpotQuery {
+                activity: Some(Activity::Sota),
+                ..SpotQuery::default()
+            }),
+            [("AD6VT".to_string(), 14310.0)]
+        );
+        assert_eq!(
+            dx(SpotQuery {
+                callsign: Some("oh2nos/p".to_string()),
+                ..SpotQuery::default()
+            })
+            .len(),
+            2
+        );
+        assert_eq!(
+            dx(SpotQuery {
+                reference: Some("oh-0123".to_string()),
+                ..SpotQuery::default()
+            }),
+            [("OH2NOS/P".to_string(), 14062.0)]
+        );
+        // Not a prefix of another reference
+        let query = SpotQuery {
+            reference: Some("OHFF-141".to_string()),
+            ..SpotQuery::default()
+        };
+        assert!(dx(query).is_empty());
+        // Wildcards of LIKE match only themselves
+        for reference in ["OHFF-14%", "OH_F-1419", "%"] {
+            let query = SpotQuery {
+                reference: Some(reference.to_string()),
+                ..SpotQuery::default()
+            };
+            assert!(dx(query).is_empty(), "{reference}");
+        }
+        assert_eq!(
+            dx(SpotQuery {
+                since: Some(unix_secs(now) - 90),
+                until: Some(unix_secs(now)),
+                ..SpotQuery::default()
+            }),
+            [("OH2NOS/P".to_string(), 14062.0)]
+        );
+        assert_eq!(
+            dx(SpotQuery {
+                limit: Some(1),
+                ..SpotQuery::default()
+            }),
+            [("AD6VT".to_string(), 14310.0)]
+        );
+    }
+
+    #[test]
+    fn test_migrate() {
+        // Table of the first version
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE spots (
+                id INTEGER PRIMARY KEY,
+                received INTEGER NOT NULL,
+                callsign TEXT NOT NULL,
+                band TEXT,
+                frequency REAL NOT NULL,
+                refs TEXT NOT NULL,
+                reporter TEXT NOT NULL,
+                spot_time TEXT NOT NULL,
+                info TEXT NOT NULL
+            );
+            INSERT INTO spots (received, callsign, band, frequency, refs, reporter, spot_time, info)
+                VALUES (0, 'OH2NOS/P', '80m', 3644.0, 'OHFF-1419', 'OH2NOS', '1146Z', 'New one!');",
+        )
+        .unwrap();
+
+        let store = SpotStore::with_connection(conn, Duration::from_secs(60)).unwrap();
+        let now = SystemTime::now();
+        store.insert(&LINE.parse().unwrap(), now).unwrap();
+        assert_eq!(store.count().unwrap(), 2);
+        assert_eq!(store.query(&SpotQuery::default()).unwrap().len(), 1);
+        // Opening again doesn't add the columns again
+        let conn = store.conn;
+        SpotStore::with_connection(conn, Duration::from_secs(60)).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! Parsed and filtered spots of clusters for programs using this crate as a
+//! library, without the Matrix bot or any sinks:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use futures::StreamExt;
+//! use puskapupu::config::CqgmaConfig;
+//! use puskapupu::filter::FilterConfig;
+//! use puskapupu::stream::SpotStream;
+//!
+//! let cluster: CqgmaConfig = toml::from_str(
+//!     r#"
+//!     host = "www.cqgma.org:7300"
+//!     username = "N0CALL"
+//!     "#,
+//! )
+//! .expect("valid config");
+//! let stream = SpotStream::start(&[cluster], FilterConfig::default()).await;
+//! let mut spots = Box::pin(stream.spots());
+//! while let Some(spot) = spots.next().await {
+//!     println!("{} {}", spot.dx, spot.frequency_mhz_string());
+//! }
+//! stream.stop().await
+//! # }
+//! ```
+//!
+//! Lost connections are reconnected and failed logins retried like in the
+//! bot, see [crate::supervisor].
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures::stream::{self, Stream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::channel::ChannelLimits;
+use crate::config::CqgmaConfig;
+use crate::cqgma::cqgma_init;
+use crate::filter::FilterConfig;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::status::Status;
+use crate::supervisor::Supervisor;
+
+/// Connections to clusters, running until [SpotStream::stop] is called or
+/// the stream is dropped.
+pub struct SpotStream {
+    spots: broadcast::Sender<Arc<DxEntry>>,
+    filter: watch::Sender<FilterConfig>,
+    status: Status,
+    /// Closing these would end the connections
+    _commands: Vec<UnboundedSender<String>>,
+    shutdown: CancellationToken,
+    supervisor: JoinHandle<io::Result<()>>,
+}
+
+impl SpotStream {
+    /// Connect to `clusters`, passing on spots which pass `filter` or the
+    /// own filter of the cluster. Must be called within a Tokio runtime.
+    pub async fn start(clusters: &[CqgmaConfig], filter: FilterConfig) -> Self {
+        Self::with_limits(clusters, filter, ChannelLimits::default()).await
+    }
+
+    /// Like [SpotStream::start] with the channel of spots sized by `limits`.
+    pub async fn with_limits(
+        clusters: &[CqgmaConfig],
+        filter: FilterConfig,
+        limits: ChannelLimits,
+    ) -> Self {
+        let (filter, filter_rx) = watch::channel(filter);
+        let status = Status::new(clusters.len(), 0);
+        let shutdown = CancellationToken::new();
+        let state = cqgma_init(clusters, filter_rx, None, limits, shutdown.clone(), &status).await;
+        let supervisor = Supervisor::new(state.tasks, true, shutdown.clone());
+        Self {
+            spots: state.spots,
+            filter,
+            status,
+            _commands: state.telnet_tx,
+            shutdown,
+            supervisor: tokio::spawn(supervisor.run()),
+        }
+    }
+
+    /// Spots from now on. A receiver falling behind skips the oldest spots,
+    /// see [CqgmaState::spots](crate::cqgma::CqgmaState::spots).
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<DxEntry>> {
+        self.spots.subscribe()
+    }
+
+    /// Spots from now on, ending after [SpotStream::stop]. Skipped spots of
+    /// a slow reader are logged.
+    pub fn spots(&self) -> impl Stream<Item = DxEntry> + Send + 'static {
+        stream::unfold(self.subscribe(), |mut spots| async move {
+            loop {
+                match spots.recv().await {
+                    Ok(spot) => return Some((DxEntry::clone(&spot), spots)),
+                    Err(RecvError::Lagged(n)) => {
+                        tracing::warn!("Spot stream is too slow to keep up. Skipped {n} spots.");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Change the filter of clusters not having their own.
+    pub fn set_filter(&self, filter: FilterConfig) {
+        self.filter.send_replace(filter);
+    }
+
+    /// Whether at least one cluster is connected.
+    pub fn is_connected(&self) -> bool {
+        let connected = |flag: &Arc<AtomicBool>| flag.load(Ordering::Relaxed);
+        self.status.clusters.iter().any(connected)
+    }
+
+    /// Counts of cluster lines parsed as spots and failed to parse.
+    pub fn metrics(&self) -> &Metrics {
+        &self.status.metrics
+    }
+
+    /// Disconnect from the clusters. Returns the error of a connection
+    /// which failed too often before this.
+    pub async fn stop(mut self) -> io::Result<()> {
+        self.shutdown.cancel();
+        (&mut self.supervisor).await?
+    }
+}
+
+impl Drop for SpotStream {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
@@ -0,0 +1,468 @@
+//! Keep long-running tasks going by restarting them when they finish.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use futures::future::select_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::task::{JoinError, JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+/// At most this many restarts within [RESTART_WINDOW] before giving up.
+pub const MAX_RESTARTS: usize = 5;
+pub const RESTART_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// Wait before the first restart of a task. Doubled for each restart up to
+/// [MAX_BACKOFF] unless the task ran for [RESTART_WINDOW].
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How long tasks get to finish after shutdown is requested.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type TaskFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+/// A task which can be started again. Tasks should run forever, so finishing
+/// at all is treated as a failure.
+pub struct Task {
+    pub name: String,
+    factory: Box<dyn FnMut() -> TaskFuture + Send>,
+}
+
+impl Task {
+    pub fn new<F, Fut>(name: impl Into<String>, mut factory: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        Self {
+            name: name.into(),
+            factory: Box::new(move || Box::pin(factory())),
+        }
+    }
+
+    /// Logs of the task are in a `task` span with its name. Tasks spawning
+    /// tasks of their own should pass the span on with
+    /// [in_current_span](tracing::Instrument::in_current_span).
+    fn spawn(&mut self) -> JoinHandle<io::Result<()>> {
+        let span = tracing::info_span!("task", name = %self.name);
+        tokio::spawn((self.factory)().instrument(span))
+    }
+}
+
+/// Restarts allowed within a sliding window of time.
+#[derive(Debug)]
+pub struct RestartBudget {
+    max_restarts: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub fn new(max_restarts: usize, window: Duration) -> Self {
+        Self {
+            max_restarts,
+            window,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Returns `true` and counts the restart if the budget allows one more
+    /// at `now`.
+    pub fn try_restart(&mut self, now: Instant) -> bool {
+        while let Some(first) = self.restarts.front() {
+            if now.duration_since(*first) < self.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+pub struct Supervisor {
+    tasks: Vec<Task>,
+    budget: RestartBudget,
+    initial_backoff: Duration,
+    restart: bool,
+    shutdown: CancellationToken,
+}
+
+impl Supervisor {
+    /// With `restart` false the first finished task ends supervision, which
+    /// is handy for debugging. Tasks are expected to finish when `shutdown`
+    /// is cancelled and are not restarted after that.
+    pub fn new(tasks: Vec<Task>, restart: bool, shutdown: CancellationToken) -> Self {
+        Self {
+            tasks,
+            budget: RestartBudget::new(MAX_RESTARTS, RESTART_WINDOW),
+            initial_backoff: INITIAL_BACKOFF,
+            restart,
+            shutdown,
+        }
+    }
+
+    /// Run tasks until restarts exhaust the budget or all tasks finish after
+    /// shutdown. Returns the error of the task which couldn't be restarted
+    /// anymore.
+    pub async fn run(mut self) -> io::Result<()> {
+        let mut running = FuturesUnordered::new();
+        for (i, task) in self.tasks.iter_mut().enumerate() {
+            running.push(join(i, task.spawn()));
+        }
+        let mut started = vec![Instant::now(); self.tasks.len()];
+        let mut backoff = vec![self.initial_backoff; self.tasks.len()];
+
+        while let Some((i, result)) = running.next().await {
+            let task = &mut self.tasks[i];
+            if self.shutdown.is_cancelled() {
+                tracing::info!("Task {} has shut down: {result:?}", task.name);
+                continue;
+            }
+            let err = exit_error(result);
+            tracing::error!("Task {} has finished: {err} ({:?})", task.name, err.kind());
+
+            let now = Instant::now();
+            if !self.restart {
+                return Err(err);
+            }
+            if !self.budget.try_restart(now) {
+                tracing::error!("Too many restarts. Giving up.");
+                return Err(err);
+            }
+
+            if now.duration_since(started[i]) >= RESTART_WINDOW {
+                backoff[i] = self.initial_backoff;
+            }
+            tracing::warn!(
+                "Restarting task {} in {} seconds",
+                task.name,
+                backoff[i].as_secs()
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(backoff[i]) => (),
+                _ = self.shutdown.cancelled() => continue,
+            }
+            backoff[i] = (backoff[i] * 2).min(MAX_BACKOFF);
+            started[i] = Instant::now();
+            running.push(join(i, task.spawn()));
+        }
+        Ok(())
+    }
+}
+
+/// Shut down `stages` in order, at most `timeout` in total. The token of a
+/// stage is cancelled and its supervisor waited for before the next stage,
+/// so eg. Matrix accounts can send the spots already received after the
+/// clusters have stopped. A failed stage doesn't stop the rest from being
+/// shut down; the first error is returned at the end.
+pub async fn shut_down(
+    stages: Vec<(CancellationToken, JoinHandle<io::Result<()>>)>,
+    timeout: Duration,
+) -> io::Result<()> {
+    let tokens: Vec<CancellationToken> = stages.iter().map(|(token, _)| token.clone()).collect();
+    let stages = async {
+        let mut first_err = None;
+        for (shutdown, supervisor) in stages {
+            shutdown.cancel();
+            let result = supervisor
+                .await
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+                .and_then(|result| result);
+            match result {
+                Ok(()) => (),
+                Err(err) if first_err.is_none() => first_err = Some(err),
+                // Only the first is returned
+                Err(err) => tracing::warn!("Unclean shutdown: {err}"),
+            }
+        }
+        first_err.map_or(Ok(()), Err)
+    };
+    match tokio::time::timeout(timeout, stages).await {
+        Ok(result) => result,
+        Err(_) => {
+            for token in tokens {
+                token.cancel();
+            }
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("tasks didn't finish in {} seconds", timeout.as_secs()),
+            ))
+        }
+    }
+}
+
+/// Why the task finished. Tasks should run forever, so even success is an
+/// error.
+fn exit_error(result: Result<io::Result<()>, JoinError>) -> io::Error {
+    match result {
+        Ok(Ok(())) => io::Error::new(io::ErrorKind::Other, "task finished"),
+        Ok(Err(err)) => err,
+        Err(err) if err.is_panic() => {
+            let panic = err.into_panic();
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            io::Error::new(io::ErrorKind::Other, format!("task panicked: {msg}"))
+        }
+        Err(err) => io::Error::new(io::ErrorKind::Other, err),
+    }
+}
+
+/// Wait for the task and tell which one it was.
+async fn join(
+    i: usize,
+    handle: JoinHandle<io::Result<()>>,
+) -> (usize, Result<io::Result<()>, JoinError>) {
+    (i, handle.await)
+}
+
+/// Wait until the first of `handles` finishes and abort the rest. Useful
+/// for making one restartable [Task] of tasks which only work together.
+pub async fn first_finished(handles: Vec<JoinHandle<io::Result<()>>>) -> io::Result<()> {
+    if handles.is_empty() {
+        return Ok(());
+    }
+    let (result, _, rest) = select_all(handles).await;
+    for handle in rest {
+        handle.abort();
+    }
+    result.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use tokio::sync::broadcast;
+    use tokio_util::sync::CancellationToken;
+    use tracing::Instrument;
+
+    use super::{shut_down, RestartBudget, Supervisor, Task};
+    use crate::metrics::SinkHealth;
+    use crate::parser::DxEntry;
+    use crate::sink;
+    use crate::testutil::{capture_logs, RecordingSink};
+
+    #[test]
+    fn test_restart_budget() {
+        let window = Duration::from_secs(60);
+        let mut budget = RestartBudget::new(2, window);
+        let now = Instant::now();
+
+        assert!(budget.try_restart(now));
+        assert!(budget.try_restart(now + Duration::from_secs(10)));
+        assert!(!budget.try_restart(now + Duration::from_secs(20)));
+        // First restart is out of the window
+        assert!(budget.try_restart(now + window));
+        assert!(!budget.try_restart(now + window));
+        assert!(budget.try_restart(now + window + Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn test_supervisor_gives_up() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counter = starts.clone();
+        let failing = Task::new("failing", move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async { Err(io::Error::new(io::ErrorKind::Other, "boom")) }
+        });
+        let forever = Task::new("forever", std::future::pending);
+
+        let mut supervisor =
+            Supervisor::new(vec![forever, failing], true, CancellationToken::new());
+        supervisor.budget = RestartBudget::new(3, Duration::from_secs(60));
+        supervisor.initial_backoff = Duration::ZERO;
+        let err = supervisor.run().await.unwrap_err();
+        assert_eq!(err.to_string(), "boom");
+        assert_eq!(starts.load(Ordering::Relaxed), 4);
+
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counter = starts.clone();
+        let failing = Task::new("failing", move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async { Ok(()) }
+        });
+        let err = Supervisor::new(vec![failing], false, CancellationToken::new())
+            .run()
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "task finished");
+        assert_eq!(starts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let shutdown = CancellationToken::new();
+        let starts = Arc::new(AtomicUsize::new(0));
+        let task = |shutdown: CancellationToken, starts: Arc<AtomicUsize>| {
+            Task::new("cooperative", move || {
+                starts.fetch_add(1, Ordering::Relaxed);
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown.cancelled().await;
+                    Ok(())
+                }
+            })
+        };
+        let tasks = vec![
+            task(shutdown.clone(), starts.clone()),
+            task(shutdown.clone(), starts.clone()),
+        ];
+        let supervisor = tokio::spawn(Supervisor::new(tasks, true, shutdown.clone()).run());
+        let timeout = Duration::from_secs(5);
+        shut_down(vec![(shutdown, supervisor)], timeout)
+            .await
+            .unwrap();
+        // Finished tasks were not restarted
+        assert_eq!(starts.load(Ordering::Relaxed), 2);
+
+        let shutdown = CancellationToken::new();
+        let stuck = Task::new("stuck", std::future::pending);
+        let supervisor = tokio::spawn(Supervisor::new(vec![stuck], true, shutdown.clone()).run());
+        let timeout = Duration::from_millis(10);
+        let err = shut_down(vec![(shutdown, supervisor)], timeout)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // A failed stage doesn't stop the later ones from shutting down
+        let (first, second) = (CancellationToken::new(), CancellationToken::new());
+        let failed = tokio::spawn(async { Err(io::Error::new(io::ErrorKind::Other, "boom")) });
+        let starts = Arc::new(AtomicUsize::new(0));
+        let tasks = vec![task(second.clone(), starts.clone())];
+        let supervisor = tokio::spawn(Supervisor::new(tasks, true, second.clone()).run());
+        let stages = vec![(first, failed), (second.clone(), supervisor)];
+        let err = shut_down(stages, Duration::from_secs(5)).await.unwrap_err();
+        assert_eq!(err.to_string(), "boom");
+        assert!(second.is_cancelled());
+        assert_eq!(starts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_task_span() {
+        let (logs, _guard) = capture_logs();
+        let entry: DxEntry =
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+                .parse()
+                .unwrap();
+        let (spots, _) = broadcast::channel(16);
+        let recording = Arc::new(RecordingSink::new("matrix"));
+
+        let task = Task::new("cqgma www.cqgma.org:7300", {
+            let (entry, spots, recording) = (Arc::new(entry.clone()), spots, recording.clone());
+            move || {
+                let subscribed = spots.subscribe();
+                spots.send(entry.clone()).unwrap();
+                let health = Arc::new(SinkHealth::default());
+                let shutdown = CancellationToken::new();
+                shutdown.cancel();
+                let inner = sink::run(Box::new(recording.clone()), subscribed, health, shutdown);
+                async move {
+                    tracing::info!("starting");
+                    tokio::spawn(inner.in_current_span()).await?
+                }
+            }
+        });
+        let _ = Supervisor::new(vec![task], false, CancellationToken::new())
+            .run()
+            .await;
+        assert_eq!(recording.sent(), ["OH2NOS/P"]);
+        let logs = logs.contents();
+        assert!(
+            logs.contains(
+                "task{name=cqgma www.cqgma.org:7300}: puskapupu::supervisor::tests: starting"
+            ),
+            "{logs}"
+        );
+        let forwarded = format!(
+            "task{{name=cqgma www.cqgma.org:7300}}:spot{{id={} dx=OH2NOS/P frequency=3644.0 reporter=OH2NOS}}: puskapupu::testutil: Sent to matrix",
+            entry.id()
+        );
+        assert!(logs.contains(&forwarded), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let (intake, drain) = (CancellationToken::new(), CancellationToken::new());
+        let (spots_tx, spots_rx) = broadcast::channel(16);
+        let sent = Arc::new(Mutex::new(Vec::new()));
+
+        let cluster = Task::new("cluster", {
+            let intake = intake.clone();
+            move || {
+                let (intake, spots) = (intake.clone(), spots_tx.clone());
+                async move {
+                    spots.send("queued").unwrap();
+                    intake.cancelled().await;
+                    // Received just before stopping
+                    spots.send("last").unwrap();
+                    Ok(())
+                }
+            }
+        });
+        let matrix = Task::new("matrix", {
+            let (drain, sent) = (drain.clone(), sent.clone());
+            let mut spots = Some(spots_rx);
+            move || {
+                let (drain, sent) = (drain.clone(), sent.clone());
+                let mut spots = spots.take().expect("not restarted");
+                async move {
+                    drain.cancelled().await;
+                    while let Ok(spot) = spots.try_recv() {
+                        sent.lock().unwrap().push(spot);
+                    }
+                    Ok(())
+                }
+            }
+        });
+
+        let clusters = tokio::spawn(Supervisor::new(vec![cluster], true, intake.clone()).run());
+        let others = tokio::spawn(Supervisor::new(vec![matrix], true, drain.clone()).run());
+        tokio::task::yield_now().await;
+        let stages = vec![(intake, clusters), (drain, others)];
+        shut_down(stages, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["queued", "last"]);
+    }
+
+    #[tokio::test]
+    async fn test_exit_error_is_logged() {
+        let (logs, _guard) = capture_logs();
+
+        let task = Task::new("cqgma www.cqgma.org:7300", || async {
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "couldn't login",
+            ))
+        });
+        let err = Supervisor::new(vec![task], false, CancellationToken::new())
+            .run()
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(logs.contents().contains(
+            "Task cqgma www.cqgma.org:7300 has finished: couldn't login (ConnectionAborted)"
+        ));
+
+        let task = Task::new("matrix", || async { panic!("oh no") });
+        let err = Supervisor::new(vec![task], false, CancellationToken::new())
+            .run()
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "task panicked: oh no");
+        assert!(logs
+            .contents()
+            .contains("Task matrix has finished: task panicked: oh no (Other)"));
+    }
+}
@@ -0,0 +1,304 @@
+//! Sending spots to Telegram chats with the Bot API.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{TelegramConfig, DEFAULT_TELEGRAM_TEMPLATE};
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+use crate::template::{Format, Template, MARKDOWN_SPECIAL};
+
+const API_URL: &str = "https://api.telegram.org";
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest message Telegram accepts, in characters.
+const MAX_MESSAGE_CHARS: usize = 4096;
+/// Telegram allows about one message per second to the same chat.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// How many times a rate limited message is tried again.
+const RATE_LIMIT_RETRIES: u32 = 3;
+/// Longest wait for rate limit. Longer waits fail the message instead.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Error response of the Bot API.
+#[derive(Deserialize)]
+struct ApiError {
+    description: Option<String>,
+    parameters: Option<ResponseParameters>,
+}
+
+#[derive(Deserialize)]
+struct ResponseParameters {
+    retry_after: Option<u64>,
+}
+
+pub struct TelegramSink {
+    name: String,
+    client: Client,
+    bot_token: String,
+    chat_id: String,
+    template: Template,
+    format: Format,
+    /// When the previous message was sent
+    last_sent: Mutex<Option<Instant>>,
+}
+
+impl TelegramSink {
+    /// Sink of `config`, the `index`th in the config.
+    pub fn new(config: &TelegramConfig, index: usize) -> io::Result<Self> {
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let template = config.template.clone().unwrap_or_else(|| {
+            Template::parse(DEFAULT_TELEGRAM_TEMPLATE).expect("default template is valid")
+        });
+        Ok(Self {
+            // Many bots may post to the same chat
+            name: format!("telegram[{index}]"),
+            client,
+            bot_token: config.bot_token.clone(),
+            chat_id: config.chat_id.clone(),
+            template,
+            format: config.format,
+            last_sent: Mutex::new(None),
+        })
+    }
+
+    /// URL of `sendMessage`. Contains the secret token.
+    fn url(&self) -> String {
+        format!("{API_URL}/bot{}/sendMessage", self.bot_token)
+    }
+
+    /// Body of `sendMessage` for `spot`: the callsign in bold followed by
+    /// the template. Plain templates are cut to fit in a message. Markup
+    /// that doesn't fit would break if cut, so the spot is sent as cut
+    /// plain text instead.
+    fn body(&self, spot: &DxEntry) -> serde_json::Value {
+        let markup = match self.format {
+            Format::Html => {
+                let callsign = Format::Html.escape(&spot.dx);
+                let text = self.template.render_as(spot, Format::Html);
+                Some((format!("<b>{callsign}</b> {text}"), "HTML"))
+            }
+            Format::Markdown => {
+                let callsign = Format::Markdown.escape(&spot.dx);
+                let text = self.template.render_as(spot, Format::Markdown);
+                Some((format!("*{callsign}* {text}"), "MarkdownV2"))
+            }
+            Format::Plain | Format::Json => None,
+        };
+        let (text, parse_mode) = match markup {
+            Some((text, parse_mode)) if text.chars().count() <= MAX_MESSAGE_CHARS => {
+                (text, parse_mode)
+            }
+            _ => {
+                let callsign = format!("*{}* ", escape(&spot.dx, MAX_MESSAGE_CHARS));
+                let room = MAX_MESSAGE_CHARS.saturating_sub(callsign.chars().count());
+                (
+                    callsign + &escape(&self.template.render(spot), room),
+                    "MarkdownV2",
+                )
+            }
+        };
+        json!({
+            "chat_id": self.chat_id,
+            "text": text,
+            "parse_mode": parse_mode,
+            "link_preview_options": { "is_disabled": true },
+        })
+    }
+
+    /// Wait until [MIN_INTERVAL] has passed since the previous message.
+    async fn wait_turn(&self) {
+        let last_sent = *self.last_sent.lock().expect("last_sent lock");
+        if let Some(last_sent) = last_sent {
+            tokio::time::sleep_until(last_sent + MIN_INTERVAL).await;
+        }
+        *self.last_sent.lock().expect("last_sent lock") = Some(Instant::now());
+    }
+}
+
+#[async_trait]
+impl Sink for TelegramSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let body = self.body(spot);
+        let mut retries = RATE_LIMIT_RETRIES;
+        loop {
+            self.wait_turn().await;
+            let response = self
+                .client
+                .post(self.url())
+                .json(&body)
+                .send()
+                .await
+                // The URL has the token
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.without_url()))?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            let error: Option<ApiError> = response.json().await.ok();
+            let retry_after = error
+                .as_ref()
+                .and_then(|error| error.parameters.as_ref()?.retry_after)
+                .map(Duration::from_secs);
+            match retry_after {
+                Some(retry_after)
+                    if status == StatusCode::TOO_MANY_REQUESTS
+                        && retries > 0
+                        && retry_after <= MAX_RETRY_AFTER =>
+                {
+                    tracing::debug!(
+                        "Rate limited by Telegram. Retrying in {} seconds.",
+                        retry_after.as_secs()
+                    );
+                    tokio::time::sleep(retry_after).await;
+                    retries -= 1;
+                }
+                _ => {
+                    let description = error.and_then(|error| error.description);
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("HTTP {status}: {}", description.unwrap_or_default()),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// `text` escaped for MarkdownV2, at most `max` characters. Cut text ends
+/// with `…`.
+fn escape(text: &str, max: usize) -> String {
+    let mut out = String::new();
+    let mut len = 0;
+    for (i, c) in text.char_indices() {
+        let escaped_len = if MARKDOWN_SPECIAL.contains(c) { 2 } else { 1 };
+        // Always leave room for the ellipsis unless this is the last char
+        let last = i + c.len_utf8() == text.len();
+        if len + escaped_len + usize::from(!last) > max {
+            out.push('…');
+            break;
+        }
+        if escaped_len == 2 {
+            out.push('\\');
+        }
+        out.push(c);
+        len += escaped_len;
+    }
+    out
+}
+
+/// Send spots from `spots` to the chat of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: TelegramConfig,
+    index: usize,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = TelegramSink::new(&config, index)?;
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{escape, TelegramSink, MAX_MESSAGE_CHARS};
+    use crate::config::TelegramConfig;
+    use crate::sink::Sink;
+    use crate::template::{Format, Template};
+
+    #[test]
+    fn test_request() {
+        let config = TelegramConfig {
+            bot_token: "123456:ABC-DEF".to_string(),
+            chat_id: "@ohffspots".to_string(),
+            template: None,
+            format: Format::Plain,
+        };
+        let mut sink = TelegramSink::new(&config, 0).unwrap();
+        assert_eq!(sink.name(), "telegram[0]");
+        assert_eq!(
+            sink.url(),
+            "https://api.telegram.org/bot123456:ABC-DEF/sendMessage"
+        );
+
+        let spot = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            sink.body(&spot),
+            json!({
+                "chat_id": "@ohffspots",
+                "text": "*OH2NOS/P* 3\\.644 MHz OHFF\\-1419 New one\\! \\(de OH2NOS 1146Z\\)",
+                "parse_mode": "MarkdownV2",
+                "link_preview_options": { "is_disabled": true },
+            })
+        );
+
+        sink.template = Template::parse(&"{info}".repeat(1000)).unwrap();
+        let text = sink.body(&spot)["text"].as_str().unwrap().to_string();
+        assert!(text.chars().count() <= MAX_MESSAGE_CHARS);
+        assert!(text.ends_with('…'));
+
+        sink.template = Template::parse("<i>{info}</i> {frequency}").unwrap();
+        sink.format = Format::Html;
+        let body = sink.body(&spot);
+        assert_eq!(body["parse_mode"], "HTML");
+        assert_eq!(
+            body["text"],
+            "<b>OH2NOS/P</b> <i>OHFF-1419 New one!</i> 3.644 MHz"
+        );
+
+        sink.template = Template::parse("_{info}_ {frequency}").unwrap();
+        sink.format = Format::Markdown;
+        let body = sink.body(&spot);
+        assert_eq!(body["parse_mode"], "MarkdownV2");
+        assert_eq!(
+            body["text"],
+            "*OH2NOS/P* _OHFF\\-1419 New one\\!_ 3\\.644 MHz"
+        );
+
+        // Too long markup is sent as cut plain text
+        for (format, template) in [
+            (Format::Html, "<i>{info}</i>"),
+            (Format::Markdown, "_{info}_"),
+        ] {
+            sink.template = Template::parse(&template.repeat(1000)).unwrap();
+            sink.format = format;
+            let body = sink.body(&spot);
+            let text = body["text"].as_str().unwrap();
+            assert_eq!(body["parse_mode"], "MarkdownV2");
+            assert!(text.chars().count() <= MAX_MESSAGE_CHARS);
+            assert!(text.starts_with("*OH2NOS/P* "));
+            assert!(text.ends_with('…'));
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("OH-0123.", 100), "OH\\-0123\\.");
+        assert_eq!(escape("abc", 3), "abc");
+        assert_eq!(escape("abcd", 3), "ab…");
+        // Escaped char doesn't fit with the ellipsis
+        assert_eq!(escape("a-b", 3), "a…");
+    }
+}
@@ -0,0 +1,307 @@
+//! Message templates with `{placeholder}`s filled from [DxEntry].
+//!
+//! Each sink renders its template in its own [Format], eg. HTML for Matrix
+//! or Markdown for Telegram. The values of placeholders are escaped for the
+//! format while the rest of the template is markup as is. `{{` and `}}` are
+//! literal braces, eg. for JSON.
+
+use std::fmt;
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::parser::DxEntry;
+
+/// Used unless something else is configured.
+pub const DEFAULT_TEMPLATE: &str = "{dx} {frequency} {info} (de {reporter} {time})";
+
+/// Spot templates are tried on when checking them, see [Template::check].
+const SAMPLE: &str = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 \"New one!\"    1146Z";
+
+/// Markup of a rendered template.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Text as is
+    #[default]
+    Plain,
+    /// HTML, values escaped as text
+    Html,
+    /// Telegram MarkdownV2, values escaped with `\\`
+    Markdown,
+    /// JSON, values escaped for use inside strings
+    Json,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Plain => "plain",
+            Format::Html => "html",
+            Format::Markdown => "markdown",
+            Format::Json => "json",
+        }
+    }
+
+    /// `value` escaped for the format.
+    pub fn escape(&self, value: &str) -> String {
+        let mut out = String::with_capacity(value.len());
+        for c in value.chars() {
+            match (self, c) {
+                (Format::Html, '&') => out.push_str("&amp;"),
+                (Format::Html, '<') => out.push_str("&lt;"),
+                (Format::Html, '>') => out.push_str("&gt;"),
+                (Format::Html, '"') => out.push_str("&quot;"),
+                (Format::Markdown, c) if MARKDOWN_SPECIAL.contains(c) => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                (Format::Json, '"') => out.push_str("\\\""),
+                (Format::Json, '\\') => out.push_str("\\\\"),
+                (Format::Json, c) if c.is_control() => {
+                    let _ = write!(out, "\\u{:04x}", u32::from(c));
+                }
+                (_, c) => out.push(c),
+            }
+        }
+        out
+    }
+}
+
+/// Characters escaped in Telegram MarkdownV2.
+pub const MARKDOWN_SPECIAL: &str = "_*[]()~`>#+-=|{}.!\\";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Dx,
+    Frequency,
+    Band,
+    Info,
+    Reporter,
+    Time,
+    Grid,
+    Reference,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("dx", Field::Dx),
+    ("frequency", Field::Frequency),
+    ("band", Field::Band),
+    ("info", Field::Info),
+    ("reporter", Field::Reporter),
+    ("time", Field::Time),
+    ("grid", Field::Grid),
+    ("reference", Field::Reference),
+];
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Template, String> {
+        let mut segments = Vec::new();
+        let mut rest = source;
+
+        let mut literal = String::new();
+        while let Some(start) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..start]);
+            let brace = &rest[start..];
+            if brace.starts_with("{{") || brace.starts_with("}}") {
+                literal.push_str(&brace[..1]);
+                rest = &brace[2..];
+                continue;
+            }
+            if brace.starts_with('}') {
+                return Err(format!("unopened '}}' in template '{source}'"));
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in template '{source}'"))?;
+            let name = &rest[start + 1..start + end];
+            let field = FIELDS
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, field)| *field)
+                .ok_or_else(|| {
+                    format!("unknown placeholder '{{{name}}}' in template '{source}'")
+                })?;
+            segments.push(Segment::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Template {
+            source: source.to_string(),
+            segments,
+        })
+    }
+
+    /// Plain text of `entry`.
+    pub fn render(&self, entry: &DxEntry) -> String {
+        self.render_as(entry, Format::Plain)
+    }
+
+    /// `entry` in `format`, with the values of placeholders escaped.
+    pub fn render_as(&self, entry: &DxEntry, format: Format) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            let value = match segment {
+                Segment::Literal(s) => {
+                    out.push_str(s);
+                    continue;
+                }
+                Segment::Field(field) => match field {
+                    Field::Dx => entry.dx.clone(),
+                    Field::Frequency => entry.frequency_mhz_string(),
+                    Field::Band => entry.band().map_or("", |b| b.name()).to_string(),
+                    Field::Info => entry.info.clone(),
+                    Field::Reporter => entry.reporter.clone(),
+                    Field::Time => format!("{}Z", entry.timestamp),
+                    Field::Grid => entry.grid.clone().unwrap_or_default(),
+                    // Looked up details if there are any, else just the code
+                    Field::Reference => match &entry.reference_info {
+                        Some(info) => info.to_string(),
+                        None => entry.references().first().cloned().unwrap_or_default(),
+                    },
+                },
+            };
+            match format {
+                Format::Plain => out.push_str(&value),
+                _ => out.push_str(&format.escape(&value)),
+            }
+        }
+        out
+    }
+
+    /// Check that spots rendered in `format` are valid, eg. that a JSON
+    /// template gives JSON. Tried on a sample spot with characters needing
+    /// escapes.
+    pub fn check(&self, format: Format) -> Result<(), String> {
+        let sample = DxEntry::parse_line(SAMPLE).expect("sample spot parses");
+        let rendered = self.render_as(&sample, format);
+        if format == Format::Json {
+            serde_json::from_str::<serde_json::Value>(&rendered)
+                .map_err(|err| format!("template '{}' doesn't give JSON: {err}", self.source))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template::parse(DEFAULT_TEMPLATE).expect("default template is valid")
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Template::parse(&s)
+    }
+}
+
+impl From<Template> for String {
+    fn from(template: Template) -> String {
+        template.source
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Template};
+    use crate::lookup::ReferenceInfo;
+    use crate::parser::DxEntry;
+
+    #[test]
+    fn test_template() {
+        let entry: DxEntry =
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            Template::default().render(&entry),
+            "OH2NOS/P 3.644 MHz OHFF-1419 New one! (de OH2NOS 1146Z)"
+        );
+
+        let template = Template::parse("{band}: {dx} {grid}").unwrap();
+        assert_eq!(template.render(&entry), "80m: OH2NOS/P ");
+
+        let template = Template::parse("{dx} {reference}").unwrap();
+        assert_eq!(template.render(&entry), "OH2NOS/P OHFF-1419");
+        let mut entry = entry;
+        entry.reference_info = Some(ReferenceInfo {
+            code: "OHFF-1419".to_string(),
+            name: "Kuusijärvi".to_string(),
+            details: None,
+            location: None,
+        });
+        assert_eq!(template.render(&entry), "OH2NOS/P OHFF-1419 Kuusijärvi");
+
+        assert!(Template::parse("{dx} {nope}").is_err());
+        assert!(Template::parse("{dx").is_err());
+        assert!(Template::parse("dx}").is_err());
+    }
+
+    #[test]
+    fn test_formats() {
+        let entry: DxEntry =
+            "DX de OH8HUB:    14062.0  OH2NOS/P     x01f OHFF-1419 CW <QRT> \"A&B\"    1150Z"
+                .parse()
+                .unwrap();
+
+        // Matrix
+        let html = Template::parse("<b>{dx}</b> {frequency} {info}").unwrap();
+        assert_eq!(
+            html.render_as(&entry, Format::Html),
+            "<b>OH2NOS/P</b> 14.062 MHz OHFF-1419 CW &lt;QRT&gt; &quot;A&amp;B&quot;"
+        );
+        // MQTT
+        let json = Template::parse(r#"{{"call": "{dx}", "info": "{info}"}}"#).unwrap();
+        let rendered = json.render_as(&entry, Format::Json);
+        assert_eq!(
+            rendered,
+            r#"{"call": "OH2NOS/P", "info": "OHFF-1419 CW <QRT> \"A&B\""}"#
+        );
+        let value: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(value["info"], "OHFF-1419 CW <QRT> \"A&B\"");
+        assert!(json.check(Format::Json).is_ok());
+        // Telegram
+        let markdown = Template::parse("*{dx}* {frequency}").unwrap();
+        assert_eq!(
+            markdown.render_as(&entry, Format::Markdown),
+            "*OH2NOS/P* 14\\.062 MHz"
+        );
+        assert_eq!(markdown.render(&entry), "*OH2NOS/P* 14.062 MHz");
+
+        let broken = Template::parse(r#"{{"call": {dx}}}"#).unwrap();
+        assert!(broken
+            .check(Format::Json)
+            .unwrap_err()
+            .starts_with("template '{{\"call\": {dx}}}' doesn't give JSON"));
+        assert!(broken.check(Format::Plain).is_ok());
+    }
+}
@@ -0,0 +1,368 @@
+//! Helpers shared by tests.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::parser::DxEntry;
+use crate::sink::Sink;
+
+/// Log writer keeping everything written to it.
+#[derive(Clone, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl io::Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Capture log lines of the current thread until the guard is dropped.
+pub fn capture_logs() -> (Capture, tracing::subscriber::DefaultGuard) {
+    let capture = Capture::default();
+    let writer = capture.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    (capture, tracing::subscriber::set_default(subscriber))
+}
+
+/// Sink keeping the callsigns of spots sent to it.
+pub struct RecordingSink {
+    name: &'static str,
+    sent: Mutex<Vec<String>>,
+    closed: AtomicBool,
+    /// Fail every send instead of keeping the spot
+    fail: bool,
+}
+
+impl RecordingSink {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            sent: Mutex::default(),
+            closed: AtomicBool::default(),
+            fail: false,
+        }
+    }
+
+    pub fn failing(name: &'static str) -> Self {
+        Self {
+            fail: true,
+            ..Self::new(name)
+        }
+    }
+
+    pub fn sent(&self) -> Vec<String> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl Sink for RecordingSink {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        if self.fail {
+            return Err(io::Error::new(io::ErrorKind::Other, "failing sink"));
+        }
+        self.sent.lock().unwrap().push(spot.dx.clone());
+        tracing::info!("Sent to {}", self.name);
+        Ok(())
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        self.closed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// What [MockCluster] does on one connection.
+#[cfg(feature = "cqgma")]
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Sent first. The username is read only after a `login: ` prompt.
+    pub greeting: &'static str,
+    /// Sent after login
+    pub lines: Vec<&'static str>,
+    /// Close the connection after the lines instead of keeping it open
+    pub hang_up: bool,
+}
+
+#[cfg(feature = "cqgma")]
+impl Session {
+    /// Login prompt followed by `lines`.
+    pub fn spots(lines: &[&'static str], hang_up: bool) -> Self {
+        Self {
+            greeting: "login: ",
+            lines: lines.to_vec(),
+            hang_up,
+        }
+    }
+}
+
+/// Local telnet server standing in for a CQGMA cluster. Each connection is
+/// served by the next [Session]. Connections after the last session are
+/// accepted but nothing is sent to them.
+#[cfg(feature = "cqgma")]
+pub struct MockCluster {
+    /// `host:port` to connect to
+    pub host: String,
+    logins: Arc<Mutex<Vec<String>>>,
+}
+
+#[cfg(feature = "cqgma")]
+impl MockCluster {
+    pub async fn start(sessions: Vec<Session>) -> Self {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let logins = Arc::new(Mutex::new(Vec::new()));
+
+        let users = logins.clone();
+        tokio::spawn(async move {
+            let mut sessions = sessions.into_iter();
+            let mut idle = Vec::new();
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let Some(session) = sessions.next() else {
+                    idle.push(stream);
+                    continue;
+                };
+                let users = users.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    stream.write_all(session.greeting.as_bytes()).await?;
+                    if session.greeting.starts_with("login:") {
+                        let mut username = String::new();
+                        stream.read_line(&mut username).await?;
+                        users.lock().unwrap().push(username.trim().to_string());
+                    }
+                    for line in session.lines {
+                        stream.write_all(format!("{line}\r\n").as_bytes()).await?;
+                    }
+                    if !session.hang_up {
+                        // Until the client goes away
+                        while stream.read_line(&mut String::new()).await? > 0 {}
+                    }
+                    io::Result::Ok(())
+                });
+            }
+        });
+
+        Self { host, logins }
+    }
+
+    /// Usernames given at login, one for each successful login.
+    pub fn logins(&self) -> Vec<String> {
+        self.logins.lock().unwrap().clone()
+    }
+}
+
+/// Local SOCKS5 or HTTP `CONNECT` proxy. Tunnels to targets which are IP
+/// addresses, other targets are only recorded.
+#[cfg(feature = "cqgma")]
+pub struct MockProxy {
+    /// `host:port` of the proxy
+    pub address: String,
+    targets: Arc<Mutex<Vec<String>>>,
+}
+
+#[cfg(feature = "cqgma")]
+impl MockProxy {
+    /// Proxy of `kind` requiring `credentials` (username, password) if
+    /// given.
+    pub async fn start(
+        kind: crate::config::ProxyKind,
+        credentials: Option<(&'static str, &'static str)>,
+    ) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let targets = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = targets.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let mut stream = stream;
+                    let target = match kind {
+                        crate::config::ProxyKind::Socks5 => {
+                            Self::socks5(&mut stream, credentials).await?
+                        }
+                        crate::config::ProxyKind::Http => {
+                            Self::http(&mut stream, credentials).await?
+                        }
+                    };
+                    let Some(target) = target else {
+                        return Ok(());
+                    };
+                    recorded.lock().unwrap().push(target.clone());
+                    match target.parse::<std::net::SocketAddr>() {
+                        Ok(addr) => {
+                            let mut cluster = tokio::net::TcpStream::connect(addr).await?;
+                            tokio::io::copy_bidirectional(&mut stream, &mut cluster).await?;
+                        }
+                        // Until the client goes away
+                        Err(_) => {
+                            while tokio::io::AsyncReadExt::read_u8(&mut stream).await.is_ok() {}
+                        }
+                    }
+                    io::Result::Ok(())
+                });
+            }
+        });
+
+        Self { address, targets }
+    }
+
+    /// Targets the proxy accepted to connect to.
+    pub fn targets(&self) -> Vec<String> {
+        self.targets.lock().unwrap().clone()
+    }
+
+    async fn socks5(
+        stream: &mut tokio::net::TcpStream,
+        credentials: Option<(&str, &str)>,
+    ) -> io::Result<Option<String>> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut greeting = [0; 2];
+        stream.read_exact(&mut greeting).await?;
+        let mut methods = vec![0; usize::from(greeting[1])];
+        stream.read_exact(&mut methods).await?;
+        let method = if credentials.is_some() { 2 } else { 0 };
+        if !methods.contains(&method) {
+            stream.write_all(&[5, 0xff]).await?;
+            return Ok(None);
+        }
+        stream.write_all(&[5, method]).await?;
+
+        if let Some((username, password)) = credentials {
+            let mut given = Vec::new();
+            stream.read_u8().await?;
+            for _ in 0..2 {
+                let mut field = vec![0; usize::from(stream.read_u8().await?)];
+                stream.read_exact(&mut field).await?;
+                given.push(String::from_utf8_lossy(&field).into_owned());
+            }
+            if given != [username, password] {
+                stream.write_all(&[1, 1]).await?;
+                return Ok(None);
+            }
+            stream.write_all(&[1, 0]).await?;
+        }
+
+        let mut request = [0; 4];
+        stream.read_exact(&mut request).await?;
+        let host = match request[3] {
+            1 => {
+                let mut ip = [0; 4];
+                stream.read_exact(&mut ip).await?;
+                std::net::Ipv4Addr::from(ip).to_string()
+            }
+            4 => {
+                let mut ip = [0; 16];
+                stream.read_exact(&mut ip).await?;
+                format!("[{}]", std::net::Ipv6Addr::from(ip))
+            }
+            _ => {
+                let mut name = vec![0; usize::from(stream.read_u8().await?)];
+                stream.read_exact(&mut name).await?;
+                String::from_utf8_lossy(&name).into_owned()
+            }
+        };
+        let port = stream.read_u16().await?;
+        stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        Ok(Some(format!("{host}:{port}")))
+    }
+
+    async fn http(
+        stream: &mut tokio::net::TcpStream,
+        credentials: Option<(&str, &str)>,
+    ) -> io::Result<Option<String>> {
+        use base64::Engine;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await?);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let target = request
+            .strip_prefix("CONNECT ")
+            .and_then(|rest| rest.split_whitespace().next())
+            .unwrap_or_default()
+            .to_string();
+        if let Some((username, password)) = credentials {
+            let expected =
+                base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+            let header = format!("\r\nProxy-Authorization: Basic {expected}\r\n");
+            if !request.contains(&header) {
+                stream
+                    .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                    .await?;
+                return Ok(None);
+            }
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        Ok(Some(target))
+    }
+}
+
+/// Lookup knowing only `references`.
+#[cfg(any(feature = "email", feature = "ntfy"))]
+pub fn lookup(references: Vec<crate::lookup::ReferenceInfo>) -> Arc<crate::lookup::Lookup> {
+    let directory = StaticDirectory(references);
+    Arc::new(crate::lookup::Lookup::new(
+        vec![Box::new(directory)],
+        std::time::Duration::from_secs(60),
+    ))
+}
+
+#[cfg(any(feature = "email", feature = "ntfy"))]
+struct StaticDirectory(Vec<crate::lookup::ReferenceInfo>);
+
+#[cfg(any(feature = "email", feature = "ntfy"))]
+#[async_trait]
+impl crate::lookup::Directory for StaticDirectory {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    fn handles(&self, _spot: &DxEntry, _reference: &str) -> bool {
+        true
+    }
+
+    async fn fetch(&self, reference: &str) -> io::Result<Option<crate::lookup::ReferenceInfo>> {
+        Ok(self.0.iter().find(|info| info.code == reference).cloned())
+    }
+}
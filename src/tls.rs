@@ -0,0 +1,25 @@
+//! TLS for sinks speaking their own protocols over TCP, verifying servers
+//! against the bundled web PKI roots.
+
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Connector offering `alpn_protocols`, eg. `xmpp-client`, if any.
+pub fn connector(alpn_protocols: &[&[u8]]) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+    TlsConnector::from(Arc::new(config))
+}
@@ -0,0 +1,43 @@
+//! Small helpers for UTC wall clock time. Cluster spots are timestamped in
+//! UTC so there's no need for time zone handling.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+pub const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Year, month and day of `t`.
+pub fn date(t: SystemTime) -> (i64, u32, u32) {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // Days to civil date from http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / SECS_PER_DAY) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Minutes since UTC midnight.
+pub fn minute_of_day(t: SystemTime) -> u16 {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    ((secs / 60) % u64::from(MINUTES_PER_DAY)) as u16
+}
+
+/// `t` as in email and RSS dates, eg. `Fri, 01 Mar 2024 12:00:00 +0000`.
+pub fn rfc5322(t: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = date(t);
+    let weekday = WEEKDAYS[(secs / SECS_PER_DAY % 7) as usize];
+    let month = MONTHS[month as usize - 1];
+    let (hours, minutes, seconds) = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    format!("{weekday}, {day:02} {month} {year} {hours:02}:{minutes:02}:{seconds:02} +0000")
+}
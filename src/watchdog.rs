@@ -0,0 +1,163 @@
+//! Notice when spots stop flowing although the tasks seem to be running,
+//! eg. when a cluster connection hangs without closing.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::WatchdogConfig;
+use crate::parser::DxEntry;
+use crate::utc;
+
+/// How often [run] checks for a stall at most.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Wait,
+    /// No spots for a while: reconnect all clusters
+    Reconnect,
+    /// Reconnecting didn't help: exit and let the service manager restart
+    Exit,
+}
+
+/// Stall detection, with time given by the caller.
+#[derive(Debug)]
+pub struct Watchdog {
+    config: WatchdogConfig,
+    /// Last spot or the start of active hours, whichever is later
+    last_spot: SystemTime,
+    reconnected: bool,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig, now: SystemTime) -> Self {
+        Self {
+            config,
+            last_spot: now,
+            reconnected: false,
+        }
+    }
+
+    pub fn spot(&mut self, now: SystemTime) {
+        self.last_spot = now;
+        self.reconnected = false;
+    }
+
+    /// What to do at `now`. Spots are expected only within active hours, so
+    /// time outside them doesn't count as stalled.
+    pub fn check(&mut self, now: SystemTime) -> Action {
+        if let Some(active) = self.config.active_hours {
+            if !active.contains(utc::minute_of_day(now)) {
+                self.last_spot = now;
+                return Action::Wait;
+            }
+        }
+        let stall = Duration::from_secs(self.config.stall_secs);
+        if now.duration_since(self.last_spot).unwrap_or_default() < stall {
+            return Action::Wait;
+        }
+        if self.reconnected {
+            return Action::Exit;
+        }
+        // Give reconnecting as long to help
+        self.last_spot = now;
+        self.reconnected = true;
+        Action::Reconnect
+    }
+}
+
+/// Watch `spots` until `shutdown` is cancelled. Clusters are reconnected
+/// through `reconnect`, see [CqgmaState::reconnect], and `exit` is
+/// cancelled if that didn't help.
+///
+/// [CqgmaState::reconnect]: crate::cqgma::CqgmaState::reconnect
+pub async fn run(
+    config: WatchdogConfig,
+    mut spots: broadcast::Receiver<Arc<DxEntry>>,
+    reconnect: Vec<Arc<Notify>>,
+    exit: CancellationToken,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let check_every = CHECK_INTERVAL.min(Duration::from_secs(config.stall_secs));
+    let mut check = tokio::time::interval(check_every);
+    let mut watchdog = Watchdog::new(config, SystemTime::now());
+
+    loop {
+        tokio::select! {
+            received = spots.recv() => match received {
+                Ok(_) | Err(RecvError::Lagged(_)) => watchdog.spot(SystemTime::now()),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = check.tick() => match watchdog.check(SystemTime::now()) {
+                Action::Wait => (),
+                Action::Reconnect => {
+                    tracing::warn!("No spots for a while. Reconnecting to clusters.");
+                    for cluster in &reconnect {
+                        cluster.notify_waiters();
+                    }
+                }
+                Action::Exit => {
+                    tracing::error!("Still no spots after reconnecting. Exiting.");
+                    exit.cancel();
+                    return Ok(());
+                }
+            },
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{Action, Watchdog};
+    use crate::config::{QuietHours, WatchdogConfig};
+
+    #[test]
+    fn test_stall_detection() {
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        // 08:00 UTC
+        let start = UNIX_EPOCH + minutes(8 * 60);
+        let config = WatchdogConfig {
+            stall_secs: 30 * 60,
+            active_hours: None,
+        };
+        let mut watchdog = Watchdog::new(config.clone(), start);
+
+        assert_eq!(watchdog.check(start + minutes(29)), Action::Wait);
+        watchdog.spot(start + minutes(29));
+        assert_eq!(watchdog.check(start + minutes(58)), Action::Wait);
+        assert_eq!(watchdog.check(start + minutes(59)), Action::Reconnect);
+        assert_eq!(watchdog.check(start + minutes(60)), Action::Wait);
+        assert_eq!(watchdog.check(start + minutes(89)), Action::Exit);
+
+        // Reconnecting helped
+        let mut watchdog = Watchdog::new(config, start);
+        assert_eq!(watchdog.check(start + minutes(30)), Action::Reconnect);
+        watchdog.spot(start + minutes(40));
+        assert_eq!(watchdog.check(start + minutes(69)), Action::Wait);
+        assert_eq!(watchdog.check(start + minutes(70)), Action::Reconnect);
+
+        // Quiet night doesn't count. Active from 06:00 to 22:00.
+        let config = WatchdogConfig {
+            stall_secs: 30 * 60,
+            active_hours: Some(QuietHours {
+                start: "06:00".to_string().try_into().unwrap(),
+                end: "22:00".to_string().try_into().unwrap(),
+            }),
+        };
+        let evening = start + minutes(13 * 60);
+        let mut watchdog = Watchdog::new(config, evening);
+        assert_eq!(watchdog.check(evening + minutes(2 * 60)), Action::Wait);
+        let dawn = evening + minutes(9 * 60);
+        assert_eq!(watchdog.check(dawn - minutes(1)), Action::Wait);
+        assert_eq!(watchdog.check(dawn + minutes(28)), Action::Wait);
+        assert_eq!(watchdog.check(dawn + minutes(29)), Action::Reconnect);
+    }
+}
@@ -0,0 +1,215 @@
+//! POSTing spots as JSON to any HTTP endpoint.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, StatusCode, Url};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::WebhookConfig;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+
+/// Wait time after the first failed request. Doubled after each retry.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Body of each request: the parsed fields of the spot and the line they
+/// were parsed from.
+#[derive(Serialize)]
+struct Payload<'a> {
+    line: &'a str,
+    #[serde(flatten)]
+    spot: &'a DxEntry,
+}
+
+/// Why a request failed and whether it's worth trying again.
+struct Failure {
+    transient: bool,
+    message: String,
+}
+
+pub struct WebhookSink {
+    name: String,
+    client: Client,
+    url: Url,
+    headers: HeaderMap,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl WebhookSink {
+    /// Sink of `config`, the `index`th in the config.
+    pub fn new(config: &WebhookConfig, index: usize) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let url = Url::parse(&config.url).map_err(|err| invalid(format!("url: {err}")))?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|err| invalid(format!("header {name}: {err}")))?;
+            let mut value = HeaderValue::try_from(value.as_str())
+                .map_err(|err| invalid(format!("header {name}: {err}")))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        let client = Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        Ok(Self {
+            // Path and query may hold credentials, so they're left out
+            name: format!("webhook[{index}] {}", url.host_str().unwrap_or_default()),
+            client,
+            url,
+            headers,
+            retries: config.retries,
+            backoff: RETRY_BACKOFF,
+        })
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<(), Failure> {
+        let response = self
+            .client
+            .post(self.url.clone())
+            .headers(self.headers.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_vec())
+            .send()
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                Err(Failure {
+                    transient: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                    message: format!("HTTP {status}"),
+                })
+            }
+            Err(err) => Err(Failure {
+                transient: err.is_timeout() || err.is_connect() || err.is_request(),
+                message: err.without_url().to_string(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let body = serde_json::to_vec(&Payload {
+            line: &spot.line,
+            spot,
+        })?;
+        let mut backoff = self.backoff;
+        let mut retries = self.retries;
+        loop {
+            match self.post(&body).await {
+                Ok(()) => return Ok(()),
+                Err(failure) if failure.transient && retries > 0 => {
+                    tracing::debug!(
+                        "POST to {} failed: {}. Retrying in {} seconds.",
+                        self.name,
+                        failure.message,
+                        backoff.as_secs_f32()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    retries -= 1;
+                }
+                Err(failure) => return Err(io::Error::new(io::ErrorKind::Other, failure.message)),
+            }
+        }
+    }
+}
+
+/// POST spots from `spots` as configured in `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: WebhookConfig,
+    index: usize,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = WebhookSink::new(&config, index)?;
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use tokio::net::TcpListener;
+
+    use super::WebhookSink;
+    use crate::config::WebhookConfig;
+    use crate::sink::Sink;
+
+    type Received = Arc<Mutex<Vec<(Option<String>, serde_json::Value)>>>;
+
+    /// Fails the first request with 503 and records the rest.
+    async fn receive(
+        State(received): State<Received>,
+        headers: HeaderMap,
+        Json(body): Json<serde_json::Value>,
+    ) -> StatusCode {
+        let mut received = received.lock().unwrap();
+        let auth = headers
+            .get("authorization")
+            .map(|v| v.to_str().unwrap().to_string());
+        received.push((auth, body));
+        if received.len() == 1 {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::NO_CONTENT
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_spot() {
+        let received = Received::default();
+        let app = Router::new()
+            .route("/spots", post(receive))
+            .with_state(received.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = WebhookConfig {
+            url: format!("http://{addr}/spots"),
+            headers: [("Authorization".to_string(), "Bearer hunter2".to_string())].into(),
+            timeout_secs: 5,
+            retries: 1,
+        };
+        let mut sink = WebhookSink::new(&config, 0).unwrap();
+        sink.backoff = Duration::from_millis(1);
+        assert_eq!(sink.name(), "webhook[0] 127.0.0.1");
+
+        let line = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z";
+        sink.send(&line.parse().unwrap()).await.unwrap();
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let (auth, body) = &received[1];
+        assert_eq!(auth.as_deref(), Some("Bearer hunter2"));
+        assert_eq!(body["line"], line);
+        assert_eq!(body["dx"], "OH2NOS/P");
+        assert_eq!(body["reporter"], "OH2NOS");
+        assert_eq!(body["frequency"], 3644.0);
+        assert_eq!(body["timestamp"], "1146");
+    }
+}
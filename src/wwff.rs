@@ -0,0 +1,155 @@
+//! WWFF reference names and locations from the directory file published at
+//! <https://wwff.co/wwff-data/wwff_directory.csv>, so lookups work offline.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use async_trait::async_trait;
+
+use crate::lookup::{Directory, ReferenceInfo};
+use crate::parser::{Activity, DxEntry};
+
+/// References of the directory file, read to memory.
+#[derive(Debug, Default)]
+pub struct WwffDirectory {
+    references: HashMap<String, ReferenceInfo>,
+}
+
+impl WwffDirectory {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Directory from CSV with a header naming at least the `reference` and
+    /// `name` columns. `latitude`, `longitude` and `status` are used if
+    /// present and deleted references are left out.
+    pub fn parse(csv: &str) -> io::Result<Self> {
+        let mut lines = csv.lines();
+        let header = fields(lines.next().unwrap_or_default());
+        let column = |name: &str| header.iter().position(|field| field == name);
+        let missing = |name: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no {name} column in WWFF directory"),
+            )
+        };
+        let reference = column("reference").ok_or_else(|| missing("reference"))?;
+        let name = column("name").ok_or_else(|| missing("name"))?;
+        let (latitude, longitude) = (column("latitude"), column("longitude"));
+        let status = column("status");
+
+        let mut references = HashMap::new();
+        for line in lines {
+            let fields = fields(line);
+            let get = |i: Option<usize>| i.and_then(|i| fields.get(i)).map(String::as_str);
+            let (Some(code), Some(name)) = (get(Some(reference)), get(Some(name))) else {
+                continue;
+            };
+            if get(status) == Some("deleted") {
+                continue;
+            }
+            let coordinate = |i| get(i).and_then(|value| value.parse::<f64>().ok());
+            let info = ReferenceInfo {
+                code: code.to_uppercase(),
+                name: name.to_string(),
+                details: None,
+                location: coordinate(latitude).zip(coordinate(longitude)),
+            };
+            references.insert(info.code.clone(), info);
+        }
+        Ok(Self { references })
+    }
+
+    pub fn len(&self) -> usize {
+        self.references.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.references.is_empty()
+    }
+}
+
+/// Fields of a CSV line. Quoted fields may have commas and `""` in them,
+/// but not line breaks.
+fn fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[async_trait]
+impl Directory for WwffDirectory {
+    fn name(&self) -> &str {
+        "wwff"
+    }
+
+    /// WWFF references have `FF` in the program, eg. `OHFF-1419`.
+    fn handles(&self, spot: &DxEntry, reference: &str) -> bool {
+        let activity = spot.cqgma_identifier.map(|(activity, _)| activity);
+        matches!(activity, None | Some(Activity::Wwff)) && reference.contains("FF-")
+    }
+
+    async fn fetch(&self, reference: &str) -> io::Result<Option<ReferenceInfo>> {
+        Ok(self.references.get(reference).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{fields, WwffDirectory};
+    use crate::lookup::Lookup;
+    use crate::parser::DxEntry;
+
+    const DIRECTORY: &str = "\
+reference,status,name,program,dxcc,state,county,continent,iota,iaruLocator,latitude,longitude
+OHFF-1419,active,Kuusijärvi,OHFF,OH,,,EU,,KP20le,60.3063,25.1089
+PAFF-0237,active,\"Drents-Friese Wold, Het\",PAFF,PA,,,EU,,JO32aw,52.9268,6.2405
+OHFF-0001,deleted,Gone,OHFF,OH,,,EU,,,,
+";
+
+    #[test]
+    fn test_fields() {
+        assert_eq!(fields("a,\"b, \"\"c\"\"\",,d"), ["a", "b, \"c\"", "", "d"]);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_reference() {
+        let wwff = WwffDirectory::parse(DIRECTORY).unwrap();
+        assert_eq!(wwff.len(), 2);
+        let lookup = Lookup::new(vec![Box::new(wwff)], Duration::from_secs(60));
+
+        let spot: DxEntry =
+            "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+                .parse()
+                .unwrap();
+        let info = lookup.reference_info(&spot).await.unwrap();
+        assert_eq!(info.to_string(), "OHFF-1419 Kuusijärvi");
+        assert_eq!(info.location, Some((60.3063, 25.1089)));
+
+        let spot: DxEntry =
+            "DX de PA3GDY:     7144.0  PA3GDY/P     paff-0237 ssb                  0910Z"
+                .parse()
+                .unwrap();
+        let info = lookup.reference_info(&spot).await.unwrap();
+        assert_eq!(info.name, "Drents-Friese Wold, Het");
+
+        assert!(WwffDirectory::parse("code,name\n").is_err());
+    }
+}
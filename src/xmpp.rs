@@ -0,0 +1,511 @@
+//! Posting spots to XMPP multi-user chat rooms (XEP-0045).
+//!
+//! Only what a bot needs: direct TLS (XEP-0368), SASL PLAIN, resource
+//! binding and joining one room. STARTTLS isn't supported. Stanzas from
+//! the server are recognized by looking for their closing tags instead of
+//! parsing XML.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::config::XmppConfig;
+use crate::feed::escape;
+use crate::metrics::Metrics;
+use crate::parser::DxEntry;
+use crate::sink::{self, Sink};
+use crate::template::Template;
+use crate::tls;
+
+/// Time for connecting, logging in and joining the room.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Resource bound to the account.
+const RESOURCE: &str = "puskapupu";
+
+/// Logged in connection which has joined the room.
+struct Session {
+    writer: WriteHalf<TlsStream<TcpStream>>,
+    /// Reads away whatever the server sends. Finishes when the connection
+    /// is closed.
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+pub struct XmppSink {
+    name: String,
+    host: String,
+    port: u16,
+    local: String,
+    domain: String,
+    password: String,
+    room: String,
+    /// Room JID with nickname, eg. `spots@conference.example.org/puskapupu`
+    occupant: String,
+    template: Template,
+    tls: TlsConnector,
+    /// Connection, or `None` until the next send connects
+    session: Mutex<Option<Session>>,
+}
+
+impl XmppSink {
+    pub fn new(config: &XmppConfig) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let (local, domain) = config
+            .account()
+            .ok_or_else(|| invalid("invalid XMPP jid"))?;
+        let (host, port) = config
+            .server_addr()
+            .ok_or_else(|| invalid("invalid XMPP server"))?;
+        Ok(Self {
+            name: format!("xmpp {}", config.room),
+            host: host.to_string(),
+            port,
+            local: local.to_string(),
+            domain: domain.to_string(),
+            password: config.password.clone(),
+            room: config.room.clone(),
+            occupant: format!("{}/{}", config.room, config.nick),
+            template: config.template.clone().unwrap_or_default(),
+            tls: tls::connector(&[b"xmpp-client"]),
+            session: Mutex::new(None),
+        })
+    }
+
+    /// Groupchat message stanza of `spot`.
+    fn message(&self, spot: &DxEntry) -> String {
+        format!(
+            "<message to='{}' type='groupchat'><body>{}</body></message>",
+            escape(&self.room),
+            escape(&self.template.render(spot))
+        )
+    }
+
+    async fn connect(&self) -> io::Result<Session> {
+        let connect = async {
+            let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+            stream.set_nodelay(true)?;
+            let server_name = ServerName::try_from(self.domain.as_str())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let mut stream = self.tls.connect(server_name, stream).await?;
+            login(
+                &mut stream,
+                (&self.local, &self.domain),
+                &self.password,
+                &self.occupant,
+            )
+            .await?;
+            Ok::<_, io::Error>(stream)
+        };
+        let stream = tokio::time::timeout(LOGIN_TIMEOUT, connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "XMPP login timed out"))??;
+        let (reader, writer) = tokio::io::split(stream);
+        let reader = tokio::spawn(drain(reader).in_current_span());
+        Ok(Session { writer, reader })
+    }
+}
+
+#[async_trait]
+impl Sink for XmppSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Connects first if not connected. A failed connection fails the send,
+    /// so reconnecting backs off like any failing sink.
+    async fn send(&self, spot: &DxEntry) -> io::Result<()> {
+        let message = self.message(spot);
+        let mut session = self.session.lock().await;
+        if session
+            .as_ref()
+            .map_or(false, |session| session.reader.is_finished())
+        {
+            tracing::warn!("Lost connection to XMPP server. Reconnecting.");
+            *session = None;
+        }
+        let writer = match session.as_mut() {
+            Some(session) => &mut session.writer,
+            None => {
+                let new = self.connect().await?;
+                tracing::info!("Joined XMPP room {}", self.occupant);
+                &mut session.insert(new).writer
+            }
+        };
+        tracing::debug!("xmpp tx: ^{message}$");
+        let result = async {
+            writer.write_all(message.as_bytes()).await?;
+            writer.flush().await
+        };
+        if let Err(err) = result.await {
+            *session = None;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    async fn close(&self) -> io::Result<()> {
+        match self.session.lock().await.take() {
+            Some(mut session) => {
+                session.writer.write_all(b"</stream:stream>").await?;
+                session.writer.shutdown().await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Open the stream, authenticate, bind a resource and join the room as
+/// `occupant`.
+async fn login<S>(
+    stream: &mut S,
+    (local, domain): (&str, &str),
+    password: &str,
+    occupant: &str,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    let features = open_stream(stream, &mut buf, domain).await?;
+    if !features.contains("<mechanism>PLAIN</mechanism>") {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "XMPP server doesn't offer SASL PLAIN",
+        ));
+    }
+    let credentials = STANDARD.encode(format!("\0{local}\0{password}"));
+    write(
+        stream,
+        &format!(
+            "<auth xmlns='urn:ietf:params:xml:ns:xmpp-sasl' mechanism='PLAIN'>{credentials}</auth>"
+        ),
+    )
+    .await?;
+    let answer = read_until(stream, &mut buf, &["<success", "</failure>"]).await?;
+    if !answer.ends_with("<success") {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("XMPP login failed: {answer}"),
+        ));
+    }
+
+    // The stream starts over after authentication
+    open_stream(stream, &mut buf, domain).await?;
+    write(
+        stream,
+        &format!("<iq type='set' id='bind'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'><resource>{RESOURCE}</resource></bind></iq>"),
+    )
+    .await?;
+    let answer = read_until(stream, &mut buf, &["</iq>"]).await?;
+    if !answer.contains("<jid>") {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("XMPP resource binding failed: {answer}"),
+        ));
+    }
+
+    write(
+        stream,
+        &format!(
+            "<presence to='{}'><x xmlns='http://jabber.org/protocol/muc'><history maxstanzas='0'/></x></presence>",
+            escape(occupant)
+        ),
+    )
+    .await?;
+    // Presences of others in the room come before our own
+    loop {
+        let presence = read_until(stream, &mut buf, &["</presence>"]).await?;
+        if has_attr(&presence, "type", "error") {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Couldn't join XMPP room: {presence}"),
+            ));
+        }
+        if has_attr(&presence, "code", "110") {
+            return Ok(());
+        }
+    }
+}
+
+/// Send the stream header and return the stream features.
+async fn open_stream<S>(stream: &mut S, buf: &mut Vec<u8>, domain: &str) -> io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write(
+        stream,
+        &format!(
+            "<?xml version='1.0'?><stream:stream to='{}' version='1.0' xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams'>",
+            escape(domain)
+        ),
+    )
+    .await?;
+    read_until(stream, buf, &["</stream:features>"]).await
+}
+
+async fn write<S: AsyncWrite + Unpin>(stream: &mut S, data: &str) -> io::Result<()> {
+    tracing::trace!("xmpp tx: ^{data}$");
+    stream.write_all(data.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Read until one of `markers`, and return what was read up to and
+/// including it. What comes after is left in `buf` for the next read.
+/// Bytes are decoded only up to a marker, so characters split between
+/// reads stay whole.
+async fn read_until<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    markers: &[&str],
+) -> io::Result<String> {
+    let mut chunk = [0; 4096];
+    loop {
+        let found = markers
+            .iter()
+            .filter_map(|marker| find(buf, marker).map(|at| at + marker.len()))
+            .min();
+        if let Some(end) = found {
+            let rest = buf.split_off(end);
+            let read = std::mem::replace(buf, rest);
+            let read = String::from_utf8_lossy(&read).into_owned();
+            tracing::trace!("xmpp rx: ^{read}$");
+            return Ok(read);
+        }
+        if let Some(start) = find(buf, "<stream:error") {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!(
+                    "XMPP stream error: {}",
+                    String::from_utf8_lossy(&buf[start..])
+                ),
+            ));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "XMPP server closed the connection",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Position of `marker` in `buf`.
+fn find(buf: &[u8], marker: &str) -> Option<usize> {
+    buf.windows(marker.len())
+        .position(|window| window == marker.as_bytes())
+}
+
+/// Read and ignore everything until the connection is closed.
+async fn drain(mut reader: ReadHalf<TlsStream<TcpStream>>) {
+    let mut chunk = [0; 4096];
+    loop {
+        match reader.read(&mut chunk).await {
+            Ok(0) => return,
+            Ok(n) => tracing::trace!("xmpp rx: ^{}$", String::from_utf8_lossy(&chunk[..n])),
+            Err(err) => {
+                tracing::debug!("Couldn't read from XMPP server: {err}");
+                return;
+            }
+        }
+    }
+}
+
+/// Whether `name='value'` or `name="value"` is in `text`.
+fn has_attr(text: &str, name: &str, value: &str) -> bool {
+    text.contains(&format!("{name}='{value}'")) || text.contains(&format!("{name}=\"{value}\""))
+}
+
+/// Post spots from `spots` to the room of `config` until `shutdown` is
+/// cancelled.
+pub async fn run(
+    config: XmppConfig,
+    spots: broadcast::Receiver<Arc<DxEntry>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let sink = XmppSink::new(&config)?;
+    let health = metrics.sink(sink.name());
+    sink::run(Box::new(sink), spots, health, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::{login, read_until, XmppSink};
+    use crate::config::XmppConfig;
+    use crate::template::Template;
+
+    fn config() -> XmppConfig {
+        XmppConfig {
+            jid: "puskapupu@example.org".to_string(),
+            password: "hunter2".to_string(),
+            server: None,
+            room: "spots@conference.example.org".to_string(),
+            nick: "pupu".to_string(),
+            template: None,
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let mut sink = XmppSink::new(&config()).unwrap();
+        let spot = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            sink.message(&spot),
+            "<message to='spots@conference.example.org' type='groupchat'>\
+             <body>OH2NOS/P 3.644 MHz OHFF-1419 New one! (de OH2NOS 1146Z)</body></message>"
+        );
+
+        sink.template = Template::parse("<b>{dx}</b> & '{band}'\u{7}").unwrap();
+        assert_eq!(
+            sink.message(&spot),
+            "<message to='spots@conference.example.org' type='groupchat'>\
+             <body>&lt;b&gt;OH2NOS/P&lt;/b&gt; &amp; &apos;80m&apos;</body></message>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            let mut received = Vec::new();
+            let features = "<stream:stream from='example.org' id='1' version='1.0' \
+                xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams'>\
+                <stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>\
+                <mechanism>PLAIN</mechanism></mechanisms></stream:features>";
+            received.push(read_until(&mut server, &mut buf, &["'>"]).await.unwrap());
+            server.write_all(features.as_bytes()).await.unwrap();
+            received.push(
+                read_until(&mut server, &mut buf, &["</auth>"])
+                    .await
+                    .unwrap(),
+            );
+            server
+                .write_all(b"<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>")
+                .await
+                .unwrap();
+            read_until(&mut server, &mut buf, &["'>"]).await.unwrap();
+            server
+                .write_all(
+                    b"<stream:stream><stream:features>\
+                      <bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/></stream:features>",
+                )
+                .await
+                .unwrap();
+            received.push(read_until(&mut server, &mut buf, &["</iq>"]).await.unwrap());
+            server
+                .write_all(b"<iq type='result' id='bind'><bind><jid>puskapupu@example.org/puskapupu</jid></bind></iq>")
+                .await
+                .unwrap();
+            received.push(
+                read_until(&mut server, &mut buf, &["</presence>"])
+                    .await
+                    .unwrap(),
+            );
+            server
+                .write_all(
+                    b"<presence from='spots@conference.example.org/OH8HUB'><x/></presence>\
+                      <presence from='spots@conference.example.org/pupu'>\
+                      <x><status code=\"110\"/></x></presence>",
+                )
+                .await
+                .unwrap();
+            received
+        });
+
+        login(
+            &mut client,
+            ("puskapupu", "example.org"),
+            "hunter2",
+            "spots@conference.example.org/pupu",
+        )
+        .await
+        .unwrap();
+        let received = server.await.unwrap();
+        assert!(received[0].contains("to='example.org'"));
+        // base64 of "\0puskapupu\0hunter2"
+        assert!(received[1].contains(">AHB1c2thcHVwdQBodW50ZXIy</auth>"));
+        assert!(received[2].contains("<resource>puskapupu</resource>"));
+        assert!(received[3].starts_with("<presence to='spots@conference.example.org/pupu'>"));
+    }
+
+    #[tokio::test]
+    async fn test_read_until_split_char() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let text = "<body>Kuusijärvi</body>".as_bytes();
+        // In the middle of ä
+        let split = text.iter().position(|&b| b >= 0x80).unwrap() + 1;
+        let mut buf = Vec::new();
+        let read = async {
+            read_until(&mut client, &mut buf, &["</body>"])
+                .await
+                .unwrap()
+        };
+        let write = async {
+            server.write_all(&text[..split]).await.unwrap();
+            tokio::task::yield_now().await;
+            server.write_all(&text[split..]).await.unwrap();
+        };
+        let (read, ()) = tokio::join!(read, write);
+        assert_eq!(read, "<body>Kuusijärvi</body>");
+    }
+
+    #[tokio::test]
+    async fn test_login_failure() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            read_until(&mut server, &mut buf, &["'>"]).await.unwrap();
+            server
+                .write_all(
+                    b"<stream:stream><stream:features><mechanisms>\
+                      <mechanism>PLAIN</mechanism></mechanisms></stream:features>",
+                )
+                .await
+                .unwrap();
+            read_until(&mut server, &mut buf, &["</auth>"])
+                .await
+                .unwrap();
+            server
+                .write_all(b"<failure><not-authorized/></failure>")
+                .await
+                .unwrap();
+            // Keep the connection open until the client gives up
+            let _ = read_until(&mut server, &mut buf, &["never"]).await;
+        });
+
+        let err = login(
+            &mut client,
+            ("puskapupu", "example.org"),
+            "wrong",
+            "spots@conference.example.org/pupu",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,23 @@
+//! Parsing and filtering spots without any features, as an embedder not
+//! wanting the cluster or Matrix code would. Run with
+//! `cargo test --no-default-features --test minimal` to check the crate
+//! builds that way.
+
+use std::time::SystemTime;
+
+use puskapupu::band::Band;
+use puskapupu::filter::FilterConfig;
+use puskapupu::parser::DxEntry;
+
+#[test]
+fn test_parse_and_filter() {
+    let line = "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 CW New one!     1146Z";
+    let entry: DxEntry = line.parse().unwrap();
+    assert_eq!(entry.dx, "OH2NOS/P");
+    assert_eq!(entry.band(), Some(Band::B80m));
+    assert_eq!(entry.references(), ["OHFF-1419"]);
+
+    let filter = FilterConfig::default();
+    assert!(filter.matches_line(line));
+    assert!(filter.matches(&entry, SystemTime::now()));
+}
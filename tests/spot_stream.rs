@@ -0,0 +1,82 @@
+//! Spots of a cluster through [SpotStream], as an embedder would get them.
+//! Needs the `cqgma` feature.
+
+#![cfg(feature = "cqgma")]
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use puskapupu::config::CqgmaConfig;
+use puskapupu::filter::FilterConfig;
+use puskapupu::stream::SpotStream;
+
+const LINES: &[&str] = &[
+    "DX de OH8HUB:    14310.0  AD6VT        x04s W6/ND-101                 1959Z",
+    "OH8HUB de OH2NOS",
+    // Neither the spotter nor the reference is Finnish
+    "DX de AD6VT:     14310.0  AD6VT        x04s W6/ND-101                 1959Z",
+    "DX de OH2NOS:     3644.0  OH2NOS/P     x01f OHFF-1419 New one!        1146Z",
+    "DX de OH2NOS:     3644.0",
+];
+
+/// Telnet server asking for login and then sending [LINES], once they are
+/// being waited for.
+async fn mock_cluster(subscribed: tokio::sync::oneshot::Receiver<()>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let host = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        stream.write_all(b"login: ").await.unwrap();
+        let mut username = String::new();
+        stream.read_line(&mut username).await.unwrap();
+        assert_eq!(username.trim(), "N0CALL");
+        subscribed.await.unwrap();
+        for line in LINES {
+            stream
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .unwrap();
+        }
+        // Until the client goes away
+        while stream.read_line(&mut String::new()).await.unwrap_or(0) > 0 {}
+    });
+    host
+}
+
+#[tokio::test]
+async fn test_spot_stream() {
+    let (subscribed_tx, subscribed) = tokio::sync::oneshot::channel();
+    let cluster = CqgmaConfig {
+        host: mock_cluster(subscribed).await,
+        username: "N0CALL".to_string(),
+        password: None,
+        filter: None,
+        reconnect_min_secs: 0,
+        reconnect_max_secs: 0,
+        max_consecutive_failures: None,
+        proxy: None,
+    };
+    let stream = SpotStream::start(&[cluster], FilterConfig::default()).await;
+    let spots = stream.spots();
+    subscribed_tx.send(()).unwrap();
+
+    let spots: Vec<_> = tokio::time::timeout(Duration::from_secs(5), spots.take(2).collect())
+        .await
+        .expect("spots in time");
+    let calls: Vec<_> = spots.iter().map(|spot| spot.dx.as_str()).collect();
+    assert_eq!(calls, ["AD6VT", "OH2NOS/P"]);
+    assert_eq!(spots[1].references(), ["OHFF-1419"]);
+    assert!(stream.is_connected());
+
+    let stopped = stream.spots();
+    stream.stop().await.unwrap();
+    // Ends once the clusters are disconnected
+    let rest: Vec<_> = tokio::time::timeout(Duration::from_secs(5), stopped.collect())
+        .await
+        .expect("end of spots in time");
+    assert!(rest.is_empty());
+}